@@ -0,0 +1,112 @@
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+use std::process::Command;
+use std::time::Duration;
+
+use py_spy::chrometrace::Chrometrace;
+use py_spy::stack_trace::FrameKind;
+use py_spy::{Config, Frame, PythonSpy, StackTrace};
+
+const STACK_DEPTH: usize = 256;
+const THREADS: u64 = 8;
+
+const DEEP_STACK: &str = "
+import time
+
+def recurse(depth):
+    if depth == 0:
+        time.sleep(1000)
+    recurse(depth - 1)
+
+recurse(256)
+";
+
+/// Samples a python process that is sleeping at the bottom of a deep recursion. This measures
+/// the cost of walking the frames of another process, with and without batching up the reads
+fn bench_frame_walk(c: &mut Criterion) {
+    let mut child = Command::new("python")
+        .arg("-c")
+        .arg(DEEP_STACK)
+        .spawn()
+        .expect("Failed to start python");
+
+    let mut group = c.benchmark_group("frame_walk");
+    for read_batch_size in [0, 4096] {
+        let config = Config {
+            read_batch_size,
+            ..Default::default()
+        };
+        let mut spy = PythonSpy::retry_new(child.id() as _, &config, 20).unwrap();
+        // wait for the recursion to bottom out
+        while spy.get_stack_traces().unwrap()[0].frames.len() <= STACK_DEPTH {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        group.bench_function(format!("batch_{}", read_batch_size), |b| {
+            b.iter(|| black_box(spy.get_stack_traces().unwrap()))
+        });
+    }
+    group.finish();
+
+    child.kill().unwrap();
+    child.wait().unwrap();
+}
+
+fn trace(thread_id: u64, leaf: &str) -> StackTrace {
+    let frame = |name: String, line: i32| Frame {
+        name,
+        filename: format!("/srv/app/worker_{}.py", thread_id),
+        module: None,
+        short_filename: None,
+        line,
+        locals: None,
+        is_native: false,
+        frame_kind: FrameKind::Regular,
+        code_id: None,
+    };
+    let mut frames = vec![frame(leaf.to_owned(), 1)];
+    frames.extend((0..63).map(|depth| frame(format!("function_{}", depth), depth)));
+    StackTrace {
+        pid: 1,
+        thread_id,
+        interpreter_id: None,
+        thread_name: Some(format!("Thread-{}", thread_id)),
+        daemon: None,
+        os_thread_id: Some(thread_id),
+        active: true,
+        owns_gil: false,
+        traced: false,
+        frames,
+        incomplete: false,
+        task_address: None,
+        process_info: None,
+    }
+}
+
+/// Feeds chrometrace batches of samples from several threads, where the leaf frame of each
+/// thread changes on every sample (so that begin/end events are written each time)
+fn bench_chrometrace_increment(c: &mut Criterion) {
+    let batches: Vec<Vec<StackTrace>> = ["compute", "wait"]
+        .iter()
+        .map(|leaf| (0..THREADS).map(|tid| trace(tid, leaf)).collect())
+        .collect();
+
+    let config = Config {
+        show_line_numbers: true,
+        ..Default::default()
+    };
+    let mut chrometrace = Chrometrace::new(&config).unwrap();
+    let mut sample = 0;
+    c.bench_function("chrometrace_increment", |b| {
+        b.iter_batched(
+            || {
+                sample += 1;
+                let timestamp = Duration::from_millis(sample as u64 * 10);
+                (batches[sample % batches.len()].clone(), timestamp)
+            },
+            |(batch, timestamp)| chrometrace.increment(batch, timestamp).unwrap(),
+            BatchSize::SmallInput,
+        )
+    });
+}
+
+criterion_group!(benches, bench_frame_walk, bench_chrometrace_increment);
+criterion_main!(benches);
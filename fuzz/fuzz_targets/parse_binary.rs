@@ -0,0 +1,12 @@
+#![no_main]
+
+use std::path::Path;
+
+use libfuzzer_sys::fuzz_target;
+use py_spy::binary_parser::parse_binary_bytes;
+
+// Malformed binaries should return an error rather than panicking. Run with
+// 'cargo +nightly fuzz run parse_binary'
+fuzz_target!(|data: &[u8]| {
+    let _ = parse_binary_bytes(Path::new("fuzz"), data, 0x400000, 0x1000, None);
+});
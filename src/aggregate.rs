@@ -0,0 +1,113 @@
+use std::collections::{HashMap, HashSet};
+
+use serde_derive::Serialize;
+
+use crate::stack_trace::StackTrace;
+
+/// The number of samples a function was seen in
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+pub struct FunctionStat {
+    pub name: String,
+    pub filename: String,
+    /// The number of samples where this was the innermost function
+    pub own: u64,
+    /// The number of samples where this function was anywhere on the stack
+    pub total: u64,
+}
+
+/// Aggregates stack traces into the number of samples each function was seen in, like the
+/// table shown by 'py-spy top'. Functions are keyed by their name and filename, and every
+/// trace is counted as a sample - so idle threads should be filtered out beforehand if they
+/// aren't wanted.
+#[derive(Debug, Default)]
+pub struct Aggregator {
+    // (own, total) by (name, filename)
+    counts: HashMap<(String, String), (u64, u64)>,
+    samples: u64,
+}
+
+impl Aggregator {
+    pub fn new() -> Aggregator {
+        Aggregator::default()
+    }
+
+    pub fn add(&mut self, traces: &[StackTrace]) {
+        for trace in traces {
+            self.samples += 1;
+            // recursive functions can show up several times in the same stack, only count
+            // these once per sample so that totals stay below the number of samples
+            let mut seen = HashSet::new();
+            for (i, frame) in trace.frames.iter().enumerate() {
+                if !seen.insert((&frame.name, &frame.filename)) {
+                    continue;
+                }
+                let counts = self
+                    .counts
+                    .entry((frame.name.clone(), frame.filename.clone()))
+                    .or_default();
+                if i == 0 {
+                    counts.0 += 1;
+                }
+                counts.1 += 1;
+            }
+        }
+    }
+
+    /// The number of traces that have been added
+    pub fn samples(&self) -> u64 {
+        self.samples
+    }
+
+    /// Returns the stats for each function, ordered by total and then own samples
+    pub fn finish(self) -> Vec<FunctionStat> {
+        let mut stats: Vec<FunctionStat> = self
+            .counts
+            .into_iter()
+            .map(|((name, filename), (own, total))| FunctionStat {
+                name,
+                filename,
+                own,
+                total,
+            })
+            .collect();
+        stats.sort_by(|a, b| {
+            (b.total, b.own)
+                .cmp(&(a.total, a.own))
+                .then_with(|| (&a.name, &a.filename).cmp(&(&b.name, &b.filename)))
+        });
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trace(names: &[&str]) -> StackTrace {
+        names
+            .iter()
+            .fold(StackTrace::builder(), |builder, name| {
+                builder.frame(name, "app.py", 0)
+            })
+            .build()
+    }
+
+    #[test]
+    fn test_aggregator() {
+        let mut aggregator = Aggregator::new();
+        // frames are leaf first
+        aggregator.add(&[
+            trace(&["fib", "fib", "fib", "main"]),
+            trace(&["parse", "main"]),
+        ]);
+        aggregator.add(&[trace(&["fib", "main"])]);
+        assert_eq!(aggregator.samples(), 3);
+
+        let stats = aggregator.finish();
+        let stats: Vec<(&str, u64, u64)> = stats
+            .iter()
+            .map(|stat| (stat.name.as_str(), stat.own, stat.total))
+            .collect();
+        assert_eq!(stats, vec![("main", 0, 3), ("fib", 2, 2), ("parse", 1, 1)]);
+    }
+}
@@ -0,0 +1,199 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::io::Write;
+use std::sync::Arc;
+
+use anyhow::Error;
+
+use crate::stack_trace::{Frame, ProcessInfo, StackTrace};
+
+/// Replaces function and file names in stack traces with opaque tokens, so that profiles
+/// can be shared without exposing the names in the profiled code. The same name always maps
+/// to the same token within a capture, so the shape of the profile is preserved.
+pub struct Anonymizer {
+    salt: u64,
+    keep_stdlib: bool,
+    tokens: HashMap<String, String>,
+}
+
+impl Anonymizer {
+    pub fn new(keep_stdlib: bool) -> Anonymizer {
+        // salt the hashes so that tokens can't be reversed by hashing likely names
+        Anonymizer {
+            salt: rand::random(),
+            keep_stdlib,
+            tokens: HashMap::new(),
+        }
+    }
+
+    pub fn anonymize(&mut self, trace: &mut StackTrace) {
+        for frame in trace.frames.iter_mut() {
+            self.anonymize_frame(frame);
+        }
+        trace.thread_name = trace
+            .thread_name
+            .as_ref()
+            .map(|name| self.token("thread", name));
+        // command lines have the paths and arguments of the profiled program
+        trace.process_info = trace
+            .process_info
+            .take()
+            .map(|info| Arc::new(self.anonymize_process(&info)));
+    }
+
+    fn anonymize_process(&mut self, info: &ProcessInfo) -> ProcessInfo {
+        ProcessInfo {
+            pid: info.pid,
+            command_line: self.token("cmdline", &info.command_line),
+            parent: info
+                .parent
+                .as_ref()
+                .map(|parent| Box::new(self.anonymize_process(parent))),
+        }
+    }
+
+    fn anonymize_frame(&mut self, frame: &mut Frame) {
+        // the values of variables could give away as much as the names do
+        frame.locals = None;
+        if self.keep_stdlib && is_stdlib(&frame.filename) {
+            return;
+        }
+        frame.name = self.token("func", &frame.name);
+        frame.filename = self.token("file", &frame.filename);
+        frame.short_filename = frame
+            .short_filename
+            .as_ref()
+            .map(|filename| self.token("file", filename));
+        frame.module = frame
+            .module
+            .as_ref()
+            .map(|module| self.token("module", module));
+    }
+
+    fn token(&mut self, prefix: &str, value: &str) -> String {
+        if value.is_empty() {
+            return String::new();
+        }
+        let mut hasher = DefaultHasher::new();
+        self.salt.hash(&mut hasher);
+        value.hash(&mut hasher);
+        // the whole hash is used, since with 32 bits names would start to collide in
+        // profiles with tens of thousands of functions
+        let token = format!("{}_{:016x}", prefix, hasher.finish());
+        self.tokens.insert(token.clone(), value.to_owned());
+        token
+    }
+
+    /// Writes out the mapping of tokens to original names as json
+    pub fn write_keyfile(&self, w: &mut dyn Write) -> Result<(), Error> {
+        serde_json::to_writer_pretty(&mut *w, &self.tokens)?;
+        writeln!(w)?;
+        Ok(())
+    }
+}
+
+/// Returns true if the filename looks like it belongs to the python standard library
+fn is_stdlib(filename: &str) -> bool {
+    if filename.starts_with("<frozen ") {
+        return true;
+    }
+    let filename = filename.replace('\\', "/");
+    let in_lib = filename.contains("/lib/python") || filename.contains("/Lib/");
+    in_lib && !filename.contains("site-packages") && !filename.contains("dist-packages")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stack_trace::FrameKind;
+
+    fn frame(name: &str, filename: &str) -> Frame {
+        Frame {
+            name: name.to_owned(),
+            filename: filename.to_owned(),
+            module: None,
+            short_filename: None,
+            line: 10,
+            locals: None,
+            is_native: false,
+            frame_kind: FrameKind::Regular,
+            code_id: None,
+        }
+    }
+
+    #[test]
+    fn test_anonymize_frame() {
+        let mut anonymizer = Anonymizer::new(true);
+
+        let mut a = frame("process_payment", "/srv/app/billing.py");
+        let mut b = frame("process_payment", "/srv/app/billing.py");
+        anonymizer.anonymize_frame(&mut a);
+        anonymizer.anonymize_frame(&mut b);
+        assert_eq!(a, b);
+        assert!(a.name.starts_with("func_"));
+        assert_eq!(a.name.len(), "func_".len() + 16);
+        assert!(a.filename.starts_with("file_"));
+        assert_eq!(a.line, 10);
+        assert_eq!(anonymizer.tokens[&a.name], "process_payment");
+
+        let mut stdlib = frame("wait", "/usr/lib/python3.10/threading.py");
+        anonymizer.anonymize_frame(&mut stdlib);
+        assert_eq!(stdlib.name, "wait");
+
+        let mut vendored = frame("get", "/usr/lib/python3.10/site-packages/requests/api.py");
+        anonymizer.anonymize_frame(&mut vendored);
+        assert_ne!(vendored.name, "get");
+    }
+
+    #[test]
+    fn test_anonymize_process_names() {
+        use crate::chrometrace::Chrometrace;
+        use crate::config::Config;
+        use flate2::read::GzDecoder;
+        use std::io::Read;
+        use std::time::Duration;
+
+        let mut anonymizer = Anonymizer::new(true);
+        let mut chrometrace = Chrometrace::new(&Config {
+            subprocesses: true,
+            ..Default::default()
+        })
+        .unwrap();
+        let mut trace = StackTrace::builder()
+            .pid(2)
+            .frame("main", "/srv/app/worker.py", 10)
+            .build();
+        trace.process_info = Some(Arc::new(ProcessInfo {
+            pid: 2,
+            command_line: "python /srv/app/worker.py --password hunter2".to_owned(),
+            parent: Some(Box::new(ProcessInfo {
+                pid: 1,
+                command_line: "python /srv/app/launcher.py".to_owned(),
+                parent: None,
+            })),
+        }));
+        anonymizer.anonymize(&mut trace);
+        let info = trace.process_info.as_ref().unwrap();
+        assert!(info.command_line.starts_with("cmdline_"));
+        assert!(info
+            .parent
+            .as_ref()
+            .unwrap()
+            .command_line
+            .starts_with("cmdline_"));
+
+        chrometrace
+            .increment(vec![trace], Duration::from_millis(10))
+            .unwrap();
+        let mut compressed = Vec::new();
+        chrometrace.write(&mut compressed).unwrap();
+        let mut written = String::new();
+        GzDecoder::new(&compressed[..])
+            .read_to_string(&mut written)
+            .unwrap();
+        assert!(written.contains("cmdline_"));
+        assert!(!written.contains("/srv/app"));
+        assert!(!written.contains("hunter2"));
+    }
+}
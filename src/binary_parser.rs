@@ -1,19 +1,55 @@
 use std::collections::HashMap;
 use std::fs::File;
-use std::path::Path;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::SystemTime;
 
-use anyhow::Error;
+use anyhow::{Context, Error};
+use goblin::mach::exports::{
+    ExportInfo, EXPORT_SYMBOL_FLAGS_KIND_ABSOLUTE, EXPORT_SYMBOL_FLAGS_KIND_MASK,
+};
+use goblin::pe::export::Reexport;
 use goblin::Object;
+use lazy_static::lazy_static;
+use lru::LruCache;
 use memmap::Mmap;
+use serde_derive::Serialize;
 
+#[cfg(unwind)]
+use crate::dwarf::LineTable;
+
+/// The page size that ELF segments are assumed to be mapped with
+const ELF_PAGE_SIZE: u64 = 0x1000;
+
+/// The number of parsed binaries that parse_binary_cached holds on to by default
+const DEFAULT_BINARY_CACHE_SIZE: usize = 32;
+
+#[derive(Clone)]
 pub struct BinaryInfo {
     pub filename: std::path::PathBuf,
     pub symbols: HashMap<String, u64>,
+    /// The sizes of the symbols that have one in the symbol table, which is only ELF binaries
+    pub symbol_sizes: HashMap<String, u64>,
+    /// Exports that are forwarded to another library, which is only PE binaries. These map the
+    /// name of the export to where it's forwarded, like 'NTDLL.RtlAllocateHeap' (or
+    /// 'NTDLL.#123' when forwarded by ordinal), and aren't in symbols since the code for them
+    /// lives in the other library
+    #[allow(dead_code)]
+    pub forwarded_exports: HashMap<String, String>,
     pub bss_addr: u64,
     pub bss_size: u64,
+    /// Set when the binary doesn't have a usable .bss section, and bss_addr and bss_size
+    /// cover some other range of writable memory instead
+    pub bss_fallback: Option<BssFallback>,
     pub offset: u64,
     pub addr: u64,
     pub size: u64,
+    // the symbols sorted by address, which is only built when looking up symbols by address
+    address_index: OnceLock<Vec<(u64, String)>>,
+    // the line table from the DWARF debug info, which is only parsed when looking up source lines
+    #[cfg(unwind)]
+    line_table: OnceLock<Option<LineTable>>,
 }
 
 impl BinaryInfo {
@@ -21,20 +57,309 @@ impl BinaryInfo {
     pub fn contains(&self, addr: u64) -> bool {
         addr >= self.addr && addr < (self.addr + self.size)
     }
+
+    /// Returns the name and address of the closest symbol at or below an address. When several
+    /// symbols have the same address, the first one by name is returned. The symbols are indexed
+    /// by address the first time this is called, so later changes to them aren't picked up
+    #[allow(dead_code)]
+    pub fn symbol_for_address(&self, addr: u64) -> Option<(&str, u64)> {
+        let index = self.address_index();
+        let position = index.partition_point(|&(value, _)| value <= addr);
+        let (value, name) = index.get(position.checked_sub(1)?)?;
+        Some((name, *value))
+    }
+
+    /// Returns the size of a symbol. This is the size from the symbol table when there is one,
+    /// and otherwise the distance to the next symbol by address (like for Mach-O and PE binaries,
+    /// which don't store sizes). This is None for unknown symbols, and for the last symbol
+    /// when its size isn't known
+    #[allow(dead_code)]
+    pub fn symbol_size(&self, name: &str) -> Option<u64> {
+        if let Some(&size) = self.symbol_sizes.get(name) {
+            return Some(size);
+        }
+        let value = *self.symbols.get(name)?;
+        let index = self.address_index();
+        let position = index.partition_point(|&(other, _)| other <= value);
+        index.get(position).map(|(next, _)| next - value)
+    }
+
+    /// Returns the names and addresses of the symbols matching a glob pattern, sorted by name.
+    /// '*' matches any number of characters and '?' matches a single one, so 'Py*Runtime*'
+    /// finds '_PyRuntime' as well as versioned names
+    #[allow(dead_code)]
+    pub fn find_symbols(&self, pattern: &str) -> Vec<(&str, u64)> {
+        let pattern: Vec<char> = pattern.chars().collect();
+        let mut matches: Vec<(&str, u64)> = self
+            .symbols
+            .iter()
+            .filter(|(name, _)| glob_matches(&pattern, name))
+            .map(|(name, &value)| (name.as_str(), value))
+            .collect();
+        matches.sort_unstable();
+        matches
+    }
+
+    #[allow(dead_code)]
+    fn address_index(&self) -> &[(u64, String)] {
+        self.address_index.get_or_init(|| {
+            let mut index: Vec<(u64, String)> = self
+                .symbols
+                .iter()
+                .filter(|(name, _)| !name.is_empty())
+                .map(|(name, &value)| (value, name.clone()))
+                .collect();
+            index.sort_unstable();
+            index.dedup_by_key(|(value, _)| *value);
+            index
+        })
+    }
+
+    /// Returns the source file and line an address was compiled from, using the DWARF debug
+    /// info of ELF binaries. This is None when the binary doesn't have debug info (like when it
+    /// has been split out into a separate file). The file is read again and its debug info
+    /// parsed the first time this is called
+    #[cfg(unwind)]
+    #[allow(dead_code)]
+    pub fn source_line(&self, addr: u64) -> Option<(String, u32)> {
+        let table = self.line_table.get_or_init(|| {
+            let parse = || -> Result<LineTable, Error> {
+                let file = File::open(&self.filename)?;
+                let buffer = unsafe { Mmap::map(&file)? };
+                LineTable::parse(&buffer)
+            };
+            match parse() {
+                Ok(table) => Some(table),
+                Err(e) => {
+                    debug!(
+                        "Failed to read line table from '{}': {}",
+                        self.filename.display(),
+                        e
+                    );
+                    None
+                }
+            }
+        });
+        let (file, line) = table.as_ref()?.lookup(addr.wrapping_sub(self.offset))?;
+        Some((file.to_owned(), line))
+    }
 }
 
-/// Uses goblin to parse a binary file, returns information on symbols/bss/adjusted offset etc
-pub fn parse_binary(filename: &Path, addr: u64, size: u64) -> Result<BinaryInfo, Error> {
-    let offset = addr;
+/// Matches a name against a glob pattern with '*' and '?' wildcards. When a '*' doesn't work
+/// out, this goes back to the last one and has it match one more character
+#[allow(dead_code)]
+fn glob_matches(pattern: &[char], name: &str) -> bool {
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // the position of the last '*' in the pattern, and where in the name it matched up to
+    let mut star = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((star_p, star_n)) => {
+                    star = Some((star_p, star_n + 1));
+                    p = star_p + 1;
+                    n = star_n + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
 
-    let mut symbols = HashMap::new();
+/// Identifies a parsed binary in the cache. Symbol addresses depend on where the binary is
+/// loaded and which architecture of a universal binary is used, so this includes the address
+/// range and cputype. The modification time and size of the file are included so that a binary
+/// that gets rebuilt is parsed again
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct BinaryKey {
+    path: PathBuf,
+    modified: SystemTime,
+    len: u64,
+    addr: u64,
+    size: u64,
+    cputype: Option<u32>,
+}
+
+lazy_static! {
+    // None when caching has been turned off
+    static ref BINARY_CACHE: Mutex<Option<LruCache<BinaryKey, Arc<BinaryInfo>>>> = Mutex::new(
+        Some(LruCache::new(NonZeroUsize::new(DEFAULT_BINARY_CACHE_SIZE).unwrap()))
+    );
+}
+
+lazy_static! {
+    static ref DEBUG_DIRS: Mutex<Vec<PathBuf>> = Mutex::new(vec![PathBuf::from("/usr/lib/debug")]);
+}
 
+/// Sets the directories that separate debug files for stripped binaries are looked for in,
+/// which is just /usr/lib/debug by default. Binaries that have already been parsed by
+/// parse_binary_cached aren't looked up again
+#[allow(dead_code)]
+pub fn set_debug_dirs(dirs: Vec<PathBuf>) {
+    *DEBUG_DIRS.lock().unwrap() = dirs;
+}
+
+/// Sets the number of parsed binaries to keep around for parse_binary_cached, where 0 turns
+/// off caching
+#[allow(dead_code)]
+pub fn set_binary_cache_size(size: usize) {
+    let mut cache = BINARY_CACHE.lock().unwrap();
+    match (NonZeroUsize::new(size), cache.as_mut()) {
+        (Some(size), Some(cache)) => cache.resize(size),
+        (Some(size), None) => *cache = Some(LruCache::new(size)),
+        (None, _) => *cache = None,
+    }
+}
+
+/// Like parse_binary, but returns the result of an earlier call if the same file has been
+/// parsed for the same address range before. Attaching to a process that is still starting up
+/// means creating a PythonSpy over and over until it succeeds, and each of these would
+/// otherwise parse python and libpython again
+pub fn parse_binary_cached(
+    filename: &Path,
+    addr: u64,
+    size: u64,
+    cputype: Option<u32>,
+) -> Result<Arc<BinaryInfo>, Error> {
+    let key = match binary_key(filename, addr, size, cputype) {
+        Ok(key) => key,
+        Err(e) => {
+            debug!("Not caching '{}': {}", filename.display(), e);
+            return Ok(Arc::new(parse_binary(filename, addr, size, cputype)?));
+        }
+    };
+
+    if let Some(cache) = BINARY_CACHE.lock().unwrap().as_mut() {
+        if let Some(info) = cache.get(&key) {
+            return Ok(info.clone());
+        }
+    }
+
+    // don't hold the lock while parsing, since this can take a while for a large binary
+    let info = Arc::new(parse_binary(filename, addr, size, cputype)?);
+    if let Some(cache) = BINARY_CACHE.lock().unwrap().as_mut() {
+        cache.put(key, info.clone());
+    }
+    Ok(info)
+}
+
+fn binary_key(
+    filename: &Path,
+    addr: u64,
+    size: u64,
+    cputype: Option<u32>,
+) -> std::io::Result<BinaryKey> {
+    let metadata = std::fs::metadata(filename)?;
+    Ok(BinaryKey {
+        path: filename.canonicalize()?,
+        modified: metadata.modified()?,
+        len: metadata.len(),
+        addr,
+        size,
+        cputype,
+    })
+}
+
+/// Where the range of memory scanned for globals comes from, when an ELF binary doesn't have a
+/// .bss section or it is empty (like with some statically linked or stripped binaries)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BssFallback {
+    /// The initialized globals in the .data section
+    Data,
+    /// Everything from the start of the first writable segment to the end of the last one
+    WritableSegments,
+}
+
+impl std::fmt::Display for BssFallback {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            BssFallback::Data => write!(f, "the .data section"),
+            BssFallback::WritableSegments => write!(f, "the writable segments"),
+        }
+    }
+}
+
+/// The symbols and bss section found in a binary, for debugging why symbol lookup failed
+#[derive(Debug, Serialize)]
+pub struct SymbolDump {
+    pub filename: std::path::PathBuf,
+    pub bss_addr: u64,
+    pub bss_size: u64,
+    /// Symbols and their addresses, sorted by name
+    pub symbols: Vec<(String, u64)>,
+}
+
+/// Returns everything that parse_binary finds in a file. Addresses are as if the binary was
+/// loaded at address 0, so are relative to where it gets loaded in a process
+pub fn dump_symbols(filename: &Path) -> Result<SymbolDump, Error> {
+    let info = parse_binary(filename, 0, 0, None)
+        .with_context(|| format!("Failed to parse binary '{}'", filename.display()))?;
+    let mut symbols: Vec<(String, u64)> = info
+        .symbols
+        .into_iter()
+        .filter(|(name, _)| !name.is_empty())
+        .collect();
+    symbols.sort();
+    Ok(SymbolDump {
+        filename: info.filename,
+        bss_addr: info.bss_addr,
+        bss_size: info.bss_size,
+        symbols,
+    })
+}
+
+/// Uses goblin to parse a binary file, returns information on symbols/bss/adjusted offset etc
+pub fn parse_binary(
+    filename: &Path,
+    addr: u64,
+    size: u64,
+    cputype: Option<u32>,
+) -> Result<BinaryInfo, Error> {
     // Read in the filename
     let file = File::open(filename)?;
     let buffer = unsafe { Mmap::map(&file)? };
+    parse_binary_bytes(filename, &buffer, addr, size, cputype)
+}
+
+/// Parses the contents of a binary file that has already been read in. The file could be
+/// truncated or corrupt (like when it was replaced on disk while the process was running),
+/// so this needs to return an error rather than panicking on bad input.
+///
+/// The symbols of stripped ELF binaries can be in a separate debug file, which is read in
+/// from disk relative to filename when the binary links to one with .gnu_debuglink.
+///
+/// FAT Mach-O files (like universal2 builds of python) hold a binary for each architecture.
+/// The one matching cputype (the mach-o cpu type of the process) is used, or the first 64 bit
+/// one when the cpu type isn't known
+pub fn parse_binary_bytes(
+    filename: &Path,
+    buffer: &[u8],
+    addr: u64,
+    size: u64,
+    cputype: Option<u32>,
+) -> Result<BinaryInfo, Error> {
+    let offset = addr;
+
+    let mut symbols = HashMap::new();
 
     // Use goblin to parse the binary
-    match Object::parse(&buffer)? {
+    let object = Object::parse(buffer).with_context(|| {
+        format!(
+            "Failed to parse '{}' as an ELF, Mach-O or PE binary",
+            filename.display()
+        )
+    })?;
+    match object {
         Object::Mach(mach) => {
             // Get the mach binary from the archive
             let mach = match mach {
@@ -42,55 +367,121 @@ pub fn parse_binary(filename: &Path, addr: u64, size: u64) -> Result<BinaryInfo,
                 goblin::mach::Mach::Fat(fat) => {
                     let arch = fat
                         .iter_arches()
-                        .find(|arch| match arch {
-                            Ok(arch) => arch.is_64(),
-                            Err(_) => false,
+                        .find(|arch| match (arch, cputype) {
+                            (Ok(arch), Some(cputype)) => arch.cputype() == cputype,
+                            (Ok(arch), None) => arch.is_64(),
+                            (Err(_), _) => false,
                         })
+                        .ok_or_else(|| match cputype {
+                            Some(cputype) => format_err!(
+                                "Failed to find arch for cputype {:#x} in FAT archive in {}",
+                                cputype,
+                                filename.display()
+                            ),
+                            None => format_err!(
+                                "Failed to find 64 bit arch in FAT archive in {}",
+                                filename.display()
+                            ),
+                        })??;
+                    let bytes = buffer
+                        .get(arch.offset as usize..)
+                        .and_then(|bytes| bytes.get(..arch.size as usize))
                         .ok_or_else(|| {
                             format_err!(
-                                "Failed to find 64 bit arch in FAT archive in {}",
+                                "64 bit arch in FAT archive is outside of {}",
                                 filename.display()
                             )
-                        })??;
-                    let bytes = &buffer[arch.offset as usize..][..arch.size as usize];
+                        })?;
                     goblin::mach::MachO::parse(bytes, 0)?
                 }
             };
 
-            let mut bss_addr = 0;
-            let mut bss_size = 0;
+            // globals can end up in the zero-filled __common section instead of __bss
+            // (like with -fcommon), so use that when there isn't a __bss section
+            let mut bss = None;
+            let mut common = None;
             for segment in mach.segments.iter() {
                 for (section, _) in &segment.sections()? {
-                    if section.name()? == "__bss" {
-                        bss_addr = section.addr + offset;
-                        bss_size = section.size;
+                    match section.name()? {
+                        "__bss" => bss = Some((section.addr, section.size)),
+                        "__common" => common = Some((section.addr, section.size)),
+                        _ => {}
                     }
                 }
             }
+            let (bss_addr, bss_size) = bss
+                .or(common)
+                .map_or((0, 0), |(addr, size)| (addr.wrapping_add(offset), size));
 
-            if let Some(syms) = mach.symbols {
+            if let Some(syms) = &mach.symbols {
                 for symbol in syms.iter() {
                     let (name, value) = symbol?;
                     // almost every symbol we care about starts with an extra _, remove to normalize
                     // with the entries seen on linux/windows
                     if let Some(stripped_name) = name.strip_prefix('_') {
-                        symbols.insert(stripped_name.to_string(), value.n_value + offset);
+                        symbols.insert(
+                            stripped_name.to_string(),
+                            value.n_value.wrapping_add(offset),
+                        );
                     }
                 }
             }
+
+            // binaries linked with chained fixups (the default with recent SDKs) can leave
+            // exported symbols out of the symbol table, but these are still in the exports trie
+            // from LC_DYLD_EXPORTS_TRIE or LC_DYLD_INFO. Addresses in the trie are relative to
+            // the start of the __TEXT segment, apart from absolute symbols
+            let text_addr = mach
+                .segments
+                .iter()
+                .find(|segment| segment.name().ok() == Some("__TEXT"))
+                .map_or(0, |segment| segment.vmaddr);
+            match mach.exports() {
+                Ok(exports) => {
+                    for export in exports {
+                        let value = match export.info {
+                            ExportInfo::Regular { address, flags }
+                                if flags & EXPORT_SYMBOL_FLAGS_KIND_MASK
+                                    == EXPORT_SYMBOL_FLAGS_KIND_ABSOLUTE =>
+                            {
+                                address
+                            }
+                            ExportInfo::Regular { address, .. } => text_addr.wrapping_add(address),
+                            // re-exports and resolvers don't have an address in this binary
+                            _ => continue,
+                        };
+                        if let Some(stripped_name) = export.name.strip_prefix('_') {
+                            symbols
+                                .entry(stripped_name.to_string())
+                                .or_insert(value.wrapping_add(offset));
+                        }
+                    }
+                }
+                Err(e) => warn!(
+                    "Failed to read the exports trie of {}: {}",
+                    filename.display(),
+                    e
+                ),
+            }
             Ok(BinaryInfo {
                 filename: filename.to_owned(),
                 symbols,
+                symbol_sizes: HashMap::new(),
+                forwarded_exports: HashMap::new(),
                 bss_addr,
                 bss_size,
+                bss_fallback: None,
                 offset,
                 addr,
                 size,
+                address_index: OnceLock::new(),
+                #[cfg(unwind)]
+                line_table: OnceLock::new(),
             })
         }
 
         Object::Elf(elf) => {
-            let strtab = elf.shdr_strtab;
+            let strtab = &elf.shdr_strtab;
             let bss_header = elf
                 .section_headers
                 .iter()
@@ -102,21 +493,35 @@ pub fn parse_binary(filename: &Path, addr: u64, size: u64) -> Result<BinaryInfo,
                         .map_or(true, |name| name == ".bss")
                 })
                 // if we have multiple sections here, take the largest
-                .max_by_key(|header| header.sh_size)
-                .ok_or_else(|| {
-                    format_err!(
-                        "Failed to find BSS section header in {}",
-                        filename.display()
-                    )
-                })?;
+                .max_by_key(|header| header.sh_size);
+            let (bss_addr, bss_size, bss_fallback) =
+                match bss_header.filter(|header| header.sh_size != 0) {
+                    Some(header) => (header.sh_addr, header.sh_size, None),
+                    None => elf_data_range(&elf)
+                        .map(|(addr, size, fallback)| (addr, size, Some(fallback)))
+                        .ok_or_else(|| {
+                            format_err!(
+                                "Failed to find BSS section header in {}",
+                                filename.display()
+                            )
+                        })?,
+                };
 
-            let program_header = elf
+            // 'addr' and 'size' are the mapping of an executable segment. There can be more than
+            // one of these (like with a linker script), so pick the one that would be mapped with
+            // the same size, falling back to the first one
+            let executable: Vec<_> = elf
                 .program_headers
                 .iter()
-                .find(|header| {
+                .filter(|header| {
                     header.p_type == goblin::elf::program_header::PT_LOAD
                         && header.p_flags & goblin::elf::program_header::PF_X != 0
                 })
+                .collect();
+            let program_header = executable
+                .iter()
+                .find(|header| mapped_size(header) == size)
+                .or_else(|| executable.first())
                 .ok_or_else(|| {
                     format_err!(
                         "Failed to find executable PT_LOAD program header in {}",
@@ -124,61 +529,1233 @@ pub fn parse_binary(filename: &Path, addr: u64, size: u64) -> Result<BinaryInfo,
                     )
                 })?;
 
+            // the mapping starts at the beginning of the page holding the segment, which isn't
+            // necessarily where the segment starts (lld doesn't page align segments).
             // p_vaddr may be larger than the map address in case when the header has an offset and
             // the map address is relatively small. In this case we can default to 0.
-            let offset = offset.saturating_sub(program_header.p_vaddr);
+            let offset = offset.saturating_sub(page_start(program_header.p_vaddr));
 
-            for sym in elf.syms.iter() {
-                let name = elf.strtab[sym.st_name].to_string();
-                symbols.insert(name, sym.st_value + offset);
-            }
-            for dynsym in elf.dynsyms.iter() {
-                let name = elf.dynstrtab[dynsym.st_name].to_string();
-                symbols.insert(name, dynsym.st_value + offset);
+            // indexing into the string tables panics on a bad offset, so skip these symbols
+            let syms = elf
+                .syms
+                .iter()
+                .filter_map(|sym| Some((elf.strtab.get_at(sym.st_name)?, sym)));
+            let dynsyms = elf
+                .dynsyms
+                .iter()
+                .filter_map(|sym| Some((elf.dynstrtab.get_at(sym.st_name)?, sym)));
+            let mut symbol_sizes = HashMap::new();
+            add_elf_symbols(
+                &mut symbols,
+                &mut symbol_sizes,
+                syms.chain(dynsyms),
+                &elf.program_headers,
+                offset,
+            );
+
+            // stripped binaries can have their symbols in a separate debug file, which are
+            // added to the ones from the binary itself
+            if let Some(debug) = read_debug_file(filename, &elf, buffer) {
+                match goblin::elf::Elf::parse(&debug) {
+                    Ok(debug_elf) => {
+                        let syms = debug_elf
+                            .syms
+                            .iter()
+                            .filter_map(|sym| Some((debug_elf.strtab.get_at(sym.st_name)?, sym)));
+                        let (mut debug_symbols, mut debug_sizes) = (HashMap::new(), HashMap::new());
+                        add_elf_symbols(
+                            &mut debug_symbols,
+                            &mut debug_sizes,
+                            syms,
+                            &debug_elf.program_headers,
+                            offset,
+                        );
+                        for (name, value) in debug_symbols {
+                            symbols.entry(name).or_insert(value);
+                        }
+                        for (name, size) in debug_sizes {
+                            symbol_sizes.entry(name).or_insert(size);
+                        }
+                    }
+                    Err(e) => debug!(
+                        "Failed to parse debug file for {}: {}",
+                        filename.display(),
+                        e
+                    ),
+                }
             }
             Ok(BinaryInfo {
                 filename: filename.to_owned(),
                 symbols,
-                bss_addr: bss_header.sh_addr + offset,
-                bss_size: bss_header.sh_size,
+                symbol_sizes,
+                forwarded_exports: HashMap::new(),
+                bss_addr: bss_addr.wrapping_add(offset),
+                bss_size,
+                bss_fallback,
                 offset,
                 addr,
                 size,
+                address_index: OnceLock::new(),
+                #[cfg(unwind)]
+                line_table: OnceLock::new(),
             })
         }
         Object::PE(pe) => {
+            let mut forwarded_exports = HashMap::new();
             for export in pe.exports {
-                if let Some(name) = export.name {
-                    if let Some(export_offset) = export.offset {
-                        symbols.insert(name.to_string(), export_offset as u64 + offset);
+                let name = match export.name {
+                    Some(name) => name,
+                    None => continue,
+                };
+                match export.reexport {
+                    // the offset of a forwarded export is of the string naming its target, so
+                    // this has to be followed into the other library to get an address
+                    Some(reexport) => {
+                        let target = match reexport {
+                            Reexport::DLLName { export, lib } => format!("{}.{}", lib, export),
+                            Reexport::DLLOrdinal { ordinal, lib } => {
+                                format!("{}.#{}", lib, ordinal)
+                            }
+                        };
+                        debug!(
+                            "Export {} of {} is forwarded to {}",
+                            name,
+                            filename.display(),
+                            target
+                        );
+                        forwarded_exports.insert(name.to_string(), target);
+                    }
+                    None => {
+                        if let Some(export_offset) = export.offset {
+                            symbols.insert(
+                                name.to_string(),
+                                (export_offset as u64).wrapping_add(offset),
+                            );
+                        }
                     }
                 }
             }
 
+            // uninitialized globals (like the interpreter state) are in .bss when the linker
+            // doesn't merge it into .data, which is the default with MSVC
             pe.sections
                 .iter()
-                .find(|section| section.name.starts_with(b".data"))
+                .find(|section| &section.name == b".bss\0\0\0\0")
+                .or_else(|| {
+                    pe.sections
+                        .iter()
+                        .find(|section| section.name.starts_with(b".data"))
+                })
                 .ok_or_else(|| {
                     format_err!(
-                        "Failed to find .data section in PE binary of {}",
+                        "Failed to find .bss or .data section in PE binary of {}",
                         filename.display()
                     )
                 })
                 .map(|data_section| {
-                    let bss_addr = u64::from(data_section.virtual_address) + offset;
+                    let bss_addr = u64::from(data_section.virtual_address).wrapping_add(offset);
                     let bss_size = u64::from(data_section.virtual_size);
 
                     BinaryInfo {
                         filename: filename.to_owned(),
                         symbols,
+                        symbol_sizes: HashMap::new(),
+                        forwarded_exports,
                         bss_addr,
                         bss_size,
+                        bss_fallback: None,
                         offset,
                         addr,
                         size,
+                        address_index: OnceLock::new(),
+                        #[cfg(unwind)]
+                        line_table: OnceLock::new(),
                     }
                 })
         }
-        _ => Err(format_err!("Unhandled binary type")),
+        Object::Unknown(magic) => match linker_script_inputs(buffer) {
+            Some(inputs) => Err(format_err!(
+                "'{}' is a linker script rather than a binary, which points the linker at {}",
+                filename.display(),
+                inputs.join(", ")
+            )),
+            None => Err(format_err!(
+                "Unhandled binary type in '{}' (magic {:#x})",
+                filename.display(),
+                magic
+            )),
+        },
+        Object::Archive(_) => Err(format_err!(
+            "'{}' is a static library archive rather than a binary",
+            filename.display()
+        )),
+    }
+}
+
+/// Returns the files a linker script (like the 'GNU ld script' that libc.so is on most linux
+/// distributions) refers to with GROUP or INPUT commands, or None if the buffer doesn't look
+/// like one
+fn linker_script_inputs(buffer: &[u8]) -> Option<Vec<String>> {
+    let mut script = std::str::from_utf8(buffer).ok()?;
+    // drop the comments, which can mention the commands too
+    let mut text = String::new();
+    while let Some(start) = script.find("/*") {
+        text.push_str(&script[..start]);
+        let end = script[start..].find("*/")?;
+        script = &script[start + end + 2..];
+    }
+    text.push_str(script);
+
+    let mut inputs = Vec::new();
+    let mut found = false;
+    for command in ["GROUP", "INPUT"] {
+        for (start, _) in text.match_indices(command) {
+            let rest = text[start + command.len()..].trim_start();
+            let rest = match rest.strip_prefix('(') {
+                Some(rest) => rest,
+                None => continue,
+            };
+            // the files are up to the matching paren, and can be nested in AS_NEEDED ( .. )
+            let mut depth = 1;
+            let end = rest.find(|c| {
+                match c {
+                    '(' => depth += 1,
+                    ')' => depth -= 1,
+                    _ => {}
+                }
+                depth == 0
+            })?;
+            found = true;
+            inputs.extend(
+                rest[..end]
+                    .split(|c: char| c.is_whitespace() || c == '(' || c == ')' || c == ',')
+                    .filter(|input| !input.is_empty() && *input != "AS_NEEDED")
+                    .map(str::to_owned),
+            );
+        }
+    }
+    found.then_some(inputs)
+}
+
+/// Adds the symbols from the ELF symbol tables. The same name can be in both .symtab and
+/// .dynsym with different values (like when one of them is an undefined import), so symbols
+/// that are defined inside of a loaded segment are preferred over ones that aren't. Ties go to
+/// the symbol that comes last. The sizes of the chosen symbols go in sizes, when they have one.
+fn add_elf_symbols<'a>(
+    symbols: &mut HashMap<String, u64>,
+    sizes: &mut HashMap<String, u64>,
+    elf_symbols: impl Iterator<Item = (&'a str, goblin::elf::Sym)>,
+    program_headers: &[goblin::elf::ProgramHeader],
+    offset: u64,
+) {
+    let rank = |sym: &goblin::elf::Sym| {
+        if sym.st_value == 0 || sym.st_shndx == goblin::elf::section_header::SHN_UNDEF as usize {
+            0
+        } else if program_headers.iter().any(|header| {
+            header.p_type == goblin::elf::program_header::PT_LOAD
+                && (header.p_vaddr..header.p_vaddr.saturating_add(header.p_memsz))
+                    .contains(&sym.st_value)
+        }) {
+            2
+        } else {
+            1
+        }
+    };
+
+    let mut ranks: HashMap<&str, u8> = HashMap::new();
+    for (name, sym) in elf_symbols {
+        let rank = rank(&sym);
+        if rank >= ranks.get(name).copied().unwrap_or(0) {
+            ranks.insert(name, rank);
+            symbols.insert(name.to_owned(), sym.st_value.wrapping_add(offset));
+            if sym.st_size != 0 {
+                sizes.insert(name.to_owned(), sym.st_size);
+            } else {
+                sizes.remove(name);
+            }
+        }
+    }
+}
+
+/// Returns the contents of the separate debug file of an ELF binary, which is found by its
+/// build-id or the .gnu_debuglink section like gdb and perf do
+fn read_debug_file(filename: &Path, elf: &goblin::elf::Elf, buffer: &[u8]) -> Option<Mmap> {
+    let debug_dirs = DEBUG_DIRS.lock().unwrap().clone();
+    // every binary has a build-id, so only look for a debug file by it when the binary doesn't
+    // have a symbol table of its own. Only stripped binaries have a .gnu_debuglink section
+    if elf.syms.is_empty() {
+        if let Some(debug) = read_build_id_file(filename, elf, buffer, &debug_dirs) {
+            return Some(debug);
+        }
+    }
+    read_debuglink(filename, elf, buffer, &debug_dirs)
+}
+
+/// Returns the contents of the debug file for the build-id of an ELF binary, which is at
+/// '.build-id/xx/yyyy.debug' in a debug directory (where xx is the first byte of the build-id
+/// in hex, and yyyy the rest of it)
+fn read_build_id_file(
+    filename: &Path,
+    elf: &goblin::elf::Elf,
+    buffer: &[u8],
+    debug_dirs: &[PathBuf],
+) -> Option<Mmap> {
+    let build_id = elf
+        .iter_note_headers(buffer)
+        .into_iter()
+        .chain(elf.iter_note_sections(buffer, Some(".note.gnu.build-id")))
+        .flatten()
+        .filter_map(|note| note.ok())
+        .find(|note| note.n_type == goblin::elf::note::NT_GNU_BUILD_ID && note.name == "GNU")?
+        .desc;
+    let (first, rest) = build_id.split_first()?;
+    let hex: String = rest.iter().map(|b| format!("{:02x}", b)).collect();
+    for dir in debug_dirs {
+        let candidate = dir
+            .join(".build-id")
+            .join(format!("{:02x}", first))
+            .join(format!("{}.debug", hex));
+        if let Ok(debug) = File::open(&candidate).and_then(|file| unsafe { Mmap::map(&file) }) {
+            info!(
+                "Found debug file {} for {}",
+                candidate.display(),
+                filename.display()
+            );
+            return Some(debug);
+        }
+    }
+    None
+}
+
+/// Returns the contents of the separate debug file that the .gnu_debuglink section of an ELF
+/// binary points to. This is looked for in the same places as gdb does: next to the binary,
+/// in a .debug directory next to it, and under each debug directory. Files whose CRC doesn't
+/// match the one in the section are from a different build of the binary, and are skipped
+fn read_debuglink(
+    filename: &Path,
+    elf: &goblin::elf::Elf,
+    buffer: &[u8],
+    debug_dirs: &[PathBuf],
+) -> Option<Mmap> {
+    let section = elf
+        .section_headers
+        .iter()
+        .find(|header| elf.shdr_strtab.get_at(header.sh_name) == Some(".gnu_debuglink"))?;
+    let data = buffer
+        .get(section.sh_offset as usize..)?
+        .get(..section.sh_size as usize)?;
+
+    // the section holds the nul terminated filename, then the CRC aligned to 4 bytes
+    let name_len = data.iter().position(|&b| b == 0)?;
+    let debug_name = std::str::from_utf8(&data[..name_len]).ok()?;
+    let crc_bytes: [u8; 4] = data.get((name_len + 4) & !3..)?.get(..4)?.try_into().ok()?;
+    let crc = if elf.little_endian {
+        u32::from_le_bytes(crc_bytes)
+    } else {
+        u32::from_be_bytes(crc_bytes)
+    };
+
+    // look next to where links like /proc/pid/exe point to, rather than next to the link
+    let filename = match std::fs::read_link(filename) {
+        Ok(target) => filename.parent()?.join(target),
+        Err(_) => filename.to_owned(),
+    };
+    let dir = filename.parent()?;
+    let mut candidates = vec![dir.join(debug_name), dir.join(".debug").join(debug_name)];
+    for debug_dir in debug_dirs {
+        let relative = dir.strip_prefix("/").unwrap_or(dir);
+        candidates.push(debug_dir.join(relative).join(debug_name));
+    }
+    for candidate in candidates {
+        // the binary can link to a debug file with its own name
+        if candidate == filename {
+            continue;
+        }
+        let debug = match File::open(&candidate).and_then(|file| unsafe { Mmap::map(&file) }) {
+            Ok(debug) => debug,
+            Err(_) => continue,
+        };
+        if crc32fast::hash(&debug) != crc {
+            debug!(
+                "Skipping debug file {} for {}, which has a different CRC",
+                candidate.display(),
+                filename.display()
+            );
+            continue;
+        }
+        info!(
+            "Found debug file {} for {}",
+            candidate.display(),
+            filename.display()
+        );
+        return Some(debug);
+    }
+    None
+}
+
+/// Returns the range of memory to scan for globals in an ELF binary without a usable .bss
+/// section, which is the .data section if there is one and otherwise all the writable segments
+fn elf_data_range(elf: &goblin::elf::Elf) -> Option<(u64, u64, BssFallback)> {
+    if let Some(data) = elf.section_headers.iter().find(|header| {
+        header.sh_type == goblin::elf::section_header::SHT_PROGBITS
+            && header.sh_size != 0
+            && elf.shdr_strtab.get_at(header.sh_name) == Some(".data")
+    }) {
+        return Some((data.sh_addr, data.sh_size, BssFallback::Data));
+    }
+
+    let writable = elf.program_headers.iter().filter(|header| {
+        header.p_type == goblin::elf::program_header::PT_LOAD
+            && header.p_flags & goblin::elf::program_header::PF_W != 0
+    });
+    let start = writable.clone().map(|header| header.p_vaddr).min()?;
+    let end = writable
+        .map(|header| header.p_vaddr.saturating_add(header.p_memsz))
+        .max()?;
+    if end <= start {
+        return None;
+    }
+    Some((start, end - start, BssFallback::WritableSegments))
+}
+
+fn page_start(addr: u64) -> u64 {
+    addr - addr % ELF_PAGE_SIZE
+}
+
+/// Returns the size of the mapping for a segment, which covers every page that its contents
+/// from the file are on
+fn mapped_size(header: &goblin::elf::ProgramHeader) -> u64 {
+    let end = header.p_vaddr.saturating_add(header.p_filesz);
+    let end = end.saturating_add(ELF_PAGE_SIZE - 1);
+    page_start(end) - page_start(header.p_vaddr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use goblin::mach::cputype::{CPU_TYPE_ARM64, CPU_TYPE_X86_64};
+
+    #[test]
+    fn test_fat_arch_out_of_bounds() {
+        // a FAT header with a single x86_64 arch, that claims to be past the end of the file
+        let mut buffer = Vec::new();
+        for value in [0xcafebabe, 1, 0x01000007, 3, 0x1000, 0x1000, 12u32] {
+            buffer.extend_from_slice(&value.to_be_bytes());
+        }
+        assert!(parse_binary_bytes(Path::new("fat"), &buffer, 0, 0, None).is_err());
+    }
+
+    #[test]
+    fn test_unhandled_files() {
+        let error = |buffer: &[u8]| {
+            let error = parse_binary_bytes(Path::new("/usr/lib/libc.so"), buffer, 0, 0, None)
+                .err()
+                .unwrap();
+            format!("{:#}", error)
+        };
+
+        let script = b"/* GNU ld script
+   Use the shared library, but some functions are only in
+   the static library, so try that secondarily.  */
+OUTPUT_FORMAT(elf64-x86-64)
+GROUP ( /lib/libc.so.6 /usr/lib/libc_nonshared.a  AS_NEEDED ( /lib/ld-linux.so.2 ) )
+";
+        assert_eq!(
+            error(script),
+            "'/usr/lib/libc.so' is a linker script rather than a binary, which points the linker at /lib/libc.so.6, /usr/lib/libc_nonshared.a, /lib/ld-linux.so.2"
+        );
+        assert_eq!(
+            linker_script_inputs(b"INPUT(-lfoo)"),
+            Some(vec!["-lfoo".to_owned()])
+        );
+        assert_eq!(linker_script_inputs(b"just some text"), None);
+
+        assert!(error(b"just some text").contains("'/usr/lib/libc.so'"));
+        assert!(error(b"").contains("'/usr/lib/libc.so'"));
+    }
+
+    type MachOSections<'a> = &'a [(&'a str, u64, u64)];
+
+    /// Builds a FAT archive holding a 64 bit Mach-O file for each of the (cputype, sections)
+    /// pairs, which has a __DATA segment with a zero-fill section for each of the
+    /// (name, addr, size) tuples
+    fn build_fat_macho(slices: &[(u32, MachOSections)]) -> Vec<u8> {
+        let mut fat = Vec::new();
+        for value in [0xcafebabe, slices.len() as u32] {
+            fat.extend_from_slice(&value.to_be_bytes());
+        }
+        let machos: Vec<Vec<u8>> = slices
+            .iter()
+            .map(|&(cputype, sections)| build_macho(cputype, sections))
+            .collect();
+        for (i, macho) in machos.iter().enumerate() {
+            let (cputype, cpusubtype) = (slices[i].0, macho_cpusubtype(slices[i].0));
+            let offset = 0x1000 * (i as u32 + 1);
+            for value in [cputype, cpusubtype, offset, macho.len() as u32, 12] {
+                fat.extend_from_slice(&value.to_be_bytes());
+            }
+        }
+        for macho in machos {
+            fat.resize(fat.len().next_multiple_of(0x1000), 0);
+            fat.extend_from_slice(&macho);
+        }
+        fat
+    }
+
+    fn macho_cpusubtype(cputype: u32) -> u32 {
+        match cputype {
+            goblin::mach::cputype::CPU_TYPE_X86_64 => goblin::mach::cputype::CPU_SUBTYPE_X86_64_ALL,
+            _ => 0,
+        }
+    }
+
+    fn build_macho(cputype: u32, sections: &[(&str, u64, u64)]) -> Vec<u8> {
+        fn push(buffer: &mut Vec<u8>, values: &[(u64, usize)]) {
+            for &(value, width) in values {
+                buffer.extend_from_slice(&value.to_le_bytes()[..width]);
+            }
+        }
+        fn push_name(buffer: &mut Vec<u8>, name: &str) {
+            let mut name = name.as_bytes().to_vec();
+            name.resize(16, 0);
+            buffer.extend_from_slice(&name);
+        }
+
+        let cmdsize = 72 + 80 * sections.len() as u64;
+        let mut macho = Vec::new();
+        push(
+            &mut macho,
+            &[
+                (0xfeedfacf, 4),
+                (cputype as u64, 4),
+                (macho_cpusubtype(cputype) as u64, 4),
+                (2, 4),
+            ],
+        );
+        push(&mut macho, &[(1, 4), (cmdsize, 4), (0, 4), (0, 4)]);
+
+        push(&mut macho, &[(0x19, 4), (cmdsize, 4)]);
+        push_name(&mut macho, "__DATA");
+        push(
+            &mut macho,
+            &[(0x1_0000_4000, 8), (0x4000, 8), (0, 8), (0, 8)],
+        );
+        push(
+            &mut macho,
+            &[(3, 4), (3, 4), (sections.len() as u64, 4), (0, 4)],
+        );
+        for &(name, addr, size) in sections {
+            push_name(&mut macho, name);
+            push_name(&mut macho, "__DATA");
+            push(&mut macho, &[(addr, 8), (size, 8), (0, 4), (3, 4)]);
+            // S_ZEROFILL sections with no relocations
+            push(
+                &mut macho,
+                &[(0, 4), (0, 4), (1, 4), (0, 4), (0, 4), (0, 4)],
+            );
+        }
+        macho
+    }
+
+    /// Builds a 64 bit Mach-O dylib without a symbol table, which only has its exports in an
+    /// LC_DYLD_EXPORTS_TRIE like when linked with chained fixups. Each export is a (name,
+    /// flags, address) tuple, where the address is relative to the __TEXT segment
+    fn build_macho_with_exports(exports: &[(&str, u64, u64)]) -> Vec<u8> {
+        fn push(buffer: &mut Vec<u8>, values: &[(u64, usize)]) {
+            for &(value, width) in values {
+                buffer.extend_from_slice(&value.to_le_bytes()[..width]);
+            }
+        }
+        fn push_uleb(buffer: &mut Vec<u8>, mut value: u64) {
+            loop {
+                let byte = (value & 0x7f) as u8;
+                value >>= 7;
+                if value == 0 {
+                    buffer.push(byte);
+                    return;
+                }
+                buffer.push(byte | 0x80);
+            }
+        }
+
+        // a root node with an edge to a terminal node for each export. The edges are written
+        // out with single byte offsets, so this only works for a few exports
+        let root_size = 2 + exports
+            .iter()
+            .map(|(name, _, _)| name.len() + 2)
+            .sum::<usize>();
+        let mut trie = vec![0, exports.len() as u8];
+        let mut nodes = Vec::new();
+        for &(name, flags, address) in exports {
+            trie.extend_from_slice(name.as_bytes());
+            trie.push(0);
+            push_uleb(&mut trie, (root_size + nodes.len()) as u64);
+            let mut info = Vec::new();
+            push_uleb(&mut info, flags);
+            push_uleb(&mut info, address);
+            nodes.push(info.len() as u8);
+            nodes.extend_from_slice(&info);
+            // no children
+            nodes.push(0);
+        }
+        trie.extend_from_slice(&nodes);
+        assert!(trie.len() < 0x80);
+
+        let trie_offset = 0x100;
+        let mut macho = Vec::new();
+        push(
+            &mut macho,
+            &[
+                (0xfeedfacf, 4),
+                (CPU_TYPE_ARM64 as u64, 4),
+                (0, 4),
+                // MH_DYLIB
+                (6, 4),
+            ],
+        );
+        push(&mut macho, &[(2, 4), (72 + 16, 4), (0, 4), (0, 4)]);
+
+        push(&mut macho, &[(0x19, 4), (72, 4)]);
+        let mut name = b"__TEXT".to_vec();
+        name.resize(16, 0);
+        macho.extend_from_slice(&name);
+        push(
+            &mut macho,
+            &[(0x1_0000_0000, 8), (0x4000, 8), (0, 8), (0x200, 8)],
+        );
+        push(&mut macho, &[(5, 4), (5, 4), (0, 4), (0, 4)]);
+
+        // LC_DYLD_EXPORTS_TRIE
+        push(
+            &mut macho,
+            &[
+                (0x8000_0033, 4),
+                (16, 4),
+                (trie_offset, 4),
+                (trie.len() as u64, 4),
+            ],
+        );
+        macho.resize(trie_offset as usize, 0);
+        macho.extend_from_slice(&trie);
+        macho.resize(0x200, 0);
+        macho
+    }
+
+    #[test]
+    fn test_macho_exports_trie() {
+        let base = 0x1000;
+        let macho = build_macho_with_exports(&[
+            ("__PyRuntime", 0, 0x2000),
+            ("_Py_GetVersion", 0, 0x1234),
+            ("_absolute", EXPORT_SYMBOL_FLAGS_KIND_ABSOLUTE, 0x42),
+            ("not_prefixed", 0, 0x3000),
+        ]);
+        let info =
+            parse_binary_bytes(Path::new("libpython3.12.dylib"), &macho, base, 0, None).unwrap();
+        assert_eq!(info.symbols["_PyRuntime"], 0x1_0000_2000 + base);
+        assert_eq!(info.symbols["Py_GetVersion"], 0x1_0000_1234 + base);
+        assert_eq!(info.symbols["absolute"], 0x42 + base);
+        assert!(!info.symbols.contains_key("not_prefixed"));
+    }
+
+    #[test]
+    fn test_macho_common_section() {
+        let base = 0x1000;
+        let macho = build_fat_macho(&[(CPU_TYPE_X86_64, &[("__common", 0x1_0000_5000, 0x40)])]);
+        let info = parse_binary_bytes(Path::new("fat"), &macho, base, 0, None).unwrap();
+        assert_eq!((info.bss_addr, info.bss_size), (0x1_0000_5000 + base, 0x40));
+
+        // __bss is preferred when there are both
+        let macho = build_fat_macho(&[(
+            CPU_TYPE_X86_64,
+            &[
+                ("__common", 0x1_0000_5000, 0x40),
+                ("__bss", 0x1_0000_6000, 0x80),
+            ],
+        )]);
+        let info = parse_binary_bytes(Path::new("fat"), &macho, base, 0, None).unwrap();
+        assert_eq!((info.bss_addr, info.bss_size), (0x1_0000_6000 + base, 0x80));
+    }
+
+    #[test]
+    fn test_macho_universal2() {
+        let macho = build_fat_macho(&[
+            (CPU_TYPE_X86_64, &[("__bss", 0x1_0000_5000, 0x40)]),
+            (CPU_TYPE_ARM64, &[("__bss", 0x1_0000_6000, 0x80)]),
+        ]);
+        let bss = |cputype| {
+            parse_binary_bytes(Path::new("fat"), &macho, 0, 0, cputype)
+                .map(|info| (info.bss_addr, info.bss_size))
+        };
+        assert_eq!(bss(Some(CPU_TYPE_ARM64)).unwrap(), (0x1_0000_6000, 0x80));
+        assert_eq!(bss(Some(CPU_TYPE_X86_64)).unwrap(), (0x1_0000_5000, 0x40));
+
+        // the first 64 bit arch is used when the cputype isn't known
+        assert_eq!(bss(None).unwrap(), (0x1_0000_5000, 0x40));
+        assert!(bss(Some(goblin::mach::cputype::CPU_TYPE_POWERPC64)).is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_bad_symbol_name() {
+        let mut buffer = std::fs::read("/proc/self/exe").unwrap();
+        let (offset, entsize) = {
+            let elf = goblin::elf::Elf::parse(&buffer).unwrap();
+            let dynsym = elf
+                .section_headers
+                .iter()
+                .find(|header| header.sh_type == goblin::elf::section_header::SHT_DYNSYM)
+                .unwrap();
+            (dynsym.sh_offset as usize, dynsym.sh_entsize as usize)
+        };
+
+        // point the name of the first real symbol (after the null symbol) past the end of
+        // the string table, which should be skipped over rather than panicking
+        buffer[offset + entsize..][..4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(parse_binary_bytes(Path::new("test"), &buffer, 0, 0, None).is_ok());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_empty_bss_section() {
+        let mut buffer = std::fs::read("/proc/self/exe").unwrap();
+        let (bss, data, writable) = {
+            let elf = goblin::elf::Elf::parse(&buffer).unwrap();
+            let section = |name| {
+                let index = elf
+                    .section_headers
+                    .iter()
+                    .position(|header| elf.shdr_strtab.get_at(header.sh_name) == Some(name))
+                    .unwrap();
+                let header = &elf.section_headers[index];
+                let offset = elf.header.e_shoff as usize + index * elf.header.e_shentsize as usize;
+                (offset, header.sh_addr, header.sh_size)
+            };
+            let writable = elf
+                .program_headers
+                .iter()
+                .find(|header| header.p_flags & goblin::elf::program_header::PF_W != 0)
+                .unwrap()
+                .p_vaddr;
+            (section(".bss"), section(".data"), writable)
+        };
+        let parse = |buffer: &[u8]| parse_binary_bytes(Path::new("test"), buffer, 0, 0, None);
+        assert_eq!(parse(&buffer).unwrap().bss_fallback, None);
+
+        // with an empty .bss the .data section is scanned instead
+        buffer[bss.0 + 32..][..8].copy_from_slice(&0u64.to_le_bytes());
+        let info = parse(&buffer).unwrap();
+        assert_eq!(info.bss_fallback, Some(BssFallback::Data));
+        assert_eq!((info.bss_addr, info.bss_size), (data.1, data.2));
+
+        // and without a .data section either, the writable segments (which hold both)
+        let name = u32::from_le_bytes(buffer[data.0..][..4].try_into().unwrap());
+        buffer[data.0..][..4].copy_from_slice(&(name + 1).to_le_bytes());
+        let info = parse(&buffer).unwrap();
+        assert_eq!(info.bss_fallback, Some(BssFallback::WritableSegments));
+        assert_eq!(info.bss_addr, writable);
+        assert!(info.bss_addr + info.bss_size >= bss.1 + bss.2);
+    }
+
+    /// Builds a minimal 64 bit ELF file with an executable PT_LOAD segment for each of the
+    /// (vaddr, size) pairs, and a single symbol
+    fn build_elf(segments: &[(u64, u64)], symbol: &str, value: u64) -> Vec<u8> {
+        build_elf_with_sections(segments, symbol, value, &[])
+    }
+
+    /// Like build_elf, but with an extra section for each of the (name, type, contents) tuples.
+    /// An empty symbol name leaves out the symbol table, like in a stripped binary
+    fn build_elf_with_sections(
+        segments: &[(u64, u64)],
+        symbol: &str,
+        value: u64,
+        extra: &[(&str, u64, &[u8])],
+    ) -> Vec<u8> {
+        fn push(buffer: &mut Vec<u8>, values: &[(u64, usize)]) {
+            for &(value, width) in values {
+                buffer.extend_from_slice(&value.to_le_bytes()[..width]);
+            }
+        }
+
+        let mut shstrtab = b"\0.bss\0.symtab\0.strtab\0.shstrtab\0".to_vec();
+        let strtab = format!("\0{}\0", symbol).into_bytes();
+        let mut symtab = vec![0; 24];
+        push(
+            &mut symtab,
+            &[(1, 4), (0x11, 1), (0, 1), (1, 2), (value, 8), (8, 8)],
+        );
+        let extra_names: Vec<u64> = extra
+            .iter()
+            .map(|(name, _, _)| {
+                let offset = shstrtab.len() as u64;
+                shstrtab.extend_from_slice(name.as_bytes());
+                shstrtab.push(0);
+                offset
+            })
+            .collect();
+
+        let phoff = 64;
+        let symtab_offset = phoff + 56 * segments.len() as u64;
+        let strtab_offset = symtab_offset + symtab.len() as u64;
+        let shstrtab_offset = strtab_offset + strtab.len() as u64;
+        let extra_offset = shstrtab_offset + shstrtab.len() as u64;
+        let extra_size: u64 = extra.iter().map(|(_, _, data)| data.len() as u64).sum();
+        let shoff = extra_offset + extra_size;
+
+        let mut elf = b"\x7fELF\x02\x01\x01".to_vec();
+        elf.resize(16, 0);
+        push(
+            &mut elf,
+            &[(3, 2), (0x3e, 2), (1, 4), (0, 8), (phoff, 8), (shoff, 8)],
+        );
+        push(
+            &mut elf,
+            &[(0, 4), (64, 2), (56, 2), (segments.len() as u64, 2)],
+        );
+        push(&mut elf, &[(64, 2), (5 + extra.len() as u64, 2), (4, 2)]);
+        for &(vaddr, size) in segments {
+            push(
+                &mut elf,
+                &[(1, 4), (5, 4), (vaddr, 8), (vaddr, 8), (vaddr, 8)],
+            );
+            push(&mut elf, &[(size, 8), (size, 8), (0x1000, 8)]);
+        }
+        elf.extend_from_slice(&symtab);
+        elf.extend_from_slice(&strtab);
+        elf.extend_from_slice(&shstrtab);
+
+        // null, .bss, .symtab, .strtab and .shstrtab section headers, then the extra ones
+        let symtab_type = if symbol.is_empty() { 0 } else { 2 };
+        let mut sections = vec![
+            (0, 0, 0, 0, 0, 0, 0, 0),
+            (1, 8, 3, 0x8000, 0, 0x100, 0, 0),
+            (
+                6,
+                symtab_type,
+                0,
+                0,
+                symtab_offset,
+                symtab.len() as u64,
+                3,
+                24,
+            ),
+            (14, 3, 0, 0, strtab_offset, strtab.len() as u64, 0, 0),
+            (22, 3, 0, 0, shstrtab_offset, shstrtab.len() as u64, 0, 0),
+        ];
+        for ((_, kind, data), name) in extra.iter().zip(extra_names) {
+            let offset = elf.len() as u64;
+            sections.push((name, *kind, 0, 0, offset, data.len() as u64, 0, 0));
+            elf.extend_from_slice(data);
+        }
+
+        for (name, kind, flags, addr, offset, size, link, entsize) in sections {
+            push(
+                &mut elf,
+                &[(name, 4), (kind, 4), (flags, 8), (addr, 8), (offset, 8)],
+            );
+            let info = if kind == 2 { 1 } else { 0 };
+            push(
+                &mut elf,
+                &[(size, 8), (link, 4), (info, 4), (8, 8), (entsize, 8)],
+            );
+        }
+        elf
+    }
+
+    /// Builds a minimal 64 bit PE file with a section header for each of the
+    /// (name, virtual address, virtual size) tuples
+    fn build_pe(sections: &[(&str, u32, u32)]) -> Vec<u8> {
+        build_pe_with_exports(sections, &[])
+    }
+
+    // like in build_elf, except that widths past 8 bytes are padded out with zeros
+    fn push(buffer: &mut Vec<u8>, values: &[(u64, usize)]) {
+        for &(value, width) in values {
+            let bytes = value.to_le_bytes();
+            buffer.extend((0..width).map(|i| bytes.get(i).copied().unwrap_or(0)));
+        }
+    }
+
+    /// Builds a PE binary with an .edata section after the other sections holding the export
+    /// directory. Exports are given by name and target, which is either the RVA of the export
+    /// or the string it's forwarded to
+    fn build_pe_with_exports(sections: &[(&str, u32, u32)], exports: &[(&str, &str)]) -> Vec<u8> {
+        const EDATA_RVA: u32 = 0x8000;
+        const EDATA_OFFSET: u64 = 0x400;
+        const EDATA_SIZE: u64 = 0x200;
+
+        // the directory, then the address, name pointer and ordinal tables, then the strings
+        let count = exports.len() as u64;
+        let mut strings = b"test.dll\0".to_vec();
+        let mut addresses = Vec::new();
+        let mut names = Vec::new();
+        let strings_start = 40 + count * 10;
+        let string_rva = |strings: &mut Vec<u8>, value: &str| {
+            let rva = EDATA_RVA as u64 + strings_start + strings.len() as u64;
+            strings.extend_from_slice(value.as_bytes());
+            strings.push(0);
+            rva
+        };
+        for &(name, target) in exports {
+            let address = match target.strip_prefix("0x") {
+                Some(rva) => u64::from_str_radix(rva, 16).unwrap(),
+                None => string_rva(&mut strings, target),
+            };
+            push(&mut addresses, &[(address, 4)]);
+            push(&mut names, &[(string_rva(&mut strings, name), 4)]);
+        }
+        let mut edata = Vec::new();
+        let table_rva = EDATA_RVA as u64 + 40;
+        push(
+            &mut edata,
+            &[
+                (0, 12),
+                (EDATA_RVA as u64 + strings_start, 4),
+                (1, 4),
+                (count, 4),
+                (count, 4),
+                (table_rva, 4),
+                (table_rva + count * 4, 4),
+                (table_rva + count * 8, 4),
+            ],
+        );
+        edata.extend_from_slice(&addresses);
+        edata.extend_from_slice(&names);
+        for ordinal in 0..count {
+            push(&mut edata, &[(ordinal, 2)]);
+        }
+        edata.extend_from_slice(&strings);
+        let edata_len = edata.len() as u64;
+        edata.resize(EDATA_SIZE as usize, 0);
+
+        // DOS header pointing at the PE header right after it
+        let mut pe = b"MZ".to_vec();
+        pe.resize(0x3c, 0);
+        push(&mut pe, &[(0x40, 4)]);
+        pe.extend_from_slice(b"PE\0\0");
+
+        // COFF header, then the PE32+ optional header with 16 empty data directories
+        push(
+            &mut pe,
+            &[
+                (0x8664, 2),
+                (sections.len() as u64 + 1, 2),
+                (0, 12),
+                (240, 2),
+                (0x22, 2),
+            ],
+        );
+        push(&mut pe, &[(0x20b, 2), (0, 22)]);
+        push(
+            &mut pe,
+            &[(0x1_4000_0000, 8), (0x1000, 4), (0x200, 4), (0, 12)],
+        );
+        push(
+            &mut pe,
+            &[(0, 4), (0x10000, 4), (0x400, 4), (0, 4), (3, 2), (0, 2)],
+        );
+        push(&mut pe, &[(0, 32), (0, 4), (16, 4)]);
+        push(&mut pe, &[(EDATA_RVA as u64, 4), (edata_len, 4), (0, 120)]);
+
+        for &(name, virtual_address, virtual_size) in sections {
+            let mut name = name.as_bytes().to_vec();
+            name.resize(8, 0);
+            pe.extend_from_slice(&name);
+            push(
+                &mut pe,
+                &[
+                    (virtual_size as u64, 4),
+                    (virtual_address as u64, 4),
+                    (0, 16),
+                ],
+            );
+            push(&mut pe, &[(0, 4), (0xc0000040, 4)]);
+        }
+        pe.extend_from_slice(b".edata\0\0");
+        push(
+            &mut pe,
+            &[
+                (EDATA_SIZE, 4),
+                (EDATA_RVA as u64, 4),
+                (EDATA_SIZE, 4),
+                (EDATA_OFFSET, 4),
+                (0, 12),
+                (0x40000040, 4),
+            ],
+        );
+        pe.resize(EDATA_OFFSET as usize, 0);
+        pe.extend_from_slice(&edata);
+        pe
+    }
+
+    #[test]
+    fn test_pe_bss_section() {
+        let base = 0x7ff6_0000_0000;
+        let pe = build_pe(&[(".text", 0x1000, 0x100), (".data", 0x2000, 0x200)]);
+        let info = parse_binary_bytes(Path::new("pe"), &pe, base, 0x10000, None).unwrap();
+        assert_eq!((info.bss_addr, info.bss_size), (base + 0x2000, 0x200));
+
+        // a separate .bss section should be used over .data, wherever it is in the table
+        let pe = build_pe(&[
+            (".text", 0x1000, 0x100),
+            (".data", 0x2000, 0x200),
+            (".bss", 0x3000, 0x300),
+        ]);
+        let info = parse_binary_bytes(Path::new("pe"), &pe, base, 0x10000, None).unwrap();
+        assert_eq!((info.bss_addr, info.bss_size), (base + 0x3000, 0x300));
+
+        let pe = build_pe(&[(".text", 0x1000, 0x100)]);
+        assert!(parse_binary_bytes(Path::new("pe"), &pe, base, 0x10000, None).is_err());
+    }
+
+    #[test]
+    fn test_pe_forwarded_exports() {
+        let base = 0x7ff6_0000_0000;
+        // the export that isn't forwarded points past the directory, into the rest of .edata
+        let pe = build_pe_with_exports(
+            &[(".data", 0x2000, 0x200)],
+            &[
+                ("PyRuntime", "0x81f0"),
+                ("HeapAlloc", "NTDLL.RtlAllocateHeap"),
+                ("ByOrdinal", "KERNELBASE.#42"),
+            ],
+        );
+        let info = parse_binary_bytes(Path::new("pe"), &pe, base, 0x10000, None).unwrap();
+        assert!(info.symbols.contains_key("PyRuntime"));
+        assert!(!info.symbols.contains_key("HeapAlloc"));
+        assert!(!info.symbols.contains_key("ByOrdinal"));
+        assert_eq!(info.forwarded_exports.len(), 2);
+        assert_eq!(info.forwarded_exports["HeapAlloc"], "NTDLL.RtlAllocateHeap");
+        assert_eq!(info.forwarded_exports["ByOrdinal"], "KERNELBASE.#42");
+    }
+
+    #[test]
+    fn test_elf_symbol_preference() {
+        let sym = |st_value, st_shndx| goblin::elf::Sym {
+            st_value,
+            st_shndx,
+            ..Default::default()
+        };
+        let program_headers = [goblin::elf::ProgramHeader {
+            p_type: goblin::elf::program_header::PT_LOAD,
+            p_vaddr: 0x1000,
+            p_memsz: 0x1000,
+            ..Default::default()
+        }];
+
+        // .symtab has the definitions, .dynsym has an undefined import for 'imported', and a
+        // value outside of any segment for 'unmapped'. The definitions should win
+        let syms = [
+            ("imported", sym(0x1100, 1)),
+            ("unmapped", sym(0x1200, 1)),
+            ("both", sym(0x1300, 1)),
+        ];
+        let dynsyms = [
+            ("imported", sym(0x1400, 0)),
+            ("unmapped", sym(0x9000, 1)),
+            ("both", sym(0x1500, 1)),
+        ];
+        let mut symbols = HashMap::new();
+        add_elf_symbols(
+            &mut symbols,
+            &mut HashMap::new(),
+            syms.into_iter().chain(dynsyms),
+            &program_headers,
+            0x10000,
+        );
+        assert_eq!(symbols["imported"], 0x11100);
+        assert_eq!(symbols["unmapped"], 0x11200);
+        // when both are defined in a segment, the .dynsym value is used like before
+        assert_eq!(symbols["both"], 0x11500);
+    }
+
+    #[test]
+    fn test_debuglink() {
+        let dir = tempfile::tempdir().unwrap();
+        let debug = build_elf(&[(0x1000, 0x100)], "_PyRuntime", 0x1080);
+        std::fs::create_dir(dir.path().join(".debug")).unwrap();
+        std::fs::write(dir.path().join(".debug/libpython.so.debug"), &debug).unwrap();
+
+        let parse = |crc: u32| {
+            let mut debuglink = b"libpython.so.debug\0\0".to_vec();
+            debuglink.extend_from_slice(&crc.to_le_bytes());
+            let elf = build_elf_with_sections(
+                &[(0x1000, 0x100)],
+                "Py_Main",
+                0x1050,
+                &[(".gnu_debuglink", 1, &debuglink)],
+            );
+            let path = dir.path().join("libpython.so");
+            std::fs::write(&path, elf).unwrap();
+            parse_binary(&path, 0x7f00_0000_1000, 0x1000, None).unwrap()
+        };
+
+        // symbols from the debug file are added to the ones in the binary
+        let info = parse(crc32fast::hash(&debug));
+        assert_eq!(info.symbols["Py_Main"], 0x7f00_0000_1050);
+        assert_eq!(info.symbols["_PyRuntime"], 0x7f00_0000_1080);
+        assert_eq!(info.symbol_sizes["_PyRuntime"], 8);
+
+        // a debug file from another build is ignored
+        let info = parse(crc32fast::hash(&debug) ^ 1);
+        assert_eq!(info.symbols["Py_Main"], 0x7f00_0000_1050);
+        assert!(!info.symbols.contains_key("_PyRuntime"));
+    }
+
+    #[test]
+    fn test_build_id() {
+        let debug_dir = tempfile::tempdir().unwrap();
+        let debug = build_elf(&[(0x1000, 0x100)], "_PyRuntime", 0x1080);
+        std::fs::create_dir_all(debug_dir.path().join(".build-id/ab")).unwrap();
+        std::fs::write(debug_dir.path().join(".build-id/ab/cdef01.debug"), debug).unwrap();
+        set_debug_dirs(vec![debug_dir.path().to_owned()]);
+
+        let mut note = Vec::new();
+        for value in [4u32, 4, goblin::elf::note::NT_GNU_BUILD_ID] {
+            note.extend_from_slice(&value.to_le_bytes());
+        }
+        note.extend_from_slice(b"GNU\0");
+        let parse = |build_id: &[u8]| {
+            let note = [&note, build_id].concat();
+            let elf = build_elf_with_sections(
+                &[(0x1000, 0x100)],
+                "",
+                0,
+                &[(".note.gnu.build-id", 7, &note)],
+            );
+            parse_binary_bytes(Path::new("stripped"), &elf, 0x7f00_0000_1000, 0x1000, None).unwrap()
+        };
+        assert_eq!(
+            parse(&[0xab, 0xcd, 0xef, 0x01]).symbols["_PyRuntime"],
+            0x7f00_0000_1080
+        );
+        assert!(parse(&[0xab, 0xcd, 0xef, 0x02]).symbols.is_empty());
+        set_debug_dirs(vec![PathBuf::from("/usr/lib/debug")]);
+    }
+
+    #[test]
+    fn test_symbol_for_address() {
+        let elf = build_elf(&[(0x1000, 0x100)], "function", 0x1050);
+        let base = 0x7f00_0000_0000;
+        let mut info =
+            parse_binary_bytes(Path::new("elf"), &elf, base + 0x1000, 0x1000, None).unwrap();
+        info.symbols.insert("alias".to_owned(), base + 0x1050);
+        info.symbols.insert("other".to_owned(), base + 0x1080);
+
+        assert_eq!(info.symbol_for_address(base + 0x1040), None);
+        assert_eq!(
+            info.symbol_for_address(base + 0x1050),
+            Some(("alias", base + 0x1050))
+        );
+        assert_eq!(
+            info.symbol_for_address(base + 0x107f),
+            Some(("alias", base + 0x1050))
+        );
+        assert_eq!(
+            info.symbol_for_address(base + 0x2000),
+            Some(("other", base + 0x1080))
+        );
+
+        // sizes come from the symbol table, or the distance to the next symbol otherwise
+        assert_eq!(info.symbol_size("function"), Some(8));
+        assert_eq!(info.symbol_size("alias"), Some(0x30));
+        assert_eq!(info.symbol_size("other"), None);
+        assert_eq!(info.symbol_size("missing"), None);
+    }
+
+    #[test]
+    fn test_find_symbols() {
+        let elf = build_elf(&[(0x1000, 0x100)], "_PyRuntime", 0x1050);
+        let mut info = parse_binary_bytes(Path::new("elf"), &elf, 0x1000, 0x1000, None).unwrap();
+        info.symbols.insert("PyRuntime_3_12".to_owned(), 0x1060);
+        info.symbols.insert("PyRun_String".to_owned(), 0x1070);
+
+        assert_eq!(
+            info.find_symbols("*PyRuntime*"),
+            vec![("PyRuntime_3_12", 0x1060), ("_PyRuntime", 0x1050)]
+        );
+        assert_eq!(
+            info.find_symbols("Py*Run*"),
+            vec![("PyRun_String", 0x1070), ("PyRuntime_3_12", 0x1060)]
+        );
+        assert_eq!(
+            info.find_symbols("?PyRuntime"),
+            vec![("_PyRuntime", 0x1050)]
+        );
+        assert_eq!(info.find_symbols("PyRuntime"), vec![]);
+        assert_eq!(info.find_symbols("*String"), vec![("PyRun_String", 0x1070)]);
+    }
+
+    #[test]
+    fn test_multiple_executable_segments() {
+        // the second segment doesn't start on a page boundary, and its mapping starts at
+        // 0x3000 in the binary
+        let elf = build_elf(&[(0x1000, 0x100), (0x3040, 0x1fc0)], "_PyRuntime", 0x3050);
+        let base = 0x7f00_0000_0000;
+
+        let info = parse_binary_bytes(Path::new("elf"), &elf, base + 0x3000, 0x2000, None).unwrap();
+        assert_eq!(info.symbols["_PyRuntime"], base + 0x3050);
+        assert_eq!(info.bss_addr, base + 0x8000);
+
+        // the first segment is used when given its mapping, or when nothing matches
+        let info = parse_binary_bytes(Path::new("elf"), &elf, base + 0x1000, 0x1000, None).unwrap();
+        assert_eq!(info.symbols["_PyRuntime"], base + 0x3050);
+        let info = parse_binary_bytes(Path::new("elf"), &elf, base + 0x1000, 0x5000, None).unwrap();
+        assert_eq!(info.symbols["_PyRuntime"], base + 0x3050);
+    }
+
+    #[no_mangle]
+    #[inline(never)]
+    extern "C" fn py_spy_binary_parser_test_function() {}
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_own_symbol_address() {
+        // find where our own executable segment is mapped, and check that the address of a
+        // function in it is computed correctly
+        let exe = std::fs::read_link("/proc/self/exe").unwrap();
+        let maps = proc_maps::get_process_maps(std::process::id() as _).unwrap();
+        let map = maps
+            .iter()
+            .find(|map| map.is_exec() && map.filename() == Some(exe.as_path()))
+            .unwrap();
+        let info = parse_binary(&exe, map.start() as u64, map.size() as u64, None).unwrap();
+        assert_eq!(
+            info.symbols["py_spy_binary_parser_test_function"],
+            py_spy_binary_parser_test_function as *const () as u64
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_parse_binary_cached() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("binary");
+        std::fs::copy("/proc/self/exe", &path).unwrap();
+
+        let first = parse_binary_cached(&path, 0x1000, 0x2000, None).unwrap();
+        let second = parse_binary_cached(&path, 0x1000, 0x2000, None).unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+
+        // the symbols are different when loaded at another address
+        let moved = parse_binary_cached(&path, 0x4000, 0x2000, None).unwrap();
+        assert!(!Arc::ptr_eq(&first, &moved));
+
+        // and the file needs to be parsed again when it changes
+        let file = File::options().write(true).open(&path).unwrap();
+        file.set_modified(SystemTime::now() + std::time::Duration::from_secs(60))
+            .unwrap();
+        let modified = parse_binary_cached(&path, 0x1000, 0x2000, None).unwrap();
+        assert!(!Arc::ptr_eq(&first, &modified));
+        assert_eq!(first.symbols, modified.symbols);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_dump_symbols() {
+        let dump = dump_symbols(Path::new("/proc/self/exe")).unwrap();
+        assert!(dump.bss_size > 0);
+        assert!(dump.symbols.iter().any(|(name, _)| name == "main"));
+        assert!(dump.symbols.windows(2).all(|pair| pair[0] <= pair[1]));
     }
 }
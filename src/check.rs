@@ -0,0 +1,163 @@
+use std::path::PathBuf;
+
+use anyhow::{Context, Error};
+use console::style;
+use remoteprocess::Pid;
+use serde_derive::Serialize;
+
+use crate::binary_parser::BinaryInfo;
+use crate::config::Config;
+use crate::memory::MemorySource;
+use crate::python_process_info::{
+    get_interpreter_address, get_python_version, get_threadstate_address, PythonProcessInfo,
+};
+
+/// The symbols that py-spy looks up to find the interpreter, its version and the thread
+/// holding the GIL. Which ones are needed depends on the version of python
+const INTERPRETER_SYMBOLS: [&str; 4] = [
+    "_PyRuntime",
+    "interp_head",
+    "_PyThreadState_Current",
+    "Py_GetVersion.version",
+];
+
+/// What was found when checking that a process can be profiled, without sampling it. Each step
+/// needs the ones before it to have worked, so everything after the step that failed is None
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct CheckReport {
+    pub pid: Pid,
+    pub command_line: String,
+    pub python_binary: Option<BinaryReport>,
+    pub libpython_binary: Option<BinaryReport>,
+    pub version: Option<String>,
+    pub interpreter_address: Option<usize>,
+    /// The address of the current thread state, which is needed for --gil. This is None when
+    /// it can't be found for this version of python
+    pub threadstate_address: Option<usize>,
+    /// Why the process can't be profiled
+    pub error: Option<String>,
+}
+
+/// The symbols found in the python binary or libpython
+#[derive(Debug, Clone, Serialize)]
+pub struct BinaryReport {
+    pub filename: PathBuf,
+    pub symbols: usize,
+    /// The symbols py-spy looks up to find the interpreter that are in the binary
+    pub interpreter_symbols: Vec<String>,
+}
+
+impl BinaryReport {
+    fn new(binary: &BinaryInfo) -> BinaryReport {
+        BinaryReport {
+            filename: binary.filename.clone(),
+            symbols: binary.symbols.len(),
+            interpreter_symbols: INTERPRETER_SYMBOLS
+                .iter()
+                .filter(|symbol| binary.symbols.contains_key(**symbol))
+                .map(|symbol| symbol.to_string())
+                .collect(),
+        }
+    }
+}
+
+impl CheckReport {
+    /// Returns whether the interpreter was found, which means the process can be profiled
+    pub fn ok(&self) -> bool {
+        self.error.is_none()
+    }
+
+    /// Prints out what was found
+    pub fn print(&self) {
+        println!(
+            "Process {}: {}",
+            style(self.pid).bold().yellow(),
+            self.command_line
+        );
+        let binaries = [
+            ("Python binary", &self.python_binary),
+            ("libpython", &self.libpython_binary),
+        ];
+        for (label, binary) in binaries {
+            match binary {
+                Some(binary) => {
+                    println!(
+                        "{}: {} ({} symbols)",
+                        label,
+                        style(binary.filename.display()).dim(),
+                        binary.symbols
+                    );
+                    if !binary.interpreter_symbols.is_empty() {
+                        println!("    found {}", binary.interpreter_symbols.join(", "));
+                    }
+                }
+                None => println!("{}: {}", label, style("not found").dim()),
+            }
+        }
+        if let Some(version) = &self.version {
+            println!("Python v{}", style(version).bold());
+        }
+        if let Some(addr) = self.interpreter_address {
+            println!("Interpreter state at 0x{:016x}", addr);
+        }
+        if let Some(addr) = self.threadstate_address {
+            println!("Current thread state at 0x{:016x}", addr);
+        }
+        if self.ok() {
+            println!(
+                "{}",
+                style(format!("py-spy can profile process {}", self.pid))
+                    .bold()
+                    .green()
+            );
+        }
+    }
+}
+
+/// Checks that py-spy can find the python interpreter in a process, by going through the same
+/// steps as when it starts sampling: parsing the python binary and libpython, finding the
+/// python version and then the interpreter. This returns an error when the process can't be
+/// opened, and otherwise a report of how far it got
+pub fn check_process(pid: Pid, config: &Config) -> Result<CheckReport, Error> {
+    let process = remoteprocess::Process::new(pid)
+        .context("Failed to open process - check if it is running.")?;
+    let memory = MemorySource::new(pid, config)?;
+
+    let mut report = CheckReport {
+        pid,
+        command_line: process
+            .cmdline()
+            .map(|args| args.join(" "))
+            .unwrap_or_default(),
+        ..Default::default()
+    };
+
+    let mut check = || -> Result<(), Error> {
+        let python_info = PythonProcessInfo::new(&process)?;
+        report.python_binary = python_info.python_binary.as_deref().map(BinaryReport::new);
+        report.libpython_binary = python_info
+            .libpython_binary
+            .as_deref()
+            .map(BinaryReport::new);
+
+        let version = get_python_version(&python_info, &memory)?;
+        report.version = Some(version.to_string());
+        if python_info.build_flags().trace_refs {
+            return Err(format_err!(
+                "Python {} was built with Py_TRACE_REFS (like debug builds of python 3.7), which isn't supported",
+                version
+            ));
+        }
+
+        let addr = get_interpreter_address(&python_info, &memory, &version)?;
+        report.interpreter_address = Some(addr);
+
+        let addr = get_threadstate_address(&python_info, &version, config)?;
+        report.threadstate_address = Some(addr).filter(|&addr| addr != 0);
+        Ok(())
+    };
+    if let Err(e) = check() {
+        report.error = Some(format!("{:#}", e));
+    }
+    Ok(report)
+}
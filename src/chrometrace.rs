@@ -0,0 +1,2925 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::{Context, Error};
+use chrono::{Local, SecondsFormat};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use remoteprocess::Pid;
+use serde_derive::Serialize;
+use tempfile::NamedTempFile;
+
+use crate::config::{Config, TraceCompression, TrackOrder};
+use crate::sampler::SamplingStats;
+use crate::stack_trace::{expand_template, FdStats, Frame, FrameKind, GCStats, StackTrace};
+
+/*
+ * This file contains code to export py-spy samples in the chrome trace event format, which
+ * can be loaded by https://ui.perfetto.dev or chrome://tracing
+ *
+ * Each python frame is emitted as a duration event, with a 'B' event when the frame first
+ * shows up in a thread's stack and an 'E' event when it disappears. The format spec can be
+ * found here:
+ * https://docs.google.com/document/d/1CvAClvFfyA5R-PhYUmn5OOQtYMH4h6I0nSsKchNAySU
+ *
+ * Optionally, the end event of each frame can also carry how many samples the frame was on
+ * the stack for ('total_samples') and how many of those it was the innermost frame in
+ * ('self_samples'). The trace viewer merges the args of the end event into the slice.
+ * Likewise, the begin event can carry the values of the local variables of the frame
+ * ('locals') when these were captured for its function.
+ *
+ * The category of each slice is where its frame came from: 'python', 'native' (with --native)
+ * or 'gil' (for the '<idle>' and '<native>' frames added with --gil-frames), so that these can
+ * be told apart in the trace viewer. Frames that py-spy makes up itself, like the thread and
+ * process frames, have the 'py-spy' category.
+ *
+ * Frames with some names (like '<module>' and '<genexpr>') can be left out of every sample
+ * before it is compared against the previous one, so that their children start and end slices
+ * at the depth the left out frames would have been at.
+ *
+ * The first sample each thread shows up in is also marked with a 'thread started' instant
+ * event ('i') on the thread's track.
+ *
+ * Timestamps are relative to when sampling started, unless an epoch is given. Timestamps are
+ * then relative to that instead, so that traces recorded separately (like of different
+ * processes) line up when they are loaded together.
+ *
+ * Optionally, the number of threads and the total number of frames across them in each
+ * sample can also be emitted as counter events. These go on a synthetic process with pid 0,
+ * so that they get a track of their own rather than being mixed in with any one process.
+ *
+ * Optionally, the coroutine at the root of each asyncio task can also be emitted as an
+ * async event ('b'/'e' with an id), which covers the time between the first and last sample
+ * the task was seen in - including the time it was suspended awaiting something. Tasks can
+ * also be followed across awaits with flow events ('s'/'f'), which draw an arrow from the last
+ * sample a task was seen in before it was suspended to the sample it was resumed in. This is
+ * only as good as the sampling rate, since a task that suspends and resumes between two
+ * samples looks like it was running the whole time.
+ *
+ * Optionally, the number of events can be limited. Once the limit is reached, nothing more is
+ * recorded apart from the end events closing the open slices - so the trace can go over the
+ * limit by the events of the sample that reached it, and the end events written at the end.
+ *
+ * Since a long capture can produce a huge number of events, these are streamed out to a
+ * zstd compressed tempfile while recording (or into memory, when the default temp directory
+ * isn't writable), and then transcoded to gzip on write. Events can also be streamed
+ * uncompressed to another writer as they are recorded (like a unix socket that some other
+ * process is reading from), in which case nothing is buffered up.
+ *
+ * Events can also be gzipped straight into the output file, for captures that are too long to
+ * fit in a tempfile. The compressor is flushed every second, so if py-spy is killed the file
+ * still holds everything up to the last flush - but without the closing ']}' of the json object,
+ * and gzip will complain about the missing trailer. Both perfetto and chrome://tracing accept
+ * an unterminated bare array of events, so the truncated trace can still be loaded after
+ * 'gunzip -c' when written with --trace-array, or by appending the missing ']}' otherwise.
+ *
+ * Recording can be paused, which ends all the open slices (and async tasks) at the time of the
+ * last sample. Samples are dropped while paused, and after resuming every frame starts a new
+ * slice - so the time spent paused shows up as a gap in the timeline, rather than as slices
+ * that stretch across it. The gap is shortened like any other when a max gap is set.
+ *
+ * How many stack traces failed to be read while sampling (and why) can be added at the end as
+ * a 'sampling_stats' metadata event, since these samples are missing from the trace.
+ *
+ * By default the events go in the 'traceEvents' array of a json object, which also holds
+ * the sampling interval, py-spy version, pid and start time of the capture in 'otherData'.
+ */
+
+#[derive(Clone, Debug, Serialize)]
+struct Args {
+    pub filename: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub line: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total_samples: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub self_samples: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub locals: Option<BTreeMap<String, String>>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct Event {
+    pub args: Args,
+    pub cat: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cname: Option<&'static str>,
+    pub name: String,
+    pub ph: String,
+    pub pid: u64,
+    pub tid: u64,
+    pub ts: u64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct AsyncEvent {
+    pub args: Args,
+    pub cat: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cname: Option<&'static str>,
+    pub id: String,
+    pub name: String,
+    pub ph: String,
+    pub pid: u64,
+    pub tid: u64,
+    pub ts: u64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct InstantEvent {
+    pub cat: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cname: Option<&'static str>,
+    pub name: String,
+    pub ph: String,
+    // scopes the event to the thread, which draws it on the thread's track
+    pub s: String,
+    pub pid: u64,
+    pub tid: u64,
+    pub ts: u64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct FlowEvent {
+    pub cat: String,
+    pub id: u64,
+    pub name: String,
+    pub ph: String,
+    // binds the end of the flow to the slice enclosing it, rather than the next one to start
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bp: Option<&'static str>,
+    pub pid: u64,
+    pub tid: u64,
+    pub ts: u64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct MetadataEvent<A> {
+    pub args: A,
+    pub name: String,
+    pub ph: String,
+    pub pid: u64,
+    pub tid: u64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct SortIndex {
+    pub sort_index: u64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct Name {
+    pub name: String,
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct CounterEvent<A> {
+    pub args: A,
+    pub name: String,
+    pub ph: String,
+    pub pid: u64,
+    pub ts: u64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct SampleCounters {
+    pub thread_count: u64,
+    pub total_frames: u64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct GCCollections {
+    pub gen0: u64,
+    pub gen1: u64,
+    pub gen2: u64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct GCEnabled {
+    pub enabled: u64,
+}
+
+/// Information about the capture, which is written before the events as the 'otherData' of
+/// the json object format
+#[derive(Clone, Debug, Serialize)]
+struct TraceMetadata {
+    pub version: &'static str,
+    pub sampling_interval_us: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pid: Option<Pid>,
+    pub start_time: String,
+}
+
+impl TraceMetadata {
+    /// Returns the metadata for a capture starting now, or None when writing a bare array
+    fn new(config: &Config) -> Option<TraceMetadata> {
+        if config.trace_array {
+            return None;
+        }
+        Some(TraceMetadata {
+            version: env!("CARGO_PKG_VERSION"),
+            sampling_interval_us: 1_000_000 / config.sampling_rate.max(1),
+            pid: config.pid,
+            start_time: start_time(),
+        })
+    }
+}
+
+fn start_time() -> String {
+    Local::now().to_rfc3339_opts(SecondsFormat::Micros, true)
+}
+
+/// The pid of the synthetic process that the sample counters are written to
+const COUNTERS_PID: u64 = 0;
+
+/// Reserved color names from the trace viewer, used to give each process a distinct color
+const PROCESS_COLORS: [&str; 8] = [
+    "rail_response",
+    "rail_animation",
+    "rail_idle",
+    "rail_load",
+    "cq_build_passed",
+    "cq_build_running",
+    "cq_build_attempt_failed",
+    "generic_work",
+];
+
+/// How often events written straight into the output file are flushed out to it
+const FILE_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Where trace events are streamed out to
+enum Sink {
+    Tempfile(zstd::Encoder<'static, NamedTempFile>),
+    // buffers the compressed events in memory, when a tempfile couldn't be created
+    Memory(zstd::Encoder<'static, Vec<u8>>),
+    Stream(Box<dyn Write + Send>),
+    File(FileEncoder),
+}
+
+/// Compresses events written straight into the output file
+enum FileEncoder {
+    None(File),
+    Gzip(GzEncoder<File>),
+    Zstd(zstd::Encoder<'static, File>),
+}
+
+impl FileEncoder {
+    fn new(
+        file: File,
+        compression: TraceCompression,
+        zstd_level: i32,
+    ) -> Result<FileEncoder, Error> {
+        Ok(match compression {
+            TraceCompression::none => FileEncoder::None(file),
+            TraceCompression::gzip => {
+                FileEncoder::Gzip(GzEncoder::new(file, Compression::default()))
+            }
+            TraceCompression::zstd => FileEncoder::Zstd(zstd_encoder(file, zstd_level)?),
+        })
+    }
+
+    fn finish(self) -> std::io::Result<()> {
+        match self {
+            FileEncoder::None(mut file) => file.flush(),
+            FileEncoder::Gzip(encoder) => encoder.finish().map(|_| ()),
+            FileEncoder::Zstd(encoder) => encoder.finish().map(|_| ()),
+        }
+    }
+}
+
+/// Creates a zstd encoder, checking the level first since zstd clamps levels that are out
+/// of range rather than returning an error. Level 0 is zstd's default level
+fn zstd_encoder<W: Write>(w: W, level: i32) -> Result<zstd::Encoder<'static, W>, Error> {
+    let levels = zstd::compression_level_range();
+    if !levels.contains(&level) {
+        return Err(format_err!(
+            "Invalid zstd compression level {}, which should be from {} to {}",
+            level,
+            levels.start(),
+            levels.end()
+        ));
+    }
+    Ok(zstd::Encoder::new(w, level)?)
+}
+
+impl Write for FileEncoder {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            FileEncoder::None(file) => file.write(buf),
+            FileEncoder::Gzip(encoder) => encoder.write(buf),
+            FileEncoder::Zstd(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            FileEncoder::None(file) => file.flush(),
+            FileEncoder::Gzip(encoder) => encoder.flush(),
+            FileEncoder::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
+
+impl Write for Sink {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Sink::Tempfile(encoder) => encoder.write(buf),
+            Sink::Memory(encoder) => encoder.write(buf),
+            Sink::Stream(stream) => stream.write(buf),
+            Sink::File(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Sink::Tempfile(encoder) => encoder.flush(),
+            Sink::Memory(encoder) => encoder.flush(),
+            Sink::Stream(stream) => stream.flush(),
+            Sink::File(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// Streams out trace events to a compressed tempfile, or directly to another writer. Events
+/// are written into the 'traceEvents' array of a json object along with the metadata, or into
+/// a bare array when there isn't any
+struct Writer {
+    encoder: BufWriter<Sink>,
+    first: bool,
+    last_flush: Instant,
+    // the number of events written so far
+    events: u64,
+    object: bool,
+}
+
+impl Writer {
+    /// Creates a writer that buffers events in a tempfile, in the given directory or the
+    /// default one for the system ($TMPDIR). When no directory is given and the default one
+    /// isn't writable, events are buffered in memory instead
+    fn new(
+        tmpdir: Option<&Path>,
+        zstd_level: i32,
+        metadata: Option<&TraceMetadata>,
+    ) -> Result<Writer, Error> {
+        let dir = tmpdir
+            .map(Path::to_path_buf)
+            .unwrap_or_else(std::env::temp_dir);
+        let sink = match NamedTempFile::new_in(&dir) {
+            Ok(file) => Sink::Tempfile(zstd_encoder(file, zstd_level)?),
+            Err(e) if tmpdir.is_none() => {
+                warn!(
+                    "Failed to create a temporary file in '{}' ({}), buffering chrometrace events in memory instead. Use --trace-tmpdir to buffer them in another directory",
+                    dir.display(),
+                    e
+                );
+                Sink::Memory(zstd_encoder(Vec::new(), zstd_level)?)
+            }
+            Err(e) => {
+                return Err(format_err!(
+                    "Failed to create a temporary file for chrometrace events in '{}': {}",
+                    dir.display(),
+                    e
+                ))
+            }
+        };
+        Writer::with_sink(sink, metadata)
+    }
+
+    fn with_sink(sink: Sink, metadata: Option<&TraceMetadata>) -> Result<Writer, Error> {
+        let mut encoder = BufWriter::new(sink);
+        if let Some(metadata) = metadata {
+            encoder.write_all(b"{\"otherData\":")?;
+            serde_json::to_writer(&mut encoder, metadata)?;
+            encoder.write_all(b",\"traceEvents\":")?;
+        }
+        encoder.write_all(b"[")?;
+        Ok(Writer {
+            encoder,
+            first: true,
+            last_flush: Instant::now(),
+            events: 0,
+            object: metadata.is_some(),
+        })
+    }
+
+    /// Sends buffered events on to a live stream, so that they show up as each sample is
+    /// recorded. Events written into a file are only flushed every so often, since each flush
+    /// hurts the compression, and this does nothing at all when writing to a tempfile
+    fn flush_stream(&mut self) -> Result<(), Error> {
+        match self.encoder.get_ref() {
+            Sink::Stream(_) => self.encoder.flush()?,
+            Sink::File(_) if self.last_flush.elapsed() >= FILE_FLUSH_INTERVAL => {
+                self.encoder.flush()?;
+                self.last_flush = Instant::now();
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn write_event<T: serde::Serialize>(&mut self, event: &T) -> Result<(), Error> {
+        if !self.first {
+            self.encoder.write_all(b",\n")?;
+        }
+        self.first = false;
+        self.events += 1;
+        serde_json::to_writer(&mut self.encoder, event)?;
+        Ok(())
+    }
+
+    /// Terminates the json array (and object) and returns where the events were written to
+    fn close(mut self) -> Result<Sink, Error> {
+        let end: &[u8] = if self.object { b"]}\n" } else { b"]\n" };
+        self.encoder.write_all(end)?;
+        let mut sink = self
+            .encoder
+            .into_inner()
+            .map_err(|e| e.into_error())
+            .context("Failed to flush trace events")?;
+        sink.flush()?;
+        Ok(sink)
+    }
+}
+
+/// Per thread information used to order and label the tracks in the output
+#[derive(Debug, Default)]
+struct ThreadInfo {
+    samples: u64,
+    name: Option<String>,
+    main: bool,
+    // how many threads were seen before this one
+    first_seen: usize,
+    formatted_tid: String,
+    // the name written out for the track of this thread so far
+    label: Option<String>,
+}
+
+/// The number of samples an open slice has been seen in
+#[derive(Debug, Default, Clone, Copy)]
+struct SliceCounts {
+    total: u64,
+    own: u64,
+}
+
+/// An asyncio task (or other coroutine) that has been seen in the samples, identified by
+/// the address of its outermost coroutine frame
+#[derive(Debug)]
+struct Task {
+    name: String,
+    filename: String,
+    tid: u64,
+    last_ts: u64,
+    // the thread and sample the task was last seen in
+    last_tid: u64,
+    last_sample: u64,
+}
+
+pub struct Chrometrace {
+    writer: Writer,
+    last_ts: u64,
+    // ordered so that the end events for threads that stop showing up are written in the
+    // same order every time
+    prev_traces: BTreeMap<(Pid, u64), StackTrace>,
+    threads: HashMap<(Pid, u64), ThreadInfo>,
+    gc_stats: HashMap<Pid, GCStats>,
+    fd_stats: HashMap<Pid, FdStats>,
+    tasks: HashMap<(Pid, usize), Task>,
+    show_linenumbers: bool,
+    track_order: TrackOrder,
+    compression: TraceCompression,
+    zstd_level: i32,
+    tmpdir: Option<PathBuf>,
+    async_events: bool,
+    async_flows: bool,
+    collapse_recursion: bool,
+    omit_frames: HashSet<String>,
+    // gaps between samples longer than max_gap are shortened to sample_interval, with the
+    // total time taken out of the timeline in skipped
+    max_gap: Option<Duration>,
+    sample_interval: Duration,
+    skipped: Duration,
+    last_sample: Option<Duration>,
+    // the sample timestamp that timestamps are relative to. This is the start of sampling,
+    // or after a reset, the first sample recorded after it
+    origin: Option<Duration>,
+    // the time from the epoch to the start of sampling, which is worked out from the first sample
+    trace_epoch: Option<SystemTime>,
+    epoch_offset: Option<Duration>,
+    // the number of samples recorded, and the id of the last flow event
+    samples: u64,
+    flow_id: u64,
+    sample_counts: bool,
+    show_counters: bool,
+    // the sample counts of the open slices of each thread, ordered from the root frame
+    slice_counts: HashMap<(Pid, u64), Vec<SliceCounts>>,
+    // when slices have a minimum duration, this has the start times of the open slices of each
+    // thread (ordered from the root frame), and the time of the last event written for it
+    min_duration: u64,
+    slice_starts: HashMap<(Pid, u64), (Vec<u64>, u64)>,
+    color_by_process: bool,
+    process_label: Option<String>,
+    thread_label: Option<String>,
+    // command lines of each process, only collected when labelling processes
+    command_lines: HashMap<Pid, String>,
+    // names given to processes, and the processes that have shown up in the samples of the
+    // current capture. Without a process label template, subprocesses are named by command line
+    process_names: HashMap<Pid, String>,
+    processes: HashSet<Pid>,
+    name_subprocesses: bool,
+    // command lines aren't read from the processes themselves when anonymizing
+    anonymize: bool,
+    // the threads that have shown up in the samples of the current capture
+    started_threads: HashSet<(Pid, u64)>,
+    max_events: Option<u64>,
+    metadata: Option<TraceMetadata>,
+    // samples are dropped while recording is paused
+    recording: bool,
+}
+
+impl Chrometrace {
+    pub fn new(config: &Config) -> Result<Chrometrace, Error> {
+        let metadata = TraceMetadata::new(config);
+        let tmpdir = config.trace_tmpdir.as_ref().map(Path::new);
+        let writer = Writer::new(tmpdir, config.zstd_level, metadata.as_ref())?;
+        Chrometrace::with_writer(config, writer, metadata)
+    }
+
+    /// Creates a trace that streams out uncompressed events to a writer as they are recorded,
+    /// instead of writing everything out at the end
+    pub fn streaming(config: &Config, stream: Box<dyn Write + Send>) -> Result<Chrometrace, Error> {
+        let metadata = TraceMetadata::new(config);
+        let writer = Writer::with_sink(Sink::Stream(stream), metadata.as_ref())?;
+        Chrometrace::with_writer(config, writer, metadata)
+    }
+
+    /// Creates a trace that compresses events straight into a file as they are recorded,
+    /// instead of buffering them up in a tempfile. The file is finished off by 'write', and
+    /// nothing more is written out after that
+    pub fn new_streaming(config: &Config, path: &Path) -> Result<Chrometrace, Error> {
+        let file = File::create(path)?;
+        let encoder = FileEncoder::new(file, config.trace_compression, config.zstd_level)?;
+        let metadata = TraceMetadata::new(config);
+        let writer = Writer::with_sink(Sink::File(encoder), metadata.as_ref())?;
+        Chrometrace::with_writer(config, writer, metadata)
+    }
+
+    fn with_writer(
+        config: &Config,
+        writer: Writer,
+        metadata: Option<TraceMetadata>,
+    ) -> Result<Chrometrace, Error> {
+        Ok(Chrometrace {
+            writer,
+            last_ts: 0,
+            prev_traces: BTreeMap::new(),
+            threads: HashMap::new(),
+            gc_stats: HashMap::new(),
+            fd_stats: HashMap::new(),
+            tasks: HashMap::new(),
+            show_linenumbers: config.show_line_numbers,
+            track_order: config.track_order,
+            compression: config.trace_compression,
+            zstd_level: config.zstd_level,
+            tmpdir: config.trace_tmpdir.as_ref().map(PathBuf::from),
+            async_events: config.async_events,
+            async_flows: config.async_flows,
+            collapse_recursion: config.collapse_recursion,
+            omit_frames: config.omit_frames.iter().cloned().collect(),
+            max_gap: config.max_gap,
+            sample_interval: Duration::from_secs_f64(1.0 / config.sampling_rate.max(1) as f64),
+            skipped: Duration::ZERO,
+            last_sample: None,
+            origin: Some(Duration::ZERO),
+            trace_epoch: config.trace_epoch,
+            epoch_offset: None,
+            samples: 0,
+            flow_id: 0,
+            sample_counts: config.sample_counts,
+            show_counters: config.show_counters,
+            slice_counts: HashMap::new(),
+            min_duration: config.min_duration_us,
+            slice_starts: HashMap::new(),
+            // only tint by process when there can be more than one, since this
+            // replaces the usual coloring by function name
+            color_by_process: config.subprocesses,
+            process_label: config.process_label.clone(),
+            thread_label: config.thread_label.clone(),
+            command_lines: HashMap::new(),
+            process_names: HashMap::new(),
+            processes: HashSet::new(),
+            name_subprocesses: config.subprocesses && config.process_label.is_none(),
+            anonymize: config.anonymize,
+            started_threads: HashSet::new(),
+            max_events: config.max_events,
+            metadata,
+            recording: true,
+        })
+    }
+
+    /// Returns the metadata for the next capture, which starts now
+    fn next_metadata(&mut self) -> Option<&TraceMetadata> {
+        let metadata = self.metadata.as_mut()?;
+        metadata.start_time = start_time();
+        Some(metadata)
+    }
+
+    /// Returns a writer that buffers up the events of the next capture
+    fn next_writer(&mut self) -> Result<Writer, Error> {
+        let tmpdir = self.tmpdir.clone();
+        let zstd_level = self.zstd_level;
+        Writer::new(tmpdir.as_deref(), zstd_level, self.next_metadata())
+    }
+
+    /// Names the track of a process. This is written out before the first event of the process,
+    /// or right away if there already are events for it
+    #[allow(dead_code)]
+    pub fn set_process_name(&mut self, pid: Pid, name: String) -> Result<(), Error> {
+        if self.processes.contains(&pid) {
+            let event = Self::name_event("process_name", pid, 0, name.clone());
+            self.writer.write_event(&event)?;
+        }
+        self.process_names.insert(pid, name);
+        Ok(())
+    }
+
+    /// Writes out the name of a process the first time it shows up in a sample
+    fn record_process(&mut self, trace: &StackTrace) -> Result<(), Error> {
+        if !self.processes.insert(trace.pid) {
+            return Ok(());
+        }
+        let name = match self.process_names.get(&trace.pid) {
+            Some(name) => name.clone(),
+            None if self.name_subprocesses => match trace.process_info.as_ref() {
+                Some(info) => info.command_line.clone(),
+                None => return Ok(()),
+            },
+            None => return Ok(()),
+        };
+        let event = Self::name_event("process_name", trace.pid, 0, name);
+        self.writer.write_event(&event)
+    }
+
+    /// Returns the color for all the events of a process, which is assigned by pid so
+    /// that it stays the same between captures
+    fn process_color(&self, pid: Pid) -> Option<&'static str> {
+        if !self.color_by_process {
+            return None;
+        }
+        Some(PROCESS_COLORS[pid as usize % PROCESS_COLORS.len()])
+    }
+
+    /// Records all the stack traces collected in a single sample. Events are written with the
+    /// time the sample was taken, rather than the time they are recorded
+    pub fn increment(&mut self, traces: Vec<StackTrace>, timestamp: Duration) -> Result<(), Error> {
+        if self.is_full() || !self.recording {
+            return Ok(());
+        }
+        let timestamp = timestamp.saturating_sub(*self.origin.get_or_insert(timestamp));
+        let now = (self.compress_gaps(timestamp) + self.epoch_offset(timestamp)).as_micros() as u64;
+        self.last_ts = now;
+        self.samples += 1;
+        if self.show_counters {
+            self.record_counters(&traces, now)?;
+        }
+
+        let mut prev_traces = std::mem::take(&mut self.prev_traces);
+        for mut trace in traces {
+            // like with collapsing, the filtered trace is what's compared against in the next
+            // sample, so that every slice that is started is also ended
+            if !self.omit_frames.is_empty() {
+                trace
+                    .frames
+                    .retain(|frame| !self.omit_frames.contains(&frame.name));
+            }
+            // the collapsed trace is what gets stored for the next sample, so a change in the
+            // depth of a recursion shows up as a different name and starts a new slice
+            if self.collapse_recursion {
+                self.collapse_recursive_frames(&mut trace);
+            }
+            self.record_process(&trace)?;
+            let key = (trace.pid, trace.thread_id);
+            let prev = prev_traces.remove(&key);
+            self.record_thread_start(&trace, now)?;
+            self.record_events(prev.as_ref(), &trace, now)?;
+            if self.async_events || self.async_flows {
+                self.record_task(&trace, now)?;
+            }
+
+            let seen = self.threads.len();
+            let info = self.threads.entry(key).or_default();
+            if info.samples == 0 {
+                info.first_seen = seen;
+            }
+            info.samples += 1;
+            if trace.thread_name.is_some() {
+                info.name = trace.thread_name.clone();
+            }
+            info.main = is_main_thread(&trace);
+            info.formatted_tid = trace.format_threadid();
+
+            // name the track as soon as the thread shows up (or when it is renamed), so that
+            // this is there when streaming. Label templates are only written out at the end
+            if self.thread_label.is_none() {
+                let label = info.name.as_ref().unwrap_or(&info.formatted_tid);
+                if info.label.as_ref() != Some(label) {
+                    info.label = Some(label.clone());
+                    let event = Self::name_event("thread_name", key.0, key.1, label.clone());
+                    self.writer.write_event(&event)?;
+                }
+            }
+            if self.process_label.is_some() && !self.command_lines.contains_key(&trace.pid) {
+                self.command_lines.insert(
+                    trace.pid,
+                    command_line(&trace, self.anonymize).unwrap_or_default(),
+                );
+            }
+
+            self.prev_traces.insert(key, trace);
+        }
+
+        // Close out all the frames from threads that didn't show up in this sample. Frames are
+        // leaf first, so these end in the reverse order they were begun in
+        for trace in prev_traces.values() {
+            self.close_slices(trace, now)?;
+        }
+        self.writer.flush_stream()
+    }
+
+    /// Pauses or resumes recording. Pausing ends the open slices and async tasks at the time of
+    /// the last sample, and samples are dropped until recording is resumed. The frames of the
+    /// first sample after that all start new slices
+    #[allow(dead_code)]
+    pub fn set_recording(&mut self, on: bool) -> Result<(), Error> {
+        if self.recording && !on {
+            self.close_all(self.last_ts)?;
+            self.writer.flush_stream()?;
+        }
+        self.recording = on;
+        Ok(())
+    }
+
+    /// Returns whether samples are being recorded, which is until recording is paused
+    #[allow(dead_code)]
+    pub fn is_recording(&self) -> bool {
+        self.recording
+    }
+
+    /// Writes out an instant event the first time a thread shows up in a sample, which marks
+    /// when it started being sampled
+    fn record_thread_start(&mut self, trace: &StackTrace, now: u64) -> Result<(), Error> {
+        if !self.started_threads.insert((trace.pid, trace.thread_id)) {
+            return Ok(());
+        }
+        self.writer.write_event(&InstantEvent {
+            cat: "py-spy".to_owned(),
+            cname: self.process_color(trace.pid),
+            name: "thread started".to_owned(),
+            ph: "i".to_owned(),
+            s: "t".to_owned(),
+            pid: trace.pid as u64,
+            tid: trace.thread_id,
+            ts: now,
+        })
+    }
+
+    /// Returns whether the event limit has been reached, after which samples aren't recorded
+    /// anymore. The slices that are still open are ended by 'write'
+    pub fn is_full(&self) -> bool {
+        self.max_events
+            .is_some_and(|max_events| self.writer.events >= max_events)
+    }
+
+    /// Returns the time to record a sample at. When a max_gap is set, gaps between samples
+    /// that are longer than it are shortened to the sampling interval, and everything after
+    /// the gap is moved back to match. This keeps the timeline readable when the profiler
+    /// wasn't running for a while (like when the machine was suspended), at the cost of the
+    /// trace not lining up with wall clock time after the gap
+    fn compress_gaps(&mut self, timestamp: Duration) -> Duration {
+        if let (Some(max_gap), Some(last)) = (self.max_gap, self.last_sample) {
+            let gap = timestamp.saturating_sub(last);
+            if gap > max_gap {
+                self.skipped += gap.saturating_sub(self.sample_interval);
+            }
+        }
+        self.last_sample = Some(timestamp);
+        timestamp.saturating_sub(self.skipped)
+    }
+
+    /// Writes out a counter event with the number of threads in a sample, and the total
+    /// number of frames on their stacks
+    fn record_counters(&mut self, traces: &[StackTrace], now: u64) -> Result<(), Error> {
+        self.writer.write_event(&CounterEvent {
+            args: SampleCounters {
+                thread_count: traces.len() as u64,
+                total_frames: traces.iter().map(|t| t.frames.len() as u64).sum(),
+            },
+            name: "threads".to_owned(),
+            ph: "C".to_owned(),
+            pid: COUNTERS_PID,
+            ts: now,
+        })
+    }
+
+    /// Returns the time from the epoch to when sampling started, which is added to every
+    /// timestamp. Sample timestamps are relative to the start of sampling, so this is worked out
+    /// from the timestamp of the first sample and the time it is recorded at
+    fn epoch_offset(&mut self, timestamp: Duration) -> Duration {
+        let epoch = match self.trace_epoch {
+            Some(epoch) => epoch,
+            None => return Duration::ZERO,
+        };
+        *self.epoch_offset.get_or_insert_with(|| {
+            SystemTime::now()
+                .checked_sub(timestamp)
+                .and_then(|start| start.duration_since(epoch).ok())
+                .unwrap_or_default()
+        })
+    }
+
+    /// Writes out counter events for the garbage collector state of a process, at the time of
+    /// the last sample. Counters are only written when their value has changed since the last call
+    pub fn record_gc_stats(&mut self, pid: Pid, stats: &GCStats) -> Result<(), Error> {
+        if self.is_full() || !self.recording {
+            return Ok(());
+        }
+        let ts = self.last_ts;
+        let prev = self.gc_stats.insert(pid, *stats);
+
+        if prev.map(|p| p.collections) != Some(stats.collections) {
+            self.writer.write_event(&CounterEvent {
+                args: GCCollections {
+                    gen0: stats.collections[0],
+                    gen1: stats.collections[1],
+                    gen2: stats.collections[2],
+                },
+                name: "gc collections".to_owned(),
+                ph: "C".to_owned(),
+                pid: pid as u64,
+                ts,
+            })?;
+        }
+
+        if prev.map(|p| p.enabled) != Some(stats.enabled) {
+            self.writer.write_event(&CounterEvent {
+                args: GCEnabled {
+                    enabled: stats.enabled as u64,
+                },
+                name: "gc enabled".to_owned(),
+                ph: "C".to_owned(),
+                pid: pid as u64,
+                ts,
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Writes out a counter event for the open file descriptors of a process, if the
+    /// number has changed since the last call
+    pub fn record_fd_stats(&mut self, pid: Pid, stats: &FdStats) -> Result<(), Error> {
+        if self.is_full() || !self.recording {
+            return Ok(());
+        }
+        if self.fd_stats.insert(pid, *stats) == Some(*stats) {
+            return Ok(());
+        }
+        self.writer.write_event(&CounterEvent {
+            args: *stats,
+            name: "open files".to_owned(),
+            ph: "C".to_owned(),
+            pid: pid as u64,
+            ts: self.last_ts,
+        })
+    }
+
+    /// Writes out how many stack traces failed to be read while sampling as a metadata event.
+    /// This is meant to be called once at the end of the capture, so is written out even when
+    /// the trace is full
+    pub fn record_sampling_stats(&mut self, stats: &SamplingStats) -> Result<(), Error> {
+        // these aren't about any one process, so go with the counters
+        self.writer.write_event(&MetadataEvent {
+            args: stats.clone(),
+            name: "sampling_stats".to_owned(),
+            ph: "M".to_owned(),
+            pid: COUNTERS_PID,
+            tid: 0,
+        })
+    }
+
+    /// Writes out end events for frames that were in the previous trace but not in this one,
+    /// and start events for frames that are new in this trace
+    fn record_events(
+        &mut self,
+        prev: Option<&StackTrace>,
+        trace: &StackTrace,
+        now: u64,
+    ) -> Result<(), Error> {
+        let prev_frames: &[Frame] = prev.map(|t| t.frames.as_slice()).unwrap_or_default();
+
+        // frames are stored leaf first, so compare from the root to find the common prefix
+        let common = prev_frames
+            .iter()
+            .rev()
+            .zip(trace.frames.iter().rev())
+            .take_while(|(a, b)| self.should_merge_frames(a, b))
+            .count();
+
+        let key = (trace.pid, trace.thread_id);
+        for (i, frame) in prev_frames[..prev_frames.len() - common].iter().enumerate() {
+            let depth = prev_frames.len() - 1 - i;
+            let ts = self.end_time(key, depth, now);
+            self.writer
+                .write_event(&self.end_event(trace, frame, depth, ts))?;
+        }
+
+        for frame in trace.frames[..trace.frames.len() - common].iter().rev() {
+            let ts = self.begin_time(key, now);
+            self.writer
+                .write_event(&self.begin_event(trace, frame, ts))?;
+        }
+
+        if self.sample_counts {
+            let counts = self
+                .slice_counts
+                .entry((trace.pid, trace.thread_id))
+                .or_default();
+            counts.truncate(common);
+            counts.resize(trace.frames.len(), SliceCounts::default());
+            for slice in counts.iter_mut() {
+                slice.total += 1;
+            }
+            if let Some(leaf) = counts.last_mut() {
+                leaf.own += 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes out end events for all the frames of a thread
+    fn close_slices(&mut self, trace: &StackTrace, now: u64) -> Result<(), Error> {
+        let key = (trace.pid, trace.thread_id);
+        for (i, frame) in trace.frames.iter().enumerate() {
+            let depth = trace.frames.len() - 1 - i;
+            let ts = self.end_time(key, depth, now);
+            self.writer
+                .write_event(&self.end_event(trace, frame, depth, ts))?;
+        }
+        self.slice_counts.remove(&key);
+        self.slice_starts.remove(&key);
+        Ok(())
+    }
+
+    /// Writes out end events for the open slices of every thread, and for every async task
+    fn close_all(&mut self, now: u64) -> Result<(), Error> {
+        let prev_traces = std::mem::take(&mut self.prev_traces);
+        for trace in prev_traces.values() {
+            self.close_slices(trace, now)?;
+        }
+        // tasks end at the last sample they were seen in
+        for (key, task) in std::mem::take(&mut self.tasks) {
+            if self.async_events {
+                self.writer.write_event(&self.task_event(key, &task, "e"))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the time to start a slice at, which is after the end of any slice of the thread
+    /// that had to be pushed back to last for the minimum duration
+    fn begin_time(&mut self, key: (Pid, u64), now: u64) -> u64 {
+        if self.min_duration == 0 {
+            return now;
+        }
+        let (starts, last) = self.slice_starts.entry(key).or_default();
+        let ts = now.max(*last);
+        starts.push(ts);
+        *last = ts;
+        ts
+    }
+
+    /// Returns the time to end the slice 'depth' frames from the root of the stack at, so that it
+    /// lasts for at least the minimum duration. Slices are ended from the innermost one out,
+    /// so this never ends a slice before the ones inside of it
+    fn end_time(&mut self, key: (Pid, u64), depth: usize, now: u64) -> u64 {
+        if self.min_duration == 0 {
+            return now;
+        }
+        let (starts, last) = self.slice_starts.entry(key).or_default();
+        starts.truncate(depth + 1);
+        let start = starts.pop().unwrap_or(0);
+        let ts = now.max(*last).max(start + self.min_duration);
+        *last = ts;
+        ts
+    }
+
+    /// Starts an async event for the task the thread is running if it hasn't been seen
+    /// before, or extends the existing one. If the task wasn't in the previous sample, this
+    /// also links it back to where it was last seen with a flow event
+    fn record_task(&mut self, trace: &StackTrace, now: u64) -> Result<(), Error> {
+        let address = match trace.task_address {
+            Some(address) => address,
+            None => return Ok(()),
+        };
+        let frame = match trace
+            .frames
+            .iter()
+            .rev()
+            .find(|frame| frame.frame_kind == FrameKind::Coroutine)
+        {
+            Some(frame) => frame,
+            None => return Ok(()),
+        };
+
+        let key = (trace.pid, address);
+        if let Some(task) = self.tasks.get_mut(&key) {
+            if task.name == frame.name && task.filename == frame.filename {
+                let resumed = task.last_sample + 1 < self.samples;
+                let (last_tid, last_ts) = (task.last_tid, task.last_ts);
+                task.last_ts = now;
+                task.last_tid = trace.thread_id;
+                task.last_sample = self.samples;
+                if resumed && self.async_flows {
+                    self.flow_id += 1;
+                    let start = self.flow_event(trace.pid, &frame.name, "s", last_tid, last_ts);
+                    self.writer.write_event(&start)?;
+                    let finish = self.flow_event(trace.pid, &frame.name, "f", trace.thread_id, now);
+                    self.writer.write_event(&finish)?;
+                }
+                return Ok(());
+            }
+
+            // the coroutine at this address finished, and its memory was reused by another
+            let task = self.tasks.remove(&key).unwrap();
+            if self.async_events {
+                self.writer.write_event(&self.task_event(key, &task, "e"))?;
+            }
+        }
+
+        let task = Task {
+            name: frame.name.clone(),
+            filename: frame.filename.clone(),
+            tid: trace.thread_id,
+            last_ts: now,
+            last_tid: trace.thread_id,
+            last_sample: self.samples,
+        };
+        if self.async_events {
+            self.writer.write_event(&self.task_event(key, &task, "b"))?;
+        }
+        self.tasks.insert(key, task);
+        Ok(())
+    }
+
+    /// Returns one end of the flow event with the current id, which starts in the slice
+    /// enclosing the time the task was suspended and finishes in the one it resumed in
+    fn flow_event(&self, pid: Pid, name: &str, ph: &str, tid: u64, ts: u64) -> FlowEvent {
+        FlowEvent {
+            cat: "await".to_owned(),
+            id: self.flow_id,
+            name: name.to_owned(),
+            ph: ph.to_owned(),
+            bp: if ph == "f" { Some("e") } else { None },
+            pid: pid as u64,
+            tid,
+            ts,
+        }
+    }
+
+    fn task_event(&self, (pid, address): (Pid, usize), task: &Task, ph: &str) -> AsyncEvent {
+        AsyncEvent {
+            args: Args {
+                filename: task.filename.clone(),
+                line: None,
+                total_samples: None,
+                self_samples: None,
+                locals: None,
+            },
+            cat: "task".to_owned(),
+            cname: self.process_color(pid),
+            id: format!("{}:0x{:x}", pid, address),
+            name: task.name.clone(),
+            ph: ph.to_owned(),
+            pid: pid as u64,
+            tid: task.tid,
+            ts: task.last_ts,
+        }
+    }
+
+    /// Replaces runs of consecutive frames that would be merged into the same slice with a
+    /// single frame, whose name has the number of frames it replaced like 'fib (x20)'
+    fn collapse_recursive_frames(&self, trace: &mut StackTrace) {
+        let mut frames: Vec<(Frame, usize)> = Vec::with_capacity(trace.frames.len());
+        for frame in trace.frames.drain(..) {
+            match frames.last_mut() {
+                Some((last, count)) if self.should_merge_frames(last, &frame) => *count += 1,
+                _ => frames.push((frame, 1)),
+            }
+        }
+        trace.frames = frames
+            .into_iter()
+            .map(|(mut frame, count)| {
+                if count > 1 {
+                    frame.name = format!("{} (x{})", frame.name, count);
+                }
+                frame
+            })
+            .collect();
+    }
+
+    /// Returns whether these frames are similar enough that they should be merged into a
+    /// single event, instead of ending one and starting another
+    fn should_merge_frames(&self, a: &Frame, b: &Frame) -> bool {
+        a.merge_key(self.show_linenumbers) == b.merge_key(self.show_linenumbers)
+    }
+
+    fn event(&self, trace: &StackTrace, frame: &Frame, ph: &str, ts: u64) -> Event {
+        Event {
+            args: Args {
+                filename: frame.filename.clone(),
+                line: if self.show_linenumbers {
+                    Some(frame.line as u32)
+                } else {
+                    None
+                },
+                total_samples: None,
+                self_samples: None,
+                locals: None,
+            },
+            cat: frame.category().to_owned(),
+            cname: self.process_color(trace.pid),
+            name: frame.name.clone(),
+            ph: ph.to_owned(),
+            pid: trace.pid as u64,
+            tid: trace.thread_id,
+            ts,
+        }
+    }
+
+    /// Returns the begin event for a frame. This carries the values of its local variables
+    /// when these were captured, which are the values from the first sample of the slice
+    fn begin_event(&self, trace: &StackTrace, frame: &Frame, ts: u64) -> Event {
+        let mut event = self.event(trace, frame, "B", ts);
+        event.args.locals = frame.locals.as_ref().map(|locals| {
+            locals
+                .iter()
+                .filter_map(|local| Some((local.name.clone(), local.repr.clone()?)))
+                .collect()
+        });
+        event
+    }
+
+    /// Returns the end event for a frame, which is 'depth' frames from the root of the stack.
+    /// This carries the sample counts of the slice, when these are being recorded
+    fn end_event(&self, trace: &StackTrace, frame: &Frame, depth: usize, ts: u64) -> Event {
+        let mut event = self.event(trace, frame, "E", ts);
+        if let Some(counts) = self
+            .slice_counts
+            .get(&(trace.pid, trace.thread_id))
+            .and_then(|counts| counts.get(depth))
+        {
+            event.args.total_samples = Some(counts.total);
+            event.args.self_samples = Some(counts.own);
+        }
+        event
+    }
+
+    fn sort_index_event(
+        name: &str,
+        pid: Pid,
+        tid: u64,
+        sort_index: usize,
+    ) -> MetadataEvent<SortIndex> {
+        MetadataEvent {
+            args: SortIndex {
+                sort_index: sort_index as u64,
+            },
+            name: name.to_owned(),
+            ph: "M".to_owned(),
+            pid: pid as u64,
+            tid,
+        }
+    }
+
+    fn name_event(name: &str, pid: Pid, tid: u64, label: String) -> MetadataEvent<Name> {
+        MetadataEvent {
+            args: Name { name: label },
+            name: name.to_owned(),
+            ph: "M".to_owned(),
+            pid: pid as u64,
+            tid,
+        }
+    }
+
+    /// Writes out process and thread name metadata events from the label templates, which
+    /// replace the default 'Process 1234' and 'Thread 5678' labels for each track
+    fn write_labels(&mut self) -> Result<(), Error> {
+        let mut events = Vec::new();
+        if self.show_counters {
+            let label = "py-spy".to_owned();
+            events.push(Self::name_event(
+                "process_name",
+                COUNTERS_PID as Pid,
+                0,
+                label,
+            ));
+        }
+        if let Some(template) = &self.process_label {
+            let mut command_lines: Vec<_> = self.command_lines.iter().collect();
+            command_lines.sort_by_key(|(pid, _)| **pid);
+            for (pid, command_line) in command_lines {
+                let label = format_process_label(template, *pid, command_line);
+                events.push(Self::name_event("process_name", *pid, 0, label));
+            }
+        }
+        if let Some(template) = &self.thread_label {
+            let mut threads: Vec<_> = self.threads.iter().collect();
+            threads.sort_by_key(|(key, _)| **key);
+            for ((pid, tid), info) in threads {
+                let label = format_thread_label(template, *pid, info);
+                events.push(Self::name_event("thread_name", *pid, *tid, label));
+            }
+        }
+        for event in events {
+            self.writer.write_event(&event)?;
+        }
+        Ok(())
+    }
+
+    /// Writes out sort index metadata events, so that perfetto displays the process and
+    /// thread tracks in the order given by the track_order option
+    fn write_track_order(&mut self) -> Result<(), Error> {
+        let mut processes: HashMap<Pid, Vec<(u64, &ThreadInfo)>> = HashMap::new();
+        for ((pid, tid), info) in self.threads.iter() {
+            processes.entry(*pid).or_default().push((*tid, info));
+        }
+
+        let mut pids: Vec<(Pid, u64)> = processes
+            .iter()
+            .map(|(pid, threads)| (*pid, threads.iter().map(|(_, info)| info.samples).sum()))
+            .collect();
+        match self.track_order {
+            TrackOrder::activity => pids.sort_by_key(|(pid, samples)| (Reverse(*samples), *pid)),
+            _ => pids.sort_by_key(|(pid, _)| *pid),
+        }
+
+        let mut events = Vec::new();
+        for (process_index, (pid, _)) in pids.iter().enumerate() {
+            events.push(Self::sort_index_event(
+                "process_sort_index",
+                *pid,
+                0,
+                process_index,
+            ));
+
+            let threads = processes.get_mut(pid).unwrap();
+            match self.track_order {
+                TrackOrder::main => threads.sort_by_key(|(tid, info)| (!info.main, *tid)),
+                TrackOrder::activity => {
+                    threads.sort_by_key(|(tid, info)| (Reverse(info.samples), *tid))
+                }
+                TrackOrder::tid => threads.sort_by_key(|(tid, _)| *tid),
+                TrackOrder::name => threads.sort_by(|(a_tid, a), (b_tid, b)| {
+                    // threads without a name go last
+                    (a.name.is_none(), &a.name, a_tid).cmp(&(b.name.is_none(), &b.name, b_tid))
+                }),
+                TrackOrder::first_seen => {
+                    // when no thread looks like the main thread, pin the one with the lowest id
+                    let main_tid = threads
+                        .iter()
+                        .find(|(_, info)| info.main)
+                        .or_else(|| threads.iter().min_by_key(|(tid, _)| *tid))
+                        .map(|(tid, _)| *tid);
+                    threads.sort_by_key(|(tid, info)| (Some(*tid) != main_tid, info.first_seen))
+                }
+            }
+            for (thread_index, (tid, _)) in threads.iter().enumerate() {
+                events.push(Self::sort_index_event(
+                    "thread_sort_index",
+                    *pid,
+                    *tid,
+                    thread_index,
+                ));
+            }
+        }
+
+        for event in events {
+            self.writer.write_event(&event)?;
+        }
+        Ok(())
+    }
+
+    /// Copies out zstd compressed events that were buffered up, with the configured compression
+    fn transcode(&self, buffered: &mut dyn Read, w: &mut dyn Write) -> Result<(), Error> {
+        match self.compression {
+            // the events are already zstd compressed, so can be copied as is
+            TraceCompression::zstd => {
+                std::io::copy(buffered, w)?;
+            }
+            TraceCompression::none => {
+                let mut decoder = zstd::Decoder::new(buffered)?;
+                std::io::copy(&mut decoder, w)?;
+            }
+            TraceCompression::gzip => {
+                let mut decoder = zstd::Decoder::new(buffered)?;
+                let mut encoder = GzEncoder::new(w, Compression::default());
+                std::io::copy(&mut decoder, &mut encoder)?;
+                encoder.finish()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes out the trace as json with the configured compression (gzip by default), and
+    /// resets the state for a new capture. When
+    /// streaming, this finishes off the json on the stream or file instead, and nothing
+    /// is written to 'w'
+    pub fn write(&mut self, w: &mut dyn Write) -> Result<(), Error> {
+        // Add end events for any unfinished slices, at the time of the last sample
+        self.close_all(self.last_ts)?;
+        self.write_labels()?;
+        self.write_track_order()?;
+        self.finish_writer(w)?;
+
+        self.threads.clear();
+        self.command_lines.clear();
+        self.processes.clear();
+        self.started_threads.clear();
+        self.gc_stats.clear();
+        self.fd_stats.clear();
+        Ok(())
+    }
+
+    /// Writes out the events recorded so far as a chunk of a longer capture, in the same format
+    /// as 'write'. Unlike 'write', the open slices aren't ended and everything else is kept,
+    /// so the next chunk carries on where this one left off: slices that span chunks begin in
+    /// one and end in a later one, and timestamps keep the same origin. The events of the
+    /// chunks can be joined up to get the whole capture. Labels (from label templates) and the
+    /// track order are only written out by 'write', and the event limit covers all the chunks.
+    /// This returns an error when streaming, since the events have already been written out
+    #[allow(dead_code)]
+    pub fn flush_chunk(&mut self, w: &mut dyn Write) -> Result<(), Error> {
+        if !matches!(
+            self.writer.encoder.get_ref(),
+            Sink::Tempfile(_) | Sink::Memory(_)
+        ) {
+            return Err(format_err!(
+                "Can't write chunks of trace events that are being streamed out"
+            ));
+        }
+        let events = self.writer.events;
+        self.finish_writer(w)?;
+        self.writer.events = events;
+        Ok(())
+    }
+
+    /// Finishes off the events written so far (writing them out to 'w' when they are buffered)
+    /// and starts a new writer for the ones after
+    fn finish_writer(&mut self, w: &mut dyn Write) -> Result<(), Error> {
+        // close off the old writer before starting the next one, so that a stream or file is
+        // still finished when a tempfile can't be created for the next capture
+        let discard = Writer::with_sink(Sink::Stream(Box::new(std::io::sink())), None)?;
+        let writer = std::mem::replace(&mut self.writer, discard);
+        match writer.close()? {
+            Sink::Tempfile(encoder) => {
+                let file = encoder.finish()?;
+                self.writer = self.next_writer()?;
+                self.transcode(&mut file.reopen()?, w)?;
+            }
+            Sink::Memory(encoder) => {
+                let buffer = encoder.finish()?;
+                self.writer = self.next_writer()?;
+                self.transcode(&mut buffer.as_slice(), w)?;
+            }
+            // the next capture is sent as a new json object (or array) on the same stream
+            Sink::Stream(stream) => {
+                self.writer = Writer::with_sink(Sink::Stream(stream), self.next_metadata())?
+            }
+            // the file is done, so the events of any later captures are discarded
+            Sink::File(encoder) => {
+                encoder.finish()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Discards everything recorded so far without writing it out, so that a new capture can
+    /// be started (like after a warmup period). Events that have already been streamed out
+    /// can't be taken back, so this returns an error when streaming. The timestamps of the new
+    /// capture start from the first sample recorded after this
+    #[allow(dead_code)]
+    pub fn reset(&mut self) -> Result<(), Error> {
+        if !matches!(
+            self.writer.encoder.get_ref(),
+            Sink::Tempfile(_) | Sink::Memory(_)
+        ) {
+            return Err(format_err!(
+                "Can't discard trace events that have already been streamed out"
+            ));
+        }
+        // dropping the old writer deletes its tempfile
+        self.writer = self.next_writer()?;
+        self.last_ts = 0;
+        self.prev_traces.clear();
+        self.threads.clear();
+        self.gc_stats.clear();
+        self.fd_stats.clear();
+        self.tasks.clear();
+        self.skipped = Duration::ZERO;
+        self.last_sample = None;
+        self.origin = None;
+        self.epoch_offset = None;
+        self.samples = 0;
+        self.flow_id = 0;
+        self.slice_counts.clear();
+        self.slice_starts.clear();
+        self.command_lines.clear();
+        self.processes.clear();
+        self.started_threads.clear();
+        Ok(())
+    }
+}
+
+/// Returns the command line of the process a trace came from. This is already known when
+/// sampling subprocesses, and otherwise is read from the process if it is still running.
+/// When anonymizing, only the (already anonymized) command line in the trace is used
+fn command_line(trace: &StackTrace, anonymize: bool) -> Option<String> {
+    if let Some(info) = trace.process_info.as_ref() {
+        return Some(info.command_line.clone());
+    }
+    if anonymize {
+        return None;
+    }
+    let process = remoteprocess::Process::new(trace.pid).ok()?;
+    Some(process.cmdline().ok()?.join(" "))
+}
+
+/// Fills in a process label template, where '{name}' is the program name from the command line
+fn format_process_label(template: &str, pid: Pid, command_line: &str) -> String {
+    let program = command_line.split_whitespace().next().unwrap_or("");
+    let name = program.rsplit(['/', '\\']).next().unwrap_or(program);
+    let pid = pid.to_string();
+    expand_template(template, |placeholder| match placeholder {
+        "{pid}" => Some(&pid),
+        "{name}" => Some(name),
+        "{cmdline}" => Some(command_line),
+        _ => None,
+    })
+}
+
+/// Fills in a thread label template, where '{tname}' falls back to the thread id for threads
+/// without a python name
+fn format_thread_label(template: &str, pid: Pid, info: &ThreadInfo) -> String {
+    let pid = pid.to_string();
+    expand_template(template, |placeholder| match placeholder {
+        "{pid}" => Some(&pid),
+        "{tid}" => Some(&info.formatted_tid),
+        "{tname}" => Some(info.name.as_ref().unwrap_or(&info.formatted_tid)),
+        _ => None,
+    })
+}
+
+fn is_main_thread(trace: &StackTrace) -> bool {
+    // the main thread in linux has the same thread id as the process
+    trace.thread_name.as_deref() == Some("MainThread")
+        || trace.os_thread_id == Some(trace.pid as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stack_trace::LocalVariable;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    fn trace(thread_id: u64, thread_name: &str, frames: &[&str]) -> StackTrace {
+        frames
+            .iter()
+            .fold(
+                StackTrace::builder()
+                    .pid(1)
+                    .thread_id(thread_id)
+                    .thread_name(thread_name),
+                |builder, name| builder.frame(name, "test.py", 0),
+            )
+            .build()
+    }
+
+    fn events(chrometrace: &mut Chrometrace) -> Vec<serde_json::Value> {
+        let mut compressed = Vec::new();
+        chrometrace.write(&mut compressed).unwrap();
+        decode_events(&compressed)
+    }
+
+    fn decode_events(compressed: &[u8]) -> Vec<serde_json::Value> {
+        let mut s = String::new();
+        GzDecoder::new(compressed).read_to_string(&mut s).unwrap();
+        let trace: serde_json::Value = serde_json::from_str(&s).unwrap();
+        trace["traceEvents"].as_array().unwrap().clone()
+    }
+
+    #[test]
+    fn test_chrometrace_events() {
+        let mut chrometrace = Chrometrace::new(&Config::default()).unwrap();
+        chrometrace
+            .increment(
+                vec![trace(1, "MainThread", &["b", "a"])],
+                Duration::from_millis(10),
+            )
+            .unwrap();
+        chrometrace
+            .increment(
+                vec![trace(1, "MainThread", &["c", "a"])],
+                Duration::from_millis(20),
+            )
+            .unwrap();
+
+        let events = events(&mut chrometrace);
+        let phases: Vec<(&str, &str)> = events
+            .iter()
+            .filter(|e| e["ph"] != "M")
+            .map(|e| (e["ph"].as_str().unwrap(), e["name"].as_str().unwrap()))
+            .collect();
+        assert_eq!(
+            phases,
+            vec![
+                ("i", "thread started"),
+                ("B", "a"),
+                ("B", "b"),
+                ("E", "b"),
+                ("B", "c"),
+                ("E", "c"),
+                ("E", "a")
+            ]
+        );
+
+        // the thread is only marked as started once, on its own track
+        let started: Vec<&serde_json::Value> = events.iter().filter(|e| e["ph"] == "i").collect();
+        assert_eq!(started.len(), 1);
+        assert_eq!(
+            (&started[0]["s"], &started[0]["tid"]),
+            (&"t".into(), &1.into())
+        );
+
+        // events use the time the samples were taken at, in microseconds
+        let timestamps: Vec<u64> = events
+            .iter()
+            .filter(|e| e["ph"] != "M")
+            .map(|e| e["ts"].as_u64().unwrap())
+            .collect();
+        assert_eq!(
+            timestamps,
+            vec![10000, 10000, 10000, 20000, 20000, 20000, 20000]
+        );
+
+        // line numbers aren't shown by default, so these are left out rather than null
+        assert!(events
+            .iter()
+            .filter(|e| e["ph"] == "B" || e["ph"] == "E")
+            .all(|e| e["args"]["filename"] == "test.py" && e["args"].get("line").is_none()));
+    }
+
+    #[test]
+    fn test_merge_frames() {
+        // main calls work from line 10 and then line 11, and work moves from line 20 to 21
+        let samples = [(10, 20), (11, 20), (11, 21)];
+        let slices = |show_line_numbers: bool| {
+            let config = Config {
+                show_line_numbers,
+                ..Default::default()
+            };
+            let mut chrometrace = Chrometrace::new(&config).unwrap();
+            for (i, &(caller, callee)) in samples.iter().enumerate() {
+                let trace = StackTrace::builder()
+                    .pid(1)
+                    .thread_id(1)
+                    .frame("work", "test.py", callee)
+                    .frame("main", "test.py", caller)
+                    .build();
+                chrometrace
+                    .increment(vec![trace], Duration::from_millis(10 * i as u64))
+                    .unwrap();
+            }
+            events(&mut chrometrace)
+                .iter()
+                .filter(|e| e["ph"] == "B" || e["ph"] == "E")
+                .map(|e| {
+                    format!(
+                        "{} {}:{}",
+                        e["ph"].as_str().unwrap(),
+                        e["name"].as_str().unwrap(),
+                        e["args"]["line"]
+                    )
+                })
+                .collect::<Vec<String>>()
+        };
+
+        // without line numbers, the samples are all merged into the same slices (which are
+        // ended when the trace is written)
+        assert_eq!(
+            slices(false),
+            vec!["B main:null", "B work:null", "E work:null", "E main:null"]
+        );
+
+        // otherwise only the frames that stayed on the same line are merged, which ends the
+        // callee along with its caller
+        assert_eq!(
+            slices(true),
+            vec![
+                "B main:10",
+                "B work:20",
+                "E work:20",
+                "E main:10",
+                "B main:11",
+                "B work:20",
+                "E work:20",
+                "B work:21",
+                "E work:21",
+                "E main:11"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_vanished_threads() {
+        let mut chrometrace = Chrometrace::new(&Config::default()).unwrap();
+        chrometrace
+            .increment(
+                vec![
+                    trace(3, "c", &["c2", "c1", "c0"]),
+                    trace(1, "a", &["a2", "a1", "a0"]),
+                    trace(2, "b", &["b1", "b0"]),
+                ],
+                Duration::from_millis(10),
+            )
+            .unwrap();
+        // threads 1 and 3 go away, and their slices are ended at the same time as the ones
+        // for thread 2 are when the trace is written
+        chrometrace
+            .increment(
+                vec![trace(2, "b", &["b1", "b0"])],
+                Duration::from_millis(20),
+            )
+            .unwrap();
+
+        let events = events(&mut chrometrace);
+        let ended: Vec<&str> = events
+            .iter()
+            .filter(|e| e["ph"] == "E" && e["ts"] == 20000)
+            .map(|e| e["name"].as_str().unwrap())
+            .collect();
+        assert_eq!(ended, vec!["a2", "a1", "a0", "c2", "c1", "c0", "b1", "b0"]);
+
+        // every end event matches the innermost open slice on its thread
+        let mut open: HashMap<u64, Vec<&str>> = HashMap::new();
+        for e in events.iter().filter(|e| e["ph"] == "B" || e["ph"] == "E") {
+            let slices = open.entry(e["tid"].as_u64().unwrap()).or_default();
+            let name = e["name"].as_str().unwrap();
+            if e["ph"] == "B" {
+                slices.push(name);
+            } else {
+                assert_eq!(slices.pop(), Some(name));
+            }
+        }
+        assert!(open.values().all(|slices| slices.is_empty()));
+    }
+
+    #[test]
+    fn test_sample_counts() {
+        let config = Config {
+            sample_counts: true,
+            ..Default::default()
+        };
+        let mut chrometrace = Chrometrace::new(&config).unwrap();
+        let samples: [&[&str]; 4] = [&["b", "a"], &["b", "a"], &["a"], &["c", "a"]];
+        for (i, frames) in samples.iter().enumerate() {
+            chrometrace
+                .increment(
+                    vec![trace(1, "MainThread", frames)],
+                    Duration::from_millis(10 * i as u64),
+                )
+                .unwrap();
+        }
+
+        let written = events(&mut chrometrace);
+        let counts: Vec<(&str, u64, u64)> = written
+            .iter()
+            .filter(|e| e["ph"] == "E")
+            .map(|e| {
+                (
+                    e["name"].as_str().unwrap(),
+                    e["args"]["total_samples"].as_u64().unwrap(),
+                    e["args"]["self_samples"].as_u64().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(counts, vec![("b", 2, 2), ("c", 1, 1), ("a", 4, 1)]);
+
+        // the counts are left out by default
+        let mut chrometrace = Chrometrace::new(&Config::default()).unwrap();
+        chrometrace
+            .increment(vec![trace(1, "MainThread", &["a"])], Duration::ZERO)
+            .unwrap();
+        assert!(events(&mut chrometrace)
+            .iter()
+            .all(|e| e["args"].get("self_samples").is_none()));
+    }
+
+    #[test]
+    fn test_categories() {
+        // leaf first: a native function called from python, and an idle thread
+        let mut main = trace(1, "MainThread", &["compress", "save", "main"]);
+        main.frames[0].is_native = true;
+        main.frames[1].code_id = Some(0x1000);
+        let mut idle = trace(2, "worker", &[]);
+        idle.active = false;
+        idle.add_gil_frame(true);
+
+        let mut chrometrace = Chrometrace::new(&Config::default()).unwrap();
+        chrometrace
+            .increment(vec![main, idle], Duration::from_millis(10))
+            .unwrap();
+
+        let events = events(&mut chrometrace);
+        let categories: Vec<(&str, &str)> = events
+            .iter()
+            .filter(|e| e["ph"] == "B")
+            .map(|e| (e["name"].as_str().unwrap(), e["cat"].as_str().unwrap()))
+            .collect();
+        assert_eq!(
+            categories,
+            vec![
+                ("main", "py-spy"),
+                ("save", "python"),
+                ("compress", "native"),
+                ("<idle>", "gil")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_locals() {
+        let local = |name: &str, repr: Option<&str>| LocalVariable {
+            name: name.to_owned(),
+            addr: 0,
+            arg: true,
+            repr: repr.map(|r| r.to_owned()),
+        };
+        let mut first = trace(1, "MainThread", &["handle", "main"]);
+        first.frames[0].locals = Some(vec![local("n", Some("10")), local("data", None)]);
+        // the values from later samples of the same slice are left out
+        let mut second = trace(1, "MainThread", &["handle", "main"]);
+        second.frames[0].locals = Some(vec![local("n", Some("11"))]);
+
+        let mut chrometrace = Chrometrace::new(&Config::default()).unwrap();
+        chrometrace
+            .increment(vec![first], Duration::from_millis(10))
+            .unwrap();
+        chrometrace
+            .increment(vec![second], Duration::from_millis(20))
+            .unwrap();
+
+        let events = events(&mut chrometrace);
+        let locals: Vec<(&str, &str, &serde_json::Value)> = events
+            .iter()
+            .filter(|e| e["ph"] == "B" || e["ph"] == "E")
+            .map(|e| {
+                (
+                    e["ph"].as_str().unwrap(),
+                    e["name"].as_str().unwrap(),
+                    &e["args"]["locals"],
+                )
+            })
+            .collect();
+        assert_eq!(
+            locals,
+            vec![
+                ("B", "main", &serde_json::Value::Null),
+                ("B", "handle", &serde_json::json!({"n": "10"})),
+                ("E", "handle", &serde_json::Value::Null),
+                ("E", "main", &serde_json::Value::Null),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_counters() {
+        let mut chrometrace = Chrometrace::new(&Config {
+            show_counters: true,
+            ..Default::default()
+        })
+        .unwrap();
+        chrometrace
+            .increment(
+                vec![
+                    trace(1, "MainThread", &["b", "a"]),
+                    trace(2, "worker", &["c", "b", "a"]),
+                ],
+                Duration::from_millis(10),
+            )
+            .unwrap();
+        chrometrace
+            .increment(vec![], Duration::from_millis(20))
+            .unwrap();
+
+        let written = events(&mut chrometrace);
+        let counters: Vec<(u64, u64, u64)> = written
+            .iter()
+            .filter(|e| e["ph"] == "C")
+            .map(|e| {
+                assert_eq!(e["pid"], 0);
+                (
+                    e["ts"].as_u64().unwrap(),
+                    e["args"]["thread_count"].as_u64().unwrap(),
+                    e["args"]["total_frames"].as_u64().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(counters, vec![(10000, 2, 5), (20000, 0, 0)]);
+
+        // counters are left out by default
+        let mut chrometrace = Chrometrace::new(&Config::default()).unwrap();
+        chrometrace
+            .increment(vec![trace(1, "MainThread", &["a"])], Duration::ZERO)
+            .unwrap();
+        assert!(events(&mut chrometrace).iter().all(|e| e["ph"] != "C"));
+    }
+
+    #[test]
+    fn test_gc_stats() {
+        let mut chrometrace = Chrometrace::new(&Config::default()).unwrap();
+        let mut stats = GCStats {
+            enabled: true,
+            collections: [10, 1, 0],
+        };
+        chrometrace.record_gc_stats(1, &stats).unwrap();
+        chrometrace.record_gc_stats(1, &stats).unwrap();
+        stats.collections[0] += 1;
+        chrometrace.record_gc_stats(1, &stats).unwrap();
+
+        let fds = FdStats {
+            files: 12,
+            sockets: 2,
+        };
+        chrometrace.record_fd_stats(1, &fds).unwrap();
+        chrometrace.record_fd_stats(1, &fds).unwrap();
+
+        let events = events(&mut chrometrace);
+        let counters: Vec<&str> = events
+            .iter()
+            .filter(|e| e["ph"] == "C")
+            .map(|e| e["name"].as_str().unwrap())
+            .collect();
+        assert_eq!(
+            counters,
+            vec![
+                "gc collections",
+                "gc enabled",
+                "gc collections",
+                "open files"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_async_events() {
+        let task = |tid, name: &str, address| {
+            let mut trace = trace(tid, "MainThread", &["recv", name, "_run_once"]);
+            trace.frames[1].frame_kind = FrameKind::Coroutine;
+            trace.task_address = Some(address);
+            trace
+        };
+
+        let mut chrometrace = Chrometrace::new(&Config {
+            async_events: true,
+            ..Default::default()
+        })
+        .unwrap();
+        chrometrace
+            .increment(vec![task(1, "handle", 0x10)], Duration::from_millis(30))
+            .unwrap();
+        chrometrace
+            .increment(
+                vec![trace(1, "MainThread", &["_run_once"])],
+                Duration::from_millis(40),
+            )
+            .unwrap();
+        chrometrace
+            .increment(vec![task(1, "handle", 0x10)], Duration::from_millis(50))
+            .unwrap();
+        // a different coroutine reusing the same address is a new task
+        chrometrace
+            .increment(vec![task(1, "cleanup", 0x10)], Duration::from_millis(60))
+            .unwrap();
+
+        let events = events(&mut chrometrace);
+        let tasks: Vec<(&str, &str, &str)> = events
+            .iter()
+            .filter(|e| e["cat"] == "task")
+            .map(|e| {
+                (
+                    e["ph"].as_str().unwrap(),
+                    e["name"].as_str().unwrap(),
+                    e["id"].as_str().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            tasks,
+            vec![
+                ("b", "handle", "1:0x10"),
+                ("e", "handle", "1:0x10"),
+                ("b", "cleanup", "1:0x10"),
+                ("e", "cleanup", "1:0x10"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_async_flows() {
+        let task = |tid, address| {
+            let mut trace = trace(tid, "MainThread", &["recv", "handle", "_run_once"]);
+            trace.frames[1].frame_kind = FrameKind::Coroutine;
+            trace.task_address = Some(address);
+            trace
+        };
+
+        let mut chrometrace = Chrometrace::new(&Config {
+            async_flows: true,
+            ..Default::default()
+        })
+        .unwrap();
+        // the task is suspended in the second sample, resumes on another thread in the third,
+        // and then keeps running
+        let samples = [
+            vec![task(1, 0x10)],
+            vec![trace(1, "MainThread", &["_run_once"])],
+            vec![task(2, 0x10)],
+            vec![task(2, 0x10)],
+        ];
+        for (i, sample) in samples.into_iter().enumerate() {
+            chrometrace
+                .increment(sample, Duration::from_millis(10 * (i as u64 + 1)))
+                .unwrap();
+        }
+
+        let written = events(&mut chrometrace);
+        let flows: Vec<(&str, u64, u64, u64)> = written
+            .iter()
+            .filter(|e| e["cat"] == "await")
+            .map(|e| {
+                (
+                    e["ph"].as_str().unwrap(),
+                    e["id"].as_u64().unwrap(),
+                    e["tid"].as_u64().unwrap(),
+                    e["ts"].as_u64().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(flows, vec![("s", 1, 1, 10000), ("f", 1, 2, 30000)]);
+
+        // the async events for each task are only written out when asked for
+        assert!(written.iter().all(|e| e["cat"] != "task"));
+    }
+
+    #[test]
+    fn test_collapse_recursion() {
+        let mut chrometrace = Chrometrace::new(&Config {
+            collapse_recursion: true,
+            ..Default::default()
+        })
+        .unwrap();
+        let samples: [&[&str]; 3] = [
+            &["fib", "fib", "fib", "main"],
+            &["fib", "fib", "fib", "main"],
+            &["add", "fib", "fib", "main"],
+        ];
+        for (i, frames) in samples.iter().enumerate() {
+            chrometrace
+                .increment(
+                    vec![trace(1, "MainThread", frames)],
+                    Duration::from_millis(10 * i as u64),
+                )
+                .unwrap();
+        }
+
+        let written = events(&mut chrometrace);
+        let phases: Vec<(&str, &str)> = written
+            .iter()
+            .filter(|e| e["ph"] == "B" || e["ph"] == "E")
+            .map(|e| (e["ph"].as_str().unwrap(), e["name"].as_str().unwrap()))
+            .collect();
+        // the recursion is a single slice until its depth changes
+        assert_eq!(
+            phases,
+            vec![
+                ("B", "main"),
+                ("B", "fib (x3)"),
+                ("E", "fib (x3)"),
+                ("B", "fib (x2)"),
+                ("B", "add"),
+                ("E", "add"),
+                ("E", "fib (x2)"),
+                ("E", "main"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_omit_frames() {
+        let mut chrometrace = Chrometrace::new(&Config {
+            omit_frames: vec!["<module>".to_owned(), "<genexpr>".to_owned()],
+            ..Default::default()
+        })
+        .unwrap();
+        let samples: [&[&str]; 3] = [
+            &["<genexpr>", "main", "<module>"],
+            &["sum", "main", "<module>"],
+            &["<genexpr>", "main", "<module>"],
+        ];
+        for (i, frames) in samples.iter().enumerate() {
+            chrometrace
+                .increment(
+                    vec![trace(1, "MainThread", frames)],
+                    Duration::from_millis(10 * i as u64),
+                )
+                .unwrap();
+        }
+
+        let written = events(&mut chrometrace);
+        let phases: Vec<(&str, &str)> = written
+            .iter()
+            .filter(|e| e["ph"] == "B" || e["ph"] == "E")
+            .map(|e| (e["ph"].as_str().unwrap(), e["name"].as_str().unwrap()))
+            .collect();
+        assert_eq!(
+            phases,
+            vec![("B", "main"), ("B", "sum"), ("E", "sum"), ("E", "main"),]
+        );
+    }
+
+    #[test]
+    fn test_sampling_stats() {
+        let mut chrometrace = Chrometrace::new(&Config::default()).unwrap();
+        let mut stats = SamplingStats {
+            attempts: 10,
+            successes: 9,
+            ..Default::default()
+        };
+        stats.failures.insert("No such process".to_owned(), 1);
+        chrometrace.record_sampling_stats(&stats).unwrap();
+
+        let written = events(&mut chrometrace);
+        let event = written
+            .iter()
+            .find(|e| e["name"] == "sampling_stats")
+            .unwrap();
+        assert_eq!(event["ph"], "M");
+        assert_eq!(event["args"]["attempts"], 10);
+        assert_eq!(event["args"]["successes"], 9);
+        assert_eq!(event["args"]["failures"]["No such process"], 1);
+    }
+
+    #[test]
+    fn test_max_gap() {
+        let mut chrometrace = Chrometrace::new(&Config {
+            max_gap: Some(Duration::from_secs(1)),
+            sampling_rate: 100,
+            ..Default::default()
+        })
+        .unwrap();
+        // there is a 60s gap between the second and third samples, which should be
+        // shortened to the 10ms sampling interval
+        let samples: [(&[&str], u64); 4] = [
+            (&["a"], 10),
+            (&["b"], 20),
+            (&["a"], 60_020),
+            (&["b"], 60_030),
+        ];
+        for (frames, ms) in samples {
+            chrometrace
+                .increment(
+                    vec![trace(1, "MainThread", frames)],
+                    Duration::from_millis(ms),
+                )
+                .unwrap();
+        }
+
+        let timestamps: Vec<u64> = events(&mut chrometrace)
+            .iter()
+            .filter(|e| e["ph"] == "B")
+            .map(|e| e["ts"].as_u64().unwrap())
+            .collect();
+        assert_eq!(timestamps, vec![10_000, 20_000, 30_000, 40_000]);
+    }
+
+    #[test]
+    fn test_pause_recording() {
+        let mut chrometrace = Chrometrace::new(&Config::default()).unwrap();
+        let sample = |chrometrace: &mut Chrometrace, ms| {
+            chrometrace
+                .increment(
+                    vec![trace(1, "MainThread", &["b", "a"])],
+                    Duration::from_millis(ms),
+                )
+                .unwrap();
+        };
+        sample(&mut chrometrace, 10);
+        chrometrace.set_recording(false).unwrap();
+        assert!(!chrometrace.is_recording());
+        sample(&mut chrometrace, 20);
+        // pausing twice doesn't end the slices again
+        chrometrace.set_recording(false).unwrap();
+        chrometrace.set_recording(true).unwrap();
+        sample(&mut chrometrace, 30);
+
+        // the slices end when paused, and start over when the stack is seen again
+        let events = events(&mut chrometrace);
+        let slices: Vec<(&str, &str, u64)> = events
+            .iter()
+            .filter(|e| e["ph"] == "B" || e["ph"] == "E")
+            .map(|e| {
+                (
+                    e["ph"].as_str().unwrap(),
+                    e["name"].as_str().unwrap(),
+                    e["ts"].as_u64().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            slices,
+            vec![
+                ("B", "a", 10_000),
+                ("B", "b", 10_000),
+                ("E", "b", 10_000),
+                ("E", "a", 10_000),
+                ("B", "a", 30_000),
+                ("B", "b", 30_000),
+                ("E", "b", 30_000),
+                ("E", "a", 30_000),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_max_events() {
+        let mut chrometrace = Chrometrace::new(&Config {
+            max_events: Some(3),
+            ..Default::default()
+        })
+        .unwrap();
+        let samples: [&[&str]; 3] = [&["b", "a"], &["c", "a"], &["d"]];
+        for (i, frames) in samples.iter().enumerate() {
+            chrometrace
+                .increment(
+                    vec![trace(1, "MainThread", frames)],
+                    Duration::from_millis(10 * i as u64),
+                )
+                .unwrap();
+            assert!(chrometrace.is_full());
+        }
+
+        // samples after the limit are dropped, and the open slices are still ended
+        let events = events(&mut chrometrace);
+        let phases: Vec<(&str, &str)> = events
+            .iter()
+            .filter(|e| e["ph"] == "B" || e["ph"] == "E")
+            .map(|e| (e["ph"].as_str().unwrap(), e["name"].as_str().unwrap()))
+            .collect();
+        assert_eq!(phases, vec![("B", "a"), ("B", "b"), ("E", "b"), ("E", "a")]);
+
+        // the limit applies to each capture
+        assert!(!chrometrace.is_full());
+    }
+
+    #[test]
+    fn test_trace_epoch() {
+        // sampling started 5s after the epoch, and the first sample was taken 10ms after that
+        let epoch = SystemTime::now() - Duration::from_millis(5010);
+        let mut chrometrace = Chrometrace::new(&Config {
+            trace_epoch: Some(epoch),
+            ..Default::default()
+        })
+        .unwrap();
+        for (frames, ms) in [(&["a"], 10), (&["b"], 20)] {
+            chrometrace
+                .increment(
+                    vec![trace(1, "MainThread", frames)],
+                    Duration::from_millis(ms),
+                )
+                .unwrap();
+        }
+
+        let timestamps: Vec<u64> = events(&mut chrometrace)
+            .iter()
+            .filter(|e| e["ph"] == "B")
+            .map(|e| e["ts"].as_u64().unwrap())
+            .collect();
+        assert!((5_010_000..5_500_000).contains(&timestamps[0]));
+        assert_eq!(timestamps[1] - timestamps[0], 10_000);
+    }
+
+    #[test]
+    fn test_min_duration() {
+        let mut chrometrace = Chrometrace::new(&Config {
+            min_duration_us: 5,
+            ..Default::default()
+        })
+        .unwrap();
+        // the second sample is taken in the same microsecond as the first, and everything in
+        // the last one is closed off at the time it was taken
+        let samples: [(&[&str], u64); 3] = [(&["b", "a"], 10), (&["c", "a"], 10), (&["d"], 100)];
+        for (frames, us) in samples {
+            chrometrace
+                .increment(
+                    vec![trace(1, "MainThread", frames)],
+                    Duration::from_micros(us),
+                )
+                .unwrap();
+        }
+
+        let written = events(&mut chrometrace);
+        let events: Vec<(&str, &str, u64)> = written
+            .iter()
+            .filter(|e| e["ph"] == "B" || e["ph"] == "E")
+            .map(|e| {
+                (
+                    e["ph"].as_str().unwrap(),
+                    e["name"].as_str().unwrap(),
+                    e["ts"].as_u64().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            events,
+            vec![
+                ("B", "a", 10),
+                ("B", "b", 10),
+                ("E", "b", 15),
+                ("B", "c", 15),
+                ("E", "c", 100),
+                ("E", "a", 100),
+                ("B", "d", 100),
+                ("E", "d", 105),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_process_colors() {
+        let mut child = trace(1, "MainThread", &["a"]);
+        child.pid = 2;
+
+        let mut chrometrace = Chrometrace::new(&Config {
+            subprocesses: true,
+            ..Default::default()
+        })
+        .unwrap();
+        chrometrace
+            .increment(
+                vec![trace(1, "MainThread", &["a"]), child.clone()],
+                Duration::from_millis(70),
+            )
+            .unwrap();
+        let written = events(&mut chrometrace);
+        let colors: HashMap<u64, &str> = written
+            .iter()
+            .filter(|e| e["ph"] == "B")
+            .map(|e| (e["pid"].as_u64().unwrap(), e["cname"].as_str().unwrap()))
+            .collect();
+        assert_eq!(colors[&1], PROCESS_COLORS[1]);
+        assert_eq!(colors[&2], PROCESS_COLORS[2]);
+
+        // events aren't tinted when only profiling a single process
+        let mut chrometrace = Chrometrace::new(&Config::default()).unwrap();
+        chrometrace
+            .increment(vec![child], Duration::from_millis(80))
+            .unwrap();
+        assert!(events(&mut chrometrace)
+            .iter()
+            .all(|e| e.get("cname").is_none()));
+    }
+
+    #[test]
+    fn test_track_order() {
+        let mut chrometrace = Chrometrace::new(&Config {
+            track_order: TrackOrder::activity,
+            ..Default::default()
+        })
+        .unwrap();
+        chrometrace
+            .increment(
+                vec![trace(1, "MainThread", &["a"]), trace(2, "worker", &["a"])],
+                Duration::from_millis(90),
+            )
+            .unwrap();
+        chrometrace
+            .increment(vec![trace(2, "worker", &["a"])], Duration::from_millis(100))
+            .unwrap();
+
+        let sort_index: HashMap<u64, u64> = events(&mut chrometrace)
+            .iter()
+            .filter(|e| e["name"] == "thread_sort_index")
+            .map(|e| {
+                (
+                    e["tid"].as_u64().unwrap(),
+                    e["args"]["sort_index"].as_u64().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(sort_index[&2], 0);
+        assert_eq!(sort_index[&1], 1);
+
+        let sort_index = |traces: Vec<StackTrace>| -> Vec<u64> {
+            let mut chrometrace = Chrometrace::new(&Config {
+                track_order: TrackOrder::first_seen,
+                ..Default::default()
+            })
+            .unwrap();
+            chrometrace
+                .increment(traces, Duration::from_millis(100))
+                .unwrap();
+            let mut sort_index: Vec<(u64, u64)> = events(&mut chrometrace)
+                .iter()
+                .filter(|e| e["name"] == "thread_sort_index")
+                .map(|e| {
+                    (
+                        e["args"]["sort_index"].as_u64().unwrap(),
+                        e["tid"].as_u64().unwrap(),
+                    )
+                })
+                .collect();
+            sort_index.sort();
+            sort_index.into_iter().map(|(_, tid)| tid).collect()
+        };
+        assert_eq!(
+            sort_index(vec![
+                trace(7, "worker", &["a"]),
+                trace(5, "MainThread", &["a"]),
+                trace(3, "other", &["a"]),
+            ]),
+            vec![5, 7, 3]
+        );
+        // without a main thread, the lowest thread id goes first
+        assert_eq!(
+            sort_index(vec![
+                trace(7, "worker", &["a"]),
+                trace(9, "other", &["a"]),
+                trace(3, "other", &["a"]),
+            ]),
+            vec![3, 7, 9]
+        );
+    }
+
+    #[test]
+    fn test_labels() {
+        let mut chrometrace = Chrometrace::new(&Config {
+            process_label: Some("{name} (pid {pid})".to_owned()),
+            thread_label: Some("{tname} [{tid}]".to_owned()),
+            ..Default::default()
+        })
+        .unwrap();
+        let mut worker = trace(2, "worker", &["a"]);
+        worker.os_thread_id = Some(1234);
+        worker.process_info = Some(std::sync::Arc::new(crate::stack_trace::ProcessInfo {
+            pid: 1,
+            command_line: "/usr/bin/gunicorn app:main".to_owned(),
+            parent: None,
+        }));
+        chrometrace
+            .increment(vec![worker], Duration::from_millis(10))
+            .unwrap();
+
+        let labelled = events(&mut chrometrace);
+        let label = |name: &str| {
+            labelled
+                .iter()
+                .find(|e| e["name"] == name)
+                .map(|e| e["args"]["name"].clone())
+        };
+        assert_eq!(label("process_name").unwrap(), "gunicorn (pid 1)");
+        assert_eq!(label("thread_name").unwrap(), "worker [1234]");
+
+        // without templates, threads are named once as they show up (or are renamed), and
+        // process names are left alone
+        let mut chrometrace = Chrometrace::new(&Config::default()).unwrap();
+        let mut unnamed = trace(3, "", &["a"]);
+        unnamed.thread_name = None;
+        unnamed.os_thread_id = Some(5678);
+        for name in ["worker", "worker", "renamed"] {
+            chrometrace
+                .increment(
+                    vec![trace(2, name, &["a"]), unnamed.clone()],
+                    Duration::from_millis(10),
+                )
+                .unwrap();
+        }
+        let labelled = events(&mut chrometrace);
+        let names: Vec<(u64, &str)> = labelled
+            .iter()
+            .filter(|e| e["name"] == "thread_name")
+            .map(|e| {
+                (
+                    e["tid"].as_u64().unwrap(),
+                    e["args"]["name"].as_str().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(names, vec![(2, "worker"), (3, "5678"), (2, "renamed")]);
+        assert!(labelled.iter().all(|e| e["name"] != "process_name"));
+    }
+
+    #[test]
+    fn test_label_templates() {
+        let labels = |config: Config, traces: Vec<StackTrace>| {
+            let mut chrometrace = Chrometrace::new(&config).unwrap();
+            chrometrace
+                .increment(traces, Duration::from_millis(10))
+                .unwrap();
+            events(&mut chrometrace)
+                .iter()
+                .filter(|e| e["ph"] == "M" && e["name"].as_str().unwrap().ends_with("_name"))
+                .map(|e| {
+                    (
+                        e["pid"].as_u64().unwrap(),
+                        e["tid"].as_u64().unwrap(),
+                        e["args"]["name"].as_str().unwrap().to_owned(),
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+        let process = |pid: Pid, tid: u64, thread_name: &str, command_line: &str| {
+            let mut trace = trace(tid, thread_name, &["a"]);
+            trace.pid = pid;
+            trace.process_info = Some(std::sync::Arc::new(crate::stack_trace::ProcessInfo {
+                pid,
+                command_line: command_line.to_owned(),
+                parent: None,
+            }));
+            trace
+        };
+
+        // placeholders in the values filled in aren't expanded again, and the labels are
+        // written out in order of pid and thread id
+        let config = Config {
+            process_label: Some("{cmdline}/{pid}".to_owned()),
+            thread_label: Some("{tname}/{tid}".to_owned()),
+            ..Default::default()
+        };
+        let traces = vec![
+            process(3, 1, "{tid}", "python {pid}.py"),
+            process(2, 5, "b", "python b.py"),
+            process(2, 4, "a", "python b.py"),
+        ];
+        assert_eq!(
+            labels(config, traces),
+            vec![
+                (2, 0, "python b.py/2".to_owned()),
+                (3, 0, "python {pid}.py/3".to_owned()),
+                (2, 4, "a/0x4".to_owned()),
+                (2, 5, "b/0x5".to_owned()),
+                (3, 1, "{tid}/0x1".to_owned()),
+            ]
+        );
+
+        // when anonymizing, the command line isn't read from the running process
+        let pid = std::process::id() as Pid;
+        let mut current = trace(1, "MainThread", &["a"]);
+        current.pid = pid;
+        let config = Config {
+            process_label: Some("{pid}:{name}:{cmdline}".to_owned()),
+            ..Default::default()
+        };
+        let process_name = |labels: Vec<(u64, u64, String)>| {
+            let (_, _, name) = labels.into_iter().find(|(_, tid, _)| *tid == 0).unwrap();
+            name
+        };
+        let named = process_name(labels(config.clone(), vec![current.clone()]));
+        assert_ne!(named, format!("{}::", pid));
+        let config = Config {
+            anonymize: true,
+            ..config
+        };
+        let anonymized = process_name(labels(config, vec![current]));
+        assert_eq!(anonymized, format!("{}::", pid));
+    }
+
+    #[test]
+    fn test_process_names() {
+        let mut chrometrace = Chrometrace::new(&Config {
+            subprocesses: true,
+            ..Default::default()
+        })
+        .unwrap();
+        let mut child = trace(2, "MainThread", &["a"]);
+        child.pid = 2;
+        child.process_info = Some(std::sync::Arc::new(crate::stack_trace::ProcessInfo {
+            pid: 2,
+            command_line: "python worker.py".to_owned(),
+            parent: None,
+        }));
+        chrometrace
+            .set_process_name(1, "parent".to_owned())
+            .unwrap();
+        for _ in 0..2 {
+            chrometrace
+                .increment(
+                    vec![trace(1, "MainThread", &["a"]), child.clone()],
+                    Duration::from_millis(10),
+                )
+                .unwrap();
+        }
+        chrometrace
+            .set_process_name(2, "renamed".to_owned())
+            .unwrap();
+
+        let written = events(&mut chrometrace);
+        let names: Vec<(u64, &str)> = written
+            .iter()
+            .filter(|e| e["name"] == "process_name")
+            .map(|e| {
+                (
+                    e["pid"].as_u64().unwrap(),
+                    e["args"]["name"].as_str().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            names,
+            vec![(1, "parent"), (2, "python worker.py"), (2, "renamed")]
+        );
+
+        // each process is named before its first event
+        let first = |pid: u64| written.iter().position(|e| e["pid"] == pid).unwrap();
+        assert_eq!(written[first(1)]["name"], "process_name");
+        assert_eq!(written[first(2)]["name"], "process_name");
+    }
+
+    #[derive(Clone, Default)]
+    struct SharedBuffer(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_streaming() {
+        let stream = SharedBuffer::default();
+        let mut chrometrace =
+            Chrometrace::streaming(&Config::default(), Box::new(stream.clone())).unwrap();
+        chrometrace
+            .increment(
+                vec![trace(1, "MainThread", &["a"])],
+                Duration::from_millis(10),
+            )
+            .unwrap();
+
+        // events should be sent out as each sample is recorded
+        let streamed = String::from_utf8(stream.0.lock().unwrap().clone()).unwrap();
+        assert!(streamed.starts_with("{\"otherData\":"));
+        assert!(streamed.contains("\"ph\":\"B\""));
+
+        let mut unused = Vec::new();
+        chrometrace.write(&mut unused).unwrap();
+        assert!(unused.is_empty());
+        let streamed = String::from_utf8(stream.0.lock().unwrap().clone()).unwrap();
+        let streamed: serde_json::Value = serde_json::from_str(&streamed).unwrap();
+        assert!(streamed["traceEvents"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|e| e["ph"] == "E" && e["name"] == "a"));
+
+        // streamed events can't be discarded
+        assert!(chrometrace.reset().is_err());
+    }
+
+    #[test]
+    fn test_stream_errors() {
+        struct FullDisk;
+
+        impl Write for FullDisk {
+            fn write(&mut self, _buf: &[u8]) -> std::io::Result<usize> {
+                Err(std::io::Error::other("No space left on device"))
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        // the events are buffered up until the end, where the cause of failing to write them
+        // out should be kept
+        let mut chrometrace =
+            Chrometrace::streaming(&Config::default(), Box::new(FullDisk)).unwrap();
+        let err = chrometrace.write(&mut Vec::new()).unwrap_err();
+        assert_eq!(
+            format!("{:#}", err),
+            "Failed to flush trace events: No space left on device"
+        );
+    }
+
+    #[test]
+    fn test_reset() {
+        let mut chrometrace = Chrometrace::new(&Config::default()).unwrap();
+        chrometrace.set_process_name(1, "app".to_owned()).unwrap();
+        chrometrace
+            .increment(
+                vec![trace(1, "MainThread", &["warmup"])],
+                Duration::from_millis(10),
+            )
+            .unwrap();
+        chrometrace.reset().unwrap();
+        for ms in [20, 30] {
+            chrometrace
+                .increment(
+                    vec![trace(1, "MainThread", &["a"])],
+                    Duration::from_millis(ms),
+                )
+                .unwrap();
+        }
+
+        let events = events(&mut chrometrace);
+        assert!(events.iter().all(|e| e["name"] != "warmup"));
+        let phases: Vec<(&str, &str, u64)> = events
+            .iter()
+            .filter(|e| e["ph"] != "M")
+            .map(|e| {
+                (
+                    e["ph"].as_str().unwrap(),
+                    e["name"].as_str().unwrap(),
+                    e["ts"].as_u64().unwrap(),
+                )
+            })
+            .collect();
+        // threads seen before the reset are marked as started again, and timestamps start
+        // from the first sample after the reset
+        assert_eq!(
+            phases,
+            vec![("i", "thread started", 0), ("B", "a", 0), ("E", "a", 10000)]
+        );
+
+        // process names given before the reset still apply
+        assert!(events
+            .iter()
+            .any(|e| e["name"] == "process_name" && e["args"]["name"] == "app"));
+    }
+
+    #[test]
+    fn test_flush_chunk() {
+        let mut chrometrace = Chrometrace::new(&Config::default()).unwrap();
+        let samples: [(&[&str], u64); 3] =
+            [(&["b", "a"], 10), (&["c", "a"], 20), (&["c", "a"], 30)];
+        fn slices(events: &[serde_json::Value]) -> Vec<(&str, &str, u64)> {
+            events
+                .iter()
+                .filter(|e| e["ph"] == "B" || e["ph"] == "E")
+                .map(|e| {
+                    (
+                        e["ph"].as_str().unwrap(),
+                        e["name"].as_str().unwrap(),
+                        e["ts"].as_u64().unwrap(),
+                    )
+                })
+                .collect()
+        }
+
+        for (frames, ms) in &samples[..2] {
+            chrometrace
+                .increment(
+                    vec![trace(1, "MainThread", frames)],
+                    Duration::from_millis(*ms),
+                )
+                .unwrap();
+        }
+        let mut chunk = Vec::new();
+        chrometrace.flush_chunk(&mut chunk).unwrap();
+        // the slices that are still open are left for a later chunk to end
+        assert_eq!(
+            slices(&decode_events(&chunk)),
+            vec![
+                ("B", "a", 10_000),
+                ("B", "b", 10_000),
+                ("E", "b", 20_000),
+                ("B", "c", 20_000)
+            ]
+        );
+
+        let (frames, ms) = samples[2];
+        chrometrace
+            .increment(
+                vec![trace(1, "MainThread", frames)],
+                Duration::from_millis(ms),
+            )
+            .unwrap();
+        let events = events(&mut chrometrace);
+        assert_eq!(
+            slices(&events),
+            vec![("E", "c", 30_000), ("E", "a", 30_000)]
+        );
+        // the thread was already marked as started in the first chunk
+        assert!(events.iter().all(|e| e["ph"] != "i"));
+
+        let mut streaming =
+            Chrometrace::streaming(&Config::default(), Box::new(std::io::sink())).unwrap();
+        assert!(streaming.flush_chunk(&mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn test_compression() {
+        let written = |compression| {
+            let mut chrometrace = Chrometrace::new(&Config {
+                trace_compression: compression,
+                trace_array: true,
+                ..Default::default()
+            })
+            .unwrap();
+            chrometrace
+                .increment(vec![trace(1, "MainThread", &["a"])], Duration::ZERO)
+                .unwrap();
+            let mut output = Vec::new();
+            chrometrace.write(&mut output).unwrap();
+            output
+        };
+
+        let json = written(TraceCompression::none);
+        let events: Vec<serde_json::Value> = serde_json::from_slice(&json).unwrap();
+        assert!(events.iter().any(|e| e["ph"] == "B" && e["name"] == "a"));
+
+        let zstd = zstd::decode_all(written(TraceCompression::zstd).as_slice()).unwrap();
+        assert_eq!(zstd, json);
+
+        let mut gzip = Vec::new();
+        GzDecoder::new(written(TraceCompression::gzip).as_slice())
+            .read_to_end(&mut gzip)
+            .unwrap();
+        assert_eq!(gzip, json);
+
+        // the level of the zstd tempfile can be changed, but has to be one zstd supports
+        let config = |zstd_level| Config {
+            zstd_level,
+            ..Default::default()
+        };
+        assert!(Chrometrace::new(&config(-5)).is_ok());
+        assert!(Chrometrace::new(&config(19)).is_ok());
+        assert!(Chrometrace::new(&config(100)).is_err());
+    }
+
+    #[test]
+    fn test_tmpdir() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            trace_tmpdir: Some(dir.path().to_string_lossy().into_owned()),
+            ..Default::default()
+        };
+        let mut chrometrace = Chrometrace::new(&config).unwrap();
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+        chrometrace
+            .increment(vec![trace(1, "MainThread", &["a"])], Duration::ZERO)
+            .unwrap();
+        assert!(events(&mut chrometrace)
+            .iter()
+            .any(|e| e["ph"] == "B" && e["name"] == "a"));
+
+        // the directory that was tried should be in the error
+        let missing = dir.path().join("missing");
+        let config = Config {
+            trace_tmpdir: Some(missing.to_string_lossy().into_owned()),
+            ..Default::default()
+        };
+        let err = Chrometrace::new(&config).err().unwrap();
+        assert!(err.to_string().contains(&*missing.to_string_lossy()));
+
+        // events can also be buffered in memory, when no tempfile can be created
+        let writer =
+            Writer::with_sink(Sink::Memory(zstd_encoder(Vec::new(), 0).unwrap()), None).unwrap();
+        let mut chrometrace = Chrometrace::with_writer(&Config::default(), writer, None).unwrap();
+        chrometrace
+            .increment(vec![trace(1, "MainThread", &["warmup"])], Duration::ZERO)
+            .unwrap();
+        chrometrace.reset().unwrap();
+        chrometrace
+            .increment(vec![trace(1, "MainThread", &["a"])], Duration::ZERO)
+            .unwrap();
+        let mut output = Vec::new();
+        chrometrace.write(&mut output).unwrap();
+        let mut json = String::new();
+        GzDecoder::new(output.as_slice())
+            .read_to_string(&mut json)
+            .unwrap();
+        let events: Vec<serde_json::Value> = serde_json::from_str(&json).unwrap();
+        assert!(events.iter().any(|e| e["ph"] == "B" && e["name"] == "a"));
+        assert!(events.iter().all(|e| e["name"] != "warmup"));
+    }
+
+    #[test]
+    fn test_streaming_to_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("trace.json.gz");
+        // nothing is buffered up when streaming to a file, so the tmpdir shouldn't matter
+        let config = Config {
+            trace_tmpdir: Some(dir.path().join("missing").display().to_string()),
+            ..Default::default()
+        };
+        let mut chrometrace = Chrometrace::new_streaming(&config, &path).unwrap();
+        for i in 0..3 {
+            chrometrace
+                .increment(
+                    vec![trace(
+                        1,
+                        "MainThread",
+                        &[if i % 2 == 0 { "a" } else { "b" }],
+                    )],
+                    Duration::from_millis(10 * i),
+                )
+                .unwrap();
+            // force a flush on every sample, to check the partial output in between
+            chrometrace.writer.last_flush -= FILE_FLUSH_INTERVAL;
+            chrometrace.writer.flush_stream().unwrap();
+        }
+
+        // before the trace is finished, the file should hold an unterminated json object
+        let mut partial = String::new();
+        let _ = GzDecoder::new(File::open(&path).unwrap()).read_to_string(&mut partial);
+        assert!(partial.starts_with('{'));
+        assert!(!partial.trim_end().ends_with("]}"));
+        let partial: serde_json::Value =
+            serde_json::from_str(&format!("{}]}}", partial.trim_end())).unwrap();
+        assert_eq!(
+            partial["traceEvents"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .filter(|e| e["ph"] == "B" || e["ph"] == "E")
+                .count(),
+            5
+        );
+
+        let mut unused = Vec::new();
+        chrometrace.write(&mut unused).unwrap();
+        assert!(unused.is_empty());
+        let mut finished = String::new();
+        GzDecoder::new(File::open(&path).unwrap())
+            .read_to_string(&mut finished)
+            .unwrap();
+        let finished: serde_json::Value = serde_json::from_str(&finished).unwrap();
+        let finished = finished["traceEvents"].as_array().unwrap();
+        assert!(finished.iter().any(|e| e["ph"] == "E" && e["name"] == "a"));
+        assert!(finished.iter().any(|e| e["ph"] == "E" && e["name"] == "b"));
+    }
+
+    #[test]
+    fn test_trace_metadata() {
+        let written = |config: &Config| {
+            let mut chrometrace = Chrometrace::new(config).unwrap();
+            chrometrace
+                .increment(vec![trace(1, "MainThread", &["a"])], Duration::ZERO)
+                .unwrap();
+            let mut output = Vec::new();
+            chrometrace.write(&mut output).unwrap();
+            let mut json = String::new();
+            GzDecoder::new(output.as_slice())
+                .read_to_string(&mut json)
+                .unwrap();
+            serde_json::from_str::<serde_json::Value>(&json).unwrap()
+        };
+
+        let config = Config {
+            pid: Some(1234),
+            sampling_rate: 50,
+            ..Default::default()
+        };
+        let trace = written(&config);
+        let metadata = &trace["otherData"];
+        assert_eq!(metadata["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(metadata["sampling_interval_us"], 20000);
+        assert_eq!(metadata["pid"], 1234);
+        assert!(
+            chrono::DateTime::parse_from_rfc3339(metadata["start_time"].as_str().unwrap()).is_ok()
+        );
+        assert!(trace["traceEvents"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|e| e["ph"] == "B" && e["name"] == "a"));
+
+        // older tools only load a bare array of events
+        let trace = written(&Config {
+            trace_array: true,
+            ..config
+        });
+        assert!(trace
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|e| e["ph"] == "B" && e["name"] == "a"));
+    }
+}
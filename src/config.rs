@@ -1,9 +1,24 @@
+use std::time::{Duration, SystemTime};
+
 use clap::{
     crate_description, crate_name, crate_version, value_parser, Arg, ArgEnum, Command,
     PossibleValue,
 };
 use remoteprocess::Pid;
 
+use crate::timer::Schedule;
+
+/// The names python gives to code objects that aren't functions, which are omitted from
+/// chrometrace output with --omit-synthetic-frames
+pub const SYNTHETIC_FRAMES: [&str; 6] = [
+    "<module>",
+    "<lambda>",
+    "<genexpr>",
+    "<listcomp>",
+    "<dictcomp>",
+    "<setcomp>",
+];
+
 /// Options on how to collect samples from a python process
 #[derive(Debug, Clone, PartialEq)]
 pub struct Config {
@@ -18,6 +33,13 @@ pub struct Config {
     /// the native stack traces
     pub native: bool,
 
+    /// How to demangle symbol names in native stack traces
+    pub demangle: Demangle,
+
+    /// Glob patterns (like 'libfoo.*') for libraries that native stacks shouldn't be unwound
+    /// through. Patterns without a '/' are matched against the filename of the library
+    pub native_exclude: Vec<String>,
+
     // The following config options only apply when using py-spy as an application
     #[doc(hidden)]
     pub command: String,
@@ -28,20 +50,94 @@ pub struct Config {
     #[doc(hidden)]
     pub sampling_rate: u64,
     #[doc(hidden)]
+    pub schedule: Option<Schedule>,
+    #[doc(hidden)]
     pub filename: Option<String>,
     #[doc(hidden)]
     pub format: Option<FileFormat>,
     #[doc(hidden)]
     pub show_line_numbers: bool,
     #[doc(hidden)]
+    pub granularity: Granularity,
+    #[doc(hidden)]
+    pub track_order: TrackOrder,
+    #[doc(hidden)]
+    pub trace_compression: TraceCompression,
+    #[doc(hidden)]
+    pub zstd_level: i32,
+    #[doc(hidden)]
+    pub trace_tmpdir: Option<String>,
+    #[doc(hidden)]
+    pub name_format: Option<String>,
+    #[doc(hidden)]
+    pub process_label: Option<String>,
+    #[doc(hidden)]
+    pub thread_label: Option<String>,
+    #[doc(hidden)]
     pub duration: RecordDuration,
     #[doc(hidden)]
+    pub python_only: bool,
+    #[doc(hidden)]
+    pub gc_stats: bool,
+    #[doc(hidden)]
+    pub async_events: bool,
+    #[doc(hidden)]
+    pub async_flows: bool,
+    #[doc(hidden)]
+    pub collapse_recursion: bool,
+    #[doc(hidden)]
+    pub sample_counts: bool,
+    #[doc(hidden)]
+    pub show_counters: bool,
+    #[doc(hidden)]
+    pub stream_output: bool,
+    #[doc(hidden)]
+    pub trace_array: bool,
+    #[doc(hidden)]
+    pub max_gap: Option<Duration>,
+    #[doc(hidden)]
+    pub min_duration_us: u64,
+    #[doc(hidden)]
+    pub trace_epoch: Option<SystemTime>,
+    #[doc(hidden)]
+    pub max_events: Option<u64>,
+    #[doc(hidden)]
+    pub fd_stats: bool,
+    #[doc(hidden)]
+    pub overhead_report: bool,
+    #[doc(hidden)]
+    pub sampling_stats: bool,
+    #[doc(hidden)]
+    pub capture_locals: Vec<String>,
+    #[doc(hidden)]
+    pub collapse_paths: Vec<String>,
+    #[doc(hidden)]
+    pub keep_paths: Vec<String>,
+    #[doc(hidden)]
+    pub omit_frames: Vec<String>,
+    #[doc(hidden)]
+    pub only_threads: Vec<String>,
+    #[doc(hidden)]
+    pub exclude_threads: Vec<String>,
+    #[doc(hidden)]
+    pub anonymize: bool,
+    #[doc(hidden)]
+    pub anonymize_keep_stdlib: bool,
+    #[doc(hidden)]
+    pub anonymize_keyfile: Option<String>,
+    #[doc(hidden)]
     pub include_idle: bool,
     #[doc(hidden)]
     pub include_thread_ids: bool,
     #[doc(hidden)]
+    pub split_by_state: bool,
+    #[doc(hidden)]
+    pub gil_frames: bool,
+    #[doc(hidden)]
     pub subprocesses: bool,
     #[doc(hidden)]
+    pub max_subprocesses: Option<usize>,
+    #[doc(hidden)]
     pub gil_only: bool,
     #[doc(hidden)]
     pub hide_progress: bool,
@@ -50,8 +146,16 @@ pub struct Config {
     #[doc(hidden)]
     pub dump_json: bool,
     #[doc(hidden)]
+    pub dump_snapshot: bool,
+    #[doc(hidden)]
     pub dump_locals: u64,
     #[doc(hidden)]
+    pub dump_repeat: Option<f64>,
+    #[doc(hidden)]
+    pub dump_merge_identical: bool,
+    #[doc(hidden)]
+    pub dump_deadlock: bool,
+    #[doc(hidden)]
     pub full_filenames: bool,
     #[doc(hidden)]
     pub lineno: LineNo,
@@ -59,6 +163,12 @@ pub struct Config {
     pub refresh_seconds: f64,
     #[doc(hidden)]
     pub core_filename: Option<String>,
+    #[doc(hidden)]
+    pub symbols_filename: Option<String>,
+    #[doc(hidden)]
+    pub memory_fd: Option<i32>,
+    #[doc(hidden)]
+    pub read_batch_size: usize,
 }
 
 #[allow(non_camel_case_types)]
@@ -67,6 +177,8 @@ pub enum FileFormat {
     flamegraph,
     raw,
     speedscope,
+    chrometrace,
+    dot,
 }
 
 impl FileFormat {
@@ -90,6 +202,148 @@ impl std::str::FromStr for FileFormat {
     }
 }
 
+#[allow(non_camel_case_types)]
+#[derive(ArgEnum, Debug, Copy, Clone, Eq, PartialEq)]
+pub enum TrackOrder {
+    /// The main thread first, followed by the other threads ordered by thread id
+    main,
+    /// The threads with the most samples first
+    activity,
+    /// Ordered by thread id
+    tid,
+    /// Ordered by thread name
+    name,
+    /// The main thread first, followed by the other threads in the order they were first sampled
+    first_seen,
+}
+
+impl TrackOrder {
+    pub fn possible_values() -> impl Iterator<Item = PossibleValue<'static>> {
+        TrackOrder::value_variants()
+            .iter()
+            .filter_map(ArgEnum::to_possible_value)
+    }
+}
+
+impl std::str::FromStr for TrackOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        for variant in Self::value_variants() {
+            if variant.to_possible_value().unwrap().matches(s, false) {
+                return Ok(*variant);
+            }
+        }
+        Err(format!("Invalid track order: {}", s))
+    }
+}
+
+#[allow(non_camel_case_types)]
+#[derive(ArgEnum, Debug, Copy, Clone, Eq, PartialEq)]
+pub enum TraceCompression {
+    /// Plain json
+    none,
+    /// Gzipped json, which chrome://tracing and perfetto can load directly
+    gzip,
+    /// Zstandard compressed json
+    zstd,
+}
+
+impl TraceCompression {
+    pub fn possible_values() -> impl Iterator<Item = PossibleValue<'static>> {
+        TraceCompression::value_variants()
+            .iter()
+            .filter_map(ArgEnum::to_possible_value)
+    }
+
+    /// The file extension for a chrometrace file written with this compression
+    pub fn extension(&self) -> &'static str {
+        match self {
+            TraceCompression::none => "json",
+            TraceCompression::gzip => "json.gz",
+            TraceCompression::zstd => "json.zst",
+        }
+    }
+}
+
+impl std::str::FromStr for TraceCompression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        for variant in Self::value_variants() {
+            if variant.to_possible_value().unwrap().matches(s, false) {
+                return Ok(*variant);
+            }
+        }
+        Err(format!("Invalid trace compression: {}", s))
+    }
+}
+
+#[allow(non_camel_case_types)]
+#[derive(ArgEnum, Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Granularity {
+    /// Aggregate samples by the line being run in each function
+    line,
+    /// Aggregate samples by function, ignoring line numbers
+    function,
+    /// Aggregate samples by the file (or module) of each frame, merging consecutive frames
+    /// from the same file
+    file,
+}
+
+impl Granularity {
+    pub fn possible_values() -> impl Iterator<Item = PossibleValue<'static>> {
+        Granularity::value_variants()
+            .iter()
+            .filter_map(ArgEnum::to_possible_value)
+    }
+}
+
+impl std::str::FromStr for Granularity {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        for variant in Self::value_variants() {
+            if variant.to_possible_value().unwrap().matches(s, false) {
+                return Ok(*variant);
+            }
+        }
+        Err(format!("Invalid granularity: {}", s))
+    }
+}
+
+#[derive(ArgEnum, Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Demangle {
+    /// Try the rust demangler then the c++ one, falling back to the raw name
+    Auto,
+    #[clap(name = "c++")]
+    Cpp,
+    Rust,
+    /// Don't demangle, show the raw symbol names
+    Off,
+}
+
+impl Demangle {
+    pub fn possible_values() -> impl Iterator<Item = PossibleValue<'static>> {
+        Demangle::value_variants()
+            .iter()
+            .filter_map(ArgEnum::to_possible_value)
+    }
+}
+
+impl std::str::FromStr for Demangle {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        for variant in Self::value_variants() {
+            if variant.to_possible_value().unwrap().matches(s, false) {
+                return Ok(*variant);
+            }
+        }
+        Err(format!("Invalid demangle option: {}", s))
+    }
+}
+
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub enum LockingStrategy {
     NonBlocking,
@@ -123,21 +377,67 @@ impl Default for Config {
             command: String::from("top"),
             blocking: LockingStrategy::Lock,
             show_line_numbers: false,
+            track_order: TrackOrder::tid,
+            trace_compression: TraceCompression::gzip,
+            zstd_level: 0,
+            trace_tmpdir: None,
+            granularity: Granularity::line,
+            name_format: None,
+            process_label: None,
+            thread_label: None,
             sampling_rate: 100,
+            schedule: None,
             duration: RecordDuration::Unlimited,
             native: false,
+            demangle: Demangle::Auto,
+            native_exclude: Vec::new(),
             gil_only: false,
+            python_only: false,
+            gc_stats: false,
+            async_events: false,
+            async_flows: false,
+            collapse_recursion: false,
+            sample_counts: false,
+            show_counters: false,
+            stream_output: false,
+            trace_array: false,
+            max_gap: None,
+            min_duration_us: 0,
+            trace_epoch: None,
+            max_events: None,
+            fd_stats: false,
+            overhead_report: false,
+            sampling_stats: false,
+            capture_locals: Vec::new(),
+            collapse_paths: Vec::new(),
+            keep_paths: Vec::new(),
+            omit_frames: Vec::new(),
+            only_threads: Vec::new(),
+            exclude_threads: Vec::new(),
+            anonymize: false,
+            anonymize_keep_stdlib: false,
+            anonymize_keyfile: None,
             include_idle: false,
             include_thread_ids: false,
+            split_by_state: false,
+            gil_frames: false,
             hide_progress: false,
             capture_output: true,
             dump_json: false,
+            dump_snapshot: false,
             dump_locals: 0,
+            dump_repeat: None,
+            dump_merge_identical: false,
+            dump_deadlock: false,
             subprocesses: false,
+            max_subprocesses: None,
             full_filenames: false,
             lineno: LineNo::LastInstruction,
             refresh_seconds: 1.0,
             core_filename: None,
+            symbols_filename: None,
+            memory_fd: None,
+            read_batch_size: 4096,
         }
     }
 }
@@ -165,6 +465,25 @@ impl Config {
             .long("native")
             .help("Collect stack traces from native extensions written in Cython, C or C++");
 
+        #[cfg(unwind)]
+        let demangle = Arg::new("demangle")
+            .long("demangle")
+            .value_name("demangle")
+            .help("How to demangle native symbol names")
+            .takes_value(true)
+            .possible_values(Demangle::possible_values())
+            .ignore_case(true)
+            .default_value("auto");
+
+        #[cfg(unwind)]
+        let native_exclude = Arg::new("native_exclude")
+            .long("native-exclude")
+            .value_name("pattern")
+            .help("Stop unwinding native stacks at libraries matching this glob pattern (like 'libfoo.*'), showing a single frame for the library instead")
+            .takes_value(true)
+            .multiple_occurrences(true)
+            .requires("native");
+
         #[cfg(not(target_os="freebsd"))]
         let nonblocking = Arg::new("nonblocking")
                     .long("nonblocking")
@@ -183,6 +502,21 @@ impl Config {
             .short('s')
             .long("subprocesses")
             .help("Profile subprocesses of the original process");
+        let max_subprocesses = Arg::new("max_subprocesses")
+            .long("max-subprocesses")
+            .value_name("count")
+            .help("Only sample this many subprocesses at once. Other subprocesses are picked up as the ones being sampled exit")
+            .value_parser(clap::value_parser!(usize))
+            .takes_value(true)
+            .requires("subprocesses");
+
+        let granularity = Arg::new("granularity")
+            .long("granularity")
+            .value_name("granularity")
+            .help("Whether to aggregate samples by line, function or file")
+            .takes_value(true)
+            .possible_values(Granularity::possible_values())
+            .ignore_case(true);
 
         let full_filenames = Arg::new("full_filenames").long("full-filenames").help(
             "Show full Python filenames, instead of shortening to show only the package part",
@@ -201,6 +535,14 @@ impl Config {
             .long("gil")
             .help("Only include traces that are holding on to the GIL");
 
+        let read_batch_size = Arg::new("read_batch_size")
+            .long("read-batch-size")
+            .value_name("bytes")
+            .help("Read memory in blocks of this many bytes when walking stacks, and reuse them for nearby reads. 0 reads each value separately")
+            .default_value("4096")
+            .value_parser(clap::value_parser!(usize))
+            .takes_value(true);
+
         let top_delay = Arg::new("delay")
             .long("delay")
             .value_name("seconds")
@@ -210,7 +552,7 @@ impl Config {
             .takes_value(true);
 
         let record = Command::new("record")
-            .about("Records stack trace information to a flamegraph, speedscope or raw file")
+            .about("Records stack trace information to a flamegraph, speedscope, chrometrace or raw file")
             .arg(program.clone())
             .arg(pid.clone().required_unless_present("python_program"))
             .arg(full_filenames.clone())
@@ -219,7 +561,7 @@ impl Config {
                     .short('o')
                     .long("output")
                     .value_name("filename")
-                    .help("Output filename")
+                    .help("Output filename. Use 'unix:<path>' to write to a unix domain socket instead, where chrometrace events are streamed out live as they are recorded")
                     .takes_value(true)
                     .required(false),
             )
@@ -234,6 +576,64 @@ impl Config {
                     .ignore_case(true)
                     .default_value("flamegraph"),
             )
+            .arg(
+                Arg::new("track_order")
+                    .long("track-order")
+                    .value_name("order")
+                    .help("How to order the thread tracks in chrometrace output")
+                    .takes_value(true)
+                    .possible_values(TrackOrder::possible_values())
+                    .ignore_case(true)
+                    .default_value("tid"),
+            )
+            .arg(
+                Arg::new("trace_compression")
+                    .long("trace-compression")
+                    .value_name("compression")
+                    .help("How to compress chrometrace output. Only gzip can be loaded directly by chrome://tracing and perfetto")
+                    .takes_value(true)
+                    .possible_values(TraceCompression::possible_values())
+                    .ignore_case(true)
+                    .default_value("gzip"),
+            )
+            .arg(
+                Arg::new("zstd_level")
+                    .long("zstd-level")
+                    .value_name("level")
+                    .help("The zstd compression level for the temporary file that chrometrace events are buffered in, and for zstd output. Higher levels use more CPU to make the file smaller, negative levels are faster. 0 is zstd's default level")
+                    .default_value("0")
+                    .allow_hyphen_values(true)
+                    .value_parser(clap::value_parser!(i32))
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::new("trace_tmpdir")
+                    .long("trace-tmpdir")
+                    .value_name("dir")
+                    .help("Directory for the temporary file that chrometrace events are buffered in, instead of $TMPDIR (or /tmp)")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::new("name_format")
+                    .long("name-format")
+                    .value_name("template")
+                    .help("Template for frame names in flamegraph and raw output, using {file}, {module}, {line}, {func} and {kind} (eg '{file}:{line}:{func}'). {kind} is 'generator', 'async' or 'async generator' for frames that can be suspended")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::new("process_label")
+                    .long("process-label")
+                    .value_name("template")
+                    .help("Template for process track names in chrometrace output, using {name}, {pid} and {cmdline} (eg '{name} (pid {pid})')")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::new("thread_label")
+                    .long("thread-label")
+                    .value_name("template")
+                    .help("Template for thread track names in chrometrace output, using {tname}, {tid} and {pid} (eg '{tname} [{tid}]')")
+                    .takes_value(true),
+            )
             .arg(
                 Arg::new("duration")
                     .short('d')
@@ -244,7 +644,18 @@ impl Config {
                     .takes_value(true),
             )
             .arg(rate.clone())
+            .arg(
+                Arg::new("schedule")
+                    .long("schedule")
+                    .value_name("filename")
+                    .help("Read the sampling rates to use over time from a file. Each line has a time range in seconds and a rate, like '0-5 1000'. Sampling stops at the end of the schedule")
+                    .takes_value(true)
+                    .value_parser(|filename: &str| Schedule::load(filename).map_err(|e| format!("{:#}", e)))
+                    .conflicts_with_all(&["rate", "duration"]),
+            )
             .arg(subprocesses.clone())
+            .arg(max_subprocesses.clone())
+            .arg(granularity.clone().conflicts_with_all(&["function", "nolineno"]))
             .arg(Arg::new("function").short('F').long("function").help(
                 "Aggregate samples by function's first line number, instead of current line number",
             ))
@@ -259,8 +670,155 @@ impl Config {
                     .long("threads")
                     .help("Show thread ids in the output"),
             )
+            .arg(Arg::new("split_by_state").long("split-by-state").help(
+                "Split stack traces into 'running' and 'waiting' based on the thread state (implies --idle)",
+            ))
             .arg(gil.clone())
             .arg(idle.clone())
+            .arg(Arg::new("gil_frames").long("gil-frames").help(
+                "Add a '<native>' frame to threads that are running without holding the GIL, and an '<idle>' frame to threads that are blocked, so that this time isn't attributed to their last python frame",
+            ))
+            .arg(Arg::new("gc_stats").long("gc-stats").help(
+                "Record garbage collection counts as counter events in chrometrace output (python 3.9+)",
+            ))
+            .arg(Arg::new("fd_stats").long("fd-stats").help(
+                "Record the number of open files and sockets as counter events in chrometrace output (linux only)",
+            ))
+            .arg(Arg::new("async_events").long("async-events").help(
+                "Add an async event in chrometrace output for the lifetime of each asyncio task",
+            ))
+            .arg(Arg::new("async_flows").long("async-flows").help(
+                "Add flow events in chrometrace output that link each asyncio task from where it was suspended to where it resumed",
+            ))
+            .arg(Arg::new("collapse_recursion").long("collapse-recursion").help(
+                "Collapse consecutive calls of the same function into a single slice in chrometrace output, with the number of calls added to the name like 'fib (x20)'",
+            ))
+            .arg(Arg::new("sample_counts").long("sample-counts").help(
+                "Add the number of samples each frame was on the stack for (total) and was the innermost frame for (self) to chrometrace output",
+            ))
+            .arg(Arg::new("show_counters").long("counters").help(
+                "Record the number of threads and the total number of frames in each sample as counter events in chrometrace output",
+            ))
+            .arg(Arg::new("stream_output").long("stream-output").help(
+                "Write chrometrace events to the output file as they are recorded, instead of buffering them in a temporary file until the end. The file is still readable if py-spy is killed, apart from the last second of events",
+            ))
+            .arg(Arg::new("trace_array").long("trace-array").help(
+                "Write chrometrace output as a bare json array of events, without the sampling rate, py-spy version, pid and start time that are added by default. Some older tools only load this format",
+            ))
+            .arg(
+                Arg::new("max_gap")
+                    .long("max-gap")
+                    .value_name("milliseconds")
+                    .help("Shorten gaps between samples that are longer than this to the sampling interval in chrometrace output, like when py-spy wasn't scheduled or the machine was suspended. This makes the timeline easier to read, but it no longer matches wall clock time after a gap")
+                    .value_parser(clap::value_parser!(u64))
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::new("min_duration_us")
+                    .long("min-slice-duration")
+                    .value_name("microseconds")
+                    .help("Make slices in chrometrace output last at least this long, so that frames that start and end in the same microsecond aren't written out with no duration")
+                    .default_value("0")
+                    .value_parser(clap::value_parser!(u64))
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::new("trace_epoch")
+                    .long("trace-epoch")
+                    .value_name("unix time")
+                    .help("Write chrometrace timestamps relative to this time (in seconds since the unix epoch, like from 'date +%s.%N') instead of the start of sampling, so that traces recorded separately line up when loaded together")
+                    .value_parser(|secs: &str| {
+                        secs.parse::<f64>()
+                            .ok()
+                            .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+                            .map(|since_epoch| SystemTime::UNIX_EPOCH + since_epoch)
+                            .ok_or_else(|| format!("'{}' isn't a valid unix time", secs))
+                    })
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::new("max_events")
+                    .long("max-events")
+                    .value_name("count")
+                    .help("Stop sampling once this many events have been written to chrometrace output, to bound the size of the trace on long captures")
+                    .value_parser(clap::value_parser!(u64))
+                    .takes_value(true),
+            )
+            .arg(read_batch_size.clone())
+            .arg(Arg::new("overhead_report").long("overhead-report").help(
+                "Report how long the process was paused for while sampling, and the estimated slowdown this caused",
+            ))
+            .arg(Arg::new("sampling_stats").long("sampling-stats").help(
+                "Report how many stack traces failed to be read while sampling and why, and add this to chrometrace output as a metadata event",
+            ))
+            .arg(
+                Arg::new("capture_locals")
+                    .long("capture-locals")
+                    .value_name("function")
+                    .help("Add the values of the local variables of frames of functions with this name to chrometrace output. This makes sampling slower, so should only be used for a few functions")
+                    .takes_value(true)
+                    .multiple_occurrences(true),
+            )
+            .arg(
+                Arg::new("collapse_paths")
+                    .long("collapse-path")
+                    .value_name("prefix")
+                    .help("Collapse each run of frames from files matching this path prefix (like 'site-packages/django/') into a single frame for the outermost module")
+                    .takes_value(true)
+                    .multiple_occurrences(true),
+            )
+            .arg(
+                Arg::new("keep_paths")
+                    .long("keep-path")
+                    .value_name("prefix")
+                    .help("Collapse each run of frames from files that don't match any of these path prefixes into a single frame for the outermost module")
+                    .takes_value(true)
+                    .multiple_occurrences(true),
+            )
+            .arg(Arg::new("omit_synthetic_frames").long("omit-synthetic-frames").help(
+                "Leave out frames for code that isn't a function, like '<module>', '<lambda>' and '<genexpr>', from chrometrace output",
+            ))
+            .arg(
+                Arg::new("omit_frames")
+                    .long("omit-frame")
+                    .value_name("name")
+                    .help("Leave out frames with this name from chrometrace output, instead of the ones left out by --omit-synthetic-frames. Can be given several times")
+                    .takes_value(true)
+                    .multiple_occurrences(true),
+            )
+            .arg(
+                Arg::new("only_threads")
+                    .long("only-thread")
+                    .value_name("thread")
+                    .help("Only sample this thread, given by its OS thread id, python thread id (like '0x7f3a1c2b4740') or name. Can be given several times")
+                    .takes_value(true)
+                    .multiple_occurrences(true),
+            )
+            .arg(
+                Arg::new("exclude_threads")
+                    .long("exclude-thread")
+                    .value_name("thread")
+                    .help("Don't sample this thread, given by its OS thread id, python thread id or name. Can be given several times")
+                    .takes_value(true)
+                    .multiple_occurrences(true),
+            )
+            .arg(Arg::new("anonymize").long("anonymize").help(
+                "Replace function and file names in the output with opaque tokens",
+            ))
+            .arg(
+                Arg::new("anonymize_keep_stdlib")
+                    .long("anonymize-keep-stdlib")
+                    .requires("anonymize")
+                    .help("Don't anonymize frames from the python standard library"),
+            )
+            .arg(
+                Arg::new("anonymize_keyfile")
+                    .long("anonymize-keyfile")
+                    .value_name("filename")
+                    .requires("anonymize")
+                    .help("Write the mapping of anonymized tokens to the original names to this file")
+                    .takes_value(true),
+            )
             .arg(
                 Arg::new("capture")
                     .long("capture")
@@ -280,10 +838,13 @@ impl Config {
             .arg(pid.clone().required_unless_present("python_program"))
             .arg(rate.clone())
             .arg(subprocesses.clone())
+            .arg(max_subprocesses.clone())
             .arg(full_filenames.clone())
+            .arg(granularity.clone())
             .arg(gil.clone())
             .arg(idle.clone())
-            .arg(top_delay.clone());
+            .arg(top_delay.clone())
+            .arg(read_batch_size.clone());
 
         #[cfg(target_os = "linux")]
         let dump_pid = pid.clone().required_unless_present("core");
@@ -293,6 +854,8 @@ impl Config {
 
         let dump = Command::new("dump")
             .about("Dumps stack traces for a target program to stdout")
+            // keep 'py-spy d' working, which would otherwise be ambiguous with 'dump-symbols'
+            .alias("d")
             .arg(dump_pid);
 
         #[cfg(target_os = "linux")]
@@ -315,7 +878,44 @@ impl Config {
                 .short('j')
                 .long("json")
                 .help("Format output as JSON"))
-            .arg(subprocesses.clone());
+            .arg(Arg::new("format")
+                .short('f')
+                .long("format")
+                .value_name("format")
+                .help("Output format. 'json' writes a single document with the stacks of every thread, along with the pid and capture time")
+                .takes_value(true)
+                .possible_values(["text", "json"])
+                .ignore_case(true)
+                .default_value("text")
+                .conflicts_with("json"))
+            .arg(Arg::new("repeat")
+                .long("repeat")
+                .value_name("seconds")
+                .help("Keep dumping the stack traces at this interval until the process exits, which is useful for watching a hang")
+                .takes_value(true)
+                .value_parser(|secs: &str| {
+                    secs.parse::<f64>()
+                        .ok()
+                        .filter(|&secs| {
+                            Duration::try_from_secs_f64(secs).is_ok_and(|interval| !interval.is_zero())
+                        })
+                        .ok_or_else(|| format!("'{}' isn't a valid interval", secs))
+                })
+                .conflicts_with_all(&["json", "format", "subprocesses"]))
+            .arg(Arg::new("merge_identical")
+                .long("merge-identical")
+                .help("When repeating, collapse dumps that are the same as the previous one into a count, so that only changes are shown")
+                .requires("repeat"))
+            .arg(Arg::new("deadlock")
+                .long("deadlock")
+                .help("Look for threads that are deadlocked waiting on each other's locks (python 3.6+). Only RLocks record which thread holds them, so deadlocks with plain Locks can't be detected")
+                .conflicts_with_all(&["json", "format", "repeat"]))
+            .arg(subprocesses.clone())
+            .arg(read_batch_size.clone());
+
+        let check = Command::new("check")
+            .about("Checks that py-spy can find the python interpreter in a running program, without sampling it")
+            .arg(pid.clone().required(true));
 
         let completions = Command::new("completions")
             .about("Generate shell completions")
@@ -326,13 +926,37 @@ impl Config {
                     .help("Shell type"),
             );
 
+        let dump_symbols = Command::new("dump-symbols")
+            .about(
+                "Prints the symbols py-spy finds in a binary as json, for debugging symbol lookup",
+            )
+            .hide(true)
+            .arg(
+                Arg::new("binary")
+                    .value_name("path")
+                    .help("Path of the python binary or libpython to read")
+                    .required(true),
+            );
+
         // add native unwinding if appropriate
         #[cfg(unwind)]
-        let record = record.arg(native.clone());
+        let record = record
+            .arg(native.clone())
+            .arg(demangle.clone())
+            .arg(native_exclude.clone())
+            .arg(Arg::new("python_only").long("python-only").help(
+                "Strip native frames from the output, while still using them to find python frames",
+            ));
         #[cfg(unwind)]
-        let top = top.arg(native.clone());
+        let top = top
+            .arg(native.clone())
+            .arg(demangle.clone())
+            .arg(native_exclude.clone());
         #[cfg(unwind)]
-        let dump = dump.arg(native.clone());
+        let dump = dump
+            .arg(native.clone())
+            .arg(demangle.clone())
+            .arg(native_exclude.clone());
 
         // Nonblocking isn't an option for freebsd, remove
         #[cfg(not(target_os = "freebsd"))]
@@ -342,6 +966,28 @@ impl Config {
         #[cfg(not(target_os = "freebsd"))]
         let dump = dump.arg(nonblocking.clone());
 
+        // Reading memory through a file descriptor relies on procfs, so is linux only. Pausing
+        // the process needs ptrace, so this has to be used with --nonblocking
+        #[cfg(target_os = "linux")]
+        let mem_fd = Arg::new("mem_fd")
+            .long("mem-fd")
+            .value_name("fd")
+            .help(
+                "Read memory from this already open file descriptor for /proc/<pid>/mem, \
+                   rather than reading memory of the process directly",
+            )
+            .takes_value(true)
+            .value_parser(value_parser!(i32).range(0..))
+            .requires("pid")
+            .requires("nonblocking")
+            .conflicts_with("subprocesses");
+        #[cfg(target_os = "linux")]
+        let record = record.arg(mem_fd.clone());
+        #[cfg(target_os = "linux")]
+        let top = top.arg(mem_fd.clone());
+        #[cfg(target_os = "linux")]
+        let dump = dump.arg(mem_fd.clone());
+
         let mut app = Command::new(crate_name!())
             .version(crate_version!())
             .about(crate_description!())
@@ -352,6 +998,8 @@ impl Config {
             .subcommand(record)
             .subcommand(top)
             .subcommand(dump)
+            .subcommand(check)
+            .subcommand(dump_symbols)
             .subcommand(completions);
         let matches = app.clone().try_get_matches_from(args)?;
         info!("Command line args: {:?}", matches);
@@ -369,37 +1017,147 @@ impl Config {
                         RecordDuration::Seconds(seconds.parse().expect("invalid duration"))
                     }
                 };
+                config.schedule = matches.get_one::<Schedule>("schedule").cloned();
                 config.format = Some(matches.value_of_t("format")?);
                 config.filename = matches.value_of("output").map(|f| f.to_owned());
-                config.show_line_numbers = matches.occurrences_of("nolineno") == 0;
-                config.lineno = if matches.occurrences_of("nolineno") > 0 {
+                config.track_order = matches.value_of_t("track_order")?;
+                config.trace_compression = matches.value_of_t("trace_compression")?;
+                config.zstd_level = *matches.get_one::<i32>("zstd_level").unwrap();
+                config.trace_tmpdir = matches.value_of("trace_tmpdir").map(|f| f.to_owned());
+                config.name_format = matches.value_of("name_format").map(|f| f.to_owned());
+                config.process_label = matches.value_of("process_label").map(|f| f.to_owned());
+                config.thread_label = matches.value_of("thread_label").map(|f| f.to_owned());
+                // --nolineno is the same as aggregating by function
+                config.granularity = if matches.occurrences_of("nolineno") > 0 {
+                    Granularity::function
+                } else if matches.is_present("granularity") {
+                    matches.value_of_t("granularity")?
+                } else {
+                    Granularity::line
+                };
+                config.show_line_numbers = config.granularity == Granularity::line;
+                config.lineno = if config.granularity != Granularity::line {
                     LineNo::NoLine
                 } else if matches.occurrences_of("function") > 0 {
                     LineNo::First
                 } else {
                     LineNo::LastInstruction
                 };
+                if config.format == Some(FileFormat::dot)
+                    && matches.is_present("granularity")
+                    && config.granularity == Granularity::line
+                    && !config.output_shows_line_numbers()
+                {
+                    return Err(app.clone().error(
+                        clap::ErrorKind::ArgumentConflict,
+                        "dot output only shows line numbers with a --name-format using {line}, so it can't be aggregated by line otherwise",
+                    ));
+                }
+                if !config.output_shows_line_numbers() {
+                    // don't bother decoding line numbers from the line table on every sample
+                    // if the output is going to discard them anyway
+                    config.lineno = LineNo::NoLine;
+                }
                 config.include_thread_ids = matches.occurrences_of("threads") > 0;
+                config.split_by_state = matches.occurrences_of("split_by_state") > 0;
+                config.gil_frames = matches.occurrences_of("gil_frames") > 0;
                 if matches.occurrences_of("nolineno") > 0 && matches.occurrences_of("function") > 0
                 {
                     eprintln!("--function & --nolinenos can't be used together");
                     std::process::exit(1);
                 }
                 config.hide_progress = matches.occurrences_of("hideprogress") > 0;
+                config.gc_stats = matches.occurrences_of("gc_stats") > 0;
+                config.async_events = matches.occurrences_of("async_events") > 0;
+                config.async_flows = matches.occurrences_of("async_flows") > 0;
+                config.collapse_recursion = matches.occurrences_of("collapse_recursion") > 0;
+                config.sample_counts = matches.occurrences_of("sample_counts") > 0;
+                config.show_counters = matches.occurrences_of("show_counters") > 0;
+                config.stream_output = matches.occurrences_of("stream_output") > 0;
+                config.trace_array = matches.occurrences_of("trace_array") > 0;
+                config.min_duration_us = *matches.get_one::<u64>("min_duration_us").unwrap();
+                config.max_gap = matches
+                    .get_one::<u64>("max_gap")
+                    .map(|ms| Duration::from_millis(*ms));
+                config.trace_epoch = matches.get_one::<SystemTime>("trace_epoch").copied();
+                config.max_events = matches.get_one::<u64>("max_events").copied();
+                config.fd_stats = matches.occurrences_of("fd_stats") > 0;
+                config.overhead_report = matches.occurrences_of("overhead_report") > 0;
+                config.sampling_stats = matches.occurrences_of("sampling_stats") > 0;
+                config.capture_locals = matches
+                    .values_of("capture_locals")
+                    .map(|vals| vals.map(|v| v.to_owned()).collect())
+                    .unwrap_or_default();
+                config.collapse_paths = matches
+                    .values_of("collapse_paths")
+                    .map(|vals| vals.map(|v| v.to_owned()).collect())
+                    .unwrap_or_default();
+                config.keep_paths = matches
+                    .values_of("keep_paths")
+                    .map(|vals| vals.map(|v| v.to_owned()).collect())
+                    .unwrap_or_default();
+                config.omit_frames = match matches.values_of("omit_frames") {
+                    Some(vals) => vals.map(|v| v.to_owned()).collect(),
+                    None if matches.occurrences_of("omit_synthetic_frames") > 0 => {
+                        SYNTHETIC_FRAMES.iter().map(|v| v.to_string()).collect()
+                    }
+                    None => Vec::new(),
+                };
+                config.only_threads = matches
+                    .values_of("only_threads")
+                    .map(|vals| vals.map(|v| v.to_owned()).collect())
+                    .unwrap_or_default();
+                config.exclude_threads = matches
+                    .values_of("exclude_threads")
+                    .map(|vals| vals.map(|v| v.to_owned()).collect())
+                    .unwrap_or_default();
+                config.anonymize = matches.occurrences_of("anonymize") > 0;
+                config.anonymize_keep_stdlib = matches.occurrences_of("anonymize_keep_stdlib") > 0;
+                config.anonymize_keyfile =
+                    matches.value_of("anonymize_keyfile").map(|f| f.to_owned());
+                if cfg!(unwind) {
+                    config.python_only = matches.occurrences_of("python_only") > 0;
+                }
             }
             "top" => {
                 config.sampling_rate = matches.value_of_t("rate")?;
                 config.refresh_seconds = *matches.get_one::<f64>("delay").unwrap();
+                // top otherwise starts out aggregating by function, and can be toggled to
+                // aggregate by line while running
+                if matches.is_present("granularity") {
+                    config.granularity = matches.value_of_t("granularity")?;
+                    config.show_line_numbers = config.granularity == Granularity::line;
+                }
             }
             "dump" => {
                 config.dump_json = matches.occurrences_of("json") > 0;
+                config.dump_snapshot = matches
+                    .value_of("format")
+                    .is_some_and(|f| f.eq_ignore_ascii_case("json"));
                 config.dump_locals = matches.occurrences_of("locals");
+                config.dump_repeat = matches.get_one::<f64>("repeat").copied();
+                config.dump_merge_identical = matches.occurrences_of("merge_identical") > 0;
+                config.dump_deadlock = matches.occurrences_of("deadlock") > 0;
 
                 #[cfg(target_os = "linux")]
                 {
                     config.core_filename = matches.value_of("core").map(|f| f.to_owned());
                 }
             }
+            "dump-symbols" => {
+                // none of the options shared between the other subcommands apply here
+                config.command = subcommand.to_owned();
+                config.symbols_filename = matches.value_of("binary").map(|f| f.to_owned());
+                return Ok(config);
+            }
+            "check" => {
+                // this doesn't sample, so none of the sampling options apply either
+                config.command = subcommand.to_owned();
+                config.pid = matches
+                    .value_of("pid")
+                    .map(|p| p.parse().expect("invalid pid"));
+                return Ok(config);
+            }
             "completions" => {
                 let shell = matches.get_one::<clap_complete::Shell>("shell").unwrap();
                 let app_name = app.get_name().to_string();
@@ -416,6 +1174,7 @@ impl Config {
                     .map(|vals| vals.map(|v| v.to_owned()).collect());
                 config.gil_only = matches.occurrences_of("gil") > 0;
                 config.include_idle = matches.occurrences_of("idle") > 0;
+                config.max_subprocesses = matches.get_one::<usize>("max_subprocesses").copied();
             }
             _ => {}
         }
@@ -428,8 +1187,18 @@ impl Config {
             .value_of("pid")
             .map(|p| p.parse().expect("invalid pid"));
         config.full_filenames = matches.occurrences_of("full_filenames") > 0;
+        config.read_batch_size = *matches.get_one::<usize>("read_batch_size").unwrap();
+        #[cfg(target_os = "linux")]
+        {
+            config.memory_fd = matches.get_one::<i32>("mem_fd").copied();
+        }
         if cfg!(unwind) {
             config.native = matches.occurrences_of("native") > 0;
+            config.demangle = matches.value_of_t("demangle")?;
+            config.native_exclude = matches
+                .values_of("native_exclude")
+                .map(|vals| vals.map(|v| v.to_owned()).collect())
+                .unwrap_or_default();
         }
 
         config.capture_output = config.command != "record" || matches.occurrences_of("capture") > 0;
@@ -475,6 +1244,33 @@ impl Config {
         }
         Ok(config)
     }
+
+    /// Whether the output format for 'record' will show the line number of each frame
+    fn output_shows_line_numbers(&self) -> bool {
+        let template_has_line = |template: &String| template.contains("{line}");
+        match self.format {
+            Some(FileFormat::dot) => self.name_format.as_ref().is_some_and(template_has_line),
+            Some(FileFormat::flamegraph) | Some(FileFormat::raw) => self
+                .name_format
+                .as_ref()
+                .map_or(self.show_line_numbers, template_has_line),
+            _ => self.show_line_numbers,
+        }
+    }
+
+    /// Whether the module names of python frames are used, either in the frame names or to
+    /// group and collapse frames. Reading these takes extra memory reads, so they are only
+    /// read when needed
+    pub fn needs_module_names(&self) -> bool {
+        self.granularity == Granularity::file
+            || self
+                .name_format
+                .as_ref()
+                .is_some_and(|template| template.contains("{module}"))
+            || !self.collapse_paths.is_empty()
+            || !self.keep_paths.is_empty()
+            || self.dump_deadlock
+    }
 }
 
 #[cfg(test)]
@@ -533,6 +1329,360 @@ mod tests {
         assert_eq!(config_flags.include_thread_ids, true);
     }
 
+    #[test]
+    fn test_parse_name_format() {
+        let config = get_config("py-spy r -p 1234 -o foo").unwrap();
+        assert_eq!(config.name_format, None);
+        let config = get_config("py-spy r -p 1234 -o foo --name-format {file}:{func}").unwrap();
+        assert_eq!(config.name_format, Some(String::from("{file}:{func}")));
+    }
+
+    #[test]
+    fn test_parse_track_order() {
+        let config =
+            get_config("py-spy r -p 1234 -o foo -f chrometrace --track-order activity").unwrap();
+        assert_eq!(config.format, Some(FileFormat::chrometrace));
+        assert_eq!(config.track_order, TrackOrder::activity);
+        assert_eq!(
+            get_config("py-spy r -p 1234 -o foo -f chrometrace --track-order first-seen")
+                .unwrap()
+                .track_order,
+            TrackOrder::first_seen
+        );
+    }
+
+    #[test]
+    fn test_parse_gc_stats() {
+        assert!(!get_config("py-spy r -p 1234 -o foo").unwrap().gc_stats);
+        assert!(
+            get_config("py-spy r -p 1234 -o foo --gc-stats")
+                .unwrap()
+                .gc_stats
+        );
+    }
+
+    #[test]
+    fn test_parse_anonymize_args() {
+        let config =
+            get_config("py-spy r -p 1234 -o foo --anonymize --anonymize-keyfile foo.key").unwrap();
+        assert!(config.anonymize);
+        assert_eq!(config.anonymize_keyfile, Some(String::from("foo.key")));
+        assert_eq!(
+            get_config("py-spy r -p 1234 -o foo --anonymize-keep-stdlib")
+                .unwrap_err()
+                .kind,
+            clap::ErrorKind::MissingRequiredArgument
+        );
+    }
+
+    #[test]
+    fn test_parse_split_by_state() {
+        assert!(
+            !get_config("py-spy r -p 1234 -o foo")
+                .unwrap()
+                .split_by_state
+        );
+        assert!(
+            get_config("py-spy r -p 1234 -o foo --split-by-state")
+                .unwrap()
+                .split_by_state
+        );
+    }
+
+    #[test]
+    fn test_parse_async_events() {
+        assert!(!get_config("py-spy r -p 1234 -o foo").unwrap().async_events);
+        assert!(
+            get_config("py-spy r -p 1234 -o foo --async-events")
+                .unwrap()
+                .async_events
+        );
+    }
+
+    #[test]
+    fn test_parse_fd_stats() {
+        assert!(!get_config("py-spy r -p 1234 -o foo").unwrap().fd_stats);
+        assert!(
+            get_config("py-spy r -p 1234 -o foo --fd-stats")
+                .unwrap()
+                .fd_stats
+        );
+    }
+
+    #[test]
+    fn test_parse_overhead_report() {
+        assert!(
+            !get_config("py-spy r -p 1234 -o foo")
+                .unwrap()
+                .overhead_report
+        );
+        assert!(
+            get_config("py-spy r -p 1234 -o foo --overhead-report")
+                .unwrap()
+                .overhead_report
+        );
+    }
+
+    #[test]
+    fn test_parse_track_labels() {
+        let config = get_config(
+            "py-spy r -p 1234 -o foo --process-label {name}:{pid} --thread-label {tname}[{tid}]",
+        )
+        .unwrap();
+        assert_eq!(config.process_label, Some(String::from("{name}:{pid}")));
+        assert_eq!(config.thread_label, Some(String::from("{tname}[{tid}]")));
+    }
+
+    #[test]
+    fn test_parse_lineno() {
+        // line numbers shouldn't be decoded when the output doesn't show them
+        let config = get_config("py-spy r -p 1234 -o foo").unwrap();
+        assert_eq!(config.lineno, LineNo::LastInstruction);
+        let config = get_config("py-spy r -p 1234 -o foo --nolineno").unwrap();
+        assert_eq!(config.lineno, LineNo::NoLine);
+        let config = get_config("py-spy r -p 1234 -o foo --name-format {file}:{line}").unwrap();
+        assert_eq!(config.lineno, LineNo::LastInstruction);
+        let config = get_config("py-spy r -p 1234 -o foo -f dot").unwrap();
+        assert_eq!(config.lineno, LineNo::NoLine);
+        let config = get_config("py-spy r -p 1234 -o foo -f speedscope --function").unwrap();
+        assert_eq!(config.lineno, LineNo::First);
+    }
+
+    #[test]
+    fn test_parse_granularity() {
+        let config = get_config("py-spy r -p 1234 -o foo --granularity file").unwrap();
+        assert_eq!(config.granularity, Granularity::file);
+        assert!(!config.show_line_numbers);
+        let config = get_config("py-spy r -p 1234 -o foo --nolineno").unwrap();
+        assert_eq!(config.granularity, Granularity::function);
+        assert_eq!(
+            get_config("py-spy r -p 1234 -o foo --granularity line --function")
+                .unwrap_err()
+                .kind,
+            clap::ErrorKind::ArgumentConflict
+        );
+
+        // dot output doesn't show line numbers unless the name format has them
+        assert_eq!(
+            get_config("py-spy r -p 1234 -o foo -f dot --granularity line")
+                .unwrap_err()
+                .kind,
+            clap::ErrorKind::ArgumentConflict
+        );
+        let config = get_config(
+            "py-spy r -p 1234 -o foo -f dot --granularity line --name-format {func}:{line}",
+        )
+        .unwrap();
+        assert_eq!(config.lineno, LineNo::LastInstruction);
+        let config = get_config("py-spy r -p 1234 -o foo -f dot --granularity file").unwrap();
+        assert_eq!(config.granularity, Granularity::file);
+    }
+
+    #[test]
+    fn test_parse_sample_counts() {
+        assert!(!get_config("py-spy r -p 1234 -o foo").unwrap().sample_counts);
+        assert!(
+            get_config("py-spy r -p 1234 -o foo --sample-counts")
+                .unwrap()
+                .sample_counts
+        );
+    }
+
+    #[test]
+    fn test_parse_counters() {
+        assert!(!get_config("py-spy r -p 1234 -o foo").unwrap().show_counters);
+        assert!(
+            get_config("py-spy r -p 1234 -o foo --counters")
+                .unwrap()
+                .show_counters
+        );
+    }
+
+    #[test]
+    fn test_parse_stream_output() {
+        assert!(!get_config("py-spy r -p 1234 -o foo").unwrap().stream_output);
+        assert!(
+            get_config("py-spy r -p 1234 -o foo --stream-output")
+                .unwrap()
+                .stream_output
+        );
+    }
+
+    #[test]
+    fn test_parse_async_flows() {
+        assert!(!get_config("py-spy r -p 1234 -o foo").unwrap().async_flows);
+        assert!(
+            get_config("py-spy r -p 1234 -o foo --async-flows")
+                .unwrap()
+                .async_flows
+        );
+    }
+
+    #[test]
+    fn test_parse_collapse_recursion() {
+        assert!(
+            !get_config("py-spy r -p 1234 -o foo")
+                .unwrap()
+                .collapse_recursion
+        );
+        assert!(
+            get_config("py-spy r -p 1234 -o foo --collapse-recursion")
+                .unwrap()
+                .collapse_recursion
+        );
+    }
+
+    #[test]
+    fn test_parse_max_gap() {
+        assert_eq!(get_config("py-spy r -p 1234 -o foo").unwrap().max_gap, None);
+        let config = get_config("py-spy r -p 1234 -o foo --max-gap 500").unwrap();
+        assert_eq!(config.max_gap, Some(Duration::from_millis(500)));
+    }
+
+    #[test]
+    fn test_parse_trace_compression() {
+        let config = get_config("py-spy r -p 1234 -o foo -f chrometrace").unwrap();
+        assert_eq!(config.trace_compression, TraceCompression::gzip);
+        let config = get_config("py-spy r -p 1234 -o foo --trace-compression zstd").unwrap();
+        assert_eq!(config.trace_compression, TraceCompression::zstd);
+    }
+
+    #[test]
+    fn test_parse_min_slice_duration() {
+        assert_eq!(
+            get_config("py-spy r -p 1234 -o foo")
+                .unwrap()
+                .min_duration_us,
+            0
+        );
+        let config = get_config("py-spy r -p 1234 -o foo --min-slice-duration 1").unwrap();
+        assert_eq!(config.min_duration_us, 1);
+    }
+
+    #[test]
+    fn test_parse_collapse_paths() {
+        let config = get_config(
+            "py-spy r -p 1234 -o foo --collapse-path site-packages/ --collapse-path lib/python3 --keep-path /srv/app/",
+        )
+        .unwrap();
+        assert_eq!(config.collapse_paths, vec!["site-packages/", "lib/python3"]);
+        assert_eq!(config.keep_paths, vec!["/srv/app/"]);
+    }
+
+    #[test]
+    fn test_parse_gil_frames() {
+        assert!(!get_config("py-spy r -p 1234 -o foo").unwrap().gil_frames);
+        assert!(
+            get_config("py-spy r -p 1234 -o foo --gil-frames")
+                .unwrap()
+                .gil_frames
+        );
+    }
+
+    #[test]
+    fn test_parse_capture_locals() {
+        let config = get_config("py-spy r -p 1234 -o foo").unwrap();
+        assert!(config.capture_locals.is_empty());
+        let config =
+            get_config("py-spy r -p 1234 -o foo --capture-locals handle --capture-locals fetch")
+                .unwrap();
+        assert_eq!(config.capture_locals, vec!["handle", "fetch"]);
+    }
+
+    #[test]
+    fn test_parse_trace_epoch() {
+        assert_eq!(
+            get_config("py-spy r -p 1234 -o foo").unwrap().trace_epoch,
+            None
+        );
+        let config = get_config("py-spy r -p 1234 -o foo --trace-epoch 1700000000.5").unwrap();
+        assert_eq!(
+            config.trace_epoch,
+            Some(SystemTime::UNIX_EPOCH + Duration::from_millis(1_700_000_000_500))
+        );
+        assert!(get_config("py-spy r -p 1234 -o foo --trace-epoch yesterday").is_err());
+    }
+
+    #[test]
+    fn test_parse_max_events() {
+        assert_eq!(
+            get_config("py-spy r -p 1234 -o foo").unwrap().max_events,
+            None
+        );
+        let config = get_config("py-spy r -p 1234 -o foo --max-events 1000").unwrap();
+        assert_eq!(config.max_events, Some(1000));
+    }
+
+    #[test]
+    fn test_parse_trace_array() {
+        assert!(!get_config("py-spy r -p 1234 -o foo").unwrap().trace_array);
+        assert!(
+            get_config("py-spy r -p 1234 -o foo --trace-array")
+                .unwrap()
+                .trace_array
+        );
+    }
+
+    #[test]
+    fn test_parse_thread_filters() {
+        let config = get_config("py-spy r -p 1234 -o foo").unwrap();
+        assert!(config.only_threads.is_empty());
+        assert!(config.exclude_threads.is_empty());
+
+        let config =
+            get_config("py-spy r -p 1234 -o foo --only-thread 1234 --only-thread worker --exclude-thread 0x7f00")
+                .unwrap();
+        assert_eq!(config.only_threads, vec!["1234", "worker"]);
+        assert_eq!(config.exclude_threads, vec!["0x7f00"]);
+    }
+
+    #[test]
+    fn test_parse_zstd_level() {
+        assert_eq!(get_config("py-spy r -p 1234 -o foo").unwrap().zstd_level, 0);
+        assert_eq!(
+            get_config("py-spy r -p 1234 -o foo --zstd-level -5")
+                .unwrap()
+                .zstd_level,
+            -5
+        );
+    }
+
+    #[test]
+    fn test_parse_trace_tmpdir() {
+        assert_eq!(
+            get_config("py-spy r -p 1234 -o foo").unwrap().trace_tmpdir,
+            None
+        );
+        let config = get_config("py-spy r -p 1234 -o foo --trace-tmpdir /scratch").unwrap();
+        assert_eq!(config.trace_tmpdir, Some("/scratch".to_owned()));
+    }
+
+    #[test]
+    fn test_parse_omit_frames() {
+        let config = get_config("py-spy r -p 1234 -o foo").unwrap();
+        assert!(config.omit_frames.is_empty());
+        let config = get_config("py-spy r -p 1234 -o foo --omit-synthetic-frames").unwrap();
+        assert_eq!(config.omit_frames, SYNTHETIC_FRAMES);
+        let config =
+            get_config("py-spy r -p 1234 -o foo --omit-synthetic-frames --omit-frame <module> --omit-frame wrapper")
+                .unwrap();
+        assert_eq!(config.omit_frames, vec!["<module>", "wrapper"]);
+    }
+
+    #[test]
+    fn test_parse_sampling_stats() {
+        assert!(
+            !get_config("py-spy r -p 1234 -o foo")
+                .unwrap()
+                .sampling_stats
+        );
+        assert!(
+            get_config("py-spy r -p 1234 -o foo --sampling-stats")
+                .unwrap()
+                .sampling_stats
+        );
+    }
+
     #[test]
     fn test_parse_dump_args() {
         // basic use case
@@ -549,6 +1699,61 @@ mod tests {
             get_config("py-spy dump").unwrap_err().kind,
             clap::ErrorKind::MissingRequiredArgument
         );
+
+        assert!(!config.dump_snapshot);
+        let config = get_config("py-spy dump --pid 1234 --format json").unwrap();
+        assert!(config.dump_snapshot);
+        assert!(!config.dump_json);
+
+        let config = get_config("py-spy dump --pid 1234 --repeat 0.5 --merge-identical").unwrap();
+        assert_eq!(config.dump_repeat, Some(0.5));
+        for interval in ["-1", "0", "nan", "inf", "1e30", "soon"] {
+            assert_eq!(
+                get_config(&format!("py-spy dump --pid 1234 --repeat={}", interval))
+                    .unwrap_err()
+                    .kind,
+                clap::ErrorKind::ValueValidation
+            );
+        }
+        assert!(config.dump_merge_identical);
+        assert!(!config.dump_deadlock);
+        assert!(
+            get_config("py-spy dump --pid 1234 --deadlock")
+                .unwrap()
+                .dump_deadlock
+        );
+        assert_eq!(
+            get_config("py-spy dump --pid 1234 --merge-identical")
+                .unwrap_err()
+                .kind,
+            clap::ErrorKind::MissingRequiredArgument
+        );
+    }
+
+    #[test]
+    fn test_parse_check_args() {
+        let config = get_config("py-spy check --pid 1234").unwrap();
+        assert_eq!(config.command, "check");
+        assert_eq!(config.pid, Some(1234));
+
+        assert_eq!(
+            get_config("py-spy check").unwrap_err().kind,
+            clap::ErrorKind::MissingRequiredArgument
+        );
+    }
+
+    #[test]
+    fn test_parse_dump_symbols_args() {
+        let config = get_config("py-spy dump-symbols /usr/lib/libpython3.11.so").unwrap();
+        assert_eq!(config.command, "dump-symbols");
+        assert_eq!(
+            config.symbols_filename.as_deref(),
+            Some("/usr/lib/libpython3.11.so")
+        );
+        assert_eq!(
+            get_config("py-spy dump-symbols").unwrap_err().kind,
+            clap::ErrorKind::MissingRequiredArgument
+        );
     }
 
     #[test]
@@ -561,6 +1766,111 @@ mod tests {
         // short version
         let short_config = get_config("py-spy t -p 1234").unwrap();
         assert_eq!(config, short_config);
+        assert!(!config.show_line_numbers);
+
+        let config = get_config("py-spy top --pid 1234 --granularity line").unwrap();
+        assert_eq!(config.granularity, Granularity::line);
+        assert!(config.show_line_numbers);
+    }
+
+    #[test]
+    fn test_parse_max_subprocesses() {
+        let config = get_config("py-spy record --pid 1234 --subprocesses").unwrap();
+        assert_eq!(config.max_subprocesses, None);
+        let config =
+            get_config("py-spy top --pid 1234 --subprocesses --max-subprocesses 8").unwrap();
+        assert_eq!(config.max_subprocesses, Some(8));
+        assert_eq!(
+            get_config("py-spy record --pid 1234 --max-subprocesses 8")
+                .unwrap_err()
+                .kind,
+            clap::ErrorKind::MissingRequiredArgument
+        );
+    }
+
+    #[test]
+    fn test_parse_read_batch_size() {
+        let config = get_config("py-spy dump --pid 1234").unwrap();
+        assert_eq!(config.read_batch_size, 4096);
+
+        let config = get_config("py-spy record --pid 1234 --read-batch-size 0").unwrap();
+        assert_eq!(config.read_batch_size, 0);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_parse_mem_fd_args() {
+        let config = get_config("py-spy dump --pid 1234 --nonblocking --mem-fd 5").unwrap();
+        assert_eq!(config.memory_fd, Some(5));
+        assert_eq!(
+            get_config("py-spy dump --pid 1234 --nonblocking --mem-fd=-1")
+                .unwrap_err()
+                .kind,
+            clap::ErrorKind::ValueValidation
+        );
+
+        // pausing the process needs ptrace, which --mem-fd is meant to avoid
+        assert_eq!(
+            get_config("py-spy record --pid 1234 --mem-fd 5")
+                .unwrap_err()
+                .kind,
+            clap::ErrorKind::MissingRequiredArgument
+        );
+    }
+
+    #[cfg(unwind)]
+    #[test]
+    fn test_parse_native_args() {
+        let config = get_config("py-spy record --pid 1234 --native").unwrap();
+        assert_eq!(config.demangle, Demangle::Auto);
+
+        let config = get_config("py-spy record --pid 1234 --native --demangle c++").unwrap();
+        assert_eq!(config.demangle, Demangle::Cpp);
+
+        let config = get_config("py-spy dump --pid 1234 --native --demangle=off").unwrap();
+        assert_eq!(config.demangle, Demangle::Off);
+
+        let config = get_config("py-spy record --pid 1234 --native --python-only").unwrap();
+        assert!(config.python_only);
+        assert!(config.native_exclude.is_empty());
+
+        let config = get_config(
+            "py-spy record --pid 1234 --native --native-exclude libfoo.* --native-exclude libbar.so",
+        )
+        .unwrap();
+        assert_eq!(config.native_exclude, vec!["libfoo.*", "libbar.so"]);
+
+        assert_eq!(
+            get_config("py-spy dump --pid 1234 --native-exclude libfoo.*")
+                .unwrap_err()
+                .kind,
+            clap::ErrorKind::MissingRequiredArgument
+        );
+
+        assert_eq!(
+            get_config("py-spy top --pid 1234 --demangle swift")
+                .unwrap_err()
+                .kind,
+            clap::ErrorKind::InvalidValue
+        );
+    }
+
+    #[test]
+    fn test_needs_module_names() {
+        let config = get_config("py-spy record --pid 1234 -o foo").unwrap();
+        assert!(!config.needs_module_names());
+        let config =
+            get_config("py-spy record --pid 1234 -o foo --name-format {file}:{func}").unwrap();
+        assert!(!config.needs_module_names());
+        let config =
+            get_config("py-spy record --pid 1234 -o foo --name-format {module}.{func}").unwrap();
+        assert!(config.needs_module_names());
+        let config = get_config("py-spy record --pid 1234 -o foo --granularity file").unwrap();
+        assert!(config.needs_module_names());
+        let config = get_config("py-spy record --pid 1234 -o foo --collapse-path django/").unwrap();
+        assert!(config.needs_module_names());
+        let config = get_config("py-spy dump --pid 1234 --deadlock").unwrap();
+        assert!(config.needs_module_names());
     }
 
     #[test]
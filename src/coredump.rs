@@ -5,6 +5,7 @@ use std::io::Read;
 use std::os::unix::ffi::OsStrExt;
 use std::path::Path;
 use std::path::PathBuf;
+use std::sync::Arc;
 
 use anyhow::{Context, Error, Result};
 use console::style;
@@ -13,7 +14,7 @@ use remoteprocess::ProcessMemory;
 
 use crate::binary_parser::{parse_binary, BinaryInfo};
 use crate::config::Config;
-use crate::dump::print_trace;
+use crate::dump::{print_snapshot, print_trace};
 use crate::python_bindings::{
     v2_7_15, v3_10_0, v3_11_0, v3_3_7, v3_5_5, v3_6_6, v3_7_0, v3_8_0, v3_9_5,
 };
@@ -23,7 +24,7 @@ use crate::python_process_info::{
     get_interpreter_address, get_python_version, get_threadstate_address, is_python_lib,
     ContainsAddr, PythonProcessInfo,
 };
-use crate::python_threading::thread_names_from_interpreter;
+use crate::python_threading::threads_from_interpreter;
 use crate::stack_trace::{get_stack_traces, StackTrace};
 use crate::version::Version;
 
@@ -191,7 +192,9 @@ impl PythonCoreDump {
                 .find(|m| m.filename().is_some() & m.is_exec())
                 .ok_or_else(|| format_err!("Failed to get binary from coredump"))?;
             let python_filename = map.filename().unwrap();
-            let python_binary = parse_binary(python_filename, map.start() as _, map.size() as _);
+            let python_binary =
+                parse_binary(python_filename, map.start() as _, map.size() as _, None)
+                    .map(Arc::new);
             info!("Found python binary @ {}", python_filename.display());
             (python_filename.to_owned(), python_binary)
         };
@@ -207,13 +210,17 @@ impl PythonCoreDump {
                 false
             });
 
-            let mut libpython_binary: Option<BinaryInfo> = None;
+            let mut libpython_binary: Option<Arc<BinaryInfo>> = None;
             if let Some(libpython) = libmap {
                 if let Some(filename) = &libpython.filename() {
                     info!("Found libpython binary @ {}", filename.display());
-                    let parsed =
-                        parse_binary(filename, libpython.start() as u64, libpython.size() as u64)?;
-                    libpython_binary = Some(parsed);
+                    let parsed = parse_binary(
+                        filename,
+                        libpython.start() as u64,
+                        libpython.size() as u64,
+                        None,
+                    )?;
+                    libpython_binary = Some(Arc::new(parsed));
                 }
             }
             libpython_binary
@@ -315,11 +322,12 @@ impl PythonCoreDump {
 
         let mut traces =
             get_stack_traces(&interp, &self.core, self.threadstate_address, Some(config))?;
-        let thread_names = thread_names_from_interpreter(&interp, &self.core, &self.version).ok();
+        let threads = threads_from_interpreter(&interp, &self.core, &self.version).ok();
 
         for trace in &mut traces {
-            if let Some(ref thread_names) = thread_names {
-                trace.thread_name = thread_names.get(&trace.thread_id).cloned();
+            if let Some(thread) = threads.as_ref().and_then(|t| t.get(&trace.thread_id)) {
+                trace.thread_name = thread.name.clone();
+                trace.daemon = thread.daemon;
             }
 
             for frame in &mut trace.frames {
@@ -346,6 +354,15 @@ impl PythonCoreDump {
             return Ok(());
         }
 
+        if config.dump_snapshot {
+            // use the time the coredump was written as the capture time
+            let modified = std::fs::metadata(&self.core.filename)?.modified()?;
+            let timestamp = chrono::DateTime::<chrono::Utc>::from(modified)
+                .to_rfc3339_opts(chrono::SecondsFormat::Millis, true);
+            let pid = self.core.psinfo.map_or(0, |psinfo| psinfo.pr_pid);
+            return print_snapshot(pid, &self.version, timestamp, traces);
+        }
+
         if let Some(status) = self.core.status.first() {
             println!(
                 "Signal {}: {}",
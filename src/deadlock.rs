@@ -0,0 +1,365 @@
+use std::collections::{HashMap, HashSet};
+use std::os::raw::{c_char, c_ulong};
+
+use anyhow::Error;
+use remoteprocess::ProcessMemory;
+
+use crate::python_bindings::{v3_10_0, v3_11_0, v3_6_6, v3_7_0, v3_8_0, v3_9_5};
+use crate::python_data_access::{copy_string, copy_type_name, instance_dict, DictIterator};
+use crate::python_interpreters::{InterpreterState, Object, TypeObject};
+use crate::python_spy::PythonSpy;
+use crate::stack_trace::{Frame, StackTrace};
+use crate::version::Version;
+
+/*
+ * Finds deadlocks between threads, by building a graph of which thread is waiting on which
+ * other thread and looking for cycles in it.
+ *
+ * '_thread.RLock' objects (what threading.RLock returns) store the id of the thread that owns
+ * them, which gives one side of each edge. Which lock a thread is blocked on isn't stored
+ * anywhere we can read, so instead we look for held locks that are referenced from the
+ * innermost frame of each thread that is blocked acquiring a lock: its local variables,
+ * attributes of its local variables (like 'self.lock') and the globals of its module. Plain
+ * '_thread.lock' objects don't record their owner, so threads blocked on these can be reported
+ * but not put into the graph.
+ */
+
+/// The start of rlockobject from Modules/_threadmodule.c, which is the same for python 3.6-3.11
+#[repr(C)]
+#[derive(Copy, Clone)]
+#[allow(dead_code)]
+struct RLockObject {
+    ob_refcnt: isize,
+    ob_type: usize,
+    rlock_lock: usize,
+    rlock_owner: c_ulong,
+    rlock_count: c_ulong,
+}
+
+/// The start of lockobject from Modules/_threadmodule.c, which is the same for python 3.6-3.11
+#[repr(C)]
+#[derive(Copy, Clone)]
+#[allow(dead_code)]
+struct LockObject {
+    ob_refcnt: isize,
+    ob_type: usize,
+    lock_lock: usize,
+    in_weakreflist: usize,
+    locked: c_char,
+}
+
+/// A held lock that a thread might be blocked on
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Lock {
+    pub addr: usize,
+    /// How the lock was referenced from the waiting thread, like 'self.lock'
+    pub name: String,
+    /// The python thread id of the owner, which is only known for RLocks
+    pub owner: Option<u64>,
+}
+
+impl Lock {
+    pub fn kind(&self) -> &'static str {
+        if self.owner.is_some() {
+            "RLock"
+        } else {
+            "Lock"
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct DeadlockReport {
+    /// Each cycle is a list of threads along with the lock they are waiting for, where
+    /// the lock is held by the next thread in the list (wrapping around at the end)
+    pub cycles: Vec<Vec<(u64, Lock)>>,
+    /// Idle threads that reference a held lock that doesn't record its owner
+    pub unknown_owner: Vec<(u64, Lock)>,
+}
+
+/// Looks for deadlocks between the threads of a process. The traces need to have been
+/// collected with local variables. Module globals are only looked up in the main interpreter
+pub fn find_deadlocks(spy: &PythonSpy, traces: &[StackTrace]) -> Result<DeadlockReport, Error> {
+    match spy.version {
+        Version {
+            major: 3, minor: 6, ..
+        } => _find_deadlocks::<v3_6_6::_is>(spy, traces),
+        Version {
+            major: 3, minor: 7, ..
+        } => _find_deadlocks::<v3_7_0::_is>(spy, traces),
+        Version {
+            major: 3, minor: 8, ..
+        } => _find_deadlocks::<v3_8_0::_is>(spy, traces),
+        Version {
+            major: 3, minor: 9, ..
+        } => _find_deadlocks::<v3_9_5::_is>(spy, traces),
+        Version {
+            major: 3,
+            minor: 10,
+            ..
+        } => _find_deadlocks::<v3_10_0::_is>(spy, traces),
+        Version {
+            major: 3,
+            minor: 11,
+            ..
+        } => _find_deadlocks::<v3_11_0::_is>(spy, traces),
+        _ => Err(format_err!(
+            "Deadlock detection isn't supported for python {}",
+            spy.version
+        )),
+    }
+}
+
+fn _find_deadlocks<I: InterpreterState>(
+    spy: &PythonSpy,
+    traces: &[StackTrace],
+) -> Result<DeadlockReport, Error> {
+    let interp: I = spy.memory.copy_struct(spy.interpreter_address)?;
+    let mut finder = LockFinder::<I, _> {
+        process: &spy.memory,
+        version: &spy.version,
+        modules: interp.modules() as usize,
+        module_dicts: HashMap::new(),
+        _interpreter: std::marker::PhantomData,
+    };
+
+    let mut report = DeadlockReport::default();
+    let mut waits = HashMap::new();
+    for trace in traces.iter().filter(|trace| waiting_on_lock(trace)) {
+        let frame = match trace.frames.iter().find(|frame| !frame.is_native) {
+            Some(frame) => frame,
+            None => continue,
+        };
+        for lock in finder.held_locks(frame) {
+            match lock.owner {
+                Some(owner) if owner != trace.thread_id => {
+                    waits
+                        .entry(trace.thread_id)
+                        .or_insert_with(Vec::new)
+                        .push(lock);
+                }
+                Some(_) => {}
+                None => report.unknown_owner.push((trace.thread_id, lock)),
+            }
+        }
+    }
+    report.cycles = find_cycles(&waits);
+    Ok(report)
+}
+
+/// Returns whether a thread looks to be blocked acquiring a lock, rather than just being idle
+/// while it holds locks that other threads are blocked on
+fn waiting_on_lock(trace: &StackTrace) -> bool {
+    if trace.active {
+        return false;
+    }
+    // python wrappers around locks (like threading.Condition), or native frames for the
+    // acquire functions in _threadmodule.c when these were collected
+    for frame in trace.frames.iter() {
+        if frame.is_native {
+            if frame.name.contains("acquire") {
+                return true;
+            }
+            continue;
+        }
+        if frame.name == "acquire" || frame.name == "__enter__" {
+            return true;
+        }
+        break;
+    }
+    // without a way of telling, any idle thread could be waiting on a lock
+    native_lock_wait(trace).unwrap_or(true)
+}
+
+/// Returns whether the OS thread is waiting on a futex, which is what python locks block on
+#[cfg(target_os = "linux")]
+fn native_lock_wait(trace: &StackTrace) -> Option<bool> {
+    let tid = trace.os_thread_id?;
+    let syscall = std::fs::read_to_string(format!("/proc/{}/task/{}/syscall", trace.pid, tid));
+    let number: libc::c_long = syscall.ok()?.split_whitespace().next()?.parse().ok()?;
+    Some(number == libc::SYS_futex)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn native_lock_wait(_trace: &StackTrace) -> Option<bool> {
+    None
+}
+
+struct LockFinder<'a, I, P> {
+    process: &'a P,
+    version: &'a Version,
+    modules: usize,
+    // the '__dict__' of each module we've looked up, by name
+    module_dicts: HashMap<String, Option<usize>>,
+    _interpreter: std::marker::PhantomData<I>,
+}
+
+impl<'a, I: InterpreterState, P: ProcessMemory> LockFinder<'a, I, P> {
+    /// Returns the held locks that are referenced from a frame. Errors reading any of the
+    /// variables are ignored, since this is only a best effort
+    fn held_locks(&mut self, frame: &Frame) -> Vec<Lock> {
+        let mut locks = Vec::new();
+        let mut seen = HashSet::new();
+        let mut add = |lock: Option<Lock>| {
+            if let Some(lock) = lock {
+                if seen.insert(lock.addr) {
+                    locks.push(lock);
+                }
+            }
+        };
+
+        for local in frame.locals.iter().flatten() {
+            add(self.held_lock(local.addr, &local.name));
+            if let Ok(Some(attributes)) =
+                instance_dict::<I, P>(self.process, self.version, local.addr)
+            {
+                for (name, value) in self.dict_entries(attributes) {
+                    add(self.held_lock(value, &format!("{}.{}", local.name, name)));
+                }
+            }
+        }
+
+        if let Some(globals) = frame.module.as_ref().and_then(|m| self.module_dict(m)) {
+            if let Ok(globals) = DictIterator::from(self.process, self.version, globals) {
+                for (name, value) in self.dict_entries(globals) {
+                    add(self.held_lock(value, &name));
+                }
+            }
+        }
+        locks
+    }
+
+    /// Returns the lock at an address if it is currently held, or None if it isn't held or
+    /// isn't a lock at all
+    fn held_lock(&self, addr: usize, name: &str) -> Option<Lock> {
+        let owner = match copy_type_name::<I, P>(self.process, addr).ok()?.as_str() {
+            "_thread.RLock" => {
+                let rlock: RLockObject = self.process.copy_struct(addr).ok()?;
+                if rlock.rlock_count == 0 {
+                    return None;
+                }
+                // c_ulong is only 32 bits on windows
+                #[allow(clippy::unnecessary_cast)]
+                Some(rlock.rlock_owner as u64)
+            }
+            "_thread.lock" => {
+                let lock: LockObject = self.process.copy_struct(addr).ok()?;
+                if lock.locked == 0 {
+                    return None;
+                }
+                None
+            }
+            _ => return None,
+        };
+        Some(Lock {
+            addr,
+            name: name.to_owned(),
+            owner,
+        })
+    }
+
+    fn dict_entries(&self, dict: DictIterator<'a, P>) -> Vec<(String, usize)> {
+        dict.filter_map(|entry| {
+            let (key, value) = entry.ok()?;
+            let key = copy_string(key as *const I::StringObject, self.process).ok()?;
+            Some((key, value))
+        })
+        .collect()
+    }
+
+    fn module_dict(&mut self, module: &str) -> Option<usize> {
+        if let Some(dict) = self.module_dicts.get(module) {
+            return *dict;
+        }
+        let dict = self.lookup_module_dict(module).ok().flatten();
+        self.module_dicts.insert(module.to_owned(), dict);
+        dict
+    }
+
+    fn lookup_module_dict(&self, module: &str) -> Result<Option<usize>, Error> {
+        for entry in DictIterator::from(self.process, self.version, self.modules)? {
+            let (key, value) = entry?;
+            if copy_string(key as *const I::StringObject, self.process)? == module {
+                let object: I::Object = self.process.copy_struct(value)?;
+                let object_type = self.process.copy_pointer(object.ob_type())?;
+                let dict: usize = self
+                    .process
+                    .copy_struct(value + object_type.dictoffset() as usize)?;
+                return Ok(Some(dict));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Finds cycles in a graph of threads waiting on locks held by other threads. Each cycle
+/// is only returned once, starting from the thread with the lowest id
+fn find_cycles(waits: &HashMap<u64, Vec<Lock>>) -> Vec<Vec<(u64, Lock)>> {
+    let mut threads: Vec<u64> = waits.keys().copied().collect();
+    threads.sort_unstable();
+
+    let mut cycles = Vec::new();
+    for &start in &threads {
+        // only look for cycles where start is the lowest thread id, so that each is found once
+        let mut path: Vec<(u64, Lock)> = Vec::new();
+        let mut visited = HashSet::new();
+        find_cycles_from(start, start, waits, &mut path, &mut visited, &mut cycles);
+    }
+    cycles
+}
+
+fn find_cycles_from(
+    start: u64,
+    thread: u64,
+    waits: &HashMap<u64, Vec<Lock>>,
+    path: &mut Vec<(u64, Lock)>,
+    visited: &mut HashSet<u64>,
+    cycles: &mut Vec<Vec<(u64, Lock)>>,
+) {
+    visited.insert(thread);
+    for lock in waits.get(&thread).into_iter().flatten() {
+        let owner = match lock.owner {
+            Some(owner) => owner,
+            None => continue,
+        };
+        path.push((thread, lock.clone()));
+        if owner == start {
+            cycles.push(path.clone());
+        } else if owner > start && !visited.contains(&owner) {
+            find_cycles_from(start, owner, waits, path, visited, cycles);
+        }
+        path.pop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lock(addr: usize, owner: u64) -> Lock {
+        Lock {
+            addr,
+            name: format!("lock_{}", addr),
+            owner: Some(owner),
+        }
+    }
+
+    #[test]
+    fn test_find_cycles() {
+        // 1 -> 2 -> 3 -> 1 is a cycle, 4 waits on 1 but isn't part of it
+        let mut waits = HashMap::new();
+        waits.insert(1, vec![lock(10, 2)]);
+        waits.insert(2, vec![lock(20, 3)]);
+        waits.insert(3, vec![lock(30, 1)]);
+        waits.insert(4, vec![lock(30, 1)]);
+
+        let cycles = find_cycles(&waits);
+        assert_eq!(cycles.len(), 1);
+        let threads: Vec<u64> = cycles[0].iter().map(|(thread, _)| *thread).collect();
+        assert_eq!(threads, vec![1, 2, 3]);
+        assert_eq!(cycles[0][2].1.addr, 30);
+
+        waits.remove(&3);
+        assert!(find_cycles(&waits).is_empty());
+    }
+}
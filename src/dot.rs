@@ -0,0 +1,194 @@
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+
+use anyhow::Error;
+
+use crate::stack_trace::{Frame, StackTrace};
+
+/// Functions that appear in less than this fraction of samples are left out of the graph
+const MIN_NODE_FRACTION: f64 = 0.005;
+
+/// Aggregates stack traces into a call graph, where each node is a function and each edge
+/// is a call from one function to another, weighted by the number of samples. This is
+/// written out in the GraphViz DOT format, and can be rendered with 'dot -Tsvg'.
+pub struct CallGraph {
+    nodes: HashMap<String, Node>,
+    edges: HashMap<(String, String), usize>,
+    samples: usize,
+    name_format: Option<String>,
+}
+
+#[derive(Default)]
+struct Node {
+    /// The number of samples where this function was on the stack
+    total: usize,
+    /// The number of samples where this function was the one running
+    own: usize,
+}
+
+impl CallGraph {
+    pub fn new(name_format: Option<String>) -> CallGraph {
+        CallGraph {
+            nodes: HashMap::new(),
+            edges: HashMap::new(),
+            samples: 0,
+            name_format,
+        }
+    }
+
+    pub fn increment(&mut self, trace: &StackTrace) {
+        let names: Vec<String> = trace.frames.iter().map(|f| self.node_name(f)).collect();
+        self.samples += 1;
+
+        if let Some(leaf) = names.first() {
+            self.nodes.entry(leaf.clone()).or_default().own += 1;
+        }
+
+        // recursive functions can show up several times in the same stack, only count
+        // each function and call once per sample so that totals stay below 100%
+        let mut seen = HashSet::new();
+        for name in &names {
+            if seen.insert(name) {
+                self.nodes.entry(name.clone()).or_default().total += 1;
+            }
+        }
+
+        let mut seen = HashSet::new();
+        for pair in names.windows(2) {
+            let (callee, caller) = (&pair[0], &pair[1]);
+            if seen.insert((caller, callee)) {
+                *self
+                    .edges
+                    .entry((caller.clone(), callee.clone()))
+                    .or_insert(0) += 1;
+            }
+        }
+    }
+
+    fn node_name(&self, frame: &Frame) -> String {
+        if let Some(template) = &self.name_format {
+            return frame.format_name(template);
+        }
+        let filename = frame.short_filename.as_ref().unwrap_or(&frame.filename);
+        if filename.is_empty() {
+            frame.name.clone()
+        } else {
+            format!("{}\n{}", frame.name, filename)
+        }
+    }
+
+    pub fn write(&self, w: &mut dyn Write) -> Result<(), Error> {
+        let min_samples = (self.samples as f64 * MIN_NODE_FRACTION).ceil() as usize;
+
+        let mut nodes: Vec<_> = self
+            .nodes
+            .iter()
+            .filter(|(_, node)| node.total >= min_samples)
+            .collect();
+        nodes.sort_by(|a, b| b.1.total.cmp(&a.1.total).then(a.0.cmp(b.0)));
+        let ids: HashMap<&String, usize> = nodes
+            .iter()
+            .enumerate()
+            .map(|(id, (name, _))| (*name, id))
+            .collect();
+
+        let mut edges: Vec<_> = self
+            .edges
+            .iter()
+            .filter_map(|((caller, callee), count)| {
+                Some((*ids.get(caller)?, *ids.get(callee)?, *count))
+            })
+            .collect();
+        edges.sort();
+
+        writeln!(w, "digraph \"py-spy\" {{")?;
+        writeln!(
+            w,
+            "    node [shape=box, style=filled, fontname=\"Helvetica\"];"
+        )?;
+        for (id, (name, node)) in nodes.iter().enumerate() {
+            let total = self.fraction(node.total);
+            writeln!(
+                w,
+                "    n{} [label=\"{}\\ntotal: {:.1}% ({})\\nself: {:.1}% ({})\", fillcolor=\"{}\"];",
+                id,
+                escape(name),
+                100.0 * total,
+                node.total,
+                100.0 * self.fraction(node.own),
+                node.own,
+                fill_color(total)
+            )?;
+        }
+        for (caller, callee, count) in edges {
+            writeln!(
+                w,
+                "    n{} -> n{} [label=\"{}\", penwidth={:.2}];",
+                caller,
+                callee,
+                count,
+                1.0 + 7.0 * self.fraction(count)
+            )?;
+        }
+        writeln!(w, "}}")?;
+        Ok(())
+    }
+
+    fn fraction(&self, count: usize) -> f64 {
+        if self.samples == 0 {
+            return 0.0;
+        }
+        count as f64 / self.samples as f64
+    }
+}
+
+/// Escapes a string for use in a quoted DOT label
+fn escape(label: &str) -> String {
+    label
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Shades nodes from white to red, based on the fraction of samples they were on the stack for
+fn fill_color(fraction: f64) -> String {
+    let shade = 255 - (fraction.clamp(0.0, 1.0) * 180.0) as u8;
+    format!("#ff{:02x}{:02x}", shade, shade)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trace(names: &[&str]) -> StackTrace {
+        names
+            .iter()
+            .fold(StackTrace::builder(), |builder, name| {
+                builder.frame(name, "app.py", 0)
+            })
+            .build()
+    }
+
+    #[test]
+    fn test_call_graph() {
+        let mut graph = CallGraph::new(Some("{func}".to_owned()));
+        // frames are leaf first
+        graph.increment(&trace(&["fib", "fib", "fib", "main"]));
+        graph.increment(&trace(&["parse", "main"]));
+
+        assert_eq!(graph.samples, 2);
+        assert_eq!(graph.nodes["main"].total, 2);
+        assert_eq!(graph.nodes["main"].own, 0);
+        assert_eq!(graph.nodes["fib"].total, 1);
+        assert_eq!(graph.nodes["fib"].own, 1);
+        assert_eq!(graph.edges[&("fib".to_owned(), "fib".to_owned())], 1);
+        assert_eq!(graph.edges[&("main".to_owned(), "parse".to_owned())], 1);
+
+        let mut output = Vec::new();
+        graph.write(&mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.starts_with("digraph"));
+        assert!(output.contains("n0 [label=\"main\\ntotal: 100.0% (2)\\nself: 0.0% (0)\""));
+        assert!(output.contains("n0 -> n1 [label=\"1\""));
+    }
+}
@@ -1,20 +1,61 @@
+use std::time::Duration;
+
 use anyhow::Error;
+use chrono::{SecondsFormat, Utc};
 use console::{style, Term};
+use serde_derive::Serialize;
 
 use crate::config::Config;
+use crate::deadlock::{find_deadlocks, DeadlockReport};
 use crate::python_spy::PythonSpy;
-use crate::stack_trace::StackTrace;
+use crate::stack_trace::{Frame, StackTrace};
+use crate::version::Version;
 
 use remoteprocess::Pid;
 
+/// A snapshot of every thread in a process, for the json dump format
+#[derive(Debug, Serialize)]
+pub struct ProcessSnapshot<'a> {
+    pub pid: Pid,
+    pub timestamp: String,
+    pub python_version: String,
+    pub threads: Vec<ThreadSnapshot<'a>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ThreadSnapshot<'a> {
+    pub tid: u64,
+    pub name: Option<&'a str>,
+    pub state: &'a str,
+    pub daemon: Option<bool>,
+    pub traced: bool,
+    pub frames: &'a [Frame],
+}
+
 pub fn print_traces(pid: Pid, config: &Config, parent: Option<Pid>) -> Result<(), Error> {
-    let mut process = PythonSpy::new(pid, config)?;
+    // finding deadlocks needs the local variables of each frame, even if we aren't showing them
+    let mut process = if config.dump_deadlock && config.dump_locals == 0 {
+        let config = Config {
+            dump_locals: 1,
+            ..config.clone()
+        };
+        PythonSpy::new(pid, &config)?
+    } else {
+        PythonSpy::new(pid, config)?
+    };
     if config.dump_json {
         let traces = process.get_stack_traces()?;
         println!("{}", serde_json::to_string_pretty(&traces)?);
         return Ok(());
     }
 
+    if config.dump_snapshot {
+        let timestamp = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+        let traces = process.get_stack_traces()?;
+        print_snapshot(pid, &process.version, timestamp, &traces)?;
+        return Ok(());
+    }
+
     println!(
         "Process {}: {}",
         style(process.pid).bold().yellow(),
@@ -36,7 +77,26 @@ pub fn print_traces(pid: Pid, config: &Config, parent: Option<Pid>) -> Result<()
         );
     }
     println!();
-    let traces = process.get_stack_traces()?;
+
+    if let Some(interval) = config.dump_repeat {
+        let interval = Duration::try_from_secs_f64(interval)
+            .map_err(|_| format_err!("Invalid repeat interval {}", interval))?;
+        return print_traces_repeatedly(&mut process, config, interval);
+    }
+
+    let mut traces = process.get_stack_traces()?;
+    let deadlocks = if config.dump_deadlock {
+        let deadlocks = find_deadlocks(&process, &traces);
+        if config.dump_locals == 0 {
+            for frame in traces.iter_mut().flat_map(|trace| trace.frames.iter_mut()) {
+                frame.locals = None;
+            }
+        }
+        Some(deadlocks)
+    } else {
+        None
+    };
+
     for trace in traces.iter().rev() {
         print_trace(trace, true);
         if config.subprocesses {
@@ -58,6 +118,168 @@ pub fn print_traces(pid: Pid, config: &Config, parent: Option<Pid>) -> Result<()
             }
         }
     }
+
+    if let Some(deadlocks) = deadlocks {
+        println!();
+        print_deadlocks(&deadlocks?, &traces);
+    }
+    Ok(())
+}
+
+/// Prints out any deadlock cycles that were found, or a message saying that there weren't any
+fn print_deadlocks(report: &DeadlockReport, traces: &[StackTrace]) {
+    let thread_name = |thread_id: u64| match traces.iter().find(|t| t.thread_id == thread_id) {
+        Some(trace) => match trace.thread_name.as_ref() {
+            Some(name) => format!(
+                "{} \"{}\"",
+                style(trace.format_threadid()).bold().yellow(),
+                name
+            ),
+            None => format!("{}", style(trace.format_threadid()).bold().yellow()),
+        },
+        None => format!("{}", style(format!("{:#X}", thread_id)).bold().yellow()),
+    };
+
+    if report.cycles.is_empty() {
+        println!("{}", style("No deadlock cycle found").bold().green());
+    }
+    for cycle in &report.cycles {
+        println!(
+            "{}",
+            style(format!("Deadlock found between {} threads:", cycle.len()))
+                .bold()
+                .red()
+        );
+        for (thread_id, lock) in cycle {
+            println!(
+                "    Thread {} is waiting for {} '{}' ({:#x}), held by thread {}",
+                thread_name(*thread_id),
+                lock.kind(),
+                lock.name,
+                lock.addr,
+                thread_name(lock.owner.unwrap_or_default())
+            );
+        }
+    }
+
+    // plain locks don't record their owner, so we can only say that a thread might be waiting
+    if !report.unknown_owner.is_empty() {
+        println!(
+            "{}",
+            style("Threads that may be waiting on locks that don't record their owner:").bold()
+        );
+    }
+    for (thread_id, lock) in &report.unknown_owner {
+        println!(
+            "    Thread {}: {} '{}' ({:#x})",
+            thread_name(*thread_id),
+            lock.kind(),
+            lock.name,
+            lock.addr
+        );
+    }
+}
+
+/// Dumps the stack traces of the process at each interval until it exits. When merging identical
+/// dumps, a dump is only printed out when the stacks have changed since the previous one
+fn print_traces_repeatedly(
+    process: &mut PythonSpy,
+    config: &Config,
+    interval: Duration,
+) -> Result<(), Error> {
+    let term = Term::stdout();
+    let mut previous: Option<Vec<StackTrace>> = None;
+    let mut unchanged = 0;
+    loop {
+        let traces = match process.get_stack_traces() {
+            Ok(traces) => traces,
+            Err(_) if process.process.exe().is_err() => break,
+            Err(e) => return Err(e),
+        };
+
+        if config.dump_merge_identical
+            && previous
+                .as_ref()
+                .is_some_and(|previous| same_stacks(previous, &traces))
+        {
+            unchanged += 1;
+            // on a terminal keep updating the count in place, otherwise this gets written out
+            // once the stacks change
+            if term.is_term() {
+                term.clear_line()?;
+                term.write_str(&unchanged_message(unchanged))?;
+            }
+        } else {
+            finish_unchanged(&term, unchanged);
+            unchanged = 0;
+
+            let timestamp = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+            println!("{}", style(timestamp).bold());
+            for trace in traces.iter().rev() {
+                print_trace(trace, true);
+            }
+            println!();
+            previous = Some(traces);
+        }
+        std::thread::sleep(interval);
+    }
+    finish_unchanged(&term, unchanged);
+    println!("Process {} exited", style(process.pid).bold().yellow());
+    Ok(())
+}
+
+/// Whether two dumps have the same threads with the same stacks, ignoring whether the threads
+/// were idle or not
+fn same_stacks(a: &[StackTrace], b: &[StackTrace]) -> bool {
+    a.len() == b.len()
+        && a.iter()
+            .zip(b)
+            .all(|(a, b)| a.thread_id == b.thread_id && a.frames == b.frames)
+}
+
+fn unchanged_message(intervals: usize) -> String {
+    let plural = if intervals == 1 { "" } else { "s" };
+    format!(
+        "{}",
+        style(format!("Unchanged for {} interval{}", intervals, plural)).dim()
+    )
+}
+
+fn finish_unchanged(term: &Term, unchanged: usize) {
+    if unchanged == 0 {
+        return;
+    }
+    if term.is_term() {
+        println!();
+    } else {
+        println!("{}\n", unchanged_message(unchanged));
+    }
+}
+
+/// Prints a single json document containing the stacks of every thread
+pub fn print_snapshot(
+    pid: Pid,
+    version: &Version,
+    timestamp: String,
+    traces: &[StackTrace],
+) -> Result<(), Error> {
+    let snapshot = ProcessSnapshot {
+        pid,
+        timestamp,
+        python_version: version.to_string(),
+        threads: traces
+            .iter()
+            .map(|trace| ThreadSnapshot {
+                tid: trace.os_thread_id.unwrap_or(trace.thread_id),
+                name: trace.thread_name.as_deref(),
+                state: trace.status_str(),
+                daemon: trace.daemon,
+                traced: trace.traced,
+                frames: &trace.frames,
+            })
+            .collect(),
+    };
+    println!("{}", serde_json::to_string_pretty(&snapshot)?);
     Ok(())
 }
 
@@ -72,6 +294,30 @@ pub fn print_trace(trace: &StackTrace, include_activity: bool) {
         "".to_owned()
     };
 
+    // only call out the interpreter for threads belonging to a sub-interpreter
+    let status = match trace.interpreter_id {
+        Some(id) if id != 0 => format!("{} [interpreter {}]", status, id),
+        _ => status,
+    };
+
+    let status = if trace.daemon == Some(true) {
+        format!("{} [daemon]", status)
+    } else {
+        status
+    };
+
+    let status = if trace.traced {
+        format!("{} [traced]", status)
+    } else {
+        status
+    };
+
+    let status = if trace.incomplete {
+        format!("{} [incomplete]", status)
+    } else {
+        status
+    };
+
     match trace.thread_name.as_ref() {
         Some(name) => {
             println!(
@@ -0,0 +1,306 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::PathBuf;
+
+use anyhow::Error;
+use flate2::read::ZlibDecoder;
+use gimli::{EndianSlice, RunTimeEndian};
+use goblin::container::{Container, Ctx, Endian};
+use goblin::elf::compression_header::{CompressionHeader, ELFCOMPRESS_ZLIB};
+use goblin::elf::section_header::{SectionHeader, SHF_COMPRESSED, SHT_NOBITS};
+use goblin::elf::Elf;
+
+type Slice<'a> = EndianSlice<'a, RunTimeEndian>;
+
+/// Maps addresses in an ELF binary to the source file and line they were compiled from, using
+/// the line number programs from its DWARF debug info
+#[derive(Clone, Debug, Default)]
+pub struct LineTable {
+    files: Vec<String>,
+    // (address, index into files, line) sorted by address. Each row covers the addresses up
+    // to the next one, and a line of 0 marks addresses without line info (like the end of
+    // a sequence of instructions)
+    rows: Vec<(u64, usize, u32)>,
+}
+
+impl LineTable {
+    /// Parses the line number programs from an ELF binary. Binaries without debug info (like
+    /// when it has been split out into a separate file referenced by '.gnu_debuglink') give
+    /// an empty table. Compressed debug sections are decompressed first
+    pub fn parse(buffer: &[u8]) -> Result<LineTable, Error> {
+        let elf = Elf::parse(buffer)?;
+        let endian = if elf.little_endian {
+            RunTimeEndian::Little
+        } else {
+            RunTimeEndian::Big
+        };
+        let load = |id: gimli::SectionId| -> Result<Cow<[u8]>, Error> {
+            // sections compressed the old way are named '.zdebug_*' instead of '.debug_*'
+            let zdebug_name = id.name().replacen(".debug_", ".zdebug_", 1);
+            for header in elf.section_headers.iter() {
+                let name = elf.shdr_strtab.get_at(header.sh_name);
+                if name == Some(id.name()) {
+                    return section_data(&elf, buffer, header, false);
+                }
+                if name == Some(zdebug_name.as_str()) {
+                    return section_data(&elf, buffer, header, true);
+                }
+            }
+            Ok(Cow::Borrowed(&[]))
+        };
+        let sections = gimli::Dwarf::load(load)?;
+        let dwarf = sections.borrow(|section| EndianSlice::new(section, endian));
+
+        let mut table = LineTable::default();
+        let mut file_indices: HashMap<String, usize> = HashMap::new();
+        let mut units = dwarf.units();
+        while let Some(header) = units.next()? {
+            let unit = dwarf.unit(header)?;
+            let program = match unit.line_program.clone() {
+                Some(program) => program,
+                None => continue,
+            };
+
+            // the index of each file in the line program into table.files
+            let mut unit_files: HashMap<u64, usize> = HashMap::new();
+            let mut rows = program.rows();
+            while let Some((header, row)) = rows.next_row()? {
+                let line = match row.line() {
+                    Some(line) if !row.end_sequence() => line.get() as u32,
+                    _ => {
+                        table.rows.push((row.address(), 0, 0));
+                        continue;
+                    }
+                };
+                let file = match unit_files.get(&row.file_index()) {
+                    Some(&file) => file,
+                    None => {
+                        let path = match row.file(header) {
+                            Some(file) => file_path(&dwarf, &unit, header, file)?,
+                            None => String::from("?"),
+                        };
+                        let file = *file_indices.entry(path).or_insert_with_key(|path| {
+                            table.files.push(path.clone());
+                            table.files.len() - 1
+                        });
+                        unit_files.insert(row.file_index(), file);
+                        file
+                    }
+                };
+                table.rows.push((row.address(), file, line));
+            }
+        }
+
+        // when one sequence ends where another starts, the row starting the next one wins
+        table.rows.sort_by_key(|&(addr, _, line)| (addr, line != 0));
+        Ok(table)
+    }
+
+    /// Returns the source file and line for an address, relative to where the binary is loaded
+    pub fn lookup(&self, addr: u64) -> Option<(&str, u32)> {
+        let position = self
+            .rows
+            .partition_point(|&(row_addr, _, _)| row_addr <= addr);
+        let &(_, file, line) = self.rows.get(position.checked_sub(1)?)?;
+        if line == 0 {
+            return None;
+        }
+        Some((&self.files[file], line))
+    }
+}
+
+/// Returns the contents of a section, decompressing it when it's compressed. Sections can be
+/// compressed with zlib or zstd when they have the SHF_COMPRESSED flag, in which case they
+/// start with a compression header, and are compressed with zlib when they are '.zdebug_*'
+/// sections, in which case they start with 'ZLIB' and the size in big endian
+fn section_data<'a>(
+    elf: &Elf,
+    buffer: &'a [u8],
+    header: &SectionHeader,
+    zdebug: bool,
+) -> Result<Cow<'a, [u8]>, Error> {
+    let data = match buffer
+        .get(header.sh_offset as usize..)
+        .and_then(|data| data.get(..header.sh_size as usize))
+    {
+        Some(data) if header.sh_type != SHT_NOBITS => data,
+        _ => return Ok(Cow::Borrowed(&[])),
+    };
+
+    if header.sh_flags & SHF_COMPRESSED as u64 != 0 {
+        let container = if elf.is_64 {
+            Container::Big
+        } else {
+            Container::Little
+        };
+        let endian = if elf.little_endian {
+            Endian::Little
+        } else {
+            Endian::Big
+        };
+        let ctx = Ctx::new(container, endian);
+        let compression = CompressionHeader::parse(data, 0, ctx)?;
+        let compressed = &data[CompressionHeader::size(ctx)..];
+        let size = compression.ch_size as usize;
+        return match compression.ch_type {
+            ELFCOMPRESS_ZLIB => decompress(ZlibDecoder::new(compressed), size),
+            ELFCOMPRESS_ZSTD => decompress(zstd::Decoder::new(compressed)?, size),
+            other => Err(format_err!(
+                "Unknown compression type {} for debug section",
+                other
+            )),
+        };
+    }
+
+    if zdebug {
+        if let Some(compressed) = data.strip_prefix(b"ZLIB") {
+            if compressed.len() >= 8 {
+                let (size, compressed) = compressed.split_at(8);
+                let size = u64::from_be_bytes(size.try_into()?) as usize;
+                return decompress(ZlibDecoder::new(compressed), size);
+            }
+        }
+    }
+    Ok(Cow::Borrowed(data))
+}
+
+// goblin doesn't have a constant for zstd compressed sections yet
+const ELFCOMPRESS_ZSTD: u32 = 2;
+
+/// Reads in a decompressed section, which is expected to be 'size' bytes long
+fn decompress<'a, R: Read>(decoder: R, size: usize) -> Result<Cow<'a, [u8]>, Error> {
+    // the size comes from the file, so don't trust it for more than the initial allocation
+    let mut data = Vec::with_capacity(size.min(1 << 28));
+    decoder.take(size as u64).read_to_end(&mut data)?;
+    if data.len() != size {
+        return Err(format_err!(
+            "Compressed debug section is {} bytes instead of {}",
+            data.len(),
+            size
+        ));
+    }
+    Ok(Cow::Owned(data))
+}
+
+/// Returns the full path of a file in a line program, which can be relative to its directory
+/// and the directory the unit was compiled in
+fn file_path(
+    dwarf: &gimli::Dwarf<Slice>,
+    unit: &gimli::Unit<Slice>,
+    header: &gimli::LineProgramHeader<Slice>,
+    file: &gimli::FileEntry<Slice>,
+) -> Result<String, gimli::Error> {
+    let mut path = PathBuf::new();
+    if let Some(comp_dir) = unit.comp_dir {
+        path.push(comp_dir.to_string_lossy().as_ref());
+    }
+    if let Some(directory) = file.directory(header) {
+        path.push(
+            dwarf
+                .attr_string(unit, directory)?
+                .to_string_lossy()
+                .as_ref(),
+        );
+    }
+    path.push(
+        dwarf
+            .attr_string(unit, file.path_name())?
+            .to_string_lossy()
+            .as_ref(),
+    );
+    Ok(path.to_string_lossy().into_owned())
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[inline(never)]
+    fn marker() -> u32 {
+        line!()
+    }
+
+    #[test]
+    fn test_line_table() {
+        let buffer = std::fs::read(std::env::current_exe().unwrap()).unwrap();
+        let table = LineTable::parse(&buffer).unwrap();
+
+        // find where the test binary is loaded, to get the address of marker in the file
+        let addr = marker as fn() -> u32 as usize;
+        let mut info: libc::Dl_info = unsafe { std::mem::zeroed() };
+        assert_ne!(
+            unsafe { libc::dladdr(addr as *const libc::c_void, &mut info) },
+            0
+        );
+        let addr = addr as u64 - info.dli_fbase as u64;
+
+        let (file, line) = table.lookup(addr).unwrap();
+        assert!(file.ends_with("src/dwarf.rs"), "{}", file);
+        // somewhere from the start to the end of the function, depending on the compiler
+        assert!((marker() - 1..=marker() + 1).contains(&line), "{}", line);
+
+        assert_eq!(LineTable::parse(b"not an elf file").ok().map(|_| ()), None);
+        assert_eq!(LineTable::default().lookup(addr), None);
+    }
+
+    #[test]
+    fn test_compressed_sections() {
+        use flate2::write::ZlibEncoder;
+        use std::io::Write;
+
+        let buffer = std::fs::read(std::env::current_exe().unwrap()).unwrap();
+        let elf = Elf::parse(&buffer).unwrap();
+        assert!(elf.is_64 && elf.little_endian);
+
+        // compress the line programs (and the abbreviations they need to be read) in place,
+        // which fits since the compressed sections are smaller
+        let mut compressed = buffer.clone();
+        for (index, header) in elf.section_headers.iter().enumerate() {
+            let name = elf.shdr_strtab.get_at(header.sh_name).unwrap_or_default();
+            if name != ".debug_line" && name != ".debug_abbrev" {
+                continue;
+            }
+            let (offset, size) = (header.sh_offset as usize, header.sh_size as usize);
+            let mut section = Vec::new();
+            section.extend_from_slice(&ELFCOMPRESS_ZLIB.to_le_bytes());
+            section.extend_from_slice(&0u32.to_le_bytes());
+            section.extend_from_slice(&header.sh_size.to_le_bytes());
+            section.extend_from_slice(&header.sh_addralign.to_le_bytes());
+            let mut encoder = ZlibEncoder::new(section, flate2::Compression::fast());
+            encoder.write_all(&buffer[offset..offset + size]).unwrap();
+            let section = encoder.finish().unwrap();
+            compressed[offset..offset + section.len()].copy_from_slice(&section);
+
+            // sh_flags is 8 bytes into a 64 bit section header, and sh_size 32
+            let header_offset =
+                elf.header.e_shoff as usize + index * elf.header.e_shentsize as usize;
+            let flags = header.sh_flags | SHF_COMPRESSED as u64;
+            compressed[header_offset + 8..header_offset + 16].copy_from_slice(&flags.to_le_bytes());
+            compressed[header_offset + 32..header_offset + 40]
+                .copy_from_slice(&(section.len() as u64).to_le_bytes());
+        }
+        assert_ne!(compressed, buffer);
+
+        let table = LineTable::parse(&buffer).unwrap();
+        let decompressed = LineTable::parse(&compressed).unwrap();
+        assert!(!decompressed.rows.is_empty());
+        assert_eq!(decompressed.files, table.files);
+        assert_eq!(decompressed.rows, table.rows);
+
+        // sections compressed the old way start with 'ZLIB' and the size instead of a header
+        let mut encoder = ZlibEncoder::new(
+            b"ZLIB\0\0\0\0\0\0\0\x05".to_vec(),
+            flate2::Compression::fast(),
+        );
+        encoder.write_all(b"hello").unwrap();
+        let zdebug = encoder.finish().unwrap();
+        let header = SectionHeader {
+            sh_type: goblin::elf::section_header::SHT_PROGBITS,
+            sh_size: zdebug.len() as u64,
+            ..Default::default()
+        };
+        let data = section_data(&elf, &zdebug, &header, true).unwrap();
+        assert_eq!(data.as_ref(), b"hello");
+    }
+}
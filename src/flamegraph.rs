@@ -37,13 +37,15 @@ use crate::stack_trace::StackTrace;
 pub struct Flamegraph {
     pub counts: HashMap<String, usize>,
     pub show_linenumbers: bool,
+    pub name_format: Option<String>,
 }
 
 impl Flamegraph {
-    pub fn new(show_linenumbers: bool) -> Flamegraph {
+    pub fn new(show_linenumbers: bool, name_format: Option<String>) -> Flamegraph {
         Flamegraph {
             counts: HashMap::new(),
             show_linenumbers,
+            name_format,
         }
     }
 
@@ -54,6 +56,9 @@ impl Flamegraph {
             .iter()
             .rev()
             .map(|frame| {
+                if let Some(template) = &self.name_format {
+                    return frame.format_name(template);
+                }
                 let filename = match &frame.short_filename {
                     Some(f) => f,
                     None => &frame.filename,
@@ -100,3 +105,30 @@ impl Flamegraph {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stack_trace::FrameKind;
+
+    #[test]
+    fn test_frame_kind_names() {
+        let mut trace = StackTrace::builder()
+            .frame("fetch", "app.py", 12)
+            .frame("main", "app.py", 30)
+            .build();
+        trace.frames[0].frame_kind = FrameKind::Coroutine;
+
+        // frame kinds are only shown when asked for in the name format
+        let mut flamegraph = Flamegraph::new(false, None);
+        flamegraph.increment(&trace).unwrap();
+        assert_eq!(
+            flamegraph.get_lines(),
+            vec!["main (app.py);fetch (app.py) 1"]
+        );
+
+        let mut flamegraph = Flamegraph::new(false, Some("{func} [{kind}]".to_owned()));
+        flamegraph.increment(&trace).unwrap();
+        assert_eq!(flamegraph.get_lines(), vec!["main [];fetch [async] 1"]);
+    }
+}
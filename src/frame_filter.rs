@@ -0,0 +1,151 @@
+use std::path::Path;
+
+use crate::stack_trace::{Frame, StackTrace};
+
+/// Collapses frames from uninteresting code (like the internals of a framework) in stack
+/// traces. Each run of consecutive frames from files matching the collapsed paths is replaced
+/// with a single frame, named after the module of the outermost frame in the run.
+///
+/// Paths match a file when they are a prefix of its full filename, or of the filename after
+/// any directory in it, so that 'site-packages/' matches every installed package. When keep
+/// paths are given, frames from files that don't match any of them are collapsed as well.
+pub struct FrameFilter {
+    collapse: Vec<String>,
+    keep: Vec<String>,
+}
+
+impl FrameFilter {
+    pub fn new(collapse: &[String], keep: &[String]) -> FrameFilter {
+        FrameFilter {
+            collapse: collapse.to_vec(),
+            keep: keep.to_vec(),
+        }
+    }
+
+    pub fn filter(&self, trace: &mut StackTrace) {
+        // frames are leaf first, so the last frame of a run is its outermost one
+        let mut frames: Vec<(Frame, bool)> = Vec::with_capacity(trace.frames.len());
+        for frame in trace.frames.drain(..) {
+            let collapsed = self.is_collapsed(&frame);
+            match frames.last_mut() {
+                Some((last, true)) if collapsed => *last = frame,
+                _ => frames.push((frame, collapsed)),
+            }
+        }
+        trace.frames = frames
+            .into_iter()
+            .map(|(frame, collapsed)| if collapsed { collapse(frame) } else { frame })
+            .collect();
+    }
+
+    fn is_collapsed(&self, frame: &Frame) -> bool {
+        let matches = |paths: &[String]| paths.iter().any(|p| path_matches(p, &frame.filename));
+        matches(&self.collapse) || (!self.keep.is_empty() && !matches(&self.keep))
+    }
+}
+
+fn path_matches(path: &str, filename: &str) -> bool {
+    filename.starts_with(path)
+        || filename
+            .match_indices(['/', '\\'])
+            .any(|(i, _)| filename[i + 1..].starts_with(path))
+}
+
+/// Returns the frame that a run of collapsed frames is replaced with. This is the same for
+/// every sample that enters the collapsed code from the same place, so that the run is still
+/// merged across samples by the outputs
+fn collapse(outermost: Frame) -> Frame {
+    let module = match outermost.module {
+        Some(module) if !outermost.is_native => module,
+        // show 'libfoo' for '/usr/lib/libfoo.so.1', like with --native-exclude
+        _ => Path::new(&outermost.filename)
+            .file_name()
+            .map(|name| name.to_string_lossy())
+            .unwrap_or_default()
+            .split('.')
+            .next()
+            .unwrap_or_default()
+            .to_owned(),
+    };
+    Frame {
+        name: format!("<{}>", module),
+        module: Some(module),
+        short_filename: None,
+        line: 0,
+        locals: None,
+        code_id: None,
+        ..outermost
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stack_trace::FrameKind;
+
+    fn frame(name: &str, filename: &str, module: &str) -> Frame {
+        Frame {
+            name: name.to_owned(),
+            filename: filename.to_owned(),
+            module: Some(module.to_owned()),
+            short_filename: None,
+            line: 10,
+            locals: None,
+            is_native: false,
+            frame_kind: FrameKind::Regular,
+            code_id: None,
+        }
+    }
+
+    fn names(filter: &FrameFilter, frames: Vec<Frame>) -> Vec<String> {
+        let mut trace = frames
+            .into_iter()
+            .fold(StackTrace::builder(), |builder, frame| {
+                builder.push_frame(frame)
+            })
+            .build();
+        filter.filter(&mut trace);
+        trace.frames.into_iter().map(|f| f.name).collect()
+    }
+
+    #[test]
+    fn test_frame_filter() {
+        let site = "/usr/lib/python3.11/site-packages";
+        // leaf first: a view called from inside django, which was called by the app
+        let frames = vec![
+            frame(
+                "query",
+                &format!("{}/psycopg2/cursor.py", site),
+                "psycopg2.cursor",
+            ),
+            frame("view", "/srv/app/views.py", "app.views"),
+            frame(
+                "dispatch",
+                &format!("{}/django/views.py", site),
+                "django.views",
+            ),
+            frame(
+                "get_response",
+                &format!("{}/django/base.py", site),
+                "django.base",
+            ),
+            frame("main", "/srv/app/main.py", "app.main"),
+        ];
+
+        let filter = FrameFilter::new(&["site-packages/".to_owned()], &[]);
+        assert_eq!(
+            names(&filter, frames.clone()),
+            vec!["<psycopg2.cursor>", "view", "<django.base>", "main"]
+        );
+
+        let filter = FrameFilter::new(&[], &["/srv/app/".to_owned()]);
+        assert_eq!(
+            names(&filter, frames.clone()),
+            vec!["<psycopg2.cursor>", "view", "<django.base>", "main"]
+        );
+
+        // collapsed paths win over kept ones, which joins up the runs on either side
+        let filter = FrameFilter::new(&["app/views.py".to_owned()], &["/srv/app/".to_owned()]);
+        assert_eq!(names(&filter, frames), vec!["<django.base>", "main"]);
+    }
+}
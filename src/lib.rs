@@ -29,14 +29,21 @@ extern crate anyhow;
 #[macro_use]
 extern crate log;
 
+pub mod aggregate;
 pub mod binary_parser;
+pub mod check;
+pub mod chrometrace;
 pub mod config;
 #[cfg(target_os = "linux")]
 pub mod coredump;
 #[cfg(unwind)]
 mod cython;
+pub mod deadlock;
 pub mod dump;
 #[cfg(unwind)]
+mod dwarf;
+pub mod memory;
+#[cfg(unwind)]
 mod native_stack_trace;
 mod python_bindings;
 mod python_data_access;
@@ -46,6 +53,7 @@ pub mod python_spy;
 mod python_threading;
 pub mod sampler;
 pub mod stack_trace;
+mod thread_filter;
 pub mod timer;
 mod utils;
 mod version;
@@ -3,17 +3,27 @@ extern crate anyhow;
 #[macro_use]
 extern crate log;
 
+mod anonymize;
 mod binary_parser;
+mod check;
+mod chrometrace;
 mod config;
 mod console_viewer;
 #[cfg(target_os = "linux")]
 mod coredump;
 #[cfg(unwind)]
 mod cython;
+mod deadlock;
+mod dot;
 mod dump;
+#[cfg(unwind)]
+mod dwarf;
 mod flamegraph;
+mod frame_filter;
+mod memory;
 #[cfg(unwind)]
 mod native_stack_trace;
+mod output;
 mod python_bindings;
 mod python_data_access;
 mod python_interpreters;
@@ -23,21 +33,23 @@ mod python_threading;
 mod sampler;
 mod speedscope;
 mod stack_trace;
+mod thread_filter;
 mod timer;
 mod utils;
 mod version;
 
+use std::collections::{HashMap, HashSet};
 use std::io::{Read, Write};
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use std::sync::atomic::Ordering;
 use std::time::Duration;
 
 use anyhow::Error;
 use console::style;
 
-use config::{Config, FileFormat, RecordDuration};
+use config::{Config, FileFormat, LockingStrategy, RecordDuration};
 use console_viewer::ConsoleViewer;
-use stack_trace::{Frame, StackTrace};
+use sampler::{SamplingStats, StopReason};
+use stack_trace::{FdStats, Frame, GCStats, StackTrace};
 
 use chrono::{Local, SecondsFormat};
 
@@ -86,50 +98,195 @@ fn sample_console(pid: remoteprocess::Pid, config: &Config) -> Result<(), Error>
 }
 
 pub trait Recorder {
-    fn increment(&mut self, trace: &StackTrace) -> Result<(), Error>;
-    fn write(&self, w: &mut dyn Write) -> Result<(), Error>;
+    /// Records the stack traces collected in a single sample, along with the time
+    /// the sample was taken (relative to when sampling started). Recorders don't read the
+    /// clock themselves, so the same samples and timestamps always give the same output.
+    /// Nothing stores samples to be replayed yet, so for now the timestamps always come
+    /// straight from the sampler
+    fn increment(&mut self, traces: Vec<StackTrace>, timestamp: Duration) -> Result<(), Error>;
+    fn write(&mut self, w: &mut dyn Write) -> Result<(), Error>;
+
+    /// Records the garbage collector state of a process, for formats that support it
+    fn record_gc_stats(&mut self, _pid: remoteprocess::Pid, _stats: &GCStats) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Records the number of open file descriptors of a process, for formats that support it
+    fn record_fd_stats(&mut self, _pid: remoteprocess::Pid, _stats: &FdStats) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Records how many stack traces failed to be read over the capture, for formats that
+    /// support it
+    fn record_sampling_stats(&mut self, _stats: &SamplingStats) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Returns whether the output has hit a size limit, and won't record any more samples
+    fn is_full(&self) -> bool {
+        false
+    }
 }
 
-impl Recorder for speedscope::Stats {
-    fn increment(&mut self, trace: &StackTrace) -> Result<(), Error> {
-        Ok(self.record(trace)?)
+impl Recorder for chrometrace::Chrometrace {
+    fn increment(&mut self, traces: Vec<StackTrace>, timestamp: Duration) -> Result<(), Error> {
+        self.increment(traces, timestamp)
     }
-    fn write(&self, w: &mut dyn Write) -> Result<(), Error> {
+    fn write(&mut self, w: &mut dyn Write) -> Result<(), Error> {
         self.write(w)
     }
+    fn record_gc_stats(&mut self, pid: remoteprocess::Pid, stats: &GCStats) -> Result<(), Error> {
+        self.record_gc_stats(pid, stats)
+    }
+    fn record_fd_stats(&mut self, pid: remoteprocess::Pid, stats: &FdStats) -> Result<(), Error> {
+        self.record_fd_stats(pid, stats)
+    }
+    fn record_sampling_stats(&mut self, stats: &SamplingStats) -> Result<(), Error> {
+        self.record_sampling_stats(stats)
+    }
+    fn is_full(&self) -> bool {
+        self.is_full()
+    }
+}
+
+impl Recorder for speedscope::Stats {
+    fn increment(&mut self, traces: Vec<StackTrace>, _timestamp: Duration) -> Result<(), Error> {
+        for trace in &traces {
+            self.record(trace)?;
+        }
+        Ok(())
+    }
+    fn write(&mut self, w: &mut dyn Write) -> Result<(), Error> {
+        speedscope::Stats::write(self, w)
+    }
 }
 
 impl Recorder for flamegraph::Flamegraph {
-    fn increment(&mut self, trace: &StackTrace) -> Result<(), Error> {
-        Ok(self.increment(trace)?)
+    fn increment(&mut self, traces: Vec<StackTrace>, _timestamp: Duration) -> Result<(), Error> {
+        for trace in &traces {
+            self.increment(trace)?;
+        }
+        Ok(())
     }
-    fn write(&self, w: &mut dyn Write) -> Result<(), Error> {
-        self.write(w)
+    fn write(&mut self, w: &mut dyn Write) -> Result<(), Error> {
+        flamegraph::Flamegraph::write(self, w)
+    }
+}
+
+impl Recorder for dot::CallGraph {
+    fn increment(&mut self, traces: Vec<StackTrace>, _timestamp: Duration) -> Result<(), Error> {
+        for trace in &traces {
+            self.increment(trace);
+        }
+        Ok(())
+    }
+    fn write(&mut self, w: &mut dyn Write) -> Result<(), Error> {
+        dot::CallGraph::write(self, w)
     }
 }
 
 pub struct RawFlamegraph(flamegraph::Flamegraph);
 
 impl Recorder for RawFlamegraph {
-    fn increment(&mut self, trace: &StackTrace) -> Result<(), Error> {
-        Ok(self.0.increment(trace)?)
+    fn increment(&mut self, traces: Vec<StackTrace>, _timestamp: Duration) -> Result<(), Error> {
+        for trace in &traces {
+            self.0.increment(trace)?;
+        }
+        Ok(())
     }
 
-    fn write(&self, w: &mut dyn Write) -> Result<(), Error> {
+    fn write(&mut self, w: &mut dyn Write) -> Result<(), Error> {
         self.0.write_raw(w)
     }
 }
 
-fn record_samples(pid: remoteprocess::Pid, config: &Config) -> Result<(), Error> {
-    let mut output: Box<dyn Recorder> = match config.format {
-        Some(FileFormat::flamegraph) => {
-            Box::new(flamegraph::Flamegraph::new(config.show_line_numbers))
+/// A change made to each stack trace before it is passed on to another recorder
+pub trait TraceTransform {
+    fn transform(&mut self, trace: &mut StackTrace);
+
+    /// Called after the other recorder has written out its output
+    fn finish(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Transforms the stack traces before passing them on to another recorder
+pub struct Transformed<T: TraceTransform> {
+    recorder: Box<dyn Recorder>,
+    transform: T,
+}
+
+impl<T: TraceTransform> Recorder for Transformed<T> {
+    fn increment(&mut self, mut traces: Vec<StackTrace>, timestamp: Duration) -> Result<(), Error> {
+        for trace in traces.iter_mut() {
+            self.transform.transform(trace);
         }
-        Some(FileFormat::speedscope) => Box::new(speedscope::Stats::new(config)),
-        Some(FileFormat::raw) => Box::new(RawFlamegraph(flamegraph::Flamegraph::new(
-            config.show_line_numbers,
-        ))),
-        None => return Err(format_err!("A file format is required to record samples")),
+        self.recorder.increment(traces, timestamp)
+    }
+
+    fn write(&mut self, w: &mut dyn Write) -> Result<(), Error> {
+        self.recorder.write(w)?;
+        self.transform.finish()
+    }
+
+    fn record_gc_stats(&mut self, pid: remoteprocess::Pid, stats: &GCStats) -> Result<(), Error> {
+        self.recorder.record_gc_stats(pid, stats)
+    }
+
+    fn record_fd_stats(&mut self, pid: remoteprocess::Pid, stats: &FdStats) -> Result<(), Error> {
+        self.recorder.record_fd_stats(pid, stats)
+    }
+
+    fn record_sampling_stats(&mut self, stats: &SamplingStats) -> Result<(), Error> {
+        self.recorder.record_sampling_stats(stats)
+    }
+
+    fn is_full(&self) -> bool {
+        self.recorder.is_full()
+    }
+}
+
+/// Strips native frames from the stack traces
+pub struct PythonOnly;
+
+impl TraceTransform for PythonOnly {
+    fn transform(&mut self, trace: &mut StackTrace) {
+        trace.frames.retain(|frame| !frame.is_native);
+    }
+}
+
+/// Anonymizes the stack traces, writing out the keyfile to map them back at the end
+pub struct Anonymize {
+    anonymizer: anonymize::Anonymizer,
+    keyfile: Option<String>,
+}
+
+impl TraceTransform for Anonymize {
+    fn transform(&mut self, trace: &mut StackTrace) {
+        self.anonymizer.anonymize(trace);
+    }
+
+    fn finish(&mut self) -> Result<(), Error> {
+        if let Some(keyfile) = self.keyfile.as_ref() {
+            let mut out_file = std::fs::File::create(keyfile)?;
+            self.anonymizer.write_keyfile(&mut out_file)?;
+        }
+        Ok(())
+    }
+}
+
+/// Collapses frames from uninteresting code
+impl TraceTransform for frame_filter::FrameFilter {
+    fn transform(&mut self, trace: &mut StackTrace) {
+        self.filter(trace);
+    }
+}
+
+fn record_samples(pid: remoteprocess::Pid, config: &Config) -> Result<(), Error> {
+    // connect up front, so that we fail before sampling if nothing is listening
+    let socket = match config.filename.as_deref().and_then(output::socket_path) {
+        Some(path) => Some(output::SocketWriter::connect(path)?),
+        None => None,
     };
 
     let filename = match config.filename.clone() {
@@ -138,7 +295,9 @@ fn record_samples(pid: remoteprocess::Pid, config: &Config) -> Result<(), Error>
             let ext = match config.format.as_ref() {
                 Some(FileFormat::flamegraph) => "svg",
                 Some(FileFormat::speedscope) => "json",
+                Some(FileFormat::chrometrace) => config.trace_compression.extension(),
                 Some(FileFormat::raw) => "txt",
+                Some(FileFormat::dot) => "dot",
                 None => return Err(format_err!("A file format is required to record samples")),
             };
             let local_time = Local::now().to_rfc3339_opts(SecondsFormat::Secs, true);
@@ -153,6 +312,63 @@ fn record_samples(pid: remoteprocess::Pid, config: &Config) -> Result<(), Error>
         }
     };
 
+    let streaming_to_file = config.stream_output && config.format == Some(FileFormat::chrometrace);
+    // the pid is written into chrometrace output, but isn't in the config when py-spy started
+    // the python program itself
+    let trace_config = Config {
+        pid: Some(pid),
+        ..config.clone()
+    };
+    let mut output: Box<dyn Recorder> = match config.format {
+        Some(FileFormat::flamegraph) => Box::new(flamegraph::Flamegraph::new(
+            config.show_line_numbers,
+            config.name_format.clone(),
+        )),
+        Some(FileFormat::speedscope) => Box::new(speedscope::Stats::new(config)),
+        Some(FileFormat::chrometrace) => match socket.as_ref() {
+            Some(socket) => Box::new(chrometrace::Chrometrace::streaming(
+                &trace_config,
+                Box::new(socket.try_clone()?),
+            )?),
+            None if streaming_to_file => Box::new(chrometrace::Chrometrace::new_streaming(
+                &trace_config,
+                std::path::Path::new(&filename),
+            )?),
+            None => Box::new(chrometrace::Chrometrace::new(&trace_config)?),
+        },
+        Some(FileFormat::raw) => Box::new(RawFlamegraph(flamegraph::Flamegraph::new(
+            config.show_line_numbers,
+            config.name_format.clone(),
+        ))),
+        Some(FileFormat::dot) => Box::new(dot::CallGraph::new(config.name_format.clone())),
+        None => return Err(format_err!("A file format is required to record samples")),
+    };
+
+    if config.python_only {
+        output = Box::new(Transformed {
+            recorder: output,
+            transform: PythonOnly,
+        });
+    }
+
+    if config.anonymize {
+        output = Box::new(Transformed {
+            recorder: output,
+            transform: Anonymize {
+                anonymizer: anonymize::Anonymizer::new(config.anonymize_keep_stdlib),
+                keyfile: config.anonymize_keyfile.clone(),
+            },
+        });
+    }
+
+    // frames are filtered before anonymizing, since the paths to match are the real ones
+    if !config.collapse_paths.is_empty() || !config.keep_paths.is_empty() {
+        output = Box::new(Transformed {
+            recorder: output,
+            transform: frame_filter::FrameFilter::new(&config.collapse_paths, &config.keep_paths),
+        });
+    }
+
     let sampler = sampler::Sampler::new(pid, config)?;
 
     // if we're not showing a progress bar, it's probably because we've spawned the process and
@@ -165,6 +381,22 @@ fn record_samples(pid: remoteprocess::Pid, config: &Config) -> Result<(), Error>
     };
 
     let max_intervals = match &config.duration {
+        RecordDuration::Unlimited if config.schedule.is_some() => {
+            let end = config
+                .schedule
+                .as_ref()
+                .unwrap()
+                .entries
+                .last()
+                .unwrap()
+                .end;
+            println!(
+                "{}Sampling process using a schedule for {} seconds. Press Control-C to exit.",
+                lede,
+                end.as_secs_f64()
+            );
+            None
+        }
         RecordDuration::Unlimited => {
             println!(
                 "{}Sampling process {} times a second. Press Control-C to exit.",
@@ -206,16 +438,27 @@ fn record_samples(pid: remoteprocess::Pid, config: &Config) -> Result<(), Error>
     let mut samples = 0;
     println!();
 
-    let running = Arc::new(AtomicBool::new(true));
+    // the first Control-C stops sampling and writes out what was collected so far, and a
+    // second one exits straight away (like if writing the output is taking too long)
+    let running = sampler.running.clone();
     let r = running.clone();
     ctrlc::set_handler(move || {
-        r.store(false, Ordering::SeqCst);
+        if !r.swap(false, Ordering::SeqCst) {
+            eprintln!("\nExiting without writing output because Control-C pressed twice");
+            std::process::exit(130);
+        }
     })?;
 
-    let mut exit_message = "Stopped sampling because process exited";
+    let sampler_stop_reason = sampler.stop_reason.clone();
+    let skipped_subprocesses = sampler.skipped_subprocesses.clone();
+    let mut pause_times: HashMap<remoteprocess::Pid, Duration> = HashMap::new();
+    let mut elapsed = Duration::ZERO;
+    let mut stop_reason = None;
     let mut last_late_message = std::time::Instant::now();
+    let mut traced_pids = HashSet::new();
+    let mut sampling_stats = SamplingStats::new();
 
-    for mut sample in sampler {
+    for sample in sampler {
         if let Some(delay) = sample.late {
             if delay > Duration::from_secs(1) {
                 if config.hide_progress {
@@ -235,20 +478,28 @@ fn record_samples(pid: remoteprocess::Pid, config: &Config) -> Result<(), Error>
         }
 
         if !running.load(Ordering::SeqCst) {
-            exit_message = "Stopped sampling because Control-C pressed";
+            stop_reason = Some(StopReason::Interrupted);
             break;
         }
 
         intervals += 1;
         if let Some(max_intervals) = max_intervals {
             if intervals >= max_intervals {
-                exit_message = "";
+                stop_reason = Some(StopReason::DurationReached);
                 break;
             }
         }
+        sampling_stats.add(&sample);
 
-        for trace in sample.traces.iter_mut() {
-            if !(config.include_idle || trace.active) {
+        let mut traces = Vec::with_capacity(sample.traces.len());
+        for mut trace in sample.traces {
+            if trace.traced && traced_pids.insert(trace.pid) {
+                progress.suspend(|| {
+                    println!("{}Process {} has a trace function set (like from a debugger or coverage), which slows it down and may skew the profile", lede, trace.pid)
+                });
+            }
+
+            if !(config.include_idle || config.split_by_state || trace.active) {
                 continue;
             }
 
@@ -263,14 +514,7 @@ fn record_samples(pid: remoteprocess::Pid, config: &Config) -> Result<(), Error>
                 } else {
                     format!("thread ({})", threadid)
                 };
-                trace.frames.push(Frame {
-                    name: thread_fmt,
-                    filename: String::from(""),
-                    module: None,
-                    short_filename: None,
-                    line: 0,
-                    locals: None,
-                });
+                trace.frames.push(Frame::synthetic(thread_fmt));
             }
 
             if let Some(process_info) = trace.process_info.as_ref() {
@@ -284,9 +528,26 @@ fn record_samples(pid: remoteprocess::Pid, config: &Config) -> Result<(), Error>
                 }
             }
 
+            if config.split_by_state {
+                let state = if trace.active { "running" } else { "waiting" };
+                trace.frames.push(Frame::synthetic(state.to_owned()));
+            }
+
             samples += 1;
-            output.increment(trace)?;
+            traces.push(trace);
+        }
+        output.increment(traces, sample.timestamp)?;
+
+        for (pid, stats) in &sample.gc_stats {
+            output.record_gc_stats(*pid, stats)?;
         }
+        for (pid, stats) in &sample.fd_stats {
+            output.record_fd_stats(*pid, stats)?;
+        }
+        for (pid, paused) in sample.pause_times {
+            *pause_times.entry(pid).or_default() += paused;
+        }
+        elapsed = sample.timestamp;
 
         if let Some(sampling_errors) = sample.sampling_errors {
             for (pid, e) in sampling_errors {
@@ -304,16 +565,53 @@ fn record_samples(pid: remoteprocess::Pid, config: &Config) -> Result<(), Error>
             progress.set_message(msg);
         }
         progress.inc(1);
+
+        if output.is_full() {
+            stop_reason = Some(StopReason::MaxEvents);
+            break;
+        }
     }
     progress.finish();
+
+    // otherwise the sampler ran out of samples, and knows why it stopped
+    let stop_reason = stop_reason
+        .or(*sampler_stop_reason.lock().unwrap())
+        .unwrap_or(StopReason::ProcessExited);
+
     // write out a message here (so as not to interfere with progress bar) if we ended earlier
-    if !exit_message.is_empty() {
-        println!("\n{}{}", lede, exit_message);
+    if stop_reason != StopReason::DurationReached {
+        println!("\n{}Stopped sampling because {}", lede, stop_reason);
     }
 
-    {
-        let mut out_file = std::fs::File::create(&filename)?;
-        output.write(&mut out_file)?;
+    let skipped_subprocesses = skipped_subprocesses.load(Ordering::SeqCst);
+    if skipped_subprocesses > 0 {
+        println!(
+            "{}{} subprocesses weren't sampled because of the --max-subprocesses limit",
+            lede, skipped_subprocesses
+        );
+    }
+
+    if config.sampling_stats {
+        output.record_sampling_stats(&sampling_stats)?;
+    }
+
+    match socket {
+        Some(mut socket) => {
+            output.write(&mut socket)?;
+            socket.flush()?;
+            if socket.disconnected() {
+                println!(
+                    "{}The consumer disconnected from '{}' during the capture, and some output was discarded",
+                    lede, filename
+                );
+            }
+        }
+        // the events have already been written to the file as they were recorded
+        None if streaming_to_file => output.write(&mut std::io::sink())?,
+        None => {
+            let mut out_file = std::fs::File::create(&filename)?;
+            output.write(&mut out_file)?;
+        }
     }
 
     match config.format.as_ref().unwrap() {
@@ -326,7 +624,9 @@ fn record_samples(pid: remoteprocess::Pid, config: &Config) -> Result<(), Error>
             // you might be SSH'ed into a server somewhere and this isn't desired, but on
             // that is pretty unlikely for osx) (note to self: xdg-open will open on linux)
             #[cfg(target_os = "macos")]
-            std::process::Command::new("open").arg(&filename).spawn()?;
+            if output::socket_path(&filename).is_none() {
+                std::process::Command::new("open").arg(&filename).spawn()?;
+            }
         }
         FileFormat::speedscope => {
             println!(
@@ -335,6 +635,13 @@ fn record_samples(pid: remoteprocess::Pid, config: &Config) -> Result<(), Error>
             );
             println!("{}Visit https://www.speedscope.app/ to view", lede);
         }
+        FileFormat::chrometrace => {
+            println!(
+                "{}Wrote chrome trace to '{}'. Samples: {} Errors: {}",
+                lede, filename, samples, errors
+            );
+            println!("{}Visit https://ui.perfetto.dev/ to view", lede);
+        }
         FileFormat::raw => {
             println!(
                 "{}Wrote raw flamegraph data to '{}'. Samples: {} Errors: {}",
@@ -342,11 +649,82 @@ fn record_samples(pid: remoteprocess::Pid, config: &Config) -> Result<(), Error>
             );
             println!("{}You can use the flamegraph.pl script from https://github.com/brendangregg/flamegraph to generate a SVG", lede);
         }
+        FileFormat::dot => {
+            println!(
+                "{}Wrote call graph to '{}'. Samples: {} Errors: {}",
+                lede, filename, samples, errors
+            );
+            println!(
+                "{}You can use graphviz to render this, like 'dot -Tsvg {} -o callgraph.svg'",
+                lede, filename
+            );
+        }
     };
 
+    if config.overhead_report {
+        print_overhead_report(&lede, config, &pause_times, elapsed);
+    }
+    if config.sampling_stats {
+        print_sampling_stats(&lede, &sampling_stats);
+    }
+
     Ok(())
 }
 
+/// Prints out how many stack traces couldn't be read while sampling, and why. The samples
+/// these would have been in are missing from the output
+fn print_sampling_stats(lede: &str, stats: &SamplingStats) {
+    println!(
+        "{}Sampling: {} of {} stack traces couldn't be read ({:.1}%)",
+        lede,
+        stats.failed(),
+        stats.attempts,
+        stats.failed_percent()
+    );
+    let mut failures: Vec<_> = stats.failures.iter().collect();
+    failures.sort_by_key(|(_, count)| std::cmp::Reverse(**count));
+    for (reason, count) in failures {
+        println!("{}    {} x {}", lede, count, reason);
+    }
+}
+
+/// Prints out how long each process was paused for while sampling. Python can't make any
+/// progress while paused, so the fraction of the time spent paused is an estimate of how
+/// much the profiling slowed the program down
+fn print_overhead_report(
+    lede: &str,
+    config: &Config,
+    pause_times: &HashMap<remoteprocess::Pid, Duration>,
+    elapsed: Duration,
+) {
+    if config.blocking == LockingStrategy::NonBlocking {
+        println!(
+            "{}Overhead: processes weren't paused because of --nonblocking",
+            lede
+        );
+        return;
+    }
+
+    let mut pids: Vec<_> = pause_times.keys().collect();
+    pids.sort();
+    for pid in pids {
+        let paused = pause_times[pid];
+        let slowdown = if elapsed.is_zero() {
+            0.0
+        } else {
+            100.0 * paused.as_secs_f64() / elapsed.as_secs_f64()
+        };
+        println!(
+            "{}Overhead: process {} was paused for {:.1}ms over {:.1}s of sampling, an estimated {:.2}% slowdown",
+            lede,
+            pid,
+            paused.as_secs_f64() * 1000.0,
+            elapsed.as_secs_f64(),
+            slowdown
+        );
+    }
+}
+
 fn run_spy_command(pid: remoteprocess::Pid, config: &config::Config) -> Result<(), Error> {
     match config.command.as_ref() {
         "dump" => {
@@ -358,6 +736,17 @@ fn run_spy_command(pid: remoteprocess::Pid, config: &config::Config) -> Result<(
         "top" => {
             sample_console(pid, config)?;
         }
+        "check" => {
+            let report = check::check_process(pid, config)?;
+            report.print();
+            if let Some(error) = report.error {
+                return Err(format_err!(
+                    "py-spy can't profile process {}: {}",
+                    pid,
+                    error
+                ));
+            }
+        }
         _ => {
             // shouldn't happen
             return Err(format_err!("Unknown command {}", config.command));
@@ -369,6 +758,15 @@ fn run_spy_command(pid: remoteprocess::Pid, config: &config::Config) -> Result<(
 fn pyspy_main() -> Result<(), Error> {
     let config = config::Config::from_commandline();
 
+    // this only reads a file, so doesn't need a process or elevated permissions
+    if let Some(ref filename) = config.symbols_filename {
+        let dump = binary_parser::dump_symbols(std::path::Path::new(filename))?;
+        let mut stdout = std::io::stdout().lock();
+        serde_json::to_writer_pretty(&mut stdout, &dump)?;
+        writeln!(stdout)?;
+        return Ok(());
+    }
+
     #[cfg(target_os = "macos")]
     {
         if unsafe { libc::geteuid() } != 0 {
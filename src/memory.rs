@@ -0,0 +1,213 @@
+#[cfg(target_os = "linux")]
+use std::fs::File;
+
+#[cfg(target_os = "linux")]
+use anyhow::Context;
+use anyhow::Error;
+use remoteprocess::{Pid, Process, ProcessMemory};
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use crate::config::Config;
+
+/// Where to read the memory of the python process from
+pub enum MemorySource {
+    /// Reads memory with the OS apis for the process (like process_vm_readv on linux),
+    /// which needs permission to ptrace the process
+    Process(Process),
+    /// Reads memory from an already opened file descriptor for /proc/<pid>/mem. This lets
+    /// a privileged helper open the file and then drop privileges before running py-spy
+    #[cfg(target_os = "linux")]
+    File(File),
+}
+
+impl MemorySource {
+    #[cfg_attr(not(target_os = "linux"), allow(unused_variables))]
+    pub fn new(pid: Pid, config: &Config) -> Result<MemorySource, Error> {
+        #[cfg(target_os = "linux")]
+        if let Some(fd) = config.memory_fd {
+            return MemorySource::from_fd(fd);
+        }
+        Ok(MemorySource::Process(Process::new(pid)?))
+    }
+
+    #[cfg(target_os = "linux")]
+    fn from_fd(fd: i32) -> Result<MemorySource, Error> {
+        use std::os::fd::BorrowedFd;
+
+        if fd < 0 {
+            return Err(format_err!("Invalid memory file descriptor {}", fd));
+        }
+        if unsafe { libc::fcntl(fd, libc::F_GETFD) } == -1 {
+            return Err(Error::new(std::io::Error::last_os_error()))
+                .with_context(|| format!("Invalid memory file descriptor {}", fd));
+        }
+
+        // Duplicate the descriptor rather than taking ownership of it, since we can create
+        // several PythonSpy objects for a process (like when retrying on startup), and each of
+        // these closes its file when dropped.
+        // SAFETY: borrow_raw needs the fd to be open for as long as it's borrowed, which was
+        // checked above and holds since the borrow only lasts until it has been duplicated
+        let fd = unsafe { BorrowedFd::borrow_raw(fd) }
+            .try_clone_to_owned()
+            .with_context(|| format!("Failed to use memory file descriptor {}", fd))?;
+        Ok(MemorySource::File(File::from(fd)))
+    }
+}
+
+impl ProcessMemory for MemorySource {
+    fn read(&self, addr: usize, buf: &mut [u8]) -> Result<(), remoteprocess::Error> {
+        match self {
+            MemorySource::Process(process) => process.read(addr, buf),
+            #[cfg(target_os = "linux")]
+            MemorySource::File(file) => {
+                use std::os::unix::fs::FileExt;
+                file.read_exact_at(buf, addr as u64)
+                    .map_err(remoteprocess::Error::IOError)
+            }
+        }
+    }
+}
+
+/// Reads memory from another source in aligned blocks, and answers later reads that fall
+/// inside an already read block from its copy. Walking a stack reads lots of small structs that
+/// are close together, so this cuts down on the number of syscalls needed for each sample.
+///
+/// Since the blocks are never refreshed this should only be used for a single sample.
+pub struct BatchedMemory<'a, P: ProcessMemory> {
+    source: &'a P,
+    block_size: usize,
+    // blocks that couldn't be read (like when the block extends into unmapped memory) are
+    // stored as None, so that reads inside of them go directly to the source
+    blocks: RefCell<HashMap<usize, Option<Vec<u8>>>>,
+}
+
+impl<'a, P: ProcessMemory> BatchedMemory<'a, P> {
+    /// Creates a new reader, where a block_size of 0 disables batching
+    pub fn new(source: &'a P, block_size: usize) -> BatchedMemory<'a, P> {
+        BatchedMemory {
+            source,
+            block_size,
+            blocks: RefCell::new(HashMap::new()),
+        }
+    }
+
+    fn read_blocks(&self, addr: usize, buf: &mut [u8]) -> bool {
+        let mut blocks = self.blocks.borrow_mut();
+        let mut offset = 0;
+        while offset < buf.len() {
+            let current = addr + offset;
+            let start = current - current % self.block_size;
+            let block = blocks
+                .entry(start)
+                .or_insert_with(|| self.source.copy(start, self.block_size).ok());
+            let block = match block {
+                Some(block) => block,
+                None => return false,
+            };
+            let begin = current - start;
+            let count = (self.block_size - begin).min(buf.len() - offset);
+            buf[offset..offset + count].copy_from_slice(&block[begin..begin + count]);
+            offset += count;
+        }
+        true
+    }
+}
+
+impl<'a, P: ProcessMemory> ProcessMemory for BatchedMemory<'a, P> {
+    fn read(&self, addr: usize, buf: &mut [u8]) -> Result<(), remoteprocess::Error> {
+        // large reads (like copying strings or bytecode) don't benefit from batching
+        if self.block_size == 0 || buf.len() > self.block_size || !self.read_blocks(addr, buf) {
+            return self.source.read(addr, buf);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use remoteprocess::LocalProcess;
+    use std::cell::Cell;
+
+    struct CountingMemory(Cell<usize>);
+
+    impl ProcessMemory for CountingMemory {
+        fn read(&self, addr: usize, buf: &mut [u8]) -> Result<(), remoteprocess::Error> {
+            self.0.set(self.0.get() + 1);
+            LocalProcess.read(addr, buf)
+        }
+    }
+
+    // page aligned, so that reading in blocks doesn't touch memory outside of this
+    #[repr(C, align(4096))]
+    struct Pages([u64; 1024]);
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_invalid_memory_fd() {
+        assert!(MemorySource::from_fd(-1).is_err());
+
+        // an fd that isn't open, which is above the default limit on open files
+        let err = MemorySource::from_fd(1 << 24).err().unwrap();
+        assert!(format!("{:#}", err).contains("Bad file descriptor"));
+
+        let file = File::open("/proc/self/mem").unwrap();
+        let fd = std::os::fd::AsRawFd::as_raw_fd(&file);
+        assert!(MemorySource::from_fd(fd).is_ok());
+    }
+
+    #[test]
+    fn test_batched_memory() {
+        let mut pages = Box::new(Pages([0; 1024]));
+        for (i, value) in pages.0.iter_mut().enumerate() {
+            *value = i as u64;
+        }
+        let values = &pages.0;
+        let source = CountingMemory(Cell::new(0));
+        let memory = BatchedMemory::new(&source, 4096);
+
+        for value in values.iter() {
+            let copied: u64 = memory.copy_struct(value as *const u64 as usize).unwrap();
+            assert_eq!(copied, *value);
+        }
+        // the 8KB of values spans exactly 2 blocks
+        assert_eq!(source.0.get(), 2);
+
+        // reads straddling two blocks should be stitched back together
+        let boundary = values.as_ptr() as usize + 4096;
+        let straddled: [u64; 2] = memory.copy_struct(boundary - 8).unwrap();
+        assert_eq!(straddled, [511, 512]);
+
+        // a block size of 0 should pass everything through
+        let source = CountingMemory(Cell::new(0));
+        let memory = BatchedMemory::new(&source, 0);
+        for value in values.iter().take(16) {
+            memory
+                .copy_struct::<u64>(value as *const u64 as usize)
+                .unwrap();
+        }
+        assert_eq!(source.0.get(), 16);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_read_from_fd() {
+        use std::os::fd::AsRawFd;
+
+        let value: u64 = 0x1234_5678_9abc_def0;
+        let mem = File::open("/proc/self/mem").unwrap();
+        let config = Config {
+            memory_fd: Some(mem.as_raw_fd()),
+            ..Default::default()
+        };
+
+        let source = MemorySource::new(std::process::id() as Pid, &config).unwrap();
+        let copied: u64 = source.copy_struct(&value as *const u64 as usize).unwrap();
+        assert_eq!(copied, value);
+
+        // the descriptor we were given should still be usable after we're done with it
+        drop(source);
+        assert!(MemorySource::new(std::process::id() as Pid, &config).is_ok());
+    }
+}
@@ -1,21 +1,26 @@
 use anyhow::Error;
 use std::collections::HashSet;
 use std::num::NonZeroUsize;
+use std::path::Path;
+use std::sync::Arc;
 
 use cpp_demangle::{BorrowedSymbol, DemangleOptions};
 use lazy_static::lazy_static;
 use lru::LruCache;
+use proc_maps::get_process_maps;
+use regex::Regex;
 use remoteprocess::{self, Pid};
 
 use crate::binary_parser::BinaryInfo;
+use crate::config::Demangle;
 use crate::cython;
-use crate::stack_trace::Frame;
+use crate::stack_trace::{Frame, FrameKind};
 use crate::utils::resolve_filename;
 
 pub struct NativeStack {
     should_reload: bool,
-    python: Option<BinaryInfo>,
-    libpython: Option<BinaryInfo>,
+    python: Option<Arc<BinaryInfo>>,
+    libpython: Option<Arc<BinaryInfo>>,
     cython_maps: cython::SourceMaps,
     unwinder: remoteprocess::Unwinder,
     symbolicator: remoteprocess::Symbolicator,
@@ -23,13 +28,25 @@ pub struct NativeStack {
     #[allow(dead_code)]
     process: remoteprocess::Process,
     symbol_cache: LruCache<u64, remoteprocess::StackFrame>,
+    demangle: Demangle,
+    exclude_patterns: Vec<Regex>,
+    excluded: Vec<ExcludedRange>,
+}
+
+/// The address range of a library that we don't unwind native stacks through
+struct ExcludedRange {
+    start: u64,
+    end: u64,
+    filename: String,
 }
 
 impl NativeStack {
     pub fn new(
         pid: Pid,
-        python: Option<BinaryInfo>,
-        libpython: Option<BinaryInfo>,
+        python: Option<Arc<BinaryInfo>>,
+        libpython: Option<Arc<BinaryInfo>>,
+        demangle: Demangle,
+        native_exclude: &[String],
     ) -> Result<NativeStack, Error> {
         let cython_maps = cython::SourceMaps::new();
 
@@ -37,6 +54,9 @@ impl NativeStack {
         let unwinder = process.unwinder()?;
         let symbolicator = process.symbolicator()?;
 
+        let exclude_patterns: Vec<Regex> = native_exclude.iter().map(|p| glob_regex(p)).collect();
+        let excluded = get_excluded_ranges(pid, &exclude_patterns)?;
+
         Ok(NativeStack {
             cython_maps,
             unwinder,
@@ -46,6 +66,9 @@ impl NativeStack {
             libpython,
             process,
             symbol_cache: LruCache::new(NonZeroUsize::new(65536).unwrap()),
+            demangle,
+            exclude_patterns,
+            excluded,
         })
     }
 
@@ -56,19 +79,25 @@ impl NativeStack {
     ) -> Result<Vec<Frame>, Error> {
         if self.should_reload {
             self.symbolicator.reload()?;
+            // newly loaded libraries could also need excluding
+            self.excluded = get_excluded_ranges(self.process.pid, &self.exclude_patterns)?;
             self.should_reload = false;
         }
 
         // get the native stack from the thread
-        let native_stack = self.get_thread(thread)?;
+        let (native_stack, excluded) = self.get_thread(thread)?;
 
         // TODO: merging the two stack together could happen outside of thread lock
-        self.merge_native_stack(frames, native_stack)
+        self.merge_native_stack(frames, native_stack, excluded)
     }
+
+    /// Merges the python frames into the native stack. If unwinding stopped early at an
+    /// excluded library, the frame for that library is passed in as 'excluded'
     pub fn merge_native_stack(
         &mut self,
         frames: &Vec<Frame>,
         native_stack: Vec<u64>,
+        excluded: Option<Frame>,
     ) -> Result<Vec<Frame>, Error> {
         let mut python_frame_index = 0;
         let mut merged = Vec::new();
@@ -141,6 +170,9 @@ impl NativeStack {
                         short_filename: None,
                         module: None,
                         locals: None,
+                        is_native: true,
+                        frame_kind: FrameKind::Regular,
+                        code_id: None,
                     });
                 });
 
@@ -149,6 +181,16 @@ impl NativeStack {
             }
         }
 
+        // We don't know where the remaining python frames are in the stack when we stopped
+        // unwinding at an excluded library, but they have to be called before it
+        if let Some(excluded) = excluded {
+            merged.push(excluded);
+            if python_frame_index < frames.len() {
+                merged.extend_from_slice(&frames[python_frame_index..]);
+                python_frame_index = frames.len();
+            }
+        }
+
         if python_frame_index != frames.len() {
             if python_frame_index == 0 {
                 // I've seen a problem come up a bunch where we only get 1-2 native stack traces and then it fails
@@ -260,20 +302,16 @@ impl NativeStack {
                     None => frame.module.clone(),
                 };
 
-                let mut demangled = None;
-                if func.starts_with('_') {
-                    if let Ok((sym, _)) = BorrowedSymbol::with_tail(func.as_bytes()) {
-                        let options = DemangleOptions::new().no_params().no_return_type();
-                        if let Ok(sym) = sym.demangle(&options) {
-                            demangled = Some(sym);
-                        }
-                    }
-                }
+                let demangled = demangle(func, self.demangle);
                 let name = demangled.as_ref().unwrap_or(func);
                 if cython::ignore_frame(name) {
                     return None;
                 }
-                let name = cython::demangle(name).to_owned();
+                // with demangling off, leave the cython names alone too
+                let name = match self.demangle {
+                    Demangle::Off => name.to_owned(),
+                    _ => cython::demangle(name).to_owned(),
+                };
                 Some(Frame {
                     filename,
                     line,
@@ -281,6 +319,9 @@ impl NativeStack {
                     short_filename: None,
                     module: Some(frame.module.clone()),
                     locals: None,
+                    is_native: true,
+                    frame_kind: FrameKind::Regular,
+                    code_id: None,
                 })
             }
             None => Some(Frame {
@@ -290,19 +331,134 @@ impl NativeStack {
                 line: 0,
                 short_filename: None,
                 module: Some(frame.module.clone()),
+                is_native: true,
+                frame_kind: FrameKind::Regular,
+                code_id: None,
             }),
         }
     }
 
-    fn get_thread(&mut self, thread: &remoteprocess::Thread) -> Result<Vec<u64>, Error> {
+    /// Unwinds the native stack for a thread, stopping if we reach an excluded library. Returns
+    /// the instruction pointers and a frame for the excluded library if we stopped there
+    fn get_thread(
+        &mut self,
+        thread: &remoteprocess::Thread,
+    ) -> Result<(Vec<u64>, Option<Frame>), Error> {
         let mut stack = Vec::new();
         for ip in self.unwinder.cursor(thread)? {
-            stack.push(ip?);
+            let ip = ip?;
+            if let Some(range) = self
+                .excluded
+                .iter()
+                .find(|range| range.start <= ip && ip < range.end)
+            {
+                return Ok((stack, Some(range.to_frame())));
+            }
+            stack.push(ip);
+        }
+        Ok((stack, None))
+    }
+}
+
+impl ExcludedRange {
+    fn to_frame(&self) -> Frame {
+        // show 'libfoo' for '/usr/lib/libfoo.so.1'
+        let name = Path::new(&self.filename)
+            .file_name()
+            .map(|name| name.to_string_lossy())
+            .unwrap_or_default();
+        let name = name.split('.').next().unwrap_or_default();
+        Frame {
+            name: format!("<{}>", name),
+            filename: self.filename.clone(),
+            module: Some(self.filename.clone()),
+            short_filename: None,
+            line: 0,
+            locals: None,
+            is_native: true,
+            frame_kind: FrameKind::Regular,
+            code_id: None,
+        }
+    }
+}
+
+/// Finds the memory ranges of the libraries loaded by the process that match any of the patterns
+fn get_excluded_ranges(pid: Pid, patterns: &[Regex]) -> Result<Vec<ExcludedRange>, Error> {
+    if patterns.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut ranges = Vec::new();
+    for map in get_process_maps(pid)? {
+        let filename = match map.filename() {
+            Some(filename) => filename,
+            None => continue,
+        };
+        if patterns.iter().any(|p| glob_matches(p, filename)) {
+            ranges.push(ExcludedRange {
+                start: map.start() as u64,
+                end: (map.start() + map.size()) as u64,
+                filename: filename.to_string_lossy().to_string(),
+            });
+        }
+    }
+    Ok(ranges)
+}
+
+/// Converts a glob pattern like 'libfoo.*' into a regex. Only '*' and '?' are special
+fn glob_regex(pattern: &str) -> Regex {
+    let mut regex = String::from("^");
+    for c in pattern.chars() {
+        match c {
+            '*' => regex.push_str(".*"),
+            '?' => regex.push('.'),
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex.push('$');
+    Regex::new(&regex).expect("escaped glob should be a valid regex")
+}
+
+/// Matches the full path when the pattern has a path separator in it, and otherwise just the
+/// filename of the library
+fn glob_matches(pattern: &Regex, path: &Path) -> bool {
+    if pattern.as_str().contains('/') {
+        return pattern.is_match(&path.to_string_lossy());
+    }
+    path.file_name()
+        .is_some_and(|name| pattern.is_match(&name.to_string_lossy()))
+}
+
+/// Demangles a native symbol name, returning None if the name couldn't be demangled
+/// (or if demangling is turned off)
+fn demangle(func: &str, mode: Demangle) -> Option<String> {
+    match mode {
+        Demangle::Off => None,
+        Demangle::Rust => demangle_rust(func),
+        Demangle::Cpp => demangle_cpp(func),
+        Demangle::Auto => {
+            // only consider names that look mangled, otherwise we can end up 'demangling'
+            // plain C symbols into garbage. legacy rust symbols are also valid itanium
+            // symbols, so try rust first to get rid of the trailing hash
+            if !func.starts_with('_') {
+                return None;
+            }
+            demangle_rust(func).or_else(|| demangle_cpp(func))
         }
-        Ok(stack)
     }
 }
 
+fn demangle_rust(func: &str) -> Option<String> {
+    rustc_demangle::try_demangle(func)
+        .ok()
+        .map(|sym| format!("{:#}", sym))
+}
+
+fn demangle_cpp(func: &str) -> Option<String> {
+    let (sym, _) = BorrowedSymbol::with_tail(func.as_bytes()).ok()?;
+    let options = DemangleOptions::new().no_params().no_return_type();
+    sym.demangle(&options).ok()
+}
+
 #[derive(Debug)]
 enum MergeType {
     Ignore,
@@ -358,3 +514,62 @@ fn ignore_frame(function: &str, module: &str) -> bool {
 
     false
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob_matches() {
+        let pattern = glob_regex("libfoo.*");
+        assert!(glob_matches(&pattern, Path::new("/opt/vendor/libfoo.so.1")));
+        assert!(!glob_matches(
+            &pattern,
+            Path::new("/opt/vendor/libfoobar.so")
+        ));
+        assert!(!glob_matches(
+            &pattern,
+            Path::new("/opt/libfoo.d/libbar.so")
+        ));
+
+        let pattern = glob_regex("/opt/vendor/*");
+        assert!(glob_matches(&pattern, Path::new("/opt/vendor/libbar.so")));
+        assert!(!glob_matches(&pattern, Path::new("/usr/lib/libbar.so")));
+
+        let range = ExcludedRange {
+            start: 0x1000,
+            end: 0x2000,
+            filename: "/opt/vendor/libfoo.so.1".to_owned(),
+        };
+        assert_eq!(range.to_frame().name, "<libfoo>");
+    }
+
+    #[test]
+    fn test_demangle() {
+        let auto = |func| demangle(func, Demangle::Auto);
+
+        // legacy rust symbols should have their hash stripped, rather than being treated as c++
+        assert_eq!(
+            auto("_ZN4core3fmt5write17h0123456789abcdefE").as_deref(),
+            Some("core::fmt::write")
+        );
+        assert_eq!(
+            auto("_RNvNtC7mycrate6module8function").as_deref(),
+            Some("mycrate::module::function")
+        );
+        assert_eq!(
+            auto("_ZNSt6vectorIiSaIiEE9push_backERKi").as_deref(),
+            Some("std::vector<int, std::allocator<int> >::push_back")
+        );
+
+        // plain C symbols are left alone, even when they start with an underscore
+        assert_eq!(auto("PyEval_EvalFrameDefault"), None);
+        assert_eq!(auto("_PyEval_EvalFrameDefault"), None);
+
+        assert_eq!(demangle("_ZN5boost3fooEv", Demangle::Off), None);
+        assert_eq!(
+            demangle("_ZN5boost3fooEv", Demangle::Cpp).as_deref(),
+            Some("boost::foo")
+        );
+    }
+}
@@ -0,0 +1,140 @@
+use std::io::{ErrorKind, Write};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use anyhow::Error;
+
+/// Returns the path of the unix domain socket to write output to, for outputs given
+/// like 'unix:/run/pyspy.sock'
+pub fn socket_path(output: &str) -> Option<&str> {
+    output.strip_prefix("unix:")
+}
+
+/// Writes output to a unix domain socket, so that it can be ingested live by another process.
+///
+/// If the consumer on the other end goes away, the rest of the output is discarded rather
+/// than returning an error, so that the capture can still finish.
+pub struct SocketWriter {
+    path: String,
+    #[cfg(unix)]
+    stream: UnixStream,
+    disconnected: Arc<AtomicBool>,
+}
+
+impl SocketWriter {
+    #[cfg(unix)]
+    pub fn connect(path: &str) -> Result<SocketWriter, Error> {
+        let stream = UnixStream::connect(path)
+            .map_err(|e| format_err!("Failed to connect to socket '{}': {}", path, e))?;
+        Ok(SocketWriter {
+            path: path.to_owned(),
+            stream,
+            disconnected: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    #[cfg(not(unix))]
+    pub fn connect(path: &str) -> Result<SocketWriter, Error> {
+        Err(format_err!(
+            "Can't write to socket '{}': unix domain sockets aren't supported on this platform",
+            path
+        ))
+    }
+
+    /// Returns another writer for the same connection, which shares the disconnected state
+    pub fn try_clone(&self) -> Result<SocketWriter, Error> {
+        Ok(SocketWriter {
+            path: self.path.clone(),
+            #[cfg(unix)]
+            stream: self.stream.try_clone()?,
+            disconnected: self.disconnected.clone(),
+        })
+    }
+
+    /// Whether the consumer has closed its end of the socket
+    pub fn disconnected(&self) -> bool {
+        self.disconnected.load(Ordering::Relaxed)
+    }
+
+    fn check_disconnect<T>(&self, result: std::io::Result<T>, discarded: T) -> std::io::Result<T> {
+        match result {
+            Err(e) if is_disconnect(e.kind()) => {
+                if !self.disconnected.swap(true, Ordering::Relaxed) {
+                    warn!("Consumer disconnected from '{}': {}", self.path, e);
+                }
+                Ok(discarded)
+            }
+            result => result,
+        }
+    }
+}
+
+impl Write for SocketWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.disconnected() {
+            return Ok(buf.len());
+        }
+        #[cfg(unix)]
+        let result = self.stream.write(buf);
+        #[cfg(not(unix))]
+        let result = Ok(buf.len());
+        self.check_disconnect(result, buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        if self.disconnected() {
+            return Ok(());
+        }
+        #[cfg(unix)]
+        let result = self.stream.flush();
+        #[cfg(not(unix))]
+        let result = Ok(());
+        self.check_disconnect(result, ())
+    }
+}
+
+fn is_disconnect(kind: ErrorKind) -> bool {
+    matches!(
+        kind,
+        ErrorKind::BrokenPipe
+            | ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::NotConnected
+    )
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::os::unix::net::UnixListener;
+
+    #[test]
+    fn test_socket_writer() {
+        assert_eq!(socket_path("unix:/run/pyspy.sock"), Some("/run/pyspy.sock"));
+        assert_eq!(socket_path("profile.json"), None);
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("pyspy.sock");
+        let listener = UnixListener::bind(&path).unwrap();
+
+        let mut writer = SocketWriter::connect(path.to_str().unwrap()).unwrap();
+        let (mut consumer, _) = listener.accept().unwrap();
+        writer.write_all(b"hello\n").unwrap();
+        let mut buf = [0; 6];
+        consumer.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello\n");
+
+        // writes should keep succeeding once the consumer has gone away
+        drop(consumer);
+        let mut clone = writer.try_clone().unwrap();
+        for _ in 0..16 {
+            writer.write_all(&[0; 4096]).unwrap();
+        }
+        assert!(writer.disconnected());
+        assert!(clone.disconnected());
+        clone.write_all(b"discarded").unwrap();
+    }
+}
@@ -78,6 +78,41 @@ pub mod pyruntime {
         }
     }
 
+    // Offsets of the 'initialized' and 'finalizing' members of PyRuntime, which like the
+    // interpreter head come before any OS specific members. Returns None when the layout
+    // isn't known (like for the early 3.8 alphas)
+    #[cfg(not(target_arch = "arm"))]
+    pub fn get_lifecycle_offsets(version: &Version) -> Option<(usize, usize)> {
+        let pointer_size = std::mem::size_of::<usize>();
+        match version {
+            Version {
+                major: 3, minor: 7, ..
+            } => Some((0, 8)),
+            Version {
+                major: 3,
+                minor: 8,
+                patch: 0,
+                ..
+            } if matches!(version.release_flags.as_ref(), "a1" | "a2") => None,
+            Version {
+                major: 3,
+                minor: 8..=10,
+                ..
+            } => Some((12, 16)),
+            Version {
+                major: 3,
+                minor: 11,
+                ..
+            } => Some((16, 16 + pointer_size)),
+            _ => None,
+        }
+    }
+
+    #[cfg(target_arch = "arm")]
+    pub fn get_lifecycle_offsets(_version: &Version) -> Option<(usize, usize)> {
+        None
+    }
+
     // getting gilstate.tstate_current is different for all OS
     // and is also different for each python version, and even
     // between v3.8.0a1 and v3.8.0a2 =(
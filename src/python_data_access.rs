@@ -24,20 +24,19 @@ pub fn copy_string<T: StringObject, P: ProcessMemory>(
 
     let bytes = process.copy(obj.address(ptr as usize), obj.size() * kind as usize)?;
 
+    // each character is stored as a code point in 1, 2 or 4 bytes, depending on the largest
+    // one in the string: https://www.python.org/dev/peps/pep-0393/. Strings can hold lone
+    // surrogates (like from undecodable bytes in filenames), which aren't valid in utf8
+    let decode = |c: u32| char::from_u32(c).unwrap_or(char::REPLACEMENT_CHARACTER);
     match (kind, obj.ascii()) {
-        (4, _) => {
-            #[allow(clippy::cast_ptr_alignment)]
-            let chars = unsafe {
-                std::slice::from_raw_parts(bytes.as_ptr() as *const char, bytes.len() / 4)
-            };
-            Ok(chars.iter().collect())
-        }
-        (2, _) => {
-            // UCS2 strings aren't used internally after v3.3: https://www.python.org/dev/peps/pep-0393/
-            // TODO: however with python 2.7 they could be added with --enable-unicode=ucs2 configure flag.
-            //            or with python 3.2 --with-wide-unicode=ucs2
-            Err(format_err!("ucs2 strings aren't supported yet!"))
-        }
+        (4, _) => Ok(bytes
+            .chunks_exact(4)
+            .map(|c| decode(u32::from_ne_bytes([c[0], c[1], c[2], c[3]])))
+            .collect()),
+        (2, _) => Ok(bytes
+            .chunks_exact(2)
+            .map(|c| decode(u16::from_ne_bytes([c[0], c[1]]) as u32))
+            .collect()),
         (1, true) => Ok(String::from_utf8(bytes)?),
         (1, false) => Ok(bytes.iter().map(|&b| b as char).collect()),
         _ => Err(format_err!("Unknown string kind {}", kind)),
@@ -57,6 +56,65 @@ pub fn copy_bytes<T: BytesObject, P: ProcessMemory>(
     Ok(process.copy(obj.address(ptr as usize), size as usize)?)
 }
 
+/// Copies the '__name__' of the module that owns a globals dictionary. Modules set their name
+/// as one of the first entries in their dictionary, so this only looks through the first few
+/// entries rather than the whole dictionary (which can be large)
+pub fn copy_module_name<I: InterpreterState, P: ProcessMemory>(
+    process: &P,
+    version: &Version,
+    globals: usize,
+) -> Result<Option<String>, Error> {
+    // reading dictionaries is only supported on python 3.6+
+    if globals == 0 || version.major < 3 || (version.major == 3 && version.minor < 6) {
+        return Ok(None);
+    }
+    for entry in DictIterator::from(process, version, globals)?.take(8) {
+        let (key, value) = entry?;
+        if copy_string(key as *const I::StringObject, process)? == "__name__" {
+            return Ok(Some(copy_string(value as *const I::StringObject, process)?));
+        }
+    }
+    Ok(None)
+}
+
+/// Returns the name of the type of a python object (truncated to 128 bytes if longer)
+pub fn copy_type_name<I: InterpreterState, P: ProcessMemory>(
+    process: &P,
+    addr: usize,
+) -> Result<String, Error> {
+    let value: I::Object = process.copy_struct(addr)?;
+    let value_type = process.copy_pointer(value.ob_type())?;
+    let max_type_len = 128;
+    let name = process.copy(value_type.name() as usize, max_type_len)?;
+    let length = name.iter().position(|&x| x == 0).unwrap_or(max_type_len);
+    Ok(std::str::from_utf8(&name[..length])?.to_owned())
+}
+
+/// Returns an iterator over the '__dict__' of an object, or None if the object doesn't
+/// have one. Only supports python 3.6+
+pub fn instance_dict<'a, I: InterpreterState, P: ProcessMemory>(
+    process: &'a P,
+    version: &'a Version,
+    addr: usize,
+) -> Result<Option<DictIterator<'a, P>>, Error> {
+    let value: I::Object = process.copy_struct(addr)?;
+    let value_type = process.copy_pointer(value.ob_type())?;
+    if value_type.flags() & PY_TPFLAGS_MANAGED_DICT != 0 {
+        let dict =
+            DictIterator::from_managed_dict(process, version, addr, value.ob_type() as usize)?;
+        return Ok(Some(dict));
+    }
+    let dict_offset = value_type.dictoffset();
+    if dict_offset == 0 {
+        return Ok(None);
+    }
+    let dict_addr: usize = process.copy_struct((addr as isize + dict_offset) as usize)?;
+    if dict_addr == 0 {
+        return Ok(None);
+    }
+    Ok(Some(DictIterator::from(process, version, dict_addr)?))
+}
+
 /// Copies a i64 from a PyLongObject. Returns the value + if it overflowed
 pub fn copy_long<P: ProcessMemory>(process: &P, addr: usize) -> Result<(i64, bool), Error> {
     // this is PyLongObject for a specific version of python, but this works since it's binary compatible
@@ -410,7 +468,7 @@ pub mod tests {
     // and then test out that the above code handles appropriately
     use super::*;
     use crate::python_bindings::v3_7_0::{
-        PyASCIIObject, PyBytesObject, PyUnicodeObject, PyVarObject,
+        PyASCIIObject, PyBytesObject, PyCompactUnicodeObject, PyUnicodeObject, PyVarObject,
     };
     use remoteprocess::LocalProcess;
     use std::ptr::copy_nonoverlapping;
@@ -475,6 +533,39 @@ pub mod tests {
         ret
     }
 
+    #[allow(dead_code)]
+    #[repr(C)]
+    pub struct AllocatedPyCompactUnicodeObject {
+        pub base: PyCompactUnicodeObject,
+        pub storage: [u8; 4096],
+    }
+
+    /// Creates a compact string that isn't ascii, stored with the smallest kind that holds
+    /// all of its characters like python does
+    pub fn to_unicodeobject(input: &str) -> AllocatedPyCompactUnicodeObject {
+        let chars: Vec<u32> = input.chars().map(|c| c as u32).collect();
+        let kind = match chars.iter().max() {
+            Some(&c) if c > 0xffff => 4,
+            Some(&c) if c > 0xff => 2,
+            _ => 1,
+        };
+        let bytes: Vec<u8> = chars
+            .iter()
+            .flat_map(|&c| c.to_ne_bytes()[..kind as usize].to_vec())
+            .collect();
+
+        let mut base = PyCompactUnicodeObject::default();
+        base._base.length = chars.len() as isize;
+        base._base.state.set_compact(1);
+        base._base.state.set_kind(kind);
+        let mut ret = AllocatedPyCompactUnicodeObject {
+            base,
+            storage: [0 as u8; 4096],
+        };
+        ret.storage[..bytes.len()].copy_from_slice(&bytes);
+        ret
+    }
+
     #[test]
     fn test_copy_string() {
         let original = "function_name";
@@ -483,6 +574,18 @@ pub mod tests {
         let unicode: &PyUnicodeObject = unsafe { std::mem::transmute(&obj.base) };
         let copied = copy_string(unicode, &LocalProcess).unwrap();
         assert_eq!(copied, original);
+
+        // latin-1, ucs2 and ucs4 strings
+        for original in [
+            "/srv/café/función.py",
+            "/srv/путь/模块.py",
+            "/srv/💩/módulo.py",
+        ] {
+            let obj = to_unicodeobject(original);
+            let unicode: &PyUnicodeObject = unsafe { std::mem::transmute(&obj.base) };
+            let copied = copy_string(unicode, &LocalProcess).unwrap();
+            assert_eq!(copied, original);
+        }
     }
 
     #[test]
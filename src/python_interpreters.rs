@@ -14,6 +14,7 @@ This means we can't dereference them directly.
 use crate::python_bindings::{
     v2_7_15, v3_10_0, v3_11_0, v3_3_7, v3_5_5, v3_6_6, v3_7_0, v3_8_0, v3_9_5,
 };
+use crate::stack_trace::GCStats;
 
 pub trait InterpreterState {
     type ThreadState: ThreadState;
@@ -23,6 +24,11 @@ pub trait InterpreterState {
     type TupleObject: TupleObject;
     fn head(&self) -> *mut Self::ThreadState;
     fn modules(&self) -> *mut Self::Object;
+    fn next(&self) -> *mut Self;
+    // interpreter ids were only added in python 3.7
+    fn id(&self) -> Option<i64>;
+    // the gc state moved onto the interpreter in python 3.9
+    fn gc_stats(&self) -> Option<GCStats>;
 }
 
 pub trait ThreadState {
@@ -39,6 +45,8 @@ pub trait ThreadState {
     fn thread_id(&self) -> u64;
     fn native_thread_id(&self) -> Option<u64>;
     fn next(&self) -> *mut Self;
+    // whether a trace function has been set for the thread with sys.settrace
+    fn tracing(&self) -> bool;
 }
 
 pub trait FrameObject {
@@ -47,6 +55,10 @@ pub trait FrameObject {
     fn code(&self) -> *mut Self::CodeObject;
     fn lasti(&self) -> i32;
     fn back(&self) -> *mut Self;
+    // the address of the globals dictionary, which holds the '__name__' of the module
+    fn globals(&self) -> usize;
+    // the address of the local trace function for the frame, or 0 if it isn't being traced
+    fn trace(&self) -> usize;
 }
 
 pub trait CodeObject {
@@ -60,6 +72,7 @@ pub trait CodeObject {
     fn first_lineno(&self) -> i32;
     fn nlocals(&self) -> i32;
     fn argcount(&self) -> i32;
+    fn flags(&self) -> i32;
     fn varnames(&self) -> *mut Self::TupleObject;
 
     fn get_line_number(&self, lasti: i32, table: &[u8]) -> i32;
@@ -107,7 +120,7 @@ fn offset_of<T, M>(object: *const T, member: *const M) -> usize {
 /// (this code is identical across python versions, we are only abstracting the struct layouts here).
 /// String handling changes substantially between python versions, and is handled separately.
 macro_rules! PythonCommonImpl {
-    ($py: ident, $stringobject: ident) => {
+    ($py: ident, $stringobject: ident $(, $id: ident)? $(; $gc: ident)?) => {
         impl InterpreterState for $py::PyInterpreterState {
             type ThreadState = $py::PyThreadState;
             type Object = $py::PyObject;
@@ -120,6 +133,15 @@ macro_rules! PythonCommonImpl {
             fn modules(&self) -> *mut Self::Object {
                 self.modules
             }
+            fn next(&self) -> *mut Self {
+                self.next
+            }
+            fn id(&self) -> Option<i64> {
+                PythonCommonImpl!(@id self $(, $id)?)
+            }
+            fn gc_stats(&self) -> Option<GCStats> {
+                PythonCommonImpl!(@gc self $(, $gc)?)
+            }
         }
 
         impl ThreadState for $py::PyThreadState {
@@ -143,6 +165,9 @@ macro_rules! PythonCommonImpl {
             fn interp(&self) -> *mut Self::InterpreterState {
                 self.interp
             }
+            fn tracing(&self) -> bool {
+                self.c_tracefunc.is_some()
+            }
         }
 
         impl FrameObject for $py::PyFrameObject {
@@ -157,6 +182,12 @@ macro_rules! PythonCommonImpl {
             fn back(&self) -> *mut Self {
                 self.f_back
             }
+            fn globals(&self) -> usize {
+                self.f_globals as usize
+            }
+            fn trace(&self) -> usize {
+                self.f_trace as usize
+            }
         }
 
         impl Object for $py::PyObject {
@@ -178,6 +209,25 @@ macro_rules! PythonCommonImpl {
             }
         }
     };
+    (@id $self: ident, $id: ident) => {
+        Some($self.$id)
+    };
+    (@id $self: ident) => {
+        None
+    };
+    (@gc $self: ident, $gc: ident) => {
+        Some(GCStats {
+            enabled: $self.$gc.enabled != 0,
+            collections: [
+                $self.$gc.generation_stats[0].collections as u64,
+                $self.$gc.generation_stats[1].collections as u64,
+                $self.$gc.generation_stats[2].collections as u64,
+            ],
+        })
+    };
+    (@gc $self: ident) => {
+        None
+    };
 }
 
 // We can use this up until python3.10 - where code object lnotab attribute is deprecated
@@ -206,6 +256,9 @@ macro_rules! PythonCodeObjectImpl {
             fn argcount(&self) -> i32 {
                 self.co_argcount
             }
+            fn flags(&self) -> i32 {
+                self.co_flags
+            }
             fn varnames(&self) -> *mut Self::TupleObject {
                 self.co_varnames as *mut Self::TupleObject
             }
@@ -314,6 +367,22 @@ impl InterpreterState for v3_11_0::PyInterpreterState {
     fn modules(&self) -> *mut Self::Object {
         self.modules
     }
+    fn next(&self) -> *mut Self {
+        self.next
+    }
+    fn id(&self) -> Option<i64> {
+        Some(self.id)
+    }
+    fn gc_stats(&self) -> Option<GCStats> {
+        Some(GCStats {
+            enabled: self.gc.enabled != 0,
+            collections: [
+                self.gc.generation_stats[0].collections as u64,
+                self.gc.generation_stats[1].collections as u64,
+                self.gc.generation_stats[2].collections as u64,
+            ],
+        })
+    }
 }
 
 impl ThreadState for v3_11_0::PyThreadState {
@@ -340,6 +409,9 @@ impl ThreadState for v3_11_0::PyThreadState {
     fn interp(&self) -> *mut Self::InterpreterState {
         self.interp
     }
+    fn tracing(&self) -> bool {
+        self.c_tracefunc.is_some()
+    }
 }
 
 impl FrameObject for v3_11_0::_PyInterpreterFrame {
@@ -357,6 +429,14 @@ impl FrameObject for v3_11_0::_PyInterpreterFrame {
     fn back(&self) -> *mut Self {
         self.previous
     }
+    fn globals(&self) -> usize {
+        self.f_globals as usize
+    }
+    fn trace(&self) -> usize {
+        // the trace function moved onto the frame object in python 3.11, which is only
+        // created on demand. The thread state still tells us if a trace function is set
+        0
+    }
 }
 
 impl Object for v3_11_0::PyObject {
@@ -426,59 +506,68 @@ impl CodeObject for v3_11_0::PyCodeObject {
     fn argcount(&self) -> i32 {
         self.co_argcount
     }
+    fn flags(&self) -> i32 {
+        self.co_flags
+    }
     fn varnames(&self) -> *mut Self::TupleObject {
         self.co_localsplusnames as *mut Self::TupleObject
     }
 
     fn get_line_number(&self, lasti: i32, table: &[u8]) -> i32 {
-        // unpack compressed table format from python 3.11
-        // https://github.com/python/cpython/pull/91666/files
         let lasti = lasti - offset_of(self, &self.co_code_adaptive) as i32;
-        let mut line_number: i32 = self.first_lineno();
-        let mut bytecode_address: i32 = 0;
+        location_table_line_number(table, self.first_lineno(), lasti)
+    }
+}
 
-        let mut index: usize = 0;
-        loop {
-            if index >= table.len() {
-                break;
-            }
-            let byte = table[index];
-            index += 1;
-
-            let delta = ((byte & 7) as i32) + 1;
-            bytecode_address += delta * 2;
-            let code = (byte >> 3) & 15;
-            let line_delta = match code {
-                15 => 0,
-                14 => {
-                    let delta = read_signed_varint(&mut index, table);
-                    read_varint(&mut index, table); // end line
-                    read_varint(&mut index, table); // start column
-                    read_varint(&mut index, table); // end column
-                    delta
-                }
-                13 => read_signed_varint(&mut index, table),
-                10..=12 => {
-                    index += 2; // start column / end column
-                    (code - 10).into()
-                }
-                _ => {
-                    index += 1; // column
-                    0
-                }
-            };
-            line_number += line_delta as i32;
-            if bytecode_address >= lasti {
-                break;
+/// Returns the line number of the instruction at the byte offset 'lasti' into the bytecode,
+/// using the compressed location table from python 3.11+. The table format is described in
+/// https://github.com/python/cpython/blob/3.11/Objects/locations.md and is unchanged in
+/// python 3.12 and 3.13 (which instead change how the current instruction is found)
+pub fn location_table_line_number(table: &[u8], first_lineno: i32, lasti: i32) -> i32 {
+    let mut line_number: i32 = first_lineno;
+    let mut bytecode_address: i32 = 0;
+
+    let mut index: usize = 0;
+    while index < table.len() {
+        let byte = table[index];
+        index += 1;
+
+        let delta = ((byte & 7) as i32) + 1;
+        bytecode_address += delta * 2;
+        let code = (byte >> 3) & 15;
+        let line_delta = match code {
+            // no location, we keep the previous line number for these
+            15 => 0,
+            14 => {
+                let delta = read_signed_varint(&mut index, table);
+                read_varint(&mut index, table); // end line
+                read_varint(&mut index, table); // start column
+                read_varint(&mut index, table); // end column
+                delta
+            }
+            13 => read_signed_varint(&mut index, table),
+            10..=12 => {
+                index += 2; // start column / end column
+                (code - 10).into()
+            }
+            _ => {
+                index += 1; // column
+                0
             }
+        };
+        line_number += line_delta as i32;
+
+        // each entry covers the instructions up to (but not including) bytecode_address
+        if bytecode_address > lasti {
+            break;
         }
-        line_number
     }
+    line_number
 }
 
 // Python 3.10
 Python3Impl!(v3_10_0);
-PythonCommonImpl!(v3_10_0, PyUnicodeObject);
+PythonCommonImpl!(v3_10_0, PyUnicodeObject, id; gc);
 
 impl CodeObject for v3_10_0::PyCodeObject {
     type BytesObject = v3_10_0::PyBytesObject;
@@ -503,6 +592,9 @@ impl CodeObject for v3_10_0::PyCodeObject {
     fn argcount(&self) -> i32 {
         self.co_argcount
     }
+    fn flags(&self) -> i32 {
+        self.co_flags
+    }
     fn varnames(&self) -> *mut Self::TupleObject {
         self.co_varnames as *mut Self::TupleObject
     }
@@ -540,17 +632,17 @@ impl CodeObject for v3_10_0::PyCodeObject {
 }
 
 // Python 3.9
-PythonCommonImpl!(v3_9_5, PyUnicodeObject);
+PythonCommonImpl!(v3_9_5, PyUnicodeObject, id; gc);
 PythonCodeObjectImpl!(v3_9_5, PyBytesObject, PyUnicodeObject);
 Python3Impl!(v3_9_5);
 
 // Python 3.8
-PythonCommonImpl!(v3_8_0, PyUnicodeObject);
+PythonCommonImpl!(v3_8_0, PyUnicodeObject, id);
 PythonCodeObjectImpl!(v3_8_0, PyBytesObject, PyUnicodeObject);
 Python3Impl!(v3_8_0);
 
 // Python 3.7
-PythonCommonImpl!(v3_7_0, PyUnicodeObject);
+PythonCommonImpl!(v3_7_0, PyUnicodeObject, id);
 PythonCodeObjectImpl!(v3_7_0, PyBytesObject, PyUnicodeObject);
 Python3Impl!(v3_7_0);
 
@@ -634,4 +726,111 @@ mod tests {
         ];
         assert_eq!(code.get_line_number(214, &table), 5);
     }
+
+    // co_linetable for this function, starting at line 2:
+    //
+    // def f(a,
+    //       b):
+    //     x = a + b
+    //     if x > 10:
+    //         y = [i * 2
+    //              for i in range(x)]
+    //     else:
+    //         y = None
+    //
+    //     try:
+    //         z = x / b
+    //     except ZeroDivisionError:
+    //         z = 0
+    //     return (x,
+    //             y, z)
+    //
+    // with the line of each instruction (from co_lines, with 0 for instructions without a
+    // location) as compiled by each version of python
+    // python 3.10.13
+    const LINE_TABLE_3_10: [u8; 28] = [
+        8, 2, 8, 1, 6, 1, 6, 1, 8, 255, 4, 3, 2, 2, 12, 1, 12, 1, 8, 1, 2, 255, 2, 2, 4, 1, 4, 255,
+    ];
+    const LINES_3_10: [i32; 43] = [
+        4, 4, 4, 4, 5, 5, 5, 5, 6, 6, 6, 7, 7, 7, 6, 6, 6, 6, 9, 9, 11, 12, 12, 12, 12, 12, 12, 13,
+        13, 13, 13, 13, 13, 14, 14, 14, 14, 13, 15, 16, 16, 15, 15,
+    ];
+
+    // python 3.11.7
+    const LINE_TABLE_3_11: [u8; 135] = [
+        128, 0, 224, 8, 9, 136, 65, 137, 5, 128, 65, 216, 7, 8, 136, 50, 130, 118, 128, 118, 240,
+        2, 1, 13, 32, 240, 0, 1, 13, 32, 221, 22, 27, 152, 65, 145, 104, 148, 104, 240, 3, 1, 13,
+        32, 241, 0, 1, 13, 32, 244, 0, 1, 13, 32, 136, 1, 136, 1, 240, 6, 0, 13, 17, 136, 1, 240,
+        4, 3, 5, 14, 216, 12, 13, 144, 1, 137, 69, 136, 1, 136, 1, 248, 221, 11, 28, 240, 0, 1, 5,
+        14, 240, 0, 1, 5, 14, 240, 0, 1, 5, 14, 216, 12, 13, 136, 1, 136, 1, 136, 1, 240, 3, 1, 5,
+        14, 248, 248, 248, 224, 12, 13, 216, 12, 13, 136, 113, 240, 3, 1, 12, 18, 240, 0, 1, 5, 18,
+    ];
+    const LINES_3_11: [i32; 70] = [
+        2, 4, 4, 4, 4, 4, 5, 5, 5, 5, 5, 5, 6, 6, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 6, 6,
+        6, 6, 6, 6, 6, 6, 6, 6, 9, 9, 11, 12, 12, 12, 12, 12, 12, 0, 13, 13, 13, 13, 13, 13, 13,
+        13, 13, 14, 14, 14, 14, 13, 0, 0, 0, 15, 16, 16, 15, 15,
+    ];
+
+    // python 3.12.1
+    const LINE_TABLE_3_12: [u8; 123] = [
+        128, 0, 224, 8, 9, 136, 65, 137, 5, 128, 65, 216, 7, 8, 136, 50, 130, 118, 228, 22, 27,
+        152, 65, 147, 104, 246, 3, 1, 13, 32, 216, 17, 18, 240, 3, 0, 14, 15, 144, 17, 139, 85,
+        240, 0, 1, 13, 32, 136, 1, 241, 0, 1, 13, 32, 240, 6, 0, 13, 17, 136, 1, 240, 4, 3, 5, 14,
+        216, 12, 13, 144, 1, 137, 69, 136, 1, 240, 6, 0, 13, 14, 216, 12, 13, 136, 113, 240, 3, 1,
+        12, 18, 240, 0, 1, 5, 18, 249, 242, 19, 1, 13, 32, 248, 244, 14, 0, 12, 29, 242, 0, 1, 5,
+        14, 216, 12, 13, 138, 1, 240, 3, 1, 5, 14, 250,
+    ];
+    const LINES_3_12: [i32; 74] = [
+        2, 4, 4, 4, 4, 4, 5, 5, 5, 5, 5, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 6, 6, 6, 6, 6, 6, 6, 7, 6,
+        6, 6, 6, 6, 6, 6, 6, 6, 6, 9, 9, 11, 12, 12, 12, 12, 12, 15, 16, 16, 15, 15, 0, 0, 6, 6, 6,
+        0, 13, 13, 13, 13, 13, 13, 13, 13, 14, 14, 14, 14, 13, 0, 0, 0,
+    ];
+
+    // python 3.13.13
+    const LINE_TABLE_3_13: [u8; 122] = [
+        128, 0, 224, 8, 9, 137, 5, 128, 65, 216, 7, 8, 136, 50, 131, 118, 228, 22, 27, 152, 65,
+        148, 104, 243, 3, 1, 13, 32, 218, 22, 30, 144, 17, 240, 3, 0, 14, 15, 144, 17, 140, 85,
+        217, 22, 30, 240, 3, 0, 9, 10, 240, 0, 1, 13, 32, 136, 1, 240, 6, 0, 13, 17, 136, 1, 240,
+        4, 3, 5, 14, 216, 12, 13, 137, 69, 136, 1, 240, 6, 0, 13, 14, 216, 12, 13, 240, 3, 1, 12,
+        18, 240, 0, 1, 5, 18, 249, 242, 19, 1, 13, 32, 248, 244, 14, 0, 12, 29, 243, 0, 1, 5, 14,
+        216, 12, 13, 138, 1, 240, 3, 1, 5, 14, 250,
+    ];
+    const LINES_3_13: [i32; 76] = [
+        2, 4, 4, 4, 4, 5, 5, 5, 5, 5, 5, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 6, 6, 6, 6, 7, 7, 7, 7,
+        6, 6, 6, 6, 6, 6, 6, 7, 7, 6, 6, 6, 9, 9, 11, 12, 12, 12, 12, 15, 16, 15, 15, 0, 0, 6, 6,
+        6, 0, 13, 13, 13, 13, 13, 13, 13, 13, 13, 14, 14, 14, 14, 13, 0, 0, 0,
+    ];
+
+    #[test]
+    fn test_py3_10_line_table() {
+        let code = v3_10_0::PyCodeObject {
+            co_firstlineno: 2,
+            ..Default::default()
+        };
+        for (instruction, &line) in LINES_3_10.iter().enumerate() {
+            // python 3.10 passes in the instruction index rather than the byte offset
+            assert_eq!(
+                code.get_line_number(instruction as i32, &LINE_TABLE_3_10),
+                line
+            );
+        }
+    }
+
+    #[test]
+    fn test_location_table() {
+        let tables: [(&[u8], &[i32]); 3] = [
+            (&LINE_TABLE_3_11, &LINES_3_11),
+            (&LINE_TABLE_3_12, &LINES_3_12),
+            (&LINE_TABLE_3_13, &LINES_3_13),
+        ];
+        for (table, lines) in tables {
+            for (instruction, &line) in lines.iter().enumerate() {
+                if line == 0 {
+                    continue;
+                }
+                let offset = 2 * instruction as i32;
+                assert_eq!(location_table_line_number(table, 2, offset), line);
+            }
+        }
+    }
 }
@@ -6,13 +6,16 @@ use std::collections::HashMap;
 use std::mem::size_of;
 use std::path::Path;
 use std::slice;
+use std::sync::Arc;
 
 use anyhow::{Context, Error, Result};
 use lazy_static::lazy_static;
 use proc_maps::{get_process_maps, MapRange};
 use remoteprocess::{Pid, ProcessMemory};
 
-use crate::binary_parser::{parse_binary, BinaryInfo};
+#[cfg(target_os = "macos")]
+use crate::binary_parser::parse_binary;
+use crate::binary_parser::{parse_binary_cached, BinaryInfo};
 use crate::config::Config;
 use crate::python_bindings::{
     pyruntime, v2_7_15, v3_10_0, v3_11_0, v3_3_7, v3_5_5, v3_6_6, v3_7_0, v3_8_0, v3_9_5,
@@ -21,13 +24,45 @@ use crate::python_interpreters::{InterpreterState, ThreadState};
 use crate::stack_trace::get_stack_traces;
 use crate::version::Version;
 
+/// Identifies the program image a process is running, so that we can tell when it calls exec()
+/// and replaces it (even with another copy of the same program). Along with the executable,
+/// this holds where the executable and the stack are mapped, which move on every exec when
+/// address space layout randomization is on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProcessImage {
+    exe: String,
+    exe_address: Option<usize>,
+    stack_address: Option<usize>,
+}
+
+impl ProcessImage {
+    pub fn new(process: &remoteprocess::Process) -> Result<ProcessImage, Error> {
+        let exe = process.exe()?;
+        let maps = get_process_maps(process.pid)?;
+        let exe_address = maps
+            .iter()
+            .filter(|map| map.filename() == Some(Path::new(&exe)))
+            .map(|map| map.start())
+            .min();
+        let stack_address = maps
+            .iter()
+            .find(|map| map.filename() == Some(Path::new("[stack]")))
+            .map(|map| map.start());
+        Ok(ProcessImage {
+            exe,
+            exe_address,
+            stack_address,
+        })
+    }
+}
+
 /// Holds information about the python process: memory map layout, parsed binary info
 /// for python /libpython etc.
 pub struct PythonProcessInfo {
-    pub python_binary: Option<BinaryInfo>,
+    pub python_binary: Option<Arc<BinaryInfo>>,
     // if python was compiled with './configure --enabled-shared', code/symbols will
     // be in a libpython.so file instead of the executable. support that.
-    pub libpython_binary: Option<BinaryInfo>,
+    pub libpython_binary: Option<Arc<BinaryInfo>>,
     pub maps: Box<dyn ContainsAddr>,
     pub python_filename: std::path::PathBuf,
     #[cfg(target_os = "linux")]
@@ -49,6 +84,13 @@ impl PythonProcessInfo {
         #[cfg(not(windows))]
         let is_python_bin = |pathname: &str| pathname == filename;
 
+        // universal binaries on OSX hold code for several architectures, figure out which one
+        // the process is running so that we parse the matching one
+        #[cfg(target_os = "macos")]
+        let cputype = get_process_cputype(process.pid);
+        #[cfg(not(target_os = "macos"))]
+        let cputype = None;
+
         // get virtual memory layout
         let maps = get_process_maps(process.pid)?;
         info!("Got virtual memory maps from pid {}:", process.pid);
@@ -100,14 +142,15 @@ impl PythonProcessInfo {
             let filename = std::path::PathBuf::from(format!("/proc/{}/exe", process.pid));
 
             // TODO: consistent types? u64 -> usize? for map.start etc
-            let python_binary = parse_binary(&filename, map.start() as u64, map.size() as u64);
+            let python_binary =
+                parse_binary_cached(&filename, map.start() as u64, map.size() as u64, cputype);
 
             // windows symbols are stored in separate files (.pdb), load
             #[cfg(windows)]
             let python_binary = python_binary.and_then(|mut pb| {
                 get_windows_python_symbols(process.pid, &filename, map.start() as u64)
                     .map(|symbols| {
-                        pb.symbols.extend(symbols);
+                        Arc::make_mut(&mut pb).symbols.extend(symbols);
                         pb
                     })
                     .map_err(|err| err.into())
@@ -117,13 +160,15 @@ impl PythonProcessInfo {
             // (which we've added to by map.start already, so undo that here)
             #[cfg(target_os = "macos")]
             let python_binary = python_binary.map(|mut pb| {
-                let offset = pb.symbols["_mh_execute_header"] - map.start() as u64;
-                for address in pb.symbols.values_mut() {
+                // this copies the binary rather than changing the cached one
+                let binary = Arc::make_mut(&mut pb);
+                let offset = binary.symbols["_mh_execute_header"] - map.start() as u64;
+                for address in binary.symbols.values_mut() {
                     *address -= offset;
                 }
 
-                if pb.bss_addr != 0 {
-                    pb.bss_addr -= offset;
+                if binary.bss_addr != 0 {
+                    binary.bss_addr -= offset;
                 }
                 pb
             });
@@ -142,28 +187,30 @@ impl PythonProcessInfo {
                 false
             });
 
-            let mut libpython_binary: Option<BinaryInfo> = None;
+            let mut libpython_binary: Option<Arc<BinaryInfo>> = None;
             if let Some(libpython) = libmap {
                 if let Some(filename) = &libpython.filename() {
                     info!("Found libpython binary @ {}", filename.display());
 
                     // on linux the process could be running in docker, access the filename through procfs
                     #[cfg(target_os = "linux")]
-                    let filename = &std::path::PathBuf::from(format!(
-                        "/proc/{}/root{}",
-                        process.pid,
-                        filename.display()
-                    ));
+                    let filename = &process_root_path(process.pid, filename);
 
                     #[allow(unused_mut)]
-                    let mut parsed =
-                        parse_binary(filename, libpython.start() as u64, libpython.size() as u64)?;
-                    #[cfg(windows)]
-                    parsed.symbols.extend(get_windows_python_symbols(
-                        process.pid,
+                    let mut parsed = parse_binary_cached(
                         filename,
                         libpython.start() as u64,
-                    )?);
+                        libpython.size() as u64,
+                        cputype,
+                    )?;
+                    #[cfg(windows)]
+                    Arc::make_mut(&mut parsed)
+                        .symbols
+                        .extend(get_windows_python_symbols(
+                            process.pid,
+                            filename,
+                            libpython.start() as u64,
+                        )?);
                     libpython_binary = Some(parsed);
                 }
             }
@@ -207,6 +254,7 @@ impl PythonProcessInfo {
                             &libpython.filename,
                             libpython.segment.vmaddr,
                             libpython.segment.vmsize,
+                            cputype,
                         )?;
 
                         // TODO: bss addr offsets returned from parsing binary are wrong
@@ -215,7 +263,7 @@ impl PythonProcessInfo {
                         // (could later tighten this up to look at segment sections too)
                         binary.bss_addr = libpython.segment.vmaddr;
                         binary.bss_size = libpython.segment.vmsize;
-                        libpython_binary = Some(binary);
+                        libpython_binary = Some(Arc::new(binary));
                     }
                 }
             }
@@ -229,6 +277,16 @@ impl PythonProcessInfo {
             _ => python_binary.ok(),
         };
 
+        for binary in python_binary.iter().chain(libpython_binary.iter()) {
+            if let Some(fallback) = binary.bss_fallback {
+                warn!(
+                    "Failed to find a non-empty .bss section in {}, scanning {} instead",
+                    binary.filename.display(),
+                    fallback
+                );
+            }
+        }
+
         #[cfg(target_os = "linux")]
         let dockerized = is_dockerized(process.pid).unwrap_or(false);
 
@@ -261,6 +319,32 @@ impl PythonProcessInfo {
         }
         None
     }
+
+    /// Detects build options of the python binary that change the layout of its structs
+    pub fn build_flags(&self) -> BuildFlags {
+        BuildFlags::from_symbols(|symbol| self.get_symbol(symbol).is_some())
+    }
+}
+
+/// Build options that change the memory layout of the python interpreter. The offsets we
+/// use to read python objects all assume a release build
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub struct BuildFlags {
+    /// Built with '--with-pydebug'
+    pub debug: bool,
+    /// Built with Py_TRACE_REFS, which adds a pair of pointers to the header of every
+    /// object (this is implied by '--with-pydebug' before python 3.8)
+    pub trace_refs: bool,
+}
+
+impl BuildFlags {
+    fn from_symbols<F: Fn(&str) -> bool>(has_symbol: F) -> BuildFlags {
+        BuildFlags {
+            // _Py_RefTotal is only defined with Py_REF_DEBUG, which debug builds always set
+            debug: has_symbol("_Py_RefTotal"),
+            trace_refs: has_symbol("_Py_PrintReferences"),
+        }
+    }
 }
 
 /// Returns the version of python running in the process.
@@ -621,6 +705,57 @@ fn is_dockerized(pid: Pid) -> Result<bool, Error> {
     Ok(self_mnt != target_mnt)
 }
 
+/// Returns the path a file in the filesystem of a process can be opened at from ours, which
+/// is different when the process is running in a container. This goes through its root
+/// directory in procfs, falling back to the original path if the file isn't found there (like
+/// when we don't have permission to read /proc/pid/root)
+#[cfg(target_os = "linux")]
+pub fn process_root_path(pid: Pid, path: &Path) -> std::path::PathBuf {
+    resolve_root_path(Path::new(&format!("/proc/{}/root", pid)), path)
+}
+
+#[cfg(target_os = "linux")]
+fn resolve_root_path(root: &Path, path: &Path) -> std::path::PathBuf {
+    let resolved = root.join(path.strip_prefix("/").unwrap_or(path));
+    if resolved.exists() {
+        resolved
+    } else {
+        path.to_owned()
+    }
+}
+
+/// Returns the mach-o cpu type of a process, which is CPU_TYPE_X86_64 for processes running
+/// under Rosetta on Apple Silicon and CPU_TYPE_ARM64 for native ones
+#[cfg(target_os = "macos")]
+fn get_process_cputype(pid: Pid) -> Option<u32> {
+    // 'sysctl.proc_cputype' is a node that takes the pid as the last element of its mib
+    let name = std::ffi::CString::new("sysctl.proc_cputype").ok()?;
+    let mut mib = [0 as libc::c_int; 4];
+    let mut mib_len = mib.len() - 1;
+    if unsafe { libc::sysctlnametomib(name.as_ptr(), mib.as_mut_ptr(), &mut mib_len) } != 0 {
+        return None;
+    }
+    mib[mib_len] = pid as libc::c_int;
+
+    let mut cputype: libc::cpu_type_t = 0;
+    let mut size = size_of::<libc::cpu_type_t>();
+    let ret = unsafe {
+        libc::sysctl(
+            mib.as_mut_ptr(),
+            (mib_len + 1) as libc::c_uint,
+            &mut cputype as *mut _ as *mut libc::c_void,
+            &mut size,
+            std::ptr::null_mut(),
+            0,
+        )
+    };
+    if ret != 0 {
+        warn!("Failed to get cputype of process {}", pid);
+        return None;
+    }
+    Some(cputype as u32)
+}
+
 // We can't use goblin to parse external symbol files (like in a separate .pdb file) on windows,
 // So use the win32 api to load up the couple of symbols we need on windows. Note:
 // we still can get export's from the PE file
@@ -692,6 +827,45 @@ pub fn is_python_framework(pathname: &str) -> bool {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_build_flags() {
+        let flags = |symbols: &[&str]| BuildFlags::from_symbols(|s| symbols.contains(&s));
+
+        // release build
+        assert_eq!(flags(&["_PyRuntime"]), BuildFlags::default());
+
+        // --with-pydebug on python 3.8+
+        let debug = flags(&["_PyRuntime", "_Py_RefTotal"]);
+        assert!(debug.debug && !debug.trace_refs);
+
+        // --with-pydebug on python 3.7, which also enables Py_TRACE_REFS
+        let debug = flags(&["_PyRuntime", "_Py_RefTotal", "_Py_PrintReferences"]);
+        assert!(debug.debug && debug.trace_refs);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_resolve_root_path() {
+        // a directory standing in for /proc/pid/root of a containerized process
+        let root = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(root.path().join("usr/lib")).unwrap();
+        std::fs::write(root.path().join("usr/lib/libpython3.11.so.1.0"), b"").unwrap();
+
+        let lib = Path::new("/usr/lib/libpython3.11.so.1.0");
+        assert_eq!(
+            resolve_root_path(root.path(), lib),
+            root.path().join("usr/lib/libpython3.11.so.1.0")
+        );
+
+        // files that aren't in the container are opened at their original path
+        let missing = Path::new("/usr/lib/libmissing.so");
+        assert_eq!(resolve_root_path(root.path(), missing), missing);
+
+        // our own root is the same as the one in procfs
+        let exe = std::env::current_exe().unwrap();
+        assert!(process_root_path(std::process::id() as Pid, &exe).exists());
+    }
+
     #[cfg(target_os = "macos")]
     #[test]
     fn test_is_python_lib() {
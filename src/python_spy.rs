@@ -1,37 +1,61 @@
 #[cfg(windows)]
 use regex::RegexBuilder;
 use std::collections::HashMap;
-#[cfg(all(target_os = "linux", unwind))]
 use std::collections::HashSet;
 #[cfg(all(target_os = "linux", unwind))]
 use std::iter::FromIterator;
 use std::path::Path;
+use std::time::{Duration, Instant};
 
 use anyhow::{Context, Error, Result};
 use remoteprocess::{Pid, Process, ProcessMemory, Tid};
 
-use crate::config::{Config, LockingStrategy};
+use crate::config::{Config, Granularity, LockingStrategy};
+use crate::memory::{BatchedMemory, MemorySource};
 #[cfg(unwind)]
 use crate::native_stack_trace::NativeStack;
 use crate::python_bindings::{
-    v2_7_15, v3_10_0, v3_11_0, v3_3_7, v3_5_5, v3_6_6, v3_7_0, v3_8_0, v3_9_5,
+    pyruntime, v2_7_15, v3_10_0, v3_11_0, v3_3_7, v3_5_5, v3_6_6, v3_7_0, v3_8_0, v3_9_5,
 };
 use crate::python_data_access::format_variable;
 use crate::python_interpreters::{InterpreterState, ThreadState};
+#[cfg(target_os = "linux")]
+use crate::python_process_info::process_root_path;
 use crate::python_process_info::{
-    get_interpreter_address, get_python_version, get_threadstate_address, PythonProcessInfo,
+    get_interpreter_address, get_python_version, get_threadstate_address, ProcessImage,
+    PythonProcessInfo,
+};
+use crate::python_threading::{thread_lookup, PythonThread};
+use crate::stack_trace::{
+    get_gil_threadid, get_stack_trace, CopyLocals, GCStats, ModuleNames, StackTrace,
 };
-use crate::python_threading::thread_name_lookup;
-use crate::stack_trace::{get_gil_threadid, get_stack_trace, StackTrace};
+use crate::thread_filter::ThreadFilter;
 use crate::version::Version;
 
+/// How many samples in a row can fail in collect_samples before giving up
+#[allow(dead_code)]
+const MAX_SAMPLING_ERRORS: usize = 20;
+
+/// Where the python interpreter is in its lifecycle
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum RuntimeState {
+    Initializing,
+    Running,
+    Finalizing,
+}
+
 /// Lets you retrieve stack traces of a running python program
 pub struct PythonSpy {
     pub pid: Pid,
     pub process: Process,
+    pub memory: MemorySource,
     pub version: Version,
+    /// The interpreter found when attaching, which is the main interpreter unless there were
+    /// already sub-interpreters. Stack traces cover every interpreter, but things like the gc
+    /// stats, thread names and deadlock detection only look at this one
     pub interpreter_address: usize,
     pub threadstate_address: usize,
+    pub runtime_address: Option<usize>,
     pub python_filename: std::path::PathBuf,
     pub version_string: String,
     pub config: Config,
@@ -39,9 +63,17 @@ pub struct PythonSpy {
     pub native: Option<NativeStack>,
     pub short_filenames: HashMap<String, Option<String>>,
     pub python_thread_ids: HashMap<u64, Tid>,
-    pub python_thread_names: HashMap<u64, String>,
+    pub python_threads: HashMap<u64, PythonThread>,
+    /// The total time the process has been paused for while getting stack traces
+    pub pause_time: Duration,
+    /// The image the process was running when this was created, if it could be read
+    pub image: Option<ProcessImage>,
     #[cfg(target_os = "linux")]
     pub dockerized: bool,
+    thread_filter: ThreadFilter,
+    module_names: Option<ModuleNames>,
+    // the address of the head of the list of interpreters in the python runtime
+    interpreter_head: Option<usize>,
 }
 
 impl PythonSpy {
@@ -50,6 +82,11 @@ impl PythonSpy {
         let process = remoteprocess::Process::new(pid)
             .context("Failed to open process - check if it is running.")?;
 
+        let memory = MemorySource::new(pid, config)?;
+
+        // read this before anything else, so that an exec while we're starting up is noticed
+        let image = ProcessImage::new(&process).ok();
+
         // get basic process information (memory maps/symbols etc)
         let python_info = PythonProcessInfo::new(&process)?;
 
@@ -59,15 +96,43 @@ impl PythonSpy {
         #[cfg(target_os = "freebsd")]
         let _lock = process.lock();
 
-        let version = get_python_version(&python_info, &process)?;
+        let version = get_python_version(&python_info, &memory)?;
         info!("python version {} detected", version);
 
-        let interpreter_address = get_interpreter_address(&python_info, &process, &version)?;
+        // debug builds can have a different layout for the structs we're reading, which would
+        // lead to reading garbage. Refuse if every object is different, and warn otherwise
+        let build_flags = python_info.build_flags();
+        if build_flags.trace_refs {
+            return Err(format_err!(
+                "Python {} was built with Py_TRACE_REFS (like debug builds of python 3.7), which isn't supported",
+                version
+            ));
+        }
+        if build_flags.debug {
+            warn!(
+                "Python {} is a debug build (--with-pydebug), stack traces may be incorrect",
+                version
+            );
+        }
+
+        let interpreter_address = get_interpreter_address(&python_info, &memory, &version)?;
         info!("Found interpreter at 0x{:016x}", interpreter_address);
 
         // lets us figure out which thread has the GIL
         let threadstate_address = get_threadstate_address(&python_info, &version, config)?;
 
+        // lets us figure out if the interpreter is still starting up or shutting down
+        let runtime_address = python_info
+            .get_symbol("_PyRuntime")
+            .map(|&addr| addr as usize);
+
+        // sub-interpreters created after we attach are added to the head of the runtime's list
+        // of interpreters, so this is read again for each sample. It's only used when it points
+        // at the interpreter we found, since that can also be found by scanning memory
+        let interpreter_head = runtime_address
+            .map(|runtime| runtime + pyruntime::get_interp_head_offset(&version))
+            .filter(|&head| memory.copy_struct::<usize>(head).ok() == Some(interpreter_address));
+
         let version_string = format!("python{}.{}", version.major, version.minor);
 
         #[cfg(unwind)]
@@ -76,17 +141,26 @@ impl PythonSpy {
                 pid,
                 python_info.python_binary,
                 python_info.libpython_binary,
+                config.demangle,
+                &config.native_exclude,
             )?)
         } else {
             None
         };
 
+        let module_names = config
+            .needs_module_names()
+            .then(|| ModuleNames::new(&version));
+
         Ok(PythonSpy {
             pid,
             process,
+            memory,
             version,
             interpreter_address,
             threadstate_address,
+            runtime_address,
+            interpreter_head,
             python_filename: python_info.python_filename,
             version_string,
             #[cfg(unwind)]
@@ -96,10 +170,23 @@ impl PythonSpy {
             config: config.clone(),
             short_filenames: HashMap::new(),
             python_thread_ids: HashMap::new(),
-            python_thread_names: HashMap::new(),
+            python_threads: HashMap::new(),
+            pause_time: Duration::ZERO,
+            image,
+            thread_filter: ThreadFilter::new(&config.only_threads, &config.exclude_threads),
+            module_names,
         })
     }
 
+    /// Whether the process has called exec() since this was created. Everything we found out
+    /// about the process (like the address of the interpreter) is invalid after this
+    pub fn exec_detected(&self) -> bool {
+        match &self.image {
+            Some(image) => ProcessImage::new(&self.process).is_ok_and(|current| current != *image),
+            None => false,
+        }
+    }
+
     /// Creates a PythonSpy object, retrying up to max_retries times.
     /// Mainly useful for the case where the process is just started and
     /// symbols or the python interpreter might not be loaded yet.
@@ -107,13 +194,21 @@ impl PythonSpy {
         let mut retries = 0;
         loop {
             let err = match PythonSpy::new(pid, config) {
-                Ok(mut process) => {
+                Ok(mut process) => match process.get_runtime_state() {
+                    // stack traces can't be trusted until the interpreter has finished starting
+                    // up, so wait for that before returning success
+                    Ok(RuntimeState::Initializing) => {
+                        format_err!("Python interpreter is still initializing")
+                    }
+                    Ok(RuntimeState::Finalizing) => {
+                        return Err(format_err!("Python interpreter is finalizing"));
+                    }
                     // verify that we can load a stack trace before returning success
-                    match process.get_stack_traces() {
+                    _ => match process.get_stack_traces() {
                         Ok(_) => return Ok(process),
                         Err(err) => err,
-                    }
-                }
+                    },
+                },
                 Err(err) => err,
             };
 
@@ -127,6 +222,109 @@ impl PythonSpy {
         }
     }
 
+    /// Collects count samples from a process, waiting interval between each one, and returns
+    /// the stack traces from each sample without aggregating them. Failing to sample is
+    /// retried until MAX_SAMPLING_ERRORS happen in a row, and processes that call exec() are
+    /// reloaded. If the process exits this returns the samples collected before that.
+    #[allow(dead_code)]
+    pub fn collect_samples(
+        pid: Pid,
+        config: &Config,
+        count: usize,
+        interval: Duration,
+    ) -> Result<Vec<Vec<StackTrace>>, Error> {
+        let mut spy = PythonSpy::retry_new(pid, config, 20)?;
+        let mut samples = Vec::with_capacity(count);
+        let mut errors = 0;
+        let mut next = Instant::now();
+        while samples.len() < count {
+            let now = Instant::now();
+            if next > now {
+                std::thread::sleep(next - now);
+            }
+            next += interval;
+
+            match spy.get_stack_traces() {
+                Ok(traces) => {
+                    samples.push(traces);
+                    errors = 0;
+                }
+                Err(e) => {
+                    if spy.process.exe().is_err() {
+                        info!("stopped sampling pid {} because the process exited", pid);
+                        break;
+                    }
+                    if spy.exec_detected() {
+                        info!("pid {} called exec(), reloading python info", pid);
+                        spy = PythonSpy::retry_new(pid, config, 20)?;
+                        continue;
+                    }
+                    errors += 1;
+                    if errors >= MAX_SAMPLING_ERRORS {
+                        return Err(e.context(format!(
+                            "Failed to sample pid {} {} times in a row",
+                            pid, errors
+                        )));
+                    }
+                    debug!("Failed to sample pid {}, retrying: {}", pid, e);
+                }
+            }
+        }
+        Ok(samples)
+    }
+
+    /// Reads the 'initialized' and 'finalizing' flags from the python runtime, to tell if
+    /// the interpreter is starting up or shutting down. Stack traces read while this is the
+    /// case are likely to be inconsistent. Returns Running if the flags can't be found.
+    pub fn get_runtime_state(&self) -> Result<RuntimeState, Error> {
+        let (runtime, (initialized, finalizing)) = match (
+            self.runtime_address,
+            pyruntime::get_lifecycle_offsets(&self.version),
+        ) {
+            (Some(runtime), Some(offsets)) => (runtime, offsets),
+            _ => return Ok(RuntimeState::Running),
+        };
+
+        let finalizing: usize = self.memory.copy_struct(runtime + finalizing)?;
+        if finalizing != 0 {
+            return Ok(RuntimeState::Finalizing);
+        }
+        let initialized: i32 = self.memory.copy_struct(runtime + initialized)?;
+        if initialized == 0 {
+            return Ok(RuntimeState::Initializing);
+        }
+        Ok(RuntimeState::Running)
+    }
+
+    /// Gets the garbage collector state of the main interpreter. This is only
+    /// available for python 3.9+, and returns None for older versions
+    pub fn get_gc_stats(&self) -> Result<Option<GCStats>, Error> {
+        match self.version {
+            Version {
+                major: 3, minor: 9, ..
+            } => self._get_gc_stats::<v3_9_5::_is>(),
+            Version {
+                major: 3,
+                minor: 10,
+                ..
+            } => self._get_gc_stats::<v3_10_0::_is>(),
+            Version {
+                major: 3,
+                minor: 11,
+                ..
+            } => self._get_gc_stats::<v3_11_0::_is>(),
+            _ => Ok(None),
+        }
+    }
+
+    fn _get_gc_stats<I: InterpreterState>(&self) -> Result<Option<GCStats>, Error> {
+        let interp: I = self
+            .memory
+            .copy_struct(self.interpreter_address)
+            .context("Failed to copy PyInterpreterState from process")?;
+        Ok(interp.gc_stats())
+    }
+
     /// Gets a StackTrace for each thread in the current process
     pub fn get_stack_traces(&mut self) -> Result<Vec<StackTrace>, Error> {
         match self.version {
@@ -198,63 +396,143 @@ impl PythonSpy {
         // activity status from the OS (otherwise each thread would report being inactive always).
         // This has the potential for race conditions (in that the thread activity could change
         // between getting the status and locking the thread, but seems unavoidable right now
-        let _lock = if self.config.blocking == LockingStrategy::Lock {
-            Some(self.process.lock().context("Failed to suspend process")?)
-        } else {
-            None
-        };
+        if self.config.blocking != LockingStrategy::Lock {
+            return self._read_stack_traces::<I>(&thread_activity);
+        }
+        // the time taken to suspend and resume the process counts towards the pause, as does
+        // the time spent on samples that fail part way through
+        let start = Instant::now();
+        let lock = self.process.lock().context("Failed to suspend process")?;
+        let traces = self._read_stack_traces::<I>(&thread_activity);
+        drop(lock);
+        self.pause_time += start.elapsed();
+        traces
+    }
 
+    // reads the stack traces of all the threads, which happens while the process is paused
+    fn _read_stack_traces<I: InterpreterState>(
+        &mut self,
+        thread_activity: &HashMap<Tid, bool>,
+    ) -> Result<Vec<StackTrace>, Error> {
         // TODO: hoist most of this code out to stack_trace.rs, and
         // then annotate the output of that with things like native stack traces etc
         //      have moved in gil / locals etc
         let gil_thread_id =
-            get_gil_threadid::<I, Process>(self.threadstate_address, &self.process)?;
+            get_gil_threadid::<I, MemorySource>(self.threadstate_address, &self.memory)?;
+
+        // Loop over all the python interpreters in the process (embedding hosts like mod_wsgi
+        // or uWSGI can run several sub-interpreters), and collect the threads in each
+        let mut python_threads = Vec::new();
+        let mut interp_address = self
+            .interpreter_head
+            .and_then(|head| self.memory.copy_struct::<usize>(head).ok())
+            .filter(|&addr| addr != 0)
+            .unwrap_or(self.interpreter_address);
+        let mut interpreter_count = 0;
+        while interp_address != 0 {
+            let interp: I = self
+                .memory
+                .copy_struct(interp_address)
+                .context("Failed to copy PyInterpreterState from process")?;
+
+            let mut threads = interp.head();
+            while !threads.is_null() {
+                let thread: I::ThreadState = self
+                    .memory
+                    .copy_pointer(threads)
+                    .context("Failed to copy PyThreadState")?;
+
+                // This seems to happen occasionally when scanning BSS addresses for valid interpreters
+                if python_threads.len() >= 4096 {
+                    return Err(format_err!("Max thread recursion depth reached"));
+                }
 
-        // Get the python interpreter, and loop over all the python threads
-        let interp: I = self
-            .process
-            .copy_struct(self.interpreter_address)
-            .context("Failed to copy PyInterpreterState from process")?;
+                let next = thread.next();
+                python_threads.push((threads, thread, interp.id()));
+                threads = next;
+            }
 
-        let mut traces = Vec::new();
-        let mut threads = interp.head();
-        while !threads.is_null() {
-            // Get the stack trace of the python thread
-            let thread = self
-                .process
-                .copy_pointer(threads)
-                .context("Failed to copy PyThreadState")?;
-            let mut trace = get_stack_trace(
-                &thread,
-                &self.process,
-                self.config.dump_locals > 0,
-                self.config.lineno,
-            )?;
+            interpreter_count += 1;
+            if interpreter_count > 4096 {
+                return Err(format_err!("Max interpreter recursion depth reached"));
+            }
+            interp_address = interp.next() as usize;
+        }
 
-            // Try getting the native thread id
+        let all_python_threads: HashSet<u64> = python_threads
+            .iter()
+            .map(|(_, thread, _)| thread.thread_id())
+            .collect();
+
+        // Get the stack trace of each python thread
+        let mut traces = Vec::new();
+        for (thread_address, thread, interpreter_id) in python_threads {
+            // Find out which thread this is before walking its stack, so that threads that
+            // aren't being sampled can be skipped over
             let python_thread_id = thread.thread_id();
 
             // python 3.11+ has the native thread id directly on the PyThreadState object,
             // for older versions of python, try using OS specific code to get the native
             // thread id (doesn't work on freebsd, or on arm/i686 processors on linux)
-            if trace.os_thread_id.is_none() {
-                let mut os_thread_id = self._get_os_thread_id(python_thread_id, &interp)?;
+            let mut os_thread_id = thread.native_thread_id();
+            if os_thread_id.is_none() {
+                let mut tid = self._get_os_thread_id(python_thread_id, &all_python_threads)?;
 
                 // linux can see issues where pthread_ids get recycled for new OS threads,
                 // which totally breaks the caching we were doing here. Detect this and retry
-                if let Some(tid) = os_thread_id {
-                    if !thread_activity.is_empty() && !thread_activity.contains_key(&tid) {
-                        info!("clearing away thread id caches, thread {} has exited", tid);
+                if let Some(id) = tid {
+                    if !thread_activity.is_empty() && !thread_activity.contains_key(&id) {
+                        info!("clearing away thread id caches, thread {} has exited", id);
                         self.python_thread_ids.clear();
-                        self.python_thread_names.clear();
-                        os_thread_id = self._get_os_thread_id(python_thread_id, &interp)?;
+                        self.python_threads.clear();
+                        tid = self._get_os_thread_id(python_thread_id, &all_python_threads)?;
                     }
                 }
+                os_thread_id = tid.map(|id| id as u64);
+            }
 
-                trace.os_thread_id = os_thread_id.map(|id| id as u64);
+            let python_thread = self._get_python_thread(python_thread_id);
+            let thread_name = python_thread
+                .name
+                .or_else(|| os_thread_id.and_then(|tid| self._get_os_thread_name(tid as Tid)));
+            if !self
+                .thread_filter
+                .includes(python_thread_id, os_thread_id, thread_name.as_deref())
+            {
+                continue;
             }
 
-            trace.thread_name = self._get_python_thread_name(python_thread_id);
+            // Walking the stack makes lots of small reads that are close to each other,
+            // so read these in batches (that are only valid for this walk)
+            let mut trace = get_stack_trace(
+                &thread,
+                &BatchedMemory::new(&self.memory, self.config.read_batch_size),
+                Self::copy_locals(&self.config),
+                self.config.lineno,
+                self.module_names.as_mut(),
+            )?;
+
+            // If the frame chain was inconsistent (which can happen when sampling while
+            // python is reallocating its frames), re-read the thread and try once more.
+            // If it's still inconsistent the trace stays flagged as incomplete
+            if trace.incomplete {
+                let thread: I::ThreadState = self
+                    .memory
+                    .copy_pointer(thread_address)
+                    .context("Failed to copy PyThreadState")?;
+                trace = get_stack_trace(
+                    &thread,
+                    &BatchedMemory::new(&self.memory, self.config.read_batch_size),
+                    Self::copy_locals(&self.config),
+                    self.config.lineno,
+                    self.module_names.as_mut(),
+                )?;
+            }
+
+            trace.os_thread_id = os_thread_id;
+            trace.thread_name = thread_name;
+            trace.daemon = python_thread.daemon;
+            trace.interpreter_id = interpreter_id;
             trace.owns_gil = trace.thread_id == gil_thread_id;
             trace.pid = self.process.pid;
 
@@ -294,10 +572,10 @@ impl PythonSpy {
             for frame in &mut trace.frames {
                 frame.short_filename = self.shorten_filename(&frame.filename);
                 if let Some(locals) = frame.locals.as_mut() {
-                    let max_length = (128 * self.config.dump_locals) as isize;
+                    let max_length = (128 * self.config.dump_locals.max(1)) as isize;
                     for local in locals {
-                        let repr = format_variable::<I, Process>(
-                            &self.process,
+                        let repr = format_variable::<I, MemorySource>(
+                            &self.memory,
                             &self.version,
                             local.addr,
                             max_length,
@@ -307,18 +585,31 @@ impl PythonSpy {
                 }
             }
 
-            traces.push(trace);
+            if self.config.granularity == Granularity::file {
+                trace.group_by_file();
+            }
 
-            // This seems to happen occasionally when scanning BSS addresses for valid interpreters
-            if traces.len() > 4096 {
-                return Err(format_err!("Max thread recursion depth reached"));
+            if self.config.gil_frames {
+                trace.add_gil_frame(self.threadstate_address != 0);
             }
 
-            threads = thread.next();
+            traces.push(trace);
         }
         Ok(traces)
     }
 
+    /// Which frames to copy local variables from. These are copied for every frame when dumping
+    /// them, and only for the selected functions when recording them
+    fn copy_locals(config: &Config) -> CopyLocals<'_> {
+        if config.dump_locals > 0 {
+            CopyLocals::All
+        } else if !config.capture_locals.is_empty() {
+            CopyLocals::Functions(&config.capture_locals)
+        } else {
+            CopyLocals::None
+        }
+    }
+
     // heuristic fallback for determining if a thread is active, used
     // when we don't have the ability to get the thread information from the OS
     fn _heuristic_is_thread_idle(&self, trace: &StackTrace) -> bool {
@@ -339,20 +630,43 @@ impl PythonSpy {
         }
     }
 
+    /// Returns the name the OS has for a thread (like from pthread_setname_np), for threads
+    /// that python doesn't have a name for. Threads start out with the name of the process,
+    /// which isn't worth showing, so this only returns names that have been changed
+    #[cfg(target_os = "linux")]
+    fn _get_os_thread_name(&self, tid: Tid) -> Option<String> {
+        let read = |path: String| {
+            std::fs::read_to_string(path)
+                .ok()
+                .map(|name| name.trim_end().to_owned())
+        };
+        let pid = self.process.pid;
+        let name = read(format!("/proc/{}/task/{}/comm", pid, tid))?;
+        if read(format!("/proc/{}/comm", pid)).as_ref() == Some(&name) {
+            return None;
+        }
+        Some(name)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn _get_os_thread_name(&self, _tid: Tid) -> Option<String> {
+        None
+    }
+
     #[cfg(windows)]
-    fn _get_os_thread_id<I: InterpreterState>(
+    fn _get_os_thread_id(
         &mut self,
         python_thread_id: u64,
-        _interp: &I,
+        _all_python_threads: &HashSet<u64>,
     ) -> Result<Option<Tid>, Error> {
         Ok(Some(python_thread_id as Tid))
     }
 
     #[cfg(target_os = "macos")]
-    fn _get_os_thread_id<I: InterpreterState>(
+    fn _get_os_thread_id(
         &mut self,
         python_thread_id: u64,
-        _interp: &I,
+        _all_python_threads: &HashSet<u64>,
     ) -> Result<Option<Tid>, Error> {
         // If we've already know this threadid, we're good
         if let Some(thread_id) = self.python_thread_ids.get(&python_thread_id) {
@@ -373,19 +687,19 @@ impl PythonSpy {
     }
 
     #[cfg(all(target_os = "linux", not(unwind)))]
-    fn _get_os_thread_id<I: InterpreterState>(
+    fn _get_os_thread_id(
         &mut self,
         _python_thread_id: u64,
-        _interp: &I,
+        _all_python_threads: &HashSet<u64>,
     ) -> Result<Option<Tid>, Error> {
         Ok(None)
     }
 
     #[cfg(all(target_os = "linux", unwind))]
-    fn _get_os_thread_id<I: InterpreterState>(
+    fn _get_os_thread_id(
         &mut self,
         python_thread_id: u64,
-        interp: &I,
+        all_python_threads: &HashSet<u64>,
     ) -> Result<Option<Tid>, Error> {
         // in nonblocking mode, we can't get the threadid reliably (method here requires reading the RBX
         // register which requires a ptrace attach). fallback to heuristic thread activity here
@@ -403,19 +717,6 @@ impl PythonSpy {
             return Ok(Some(*thread_id));
         }
 
-        // Get a list of all the python thread ids
-        let mut all_python_threads = HashSet::new();
-        let mut threads = interp.head();
-        while !threads.is_null() {
-            let thread = self
-                .process
-                .copy_pointer(threads)
-                .context("Failed to copy PyThreadState")?;
-            let current = thread.thread_id();
-            all_python_threads.insert(current);
-            threads = thread.next();
-        }
-
         let processed_os_threads: HashSet<Tid> =
             HashSet::from_iter(self.python_thread_ids.values().copied());
 
@@ -428,7 +729,7 @@ impl PythonSpy {
                 continue;
             }
 
-            match self._get_pthread_id(&unwinder, thread, &all_python_threads) {
+            match self._get_pthread_id(&unwinder, thread, all_python_threads) {
                 Ok(pthread_id) => {
                     if pthread_id != 0 {
                         self.python_thread_ids.insert(pthread_id, threadid);
@@ -491,20 +792,23 @@ impl PythonSpy {
     }
 
     #[cfg(target_os = "freebsd")]
-    fn _get_os_thread_id<I: InterpreterState>(
+    fn _get_os_thread_id(
         &mut self,
         _python_thread_id: u64,
-        _interp: &I,
+        _all_python_threads: &HashSet<u64>,
     ) -> Result<Option<Tid>, Error> {
         Ok(None)
     }
 
-    fn _get_python_thread_name(&mut self, python_thread_id: u64) -> Option<String> {
-        match self.python_thread_names.get(&python_thread_id) {
-            Some(thread_name) => Some(thread_name.clone()),
+    fn _get_python_thread(&mut self, python_thread_id: u64) -> PythonThread {
+        match self.python_threads.get(&python_thread_id) {
+            Some(thread) => thread.clone(),
             None => {
-                self.python_thread_names = thread_name_lookup(self).unwrap_or_default();
-                self.python_thread_names.get(&python_thread_id).cloned()
+                self.python_threads = thread_lookup(self).unwrap_or_default();
+                self.python_threads
+                    .get(&python_thread_id)
+                    .cloned()
+                    .unwrap_or_default()
             }
         }
     }
@@ -525,22 +829,16 @@ impl PythonSpy {
 
         // on linux the process could be running in docker, access the filename through procfs
         #[cfg(target_os = "linux")]
-        let filename_storage;
-
-        #[cfg(target_os = "linux")]
-        let filename = if self.dockerized {
-            filename_storage = format!("/proc/{}/root{}", self.pid, filename);
-            if Path::new(&filename_storage).exists() {
-                &filename_storage
-            } else {
-                filename
-            }
+        let resolved = if self.dockerized {
+            process_root_path(self.pid, Path::new(filename))
         } else {
-            filename
+            Path::new(filename).to_owned()
         };
+        #[cfg(not(target_os = "linux"))]
+        let resolved = Path::new(filename).to_owned();
 
         // only include paths that include an __init__.py
-        let mut path = Path::new(filename);
+        let mut path = resolved.as_path();
         while let Some(parent) = path.parent() {
             path = parent;
             if !parent.join("__init__.py").exists() {
@@ -549,7 +847,7 @@ impl PythonSpy {
         }
 
         // remove the parent prefix and convert to an optional string
-        let shortened = Path::new(filename)
+        let shortened = resolved
             .strip_prefix(path)
             .ok()
             .map(|p| p.to_string_lossy().to_string());
@@ -3,7 +3,7 @@ use std::collections::HashMap;
 use anyhow::Error;
 
 use crate::python_bindings::{v3_10_0, v3_11_0, v3_6_6, v3_7_0, v3_8_0, v3_9_5};
-use crate::python_data_access::{copy_long, copy_string, DictIterator, PY_TPFLAGS_MANAGED_DICT};
+use crate::python_data_access::{copy_long, copy_string, instance_dict, DictIterator};
 use crate::python_interpreters::{InterpreterState, Object, TypeObject};
 use crate::python_spy::PythonSpy;
 
@@ -11,13 +11,20 @@ use crate::version::Version;
 
 use remoteprocess::ProcessMemory;
 
-/// Returns a hashmap of threadid: threadname, by inspecting the '_active' variable in the
+/// Information about a python thread, read from its 'threading.Thread' object
+#[derive(Debug, Clone, Default)]
+pub struct PythonThread {
+    pub name: Option<String>,
+    pub daemon: Option<bool>,
+}
+
+/// Returns a hashmap of threadid: thread info, by inspecting the '_active' variable in the
 /// 'threading' module.
-pub fn thread_names_from_interpreter<I: InterpreterState, P: ProcessMemory>(
+pub fn threads_from_interpreter<I: InterpreterState, P: ProcessMemory>(
     interp: &I,
     process: &P,
     version: &Version,
-) -> Result<HashMap<u64, String>, Error> {
+) -> Result<HashMap<u64, PythonThread>, Error> {
     let mut ret = HashMap::new();
     for entry in DictIterator::from(process, version, interp.modules() as usize)? {
         let (key, value) = entry?;
@@ -35,34 +42,26 @@ pub fn thread_names_from_interpreter<I: InterpreterState, P: ProcessMemory>(
                         let (key, value) = i?;
                         let (threadid, _) = copy_long(process, key)?;
 
-                        let thread: I::Object = process.copy_struct(value)?;
-                        let thread_type = process.copy_pointer(thread.ob_type())?;
-
-                        let dict_iter = if thread_type.flags() & PY_TPFLAGS_MANAGED_DICT != 0 {
-                            DictIterator::from_managed_dict(
-                                process,
-                                version,
-                                value,
-                                thread.ob_type() as usize,
-                            )?
-                        } else {
-                            let dict_offset = thread_type.dictoffset();
-                            let dict_addr = (value as isize + dict_offset) as usize;
-                            let thread_dict_addr: usize = process.copy_struct(dict_addr)?;
-                            DictIterator::from(process, version, thread_dict_addr)?
-                        };
+                        let dict_iter = instance_dict::<I, P>(process, version, value)?
+                            .ok_or_else(|| format_err!("thread object has no __dict__"))?;
 
+                        let mut info = PythonThread::default();
                         for i in dict_iter {
                             let (key, value) = i?;
                             let varname = copy_string(key as *const I::StringObject, process)?;
 
                             if varname == "_name" {
-                                let threadname =
-                                    copy_string(value as *const I::StringObject, process)?;
-                                ret.insert(threadid as u64, threadname);
+                                info.name =
+                                    Some(copy_string(value as *const I::StringObject, process)?);
+                            } else if varname == "_daemonic" {
+                                // bools are ints, so this just reads the value of True/False
+                                info.daemon = copy_long(process, value).ok().map(|(v, _)| v != 0);
+                            }
+                            if info.name.is_some() && info.daemon.is_some() {
                                 break;
                             }
                         }
+                        ret.insert(threadid as u64, info);
                     }
                     break;
                 }
@@ -73,42 +72,43 @@ pub fn thread_names_from_interpreter<I: InterpreterState, P: ProcessMemory>(
     Ok(ret)
 }
 
-/// Returns a hashmap of threadid: threadname, by inspecting the '_active' variable in the
+/// Returns a hashmap of threadid: thread info, by inspecting the '_active' variable in the
 /// 'threading' module.
-fn _thread_name_lookup<I: InterpreterState>(
+fn _thread_lookup<I: InterpreterState>(
     spy: &PythonSpy,
-) -> Result<HashMap<u64, String>, Error> {
-    let interp: I = spy.process.copy_struct(spy.interpreter_address)?;
-    thread_names_from_interpreter(&interp, &spy.process, &spy.version)
+) -> Result<HashMap<u64, PythonThread>, Error> {
+    let interp: I = spy.memory.copy_struct(spy.interpreter_address)?;
+    threads_from_interpreter(&interp, &spy.memory, &spy.version)
 }
 
-// try getting the threadnames, but don't sweat it if we can't. Since this relies on dictionary
+// try getting the thread names, but don't sweat it if we can't. Since this relies on dictionary
 // processing we only handle py3.6+ right now, and this doesn't work at all if the
-// threading module isn't imported in the target program
-pub fn thread_name_lookup(process: &PythonSpy) -> Option<HashMap<u64, String>> {
+// threading module isn't imported in the target program. Only threads known to the
+// threading module of the main interpreter are found
+pub fn thread_lookup(process: &PythonSpy) -> Option<HashMap<u64, PythonThread>> {
     let err = match process.version {
         Version {
             major: 3, minor: 6, ..
-        } => _thread_name_lookup::<v3_6_6::_is>(process),
+        } => _thread_lookup::<v3_6_6::_is>(process),
         Version {
             major: 3, minor: 7, ..
-        } => _thread_name_lookup::<v3_7_0::_is>(process),
+        } => _thread_lookup::<v3_7_0::_is>(process),
         Version {
             major: 3, minor: 8, ..
-        } => _thread_name_lookup::<v3_8_0::_is>(process),
+        } => _thread_lookup::<v3_8_0::_is>(process),
         Version {
             major: 3, minor: 9, ..
-        } => _thread_name_lookup::<v3_9_5::_is>(process),
+        } => _thread_lookup::<v3_9_5::_is>(process),
         Version {
             major: 3,
             minor: 10,
             ..
-        } => _thread_name_lookup::<v3_10_0::_is>(process),
+        } => _thread_lookup::<v3_10_0::_is>(process),
         Version {
             major: 3,
             minor: 11,
             ..
-        } => _thread_name_lookup::<v3_11_0::_is>(process),
+        } => _thread_lookup::<v3_11_0::_is>(process),
         _ => return None,
     };
     err.ok()
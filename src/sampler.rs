@@ -1,23 +1,69 @@
 #![allow(clippy::type_complexity)]
 
-use std::collections::HashMap;
-use std::sync::mpsc::{self, Receiver, Sender};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
 use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use anyhow::Error;
 
 use remoteprocess::Pid;
+use serde_derive::Serialize;
 
 use crate::config::Config;
-use crate::python_spy::PythonSpy;
-use crate::stack_trace::{ProcessInfo, StackTrace};
+use crate::python_spy::{PythonSpy, RuntimeState};
+use crate::stack_trace::{FdStats, GCStats, ProcessInfo, StackTrace};
 use crate::timer::Timer;
 use crate::version::Version;
 
+/// Why the sampler stopped taking samples
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    /// The process exited, or every process being sampled did when sampling subprocesses
+    ProcessExited,
+    /// The python interpreter started shutting down
+    Finalizing,
+    /// The end of the sampling schedule was reached
+    ScheduleFinished,
+    /// Sampling was stopped early, like when Control-C is pressed
+    Interrupted,
+    /// The requested duration of sampling was reached
+    DurationReached,
+    /// The output hit the --max-events limit
+    MaxEvents,
+    /// The process called exec(), and the new image couldn't be sampled
+    ExecReloadFailed,
+}
+
+impl std::fmt::Display for StopReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let reason = match self {
+            StopReason::ProcessExited => "process exited",
+            StopReason::Finalizing => "the python interpreter is shutting down",
+            StopReason::ScheduleFinished => "the schedule finished",
+            StopReason::Interrupted => "Control-C pressed",
+            StopReason::DurationReached => "the duration was reached",
+            StopReason::MaxEvents => "the --max-events limit was reached",
+            StopReason::ExecReloadFailed => {
+                "the process called exec() and the new image couldn't be sampled"
+            }
+        };
+        write!(f, "{}", reason)
+    }
+}
+
 pub struct Sampler {
     pub version: Option<Version>,
+    /// Set to why the sampler stopped, once it has stopped taking samples or been interrupted
+    pub stop_reason: Arc<Mutex<Option<StopReason>>>,
+    /// The number of subprocesses that were left out because of the max_subprocesses limit,
+    /// and haven't been sampled since
+    pub skipped_subprocesses: Arc<AtomicUsize>,
+    /// Cleared to stop sampling early, like when Control-C is pressed. This stops iterating
+    /// right away, rather than waiting for the next sample to be taken
+    pub running: Arc<AtomicBool>,
     rx: Option<Receiver<Sample>>,
     sampling_thread: Option<thread::JoinHandle<()>>,
 }
@@ -25,7 +71,18 @@ pub struct Sampler {
 pub struct Sample {
     pub traces: Vec<StackTrace>,
     pub sampling_errors: Option<Vec<(Pid, Error)>>,
+    /// The number of processes stack traces were read from for this sample, including the
+    /// ones that failed
+    pub attempts: usize,
     pub late: Option<Duration>,
+    /// When the sample was taken, relative to when sampling started
+    pub timestamp: Duration,
+    /// Garbage collector state for each process, if requested in the config
+    pub gc_stats: Vec<(Pid, GCStats)>,
+    /// Open file descriptor counts for each process, if requested in the config
+    pub fd_stats: Vec<(Pid, FdStats)>,
+    /// How long each process was paused for while taking this sample
+    pub pause_times: Vec<(Pid, Duration)>,
 }
 
 impl Sampler {
@@ -45,6 +102,8 @@ impl Sampler {
             Receiver<Result<Version, Error>>,
         ) = mpsc::channel();
         let config = config.clone();
+        let stop_reason = Arc::new(Mutex::new(None));
+        let sampling_stop_reason = stop_reason.clone();
         let sampling_thread = thread::spawn(move || {
             // We need to create this object inside the thread here since PythonSpy objects don't
             // have the Send trait implemented on linux
@@ -61,8 +120,19 @@ impl Sampler {
                 }
             };
 
-            for sleep in Timer::new(spy.config.sampling_rate as f64) {
+            let start = Instant::now();
+            // the timer only runs out at the end of a schedule
+            let mut reason = StopReason::ScheduleFinished;
+            for sleep in timer(&spy.config) {
+                let timestamp = start.elapsed();
+                if is_finalizing(&spy) {
+                    reason = StopReason::Finalizing;
+                    break;
+                }
+
                 let mut sampling_errors = None;
+                let attempts = 1;
+                let pause_time = spy.pause_time;
                 let traces = match spy.get_stack_traces() {
                     Ok(traces) => traces,
                     Err(e) => {
@@ -71,31 +141,57 @@ impl Sampler {
                                 "stopped sampling pid {} because the process exited",
                                 spy.pid
                             );
+                            reason = StopReason::ProcessExited;
                             break;
                         }
+                        match reload_after_exec(&mut spy) {
+                            Ok(true) => continue,
+                            Ok(false) => {}
+                            Err(err) => {
+                                warn!("stopped sampling pid {} after exec: {}", spy.pid, err);
+                                reason = StopReason::ExecReloadFailed;
+                                break;
+                            }
+                        }
                         sampling_errors = Some(vec![(spy.pid, e)]);
                         Vec::new()
                     }
                 };
+                let gc_stats = get_gc_stats(&spy)
+                    .map(|stats| vec![(spy.pid, stats)])
+                    .unwrap_or_default();
+                let fd_stats = get_fd_stats(spy.pid, &spy.config)
+                    .map(|stats| vec![(spy.pid, stats)])
+                    .unwrap_or_default();
+                let pause_times = vec![(spy.pid, spy.pause_time - pause_time)];
 
                 let late = sleep.err();
                 if tx
                     .send(Sample {
                         traces,
                         sampling_errors,
+                        attempts,
                         late,
+                        timestamp,
+                        gc_stats,
+                        fd_stats,
+                        pause_times,
                     })
                     .is_err()
                 {
                     break;
                 }
             }
+            sampling_stop_reason.lock().unwrap().get_or_insert(reason);
         });
 
         let version = initialized_rx.recv()??;
         Ok(Sampler {
             rx: Some(rx),
             version: Some(version),
+            stop_reason,
+            running: Arc::new(AtomicBool::new(true)),
+            skipped_subprocesses: Arc::new(AtomicUsize::new(0)),
             sampling_thread: Some(sampling_thread),
         })
     }
@@ -109,9 +205,19 @@ impl Sampler {
         let mut spies = HashMap::new();
         let mut retries = 10;
         spies.insert(pid, PythonSpyThread::new(pid, None, config)?);
+        let mut skipped = HashSet::new();
 
         loop {
-            for (childpid, parentpid) in process.child_processes()? {
+            let children = process.child_processes()?;
+            // forget about skipped children that have exited since the last pass
+            skipped.retain(|pid| children.iter().any(|(childpid, _)| childpid == pid));
+            for (childpid, parentpid) in children {
+                if !spies.contains_key(&childpid) && !below_subprocess_limit(&spies, config) {
+                    skip_subprocess(&mut skipped, childpid, config);
+                    continue;
+                }
+                skipped.remove(&childpid);
+
                 // If we can't create the child process, don't worry about it
                 // can happen with zombie child processes etc
                 match PythonSpyThread::new(childpid, Some(parentpid), config) {
@@ -142,21 +248,30 @@ impl Sampler {
         }
 
         // Create a new thread to periodically monitor for new child processes, and update
-        // the procesess map
+        // the procesess map. When there is a limit on the number of subprocesses, children
+        // that are over the limit get picked up here once some of the others have exited
         let spies = Arc::new(Mutex::new(spies));
         let monitor_spies = spies.clone();
         let monitor_config = config.clone();
+        let skipped_subprocesses = Arc::new(AtomicUsize::new(skipped.len()));
+        let monitor_skipped = skipped_subprocesses.clone();
         std::thread::spawn(move || {
             while process.exe().is_ok() {
                 match monitor_spies.lock() {
                     Ok(mut spies) => {
-                        for (childpid, parentpid) in process
+                        let children = process
                             .child_processes()
-                            .expect("failed to get subprocesses")
-                        {
+                            .expect("failed to get subprocesses");
+                        skipped.retain(|pid| children.iter().any(|(childpid, _)| childpid == pid));
+                        for (childpid, parentpid) in children {
                             if spies.contains_key(&childpid) {
                                 continue;
                             }
+                            if !below_subprocess_limit(&spies, &monitor_config) {
+                                skip_subprocess(&mut skipped, childpid, &monitor_config);
+                                continue;
+                            }
+                            skipped.remove(&childpid);
                             match PythonSpyThread::new(childpid, Some(parentpid), &monitor_config) {
                                 Ok(spy) => {
                                     spies.insert(childpid, spy);
@@ -171,6 +286,7 @@ impl Sampler {
                         error!("Failed to acquire lock: {}", e);
                     }
                 }
+                monitor_skipped.store(skipped.len(), Ordering::SeqCst);
                 std::thread::sleep(Duration::from_millis(100));
             }
         });
@@ -180,10 +296,20 @@ impl Sampler {
         // Create a new thread to generate samples
         let config = config.clone();
         let (tx, rx): (Sender<Sample>, Receiver<Sample>) = mpsc::channel();
+        let stop_reason = Arc::new(Mutex::new(None));
+        let sampling_stop_reason = stop_reason.clone();
         let sampling_thread = std::thread::spawn(move || {
-            for sleep in Timer::new(config.sampling_rate as f64) {
+            let start = Instant::now();
+            // the timer only runs out at the end of a schedule
+            let mut reason = StopReason::ScheduleFinished;
+            for sleep in timer(&config) {
+                let timestamp = start.elapsed();
                 let mut traces = Vec::new();
                 let mut sampling_errors = None;
+                let mut attempts = 0;
+                let mut gc_stats = Vec::new();
+                let mut fd_stats = Vec::new();
+                let mut pause_times = Vec::new();
 
                 let mut spies = match spies.lock() {
                     Ok(current) => current,
@@ -202,8 +328,21 @@ impl Sampler {
 
                 // collect the traces from each python spy if possible
                 for spy in spies.values_mut() {
-                    match spy.collect() {
-                        Some(Ok(mut t)) => traces.append(&mut t),
+                    let collected = spy.collect();
+                    if collected.is_some() {
+                        attempts += 1;
+                    }
+                    match collected {
+                        Some(Ok((mut t, gc, paused))) => {
+                            traces.append(&mut t);
+                            pause_times.push((spy.process.pid, paused));
+                            if let Some(gc) = gc {
+                                gc_stats.push((spy.process.pid, gc));
+                            }
+                            if let Some(fds) = get_fd_stats(spy.process.pid, &config) {
+                                fd_stats.push((spy.process.pid, fds));
+                            }
+                        }
                         Some(Err(e)) => {
                             let errors = sampling_errors.get_or_insert_with(Vec::new);
                             errors.push((spy.process.pid, e));
@@ -228,7 +367,12 @@ impl Sampler {
                     .send(Sample {
                         traces,
                         sampling_errors,
+                        attempts,
                         late,
+                        timestamp,
+                        gc_stats,
+                        fd_stats,
+                        pause_times,
                     })
                     .is_err()
                 {
@@ -237,29 +381,92 @@ impl Sampler {
 
                 // If all of our spies have stopped, we're done
                 if spies.len() == 0 || spies.values().all(|x| !x.running) {
+                    reason = StopReason::ProcessExited;
                     break;
                 }
             }
+            sampling_stop_reason.lock().unwrap().get_or_insert(reason);
         });
 
         Ok(Sampler {
             rx: Some(rx),
             version: None,
+            stop_reason,
+            skipped_subprocesses,
+            running: Arc::new(AtomicBool::new(true)),
             sampling_thread: Some(sampling_thread),
         })
     }
 }
 
+/// Counts how many times stack traces were read from a process while sampling, and how many of
+/// these failed and why. Stack traces can occasionally fail to be read (like from racing with
+/// the process changing its memory), which means the samples from these are missing
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct SamplingStats {
+    pub attempts: u64,
+    pub successes: u64,
+    /// The number of failures by the underlying error
+    pub failures: BTreeMap<String, u64>,
+}
+
+impl SamplingStats {
+    pub fn new() -> SamplingStats {
+        SamplingStats::default()
+    }
+
+    pub fn add(&mut self, sample: &Sample) {
+        let errors = sample.sampling_errors.as_deref().unwrap_or_default();
+        self.attempts += sample.attempts as u64;
+        self.successes += sample.attempts.saturating_sub(errors.len()) as u64;
+        for (_, e) in errors {
+            *self.failures.entry(e.root_cause().to_string()).or_default() += 1;
+        }
+    }
+
+    /// The number of attempts that failed
+    pub fn failed(&self) -> u64 {
+        self.failures.values().sum()
+    }
+
+    /// The percentage of attempts that failed
+    pub fn failed_percent(&self) -> f64 {
+        if self.attempts == 0 {
+            return 0.0;
+        }
+        100.0 * self.failed() as f64 / self.attempts as f64
+    }
+}
+
 impl Iterator for Sampler {
     type Item = Sample;
     fn next(&mut self) -> Option<Self::Item> {
-        self.rx.as_ref().unwrap().recv().ok()
+        // poll for the next sample, since it could be a long time coming when sampling
+        // slowly or when there is a gap in the sampling schedule
+        let rx = self.rx.as_ref().unwrap();
+        while self.running.load(Ordering::SeqCst) {
+            match rx.recv_timeout(Duration::from_millis(100)) {
+                Ok(sample) => return Some(sample),
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => return None,
+            }
+        }
+        self.stop_reason
+            .lock()
+            .unwrap()
+            .get_or_insert(StopReason::Interrupted);
+        None
     }
 }
 
 impl Drop for Sampler {
     fn drop(&mut self) {
         self.rx = None;
+        // when stopped early the sampling thread could be sleeping until its next sample,
+        // don't wait for it since it will exit once it fails to send that sample
+        if !self.running.load(Ordering::SeqCst) {
+            return;
+        }
         if let Some(t) = self.sampling_thread.take() {
             t.join().unwrap();
         }
@@ -269,7 +476,7 @@ impl Drop for Sampler {
 struct PythonSpyThread {
     initialized_rx: Receiver<Result<Version, Error>>,
     notify_tx: Sender<()>,
-    sample_rx: Receiver<Result<Vec<StackTrace>, Error>>,
+    sample_rx: Receiver<Result<(Vec<StackTrace>, Option<GCStats>, Duration), Error>>,
     initialized: Option<Result<Version, Error>>,
     pub running: bool,
     notified: bool,
@@ -286,8 +493,8 @@ impl PythonSpyThread {
         ) = mpsc::channel();
         let (notify_tx, notify_rx): (Sender<()>, Receiver<()>) = mpsc::channel();
         let (sample_tx, sample_rx): (
-            Sender<Result<Vec<StackTrace>, Error>>,
-            Receiver<Result<Vec<StackTrace>, Error>>,
+            Sender<Result<(Vec<StackTrace>, Option<GCStats>, Duration), Error>>,
+            Receiver<Result<(Vec<StackTrace>, Option<GCStats>, Duration), Error>>,
         ) = mpsc::channel();
         let config = config.clone();
         let process = remoteprocess::Process::new(pid)?;
@@ -314,7 +521,11 @@ impl PythonSpyThread {
             };
 
             for _ in notify_rx.iter() {
-                let result = spy.get_stack_traces();
+                if is_finalizing(&spy) {
+                    break;
+                }
+                let pause_time = spy.pause_time;
+                let mut result = spy.get_stack_traces();
                 if result.is_err() && spy.process.exe().is_err() {
                     info!(
                         "stopped sampling pid {} because the process exited",
@@ -322,6 +533,18 @@ impl PythonSpyThread {
                     );
                     break;
                 }
+                if result.is_err() {
+                    match reload_after_exec(&mut spy) {
+                        Ok(true) => result = spy.get_stack_traces(),
+                        Ok(false) => {}
+                        Err(err) => {
+                            warn!("stopped sampling pid {} after exec: {}", spy.pid, err);
+                            break;
+                        }
+                    }
+                }
+                let result =
+                    result.map(|traces| (traces, get_gc_stats(&spy), spy.pause_time - pause_time));
                 if sample_tx.send(result).is_err() {
                     break;
                 }
@@ -340,6 +563,11 @@ impl PythonSpyThread {
         })
     }
 
+    /// Whether this is still sampling the process, or is still starting up
+    fn active(&self) -> bool {
+        self.running || self.initialized.is_none()
+    }
+
     fn wait_initialized(&mut self) -> bool {
         match self.initialized_rx.recv() {
             Ok(status) => {
@@ -388,7 +616,7 @@ impl PythonSpyThread {
         }
     }
 
-    fn collect(&mut self) -> Option<Result<Vec<StackTrace>, Error>> {
+    fn collect(&mut self) -> Option<Result<(Vec<StackTrace>, Option<GCStats>, Duration), Error>> {
         if !self.notified {
             return None;
         }
@@ -403,6 +631,114 @@ impl PythonSpyThread {
     }
 }
 
+/// Returns true if another subprocess can be sampled without going over the max_subprocesses
+/// limit. Only subprocesses that are still being sampled count towards this
+fn below_subprocess_limit(spies: &HashMap<Pid, PythonSpyThread>, config: &Config) -> bool {
+    match config.max_subprocesses {
+        Some(max) => {
+            let active = spies
+                .values()
+                .filter(|spy| spy.parent.is_some() && spy.active())
+                .count();
+            active < max
+        }
+        None => true,
+    }
+}
+
+/// Records that a subprocess isn't being sampled because it's over the max_subprocesses limit,
+/// warning the first time that happens for it
+fn skip_subprocess(skipped: &mut HashSet<Pid>, childpid: Pid, config: &Config) {
+    if skipped.insert(childpid) {
+        warn!(
+            "Not sampling process {}, since {} subprocesses are already being sampled",
+            childpid,
+            config.max_subprocesses.unwrap_or_default()
+        );
+    }
+}
+
+/// Returns true if the interpreter has started shutting down, and so should no longer be sampled
+fn is_finalizing(spy: &PythonSpy) -> bool {
+    match spy.get_runtime_state() {
+        Ok(RuntimeState::Finalizing) => {
+            info!(
+                "stopped sampling pid {} because the python interpreter is finalizing",
+                spy.pid
+            );
+            true
+        }
+        _ => false,
+    }
+}
+
+/// How long to keep trying to sample a process after it calls exec()
+const EXEC_RELOAD_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Checks whether a process that we failed to sample has called exec(), and if so replaces the
+/// spy with one for the new image so that we can keep sampling the same pid. The new image can
+/// take a while to load libpython and start the interpreter, so this keeps trying for up to
+/// EXEC_RELOAD_TIMEOUT. Returns an error if the new image can't be profiled by then (like when
+/// it isn't python)
+fn reload_after_exec(spy: &mut PythonSpy) -> Result<bool, Error> {
+    if !spy.exec_detected() {
+        return Ok(false);
+    }
+    info!("pid {} called exec(), reloading python info", spy.pid);
+    let deadline = Instant::now() + EXEC_RELOAD_TIMEOUT;
+    loop {
+        match PythonSpy::retry_new(spy.pid, &spy.config, 1) {
+            Ok(reloaded) => {
+                *spy = reloaded;
+                return Ok(true);
+            }
+            Err(e) if Instant::now() >= deadline || spy.process.exe().is_err() => return Err(e),
+            Err(e) => {
+                debug!(
+                    "Failed to reload pid {} after exec, retrying: {}",
+                    spy.pid, e
+                );
+                std::thread::sleep(Duration::from_millis(20));
+            }
+        }
+    }
+}
+
+/// Creates a timer for sampling, using the sampling schedule if one is given
+fn timer(config: &Config) -> Timer {
+    match config.schedule.as_ref() {
+        Some(schedule) => Timer::with_schedule(schedule.clone()),
+        None => Timer::new(config.sampling_rate as f64),
+    }
+}
+
+/// Reads the garbage collector state from the process, if enabled in the config
+fn get_gc_stats(spy: &PythonSpy) -> Option<GCStats> {
+    if !spy.config.gc_stats {
+        return None;
+    }
+    match spy.get_gc_stats() {
+        Ok(stats) => stats,
+        Err(e) => {
+            debug!("Failed to get gc stats from {}: {}", spy.pid, e);
+            None
+        }
+    }
+}
+
+fn get_fd_stats(pid: Pid, config: &Config) -> Option<FdStats> {
+    if !config.fd_stats {
+        return None;
+    }
+    match FdStats::read(pid) {
+        Ok(stats) => Some(stats),
+        Err(e) => {
+            debug!("Failed to get open file descriptors from {}: {}", pid, e);
+            None
+        }
+    }
+}
+
 fn get_process_info(pid: Pid, spies: &HashMap<Pid, PythonSpyThread>) -> Option<Box<ProcessInfo>> {
     spies.get(&pid).map(|spy| {
         let parent = spy
@@ -415,3 +751,48 @@ fn get_process_info(pid: Pid, spies: &HashMap<Pid, PythonSpyThread>) -> Option<B
         })
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sampling_stats() {
+        let sample = |attempts: usize, errors: Vec<Error>| Sample {
+            traces: Vec::new(),
+            sampling_errors: if errors.is_empty() {
+                None
+            } else {
+                Some(errors.into_iter().map(|e| (1, e)).collect())
+            },
+            attempts,
+            late: None,
+            timestamp: Duration::ZERO,
+            gc_stats: Vec::new(),
+            fd_stats: Vec::new(),
+            pause_times: Vec::new(),
+        };
+        let io_error = || std::io::Error::from_raw_os_error(3);
+
+        let mut stats = SamplingStats::new();
+        assert_eq!(stats.failed_percent(), 0.0);
+        stats.add(&sample(1, Vec::new()));
+        stats.add(&sample(
+            2,
+            vec![Error::new(io_error()).context("Failed to copy PyThreadState")],
+        ));
+        stats.add(&sample(1, vec![Error::new(io_error())]));
+        stats.add(&sample(
+            1,
+            vec![format_err!("Max thread recursion depth reached")],
+        ));
+
+        assert_eq!(stats.attempts, 5);
+        assert_eq!(stats.successes, 2);
+        assert_eq!(stats.failed(), 3);
+        assert_eq!(stats.failed_percent(), 60.0);
+        // failures are grouped by the underlying error, rather than the context it's in
+        assert_eq!(stats.failures[&io_error().to_string()], 2);
+        assert_eq!(stats.failures["Max thread recursion depth reached"], 1);
+    }
+}
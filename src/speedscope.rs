@@ -193,7 +193,9 @@ impl Frame {
 pub struct Stats {
     samples: HashMap<(Pid, Tid), Vec<Vec<usize>>>,
     frames: Vec<Frame>,
-    frame_to_index: HashMap<stack_trace::Frame, usize>,
+    /// Index of each frame in the shared frame table, by its merge key. Other parts
+    /// of the frame (like local variables) aren't written out, so they aren't part of the key
+    frame_to_index: HashMap<(Option<u64>, String, String, i32), usize>,
     thread_name_map: HashMap<(Pid, Tid), String>,
     config: Config,
 }
@@ -216,10 +218,8 @@ impl Stats {
             .iter()
             .map(|frame| {
                 let frames = &mut self.frames;
-                let mut key = frame.clone();
-                if !show_line_numbers {
-                    key.line = 0;
-                }
+                let (code_id, name, filename, line) = frame.merge_key(show_line_numbers);
+                let key = (code_id, name.to_owned(), filename.to_owned(), line);
                 *self.frame_to_index.entry(key).or_insert_with(|| {
                     let len = frames.len();
                     frames.push(Frame::new(frame, show_line_numbers));
@@ -288,18 +288,16 @@ mod tests {
             short_filename: None,
             line: 0,
             locals: None,
+            is_native: false,
+            frame_kind: stack_trace::FrameKind::Regular,
+            code_id: None,
         };
 
-        let trace = stack_trace::StackTrace {
-            pid: 1,
-            thread_id: 1,
-            thread_name: None,
-            os_thread_id: None,
-            active: true,
-            owns_gil: false,
-            frames: vec![frame],
-            process_info: None,
-        };
+        let trace = stack_trace::StackTrace::builder()
+            .pid(1)
+            .thread_id(1)
+            .push_frame(frame)
+            .build();
 
         stats.record(&trace).unwrap();
         stats.write(&mut cursor).unwrap();
@@ -313,4 +311,50 @@ mod tests {
         assert_eq!(trace.profiles[0].unit, ValueUnit::Seconds);
         assert_eq!(trace.profiles[0].end_value, 1.0 / sample_rate as f64);
     }
+
+    #[test]
+    fn test_speedscope_shared_frames() {
+        let frame = |line, value: &str| stack_trace::Frame {
+            name: String::from("test"),
+            filename: String::from("test.py"),
+            module: None,
+            short_filename: None,
+            line,
+            locals: Some(vec![stack_trace::LocalVariable {
+                name: String::from("x"),
+                addr: 0,
+                arg: true,
+                repr: Some(value.to_owned()),
+            }]),
+            is_native: false,
+            frame_kind: stack_trace::FrameKind::Regular,
+            code_id: None,
+        };
+        let trace = |frame| {
+            stack_trace::StackTrace::builder()
+                .pid(1)
+                .thread_id(1)
+                .push_frame(frame)
+                .build()
+        };
+
+        // frames that only differ by their local variables should share an index
+        let mut stats = Stats::new(&Config::default());
+        stats.record(&trace(frame(1, "1"))).unwrap();
+        stats.record(&trace(frame(2, "2"))).unwrap();
+        assert_eq!(stats.frames.len(), 1);
+        assert_eq!(stats.frames[0].line, None);
+
+        // but with line numbers, each line gets its own frame
+        let config = Config {
+            show_line_numbers: true,
+            ..Default::default()
+        };
+        let mut stats = Stats::new(&config);
+        stats.record(&trace(frame(1, "1"))).unwrap();
+        stats.record(&trace(frame(1, "2"))).unwrap();
+        stats.record(&trace(frame(2, "1"))).unwrap();
+        assert_eq!(stats.frames.len(), 2);
+        assert_eq!(stats.samples[&(1, 1)], vec![vec![0], vec![0], vec![1]]);
+    }
 }
@@ -1,3 +1,4 @@
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
 
 use anyhow::{Context, Error, Result};
@@ -6,10 +7,11 @@ use remoteprocess::{Pid, ProcessMemory};
 use serde_derive::Serialize;
 
 use crate::config::{Config, LineNo};
-use crate::python_data_access::{copy_bytes, copy_string};
+use crate::python_data_access::{copy_bytes, copy_module_name, copy_string};
 use crate::python_interpreters::{
     CodeObject, FrameObject, InterpreterState, ThreadState, TupleObject,
 };
+use crate::version::Version;
 
 /// Call stack for a single python thread
 #[derive(Debug, Clone, Serialize)]
@@ -18,16 +20,30 @@ pub struct StackTrace {
     pub pid: Pid,
     /// The python thread id for this stack trace
     pub thread_id: u64,
+    /// The id of the python interpreter that owns this thread (python 3.7+)
+    pub interpreter_id: Option<i64>,
     // The python thread name for this stack trace
     pub thread_name: Option<String>,
+    /// Whether this is a daemon thread, if it could be read from its 'threading.Thread' object
+    pub daemon: Option<bool>,
     /// The OS thread id for this stack tracee
     pub os_thread_id: Option<u64>,
     /// Whether or not the thread was active
     pub active: bool,
     /// Whether or not the thread held the GIL
     pub owns_gil: bool,
+    /// Whether a trace function (like from a debugger or coverage tool) was active in the
+    /// thread, which slows it down enough to skew the profile
+    pub traced: bool,
     /// The frames
     pub frames: Vec<Frame>,
+    /// Whether the frame chain was inconsistent when read (like a cycle caused by
+    /// sampling in the middle of a frame reallocation), and the frames were truncated
+    pub incomplete: bool,
+    /// The address of the outermost coroutine frame in the stack, if any. Coroutine frames
+    /// stay at the same address while suspended, so this identifies the asyncio task (or
+    /// other coroutine) the thread is running across samples
+    pub task_address: Option<usize>,
     /// process commandline / parent process info
     pub process_info: Option<Arc<ProcessInfo>>,
 }
@@ -39,16 +55,129 @@ pub struct Frame {
     pub name: String,
     /// The full filename of the file
     pub filename: String,
-    /// The module/shared library the
+    /// The python module name (from '__name__' in the frame globals), or the shared library
+    /// for native frames
     pub module: Option<String>,
     /// A short, more readable, representation of the filename
     pub short_filename: Option<String>,
     /// The line number inside the file (or 0 for native frames without line information)
     pub line: i32,
+    /// Whether this frame comes from native code, rather than python
+    pub is_native: bool,
+    /// Whether this frame is for a generator or coroutine, rather than a regular function
+    pub frame_kind: FrameKind,
+    /// The address of the code object of a python frame, which identifies its function while
+    /// the code object is alive. This is None for native and synthetic frames
+    pub code_id: Option<u64>,
     /// Local Variables associated with the frame
     pub locals: Option<Vec<LocalVariable>>,
 }
 
+impl Frame {
+    /// Makes a frame that py-spy adds to stack traces itself, like the frames for threads and
+    /// processes, which only has a name
+    pub fn synthetic(name: String) -> Frame {
+        Frame {
+            name,
+            filename: String::from(""),
+            module: None,
+            short_filename: None,
+            line: 0,
+            locals: None,
+            is_native: false,
+            frame_kind: FrameKind::Regular,
+            code_id: None,
+        }
+    }
+
+    /// Formats the frame using a template like '{file}:{line}:{func}'. The short filename
+    /// is used for '{file}' when available, '{module}' falls back to the filename when the
+    /// module name isn't known, and '{kind}' is replaced by the frame kind label (or nothing
+    /// for regular functions)
+    pub fn format_name(&self, template: &str) -> String {
+        let filename = self.short_filename.as_ref().unwrap_or(&self.filename);
+        let line = self.line.to_string();
+        expand_template(template, |placeholder| match placeholder {
+            "{file}" => Some(filename),
+            "{module}" => Some(self.module.as_ref().unwrap_or(filename)),
+            "{line}" => Some(&line),
+            "{func}" => Some(&self.name),
+            "{kind}" => Some(self.frame_kind.label().unwrap_or("")),
+            _ => None,
+        })
+    }
+
+    /// Returns where a frame came from, for telling frames apart in the outputs: 'python' for
+    /// python functions, 'native' for native code, and 'gil' for the frames added by
+    /// add_gil_frame. Other frames (like the synthetic thread and process frames, or ones
+    /// made by hand) are 'py-spy'
+    pub fn category(&self) -> &'static str {
+        if self.is_native {
+            "native"
+        } else if self.code_id.is_some() {
+            "python"
+        } else if self.filename.is_empty() && matches!(self.name.as_str(), "<idle>" | "<native>") {
+            "gil"
+        } else {
+            "py-spy"
+        }
+    }
+
+    /// Returns what identifies this frame when merging samples in the outputs: frames are the
+    /// same when they are for the same code object, function and file, and when line numbers
+    /// are shown, the same line. The line is 0 when it isn't included.
+    ///
+    /// The code object comes first, so that frames for different code objects are told apart
+    /// without comparing names (and functions that share a name and file stay separate). The
+    /// names still have to match, since an address can be reused by another code object (or
+    /// by another process)
+    pub fn merge_key(&self, include_line: bool) -> (Option<u64>, &str, &str, i32) {
+        let line = if include_line { self.line } else { 0 };
+        (self.code_id, &self.name, &self.filename, line)
+    }
+}
+
+/// The kind of python function a frame is executing, from the flags on its code object
+#[derive(Debug, Hash, Eq, PartialEq, Ord, PartialOrd, Clone, Copy, Default, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FrameKind {
+    #[default]
+    Regular,
+    Generator,
+    Coroutine,
+    AsyncGenerator,
+}
+
+impl FrameKind {
+    // CO_GENERATOR, CO_COROUTINE, CO_ITERABLE_COROUTINE and CO_ASYNC_GENERATOR from code.h
+    const GENERATOR: i32 = 0x20;
+    const COROUTINE: i32 = 0x80;
+    const ITERABLE_COROUTINE: i32 = 0x100;
+    const ASYNC_GENERATOR: i32 = 0x200;
+
+    pub fn from_code_flags(flags: i32) -> FrameKind {
+        if flags & FrameKind::ASYNC_GENERATOR != 0 {
+            FrameKind::AsyncGenerator
+        } else if flags & (FrameKind::COROUTINE | FrameKind::ITERABLE_COROUTINE) != 0 {
+            FrameKind::Coroutine
+        } else if flags & FrameKind::GENERATOR != 0 {
+            FrameKind::Generator
+        } else {
+            FrameKind::Regular
+        }
+    }
+
+    /// A short label for frames that can be suspended, or None for regular frames
+    pub fn label(&self) -> Option<&'static str> {
+        match self {
+            FrameKind::Regular => None,
+            FrameKind::Generator => Some("generator"),
+            FrameKind::Coroutine => Some("async"),
+            FrameKind::AsyncGenerator => Some("async generator"),
+        }
+    }
+}
+
 #[derive(Debug, Hash, Eq, PartialEq, Ord, PartialOrd, Clone, Serialize)]
 pub struct LocalVariable {
     pub name: String,
@@ -57,6 +186,50 @@ pub struct LocalVariable {
     pub repr: Option<String>,
 }
 
+/// Garbage collector state for a python interpreter (python 3.9+)
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Serialize)]
+pub struct GCStats {
+    /// Whether or not automatic garbage collection is enabled
+    pub enabled: bool,
+    /// The number of collections run for each generation
+    pub collections: [u64; 3],
+}
+
+/// The number of open file descriptors in a process
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Serialize)]
+pub struct FdStats {
+    /// The total number of open file descriptors
+    pub files: u64,
+    /// How many of the open file descriptors are sockets
+    pub sockets: u64,
+}
+
+impl FdStats {
+    /// Counts the open file descriptors of a process from procfs
+    #[cfg(target_os = "linux")]
+    pub fn read(pid: Pid) -> Result<FdStats, Error> {
+        let mut stats = FdStats::default();
+        for entry in std::fs::read_dir(format!("/proc/{}/fd", pid))? {
+            stats.files += 1;
+            // the file could be closed while we're looking at it, so ignore failures here
+            let is_socket = entry
+                .and_then(|entry| std::fs::read_link(entry.path()))
+                .is_ok_and(|target| target.to_string_lossy().starts_with("socket:"));
+            if is_socket {
+                stats.sockets += 1;
+            }
+        }
+        Ok(stats)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn read(_pid: Pid) -> Result<FdStats, Error> {
+        Err(format_err!(
+            "Counting open file descriptors is only supported on linux"
+        ))
+    }
+}
+
 #[derive(Debug, Clone, Serialize)]
 pub struct ProcessInfo {
     pub pid: Pid,
@@ -88,7 +261,12 @@ where
             .copy_pointer(threads)
             .context("Failed to copy PyThreadState")?;
 
-        let mut trace = get_stack_trace(&thread, process, dump_locals > 0, lineno)?;
+        let copy_locals = if dump_locals > 0 {
+            CopyLocals::All
+        } else {
+            CopyLocals::None
+        };
+        let mut trace = get_stack_trace(&thread, process, copy_locals, lineno, None)?;
         trace.owns_gil = trace.thread_id == gil_thread_id;
 
         ret.push(trace);
@@ -101,12 +279,64 @@ where
     Ok(ret)
 }
 
-/// Gets a stack trace for an individual thread
+/// Which frames get_stack_trace copies the local variables of
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CopyLocals<'a> {
+    None,
+    All,
+    /// Only the frames of functions with these names. Since this is used while sampling,
+    /// failing to copy the local variables of a frame leaves it without them rather than
+    /// returning an error
+    Functions(&'a [String]),
+}
+
+/// The most frames read from a stack. Frame chains that are longer than this are cut off here,
+/// with a '<truncated>' frame in place of the outermost frames
+const MAX_STACK_DEPTH: usize = 4096;
+
+/// The module names of python frames, looked up from '__name__' in their globals dictionaries.
+/// Most frames come from a handful of modules, so this is kept between samples and each
+/// globals dictionary is only read once
+pub struct ModuleNames {
+    version: Version,
+    names: HashMap<usize, Option<String>>,
+}
+
+impl ModuleNames {
+    pub fn new(version: &Version) -> ModuleNames {
+        ModuleNames {
+            version: version.clone(),
+            names: HashMap::new(),
+        }
+    }
+
+    fn get<I: InterpreterState, P: ProcessMemory>(
+        &mut self,
+        process: &P,
+        globals: usize,
+    ) -> Option<String> {
+        let version = &self.version;
+        self.names
+            .entry(globals)
+            .or_insert_with(|| {
+                // failing to get the module name isn't fatal, the frame just won't have one
+                copy_module_name::<I, P>(process, version, globals).unwrap_or_else(|e| {
+                    debug!("Failed to get module name from 0x{:016x}: {}", globals, e);
+                    None
+                })
+            })
+            .clone()
+    }
+}
+
+/// Gets a stack trace for an individual thread. The module names of the frames are only read
+/// when given module_names
 pub fn get_stack_trace<T, P>(
     thread: &T,
     process: &P,
-    copy_locals: bool,
+    copy_locals: CopyLocals,
     lineno: LineNo,
+    mut module_names: Option<&mut ModuleNames>,
 ) -> Result<StackTrace, Error>
 where
     T: ThreadState,
@@ -121,8 +351,32 @@ where
         frame_address = Some(process.copy_struct(addr)?);
     }
 
+    // python 3.11+ can reallocate the data stack holding the frames, and sampling during
+    // that can lead to reading an inconsistent frame chain. Track the frames we've already
+    // seen so that we can stop if the chain loops back on itself, or goes on for far longer
+    // than any real stack would
+    let mut visited = HashSet::new();
+    let mut incomplete = false;
+    let mut task_address = None;
+    let mut traced = thread.tracing();
+
     let mut frame_ptr = thread.frame(frame_address);
     while !frame_ptr.is_null() {
+        if !visited.insert(frame_ptr as usize) {
+            debug!(
+                "Cycle detected in frame chain at 0x{:016x}",
+                frame_ptr as usize
+            );
+            incomplete = true;
+            break;
+        }
+        if frames.len() >= MAX_STACK_DEPTH {
+            // reading the stack again wouldn't get any further, so this isn't incomplete
+            debug!("Frame chain is deeper than {} frames", MAX_STACK_DEPTH);
+            frames.push(Frame::synthetic(String::from("<truncated>")));
+            break;
+        }
+
         let frame = process
             .copy_pointer(frame_ptr)
             .context("Failed to copy PyFrameObject")?;
@@ -152,23 +406,41 @@ where
             },
         };
 
-        let locals = if copy_locals {
-            Some(get_locals(&code, frame_ptr, &frame, process)?)
-        } else {
-            None
+        let locals = match copy_locals {
+            CopyLocals::None => None,
+            CopyLocals::All => Some(get_locals(&code, frame_ptr, &frame, process)?),
+            CopyLocals::Functions(functions) if functions.contains(&name) => {
+                get_locals(&code, frame_ptr, &frame, process)
+                    .map_err(|e| debug!("Failed to copy locals of {}: {}", name, e))
+                    .ok()
+            }
+            CopyLocals::Functions(_) => None,
         };
 
+        let module = module_names
+            .as_mut()
+            .and_then(|names| names.get::<T::InterpreterState, P>(process, frame.globals()));
+
+        if frame.trace() != 0 {
+            traced = true;
+        }
+
+        let frame_kind = FrameKind::from_code_flags(code.flags());
+        if frame_kind == FrameKind::Coroutine {
+            task_address = Some(frame_ptr as usize);
+        }
+
         frames.push(Frame {
             name,
             filename,
             line,
             short_filename: None,
-            module: None,
+            module,
             locals,
+            is_native: false,
+            frame_kind,
+            code_id: Some(frame.code() as usize as u64),
         });
-        if frames.len() > 4096 {
-            return Err(format_err!("Max frame recursion depth reached"));
-        }
 
         frame_ptr = frame.back();
     }
@@ -176,9 +448,14 @@ where
     Ok(StackTrace {
         pid: 0,
         frames,
+        incomplete,
+        task_address,
         thread_id: thread.thread_id(),
+        interpreter_id: None,
         thread_name: None,
+        daemon: None,
         owns_gil: false,
+        traced,
         active: true,
         os_thread_id: thread.native_thread_id(),
         process_info: None,
@@ -206,6 +483,159 @@ impl StackTrace {
             None => format!("{:#X}", self.thread_id),
         }
     }
+
+    /// Replaces each frame with one for the file it's in, named after its module (or the file
+    /// when the module isn't known). Consecutive frames from the same file are merged, so that
+    /// samples are aggregated by file rather than by function
+    pub fn group_by_file(&mut self) {
+        let mut frames: Vec<Frame> = Vec::with_capacity(self.frames.len());
+        for frame in self.frames.drain(..) {
+            if frames
+                .last()
+                .is_some_and(|last| last.filename == frame.filename)
+            {
+                continue;
+            }
+            let name = match (&frame.module, &frame.short_filename) {
+                (Some(module), _) => module.clone(),
+                (None, Some(short_filename)) => short_filename.clone(),
+                (None, None) => frame.filename.clone(),
+            };
+            frames.push(Frame {
+                name,
+                line: 0,
+                locals: None,
+                frame_kind: FrameKind::Regular,
+                code_id: None,
+                ..frame
+            });
+        }
+        self.frames = frames;
+    }
+
+    /// Adds a leaf frame to threads that aren't running python bytecode, so that the time isn't
+    /// attributed to their last python frame: '<native>' for threads that are running without
+    /// holding the GIL, and '<idle>' for threads that are blocked (on the GIL, or in a system
+    /// call). When it isn't known which thread holds the GIL, only idle threads get a frame.
+    /// Threads that already have a native leaf frame (from --native) are left alone
+    pub fn add_gil_frame(&mut self, gil_known: bool) {
+        if self.owns_gil || self.frames.first().is_some_and(|frame| frame.is_native) {
+            return;
+        }
+        let name = match (self.active, gil_known) {
+            (false, _) => "<idle>",
+            (true, true) => "<native>",
+            (true, false) => return,
+        };
+        self.frames.insert(0, Frame::synthetic(name.to_owned()));
+    }
+
+    /// Returns a builder for making stack traces by hand, like in tests
+    #[allow(dead_code)]
+    pub fn builder() -> StackTraceBuilder {
+        StackTraceBuilder {
+            trace: StackTrace {
+                pid: 0,
+                thread_id: 0,
+                interpreter_id: None,
+                thread_name: None,
+                daemon: None,
+                os_thread_id: None,
+                active: true,
+                owns_gil: false,
+                traced: false,
+                frames: Vec::new(),
+                incomplete: false,
+                task_address: None,
+                process_info: None,
+            },
+        }
+    }
+}
+
+/// Fills in the '{placeholder}'s of a template with the values returned for them, leaving
+/// anything that isn't a placeholder as it is. The template is expanded in a single pass, so
+/// placeholders in the values filled in (like a function named '{line}') are left alone
+pub fn expand_template<'a>(template: &str, value: impl Fn(&str) -> Option<&'a str>) -> String {
+    let mut expanded = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        expanded.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let placeholder = rest.find('}').map(|end| &rest[..=end]).unwrap_or(rest);
+        match value(placeholder) {
+            Some(value) => {
+                expanded.push_str(value);
+                rest = &rest[placeholder.len()..];
+            }
+            // not a placeholder, so keep the brace and carry on after it
+            None => {
+                expanded.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    expanded.push_str(rest);
+    expanded
+}
+
+/// Builds up a StackTrace for an active thread that doesn't hold the GIL. Frames are pushed
+/// leaf first, the same order they are stored in, so that each frame is called by the next
+#[derive(Debug, Clone)]
+pub struct StackTraceBuilder {
+    trace: StackTrace,
+}
+
+#[allow(dead_code)]
+impl StackTraceBuilder {
+    pub fn pid(mut self, pid: Pid) -> Self {
+        self.trace.pid = pid;
+        self
+    }
+
+    pub fn thread_id(mut self, thread_id: u64) -> Self {
+        self.trace.thread_id = thread_id;
+        self
+    }
+
+    pub fn thread_name(mut self, thread_name: &str) -> Self {
+        self.trace.thread_name = Some(thread_name.to_owned());
+        self
+    }
+
+    pub fn active(mut self, active: bool) -> Self {
+        self.trace.active = active;
+        self
+    }
+
+    pub fn owns_gil(mut self, owns_gil: bool) -> Self {
+        self.trace.owns_gil = owns_gil;
+        self
+    }
+
+    /// Pushes a regular python frame for a function
+    pub fn frame(self, name: &str, filename: &str, line: i32) -> Self {
+        self.push_frame(Frame {
+            name: name.to_owned(),
+            filename: filename.to_owned(),
+            module: None,
+            short_filename: None,
+            line,
+            locals: None,
+            is_native: false,
+            frame_kind: FrameKind::Regular,
+            code_id: None,
+        })
+    }
+
+    pub fn push_frame(mut self, frame: Frame) -> Self {
+        self.trace.frames.push(frame);
+        self
+    }
+
+    pub fn build(self) -> StackTrace {
+        self.trace
+    }
 }
 
 /// Returns the line number from a PyCodeObject (given the lasti index from a PyFrameObject)
@@ -271,22 +701,15 @@ pub fn get_gil_threadid<I: InterpreterState, P: ProcessMemory>(
 
 impl ProcessInfo {
     pub fn to_frame(&self) -> Frame {
-        Frame {
-            name: format!("process {}:\"{}\"", self.pid, self.command_line),
-            filename: String::from(""),
-            module: None,
-            short_filename: None,
-            line: 0,
-            locals: None,
-        }
+        Frame::synthetic(format!("process {}:\"{}\"", self.pid, self.command_line))
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::python_bindings::v3_7_0::PyCodeObject;
-    use crate::python_data_access::tests::to_byteobject;
+    use crate::python_bindings::v3_7_0::{PyCodeObject, PyFrameObject, PyObject, PyThreadState};
+    use crate::python_data_access::tests::{to_asciiobject, to_byteobject};
     use remoteprocess::LocalProcess;
 
     #[test]
@@ -300,4 +723,240 @@ mod tests {
         let lineno = get_line_number(&code, 30, &LocalProcess).unwrap();
         assert_eq!(lineno, 7);
     }
+
+    #[test]
+    fn test_group_by_file() {
+        let frame = |name: &str, filename: &str, module: Option<&str>| Frame {
+            name: name.to_owned(),
+            filename: filename.to_owned(),
+            module: module.map(|m| m.to_owned()),
+            short_filename: None,
+            line: 10,
+            locals: None,
+            is_native: false,
+            frame_kind: FrameKind::Coroutine,
+            code_id: None,
+        };
+        let mut trace = StackTrace::builder()
+            .push_frame(frame("parse", "/app/parser.py", Some("app.parser")))
+            .push_frame(frame("parse_item", "/app/parser.py", Some("app.parser")))
+            .push_frame(frame("main", "/app/main.py", None))
+            .push_frame(frame("helper", "/app/parser.py", Some("app.parser")))
+            .build();
+        trace.group_by_file();
+
+        let names: Vec<&str> = trace.frames.iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(names, vec!["app.parser", "/app/main.py", "app.parser"]);
+        assert!(trace.frames.iter().all(|f| f.line == 0));
+        assert!(trace
+            .frames
+            .iter()
+            .all(|f| f.frame_kind == FrameKind::Regular));
+    }
+
+    #[test]
+    fn test_merge_key() {
+        let frame = |name: &str, line: i32| Frame {
+            name: name.to_owned(),
+            filename: "/app/main.py".to_owned(),
+            module: None,
+            short_filename: None,
+            line,
+            locals: None,
+            is_native: false,
+            frame_kind: FrameKind::Regular,
+            code_id: None,
+        };
+        assert_eq!(
+            frame("main", 10).merge_key(false),
+            (None, "main", "/app/main.py", 0)
+        );
+        assert_eq!(
+            frame("main", 10).merge_key(false),
+            frame("main", 12).merge_key(false)
+        );
+        assert_ne!(
+            frame("main", 10).merge_key(true),
+            frame("main", 12).merge_key(true)
+        );
+        assert_ne!(
+            frame("main", 10).merge_key(false),
+            frame("run", 10).merge_key(false)
+        );
+
+        // functions with the same name in the same file are told apart by their code objects
+        let code = |id| Frame {
+            code_id: Some(id),
+            ..frame("inner", 10)
+        };
+        assert_eq!(code(0x1000).merge_key(false), code(0x1000).merge_key(false));
+        assert_ne!(code(0x1000).merge_key(false), code(0x2000).merge_key(false));
+        assert_ne!(
+            code(0x1000).merge_key(false),
+            frame("inner", 10).merge_key(false)
+        );
+    }
+
+    #[test]
+    fn test_add_gil_frame() {
+        let trace = |active: bool, owns_gil: bool| {
+            StackTrace::builder()
+                .active(active)
+                .owns_gil(owns_gil)
+                .frame("main", "/app/main.py", 10)
+                .build()
+        };
+        let leaf = |mut trace: StackTrace, gil_known: bool| {
+            trace.add_gil_frame(gil_known);
+            trace.frames[0].name.clone()
+        };
+
+        assert_eq!(leaf(trace(true, true), true), "main");
+        assert_eq!(leaf(trace(true, false), true), "<native>");
+        assert_eq!(leaf(trace(false, false), true), "<idle>");
+        assert_eq!(leaf(trace(true, false), false), "main");
+        assert_eq!(leaf(trace(false, false), false), "<idle>");
+
+        let mut native = trace(true, false);
+        native.frames[0].is_native = true;
+        native.add_gil_frame(true);
+        assert_eq!(native.frames.len(), 1);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_fd_stats() {
+        let _socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let stats = FdStats::read(std::process::id() as Pid).unwrap();
+        // other tests can open and close files and sockets concurrently, so only check that
+        // the socket we're holding open is counted
+        assert!(stats.sockets >= 1);
+        assert!(stats.files >= stats.sockets);
+    }
+
+    #[test]
+    fn test_frame_cycle() {
+        let mut filename = to_asciiobject("test.py");
+        let mut name = to_asciiobject("loop");
+        let mut code = PyCodeObject {
+            co_filename: &mut filename.base as *mut _ as *mut PyObject,
+            co_name: &mut name.base as *mut _ as *mut PyObject,
+            ..Default::default()
+        };
+
+        // a frame whose parent is itself, like a frame chain read mid-reallocation
+        let mut frame = PyFrameObject {
+            f_code: &mut code,
+            ..Default::default()
+        };
+        frame.f_back = &mut frame;
+
+        let thread = PyThreadState {
+            frame: &mut frame,
+            ..Default::default()
+        };
+
+        let trace = get_stack_trace(
+            &thread,
+            &LocalProcess,
+            CopyLocals::None,
+            LineNo::NoLine,
+            None,
+        )
+        .unwrap();
+        assert!(trace.incomplete);
+        assert_eq!(trace.frames.len(), 1);
+        assert_eq!(trace.frames[0].name, "loop");
+    }
+
+    #[test]
+    fn test_frame_depth_limit() {
+        let mut filename = to_asciiobject("test.py");
+        let mut name = to_asciiobject("recurse");
+        let mut code = PyCodeObject {
+            co_filename: &mut filename.base as *mut _ as *mut PyObject,
+            co_name: &mut name.base as *mut _ as *mut PyObject,
+            ..Default::default()
+        };
+
+        // a chain of frames without a cycle that is deeper than any real stack
+        let mut frames: Vec<PyFrameObject> = (0..MAX_STACK_DEPTH + 10)
+            .map(|_| PyFrameObject {
+                f_code: &mut code,
+                ..Default::default()
+            })
+            .collect();
+        for i in 1..frames.len() {
+            frames[i - 1].f_back = &mut frames[i];
+        }
+
+        let thread = PyThreadState {
+            frame: &mut frames[0],
+            ..Default::default()
+        };
+
+        let trace = get_stack_trace(
+            &thread,
+            &LocalProcess,
+            CopyLocals::None,
+            LineNo::NoLine,
+            None,
+        )
+        .unwrap();
+        assert!(!trace.incomplete);
+        assert_eq!(trace.frames.len(), MAX_STACK_DEPTH + 1);
+        assert_eq!(trace.frames[MAX_STACK_DEPTH - 1].name, "recurse");
+        assert_eq!(trace.frames[MAX_STACK_DEPTH].name, "<truncated>");
+    }
+
+    #[test]
+    fn test_format_name() {
+        let frame = Frame {
+            name: "run".to_owned(),
+            filename: "/usr/lib/python3.10/site-packages/app/module.py".to_owned(),
+            module: None,
+            short_filename: Some("app/module.py".to_owned()),
+            line: 42,
+            locals: None,
+            is_native: false,
+            frame_kind: FrameKind::Regular,
+            code_id: None,
+        };
+        assert_eq!(
+            frame.format_name("{file}:{line}:{func}"),
+            "app/module.py:42:run"
+        );
+        assert_eq!(frame.format_name("{func}"), "run");
+        assert_eq!(frame.format_name("{func}{kind}"), "run");
+        assert_eq!(frame.format_name("{{func}} {other} {"), "{run} {other} {");
+
+        // values aren't expanded again
+        let frame = Frame {
+            name: "{line}".to_owned(),
+            ..frame
+        };
+        assert_eq!(frame.format_name("{func}:{line}"), "{line}:42");
+        let frame = Frame {
+            name: "run".to_owned(),
+            ..frame
+        };
+        assert_eq!(frame.format_name("{module}.{func}"), "app/module.py.run");
+
+        let frame = Frame {
+            module: Some("app.module".to_owned()),
+            ..frame
+        };
+        assert_eq!(frame.format_name("{module}.{func}"), "app.module.run");
+
+        let frame = Frame {
+            frame_kind: FrameKind::from_code_flags(0x80 | 0x3),
+            code_id: None,
+            ..frame
+        };
+        assert_eq!(frame.frame_kind, FrameKind::Coroutine);
+        assert_eq!(frame.format_name("{func} [{kind}]"), "run [async]");
+        assert_eq!(FrameKind::from_code_flags(0x20), FrameKind::Generator);
+        assert_eq!(FrameKind::from_code_flags(0x200), FrameKind::AsyncGenerator);
+        assert_eq!(FrameKind::from_code_flags(0x3), FrameKind::Regular);
+    }
 }
@@ -0,0 +1,75 @@
+/// Selects which threads of a process get sampled. Threads can be given by their OS thread id,
+/// their python thread id (in decimal, or in hex with a '0x' prefix like in 'py-spy dump'), or
+/// their name. When there are threads to include only those get sampled, and threads that are
+/// excluded never do.
+#[derive(Debug, Clone, Default)]
+pub struct ThreadFilter {
+    include: Vec<String>,
+    exclude: Vec<String>,
+}
+
+impl ThreadFilter {
+    pub fn new(include: &[String], exclude: &[String]) -> ThreadFilter {
+        ThreadFilter {
+            include: include.to_vec(),
+            exclude: exclude.to_vec(),
+        }
+    }
+
+    pub fn includes(
+        &self,
+        python_thread_id: u64,
+        os_thread_id: Option<u64>,
+        name: Option<&str>,
+    ) -> bool {
+        let matches = |threads: &[String]| {
+            threads.iter().any(|thread| match parse_thread_id(thread) {
+                Some(id) => id == python_thread_id || Some(id) == os_thread_id,
+                None => Some(thread.as_str()) == name,
+            })
+        };
+        (self.include.is_empty() || matches(&self.include)) && !matches(&self.exclude)
+    }
+}
+
+fn parse_thread_id(thread: &str) -> Option<u64> {
+    match thread
+        .strip_prefix("0x")
+        .or_else(|| thread.strip_prefix("0X"))
+    {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => thread.parse().ok(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_thread_filter() {
+        let threads = |threads: &[&str]| -> Vec<String> {
+            threads.iter().map(|thread| thread.to_string()).collect()
+        };
+
+        assert!(ThreadFilter::default().includes(1, None, None));
+
+        let filter = ThreadFilter::new(&threads(&["1234", "0x7f00", "worker"]), &[]);
+        assert!(filter.includes(1, Some(1234), None));
+        assert!(filter.includes(0x7f00, Some(1), None));
+        assert!(filter.includes(1, None, Some("worker")));
+        assert!(!filter.includes(1, Some(2), Some("MainThread")));
+        // a name that looks like an id is only matched against ids
+        assert!(!ThreadFilter::new(&threads(&["42"]), &[]).includes(1, None, Some("42")));
+
+        let filter = ThreadFilter::new(&[], &threads(&["MainThread"]));
+        assert!(!filter.includes(1, Some(1), Some("MainThread")));
+        assert!(filter.includes(2, Some(2), Some("worker")));
+        assert!(filter.includes(2, None, None));
+
+        // excluded threads win over included ones
+        let filter = ThreadFilter::new(&threads(&["1", "2"]), &threads(&["2"]));
+        assert!(filter.includes(1, None, None));
+        assert!(!filter.includes(2, None, None));
+    }
+}
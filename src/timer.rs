@@ -2,6 +2,7 @@ use std::time::{Duration, Instant};
 #[cfg(windows)]
 use winapi::um::timeapi;
 
+use anyhow::{Context, Error};
 use rand_distr::{Distribution, Exp};
 
 /// Timer is an iterator that sleeps an appropriate amount of time between iterations
@@ -13,6 +14,8 @@ pub struct Timer {
     start: Instant,
     desired: Duration,
     exp: Exp<f64>,
+    rate: f64,
+    schedule: Option<Schedule>,
 }
 
 impl Timer {
@@ -33,8 +36,18 @@ impl Timer {
             start,
             desired: Duration::from_secs(0),
             exp: Exp::new(rate).unwrap(),
+            rate,
+            schedule: None,
         }
     }
+
+    /// Creates a timer that changes the sampling rate over time, and stops
+    /// once the end of the schedule is reached
+    pub fn with_schedule(schedule: Schedule) -> Timer {
+        let mut timer = Timer::new(schedule.entries[0].rate);
+        timer.schedule = Some(schedule);
+        timer
+    }
 }
 
 impl Iterator for Timer {
@@ -43,6 +56,18 @@ impl Iterator for Timer {
     fn next(&mut self) -> Option<Self::Item> {
         let elapsed = self.start.elapsed();
 
+        if let Some(schedule) = self.schedule.as_ref() {
+            let entry = schedule.entry_at(self.desired)?;
+            // skip over any gaps in the schedule
+            if entry.start > self.desired {
+                self.desired = entry.start;
+            }
+            if entry.rate != self.rate {
+                self.rate = entry.rate;
+                self.exp = Exp::new(entry.rate).unwrap();
+            }
+        }
+
         // figure out how many nanoseconds should come between the previous and
         // the next sample using an exponential distribution to avoid aliasing
         let nanos = 1_000_000_000.0 * self.exp.sample(&mut rand::thread_rng());
@@ -62,6 +87,95 @@ impl Iterator for Timer {
     }
 }
 
+/// A single entry in a sampling schedule: sample at 'rate' times a second
+/// between 'start' and 'end'
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScheduleEntry {
+    pub start: Duration,
+    pub end: Duration,
+    pub rate: f64,
+}
+
+/// A plan for how often to sample over the course of a recording
+#[derive(Debug, Clone, PartialEq)]
+pub struct Schedule {
+    pub entries: Vec<ScheduleEntry>,
+}
+
+impl Schedule {
+    /// Loads a schedule from a file
+    pub fn load(filename: &str) -> Result<Schedule, Error> {
+        let contents = std::fs::read_to_string(filename)
+            .with_context(|| format!("Failed to read schedule file '{}'", filename))?;
+        Schedule::parse(&contents)
+    }
+
+    /// Parses a schedule, with one entry per line in the format 'start-end rate' where the
+    /// start and end times are in seconds from the start of recording. For instance, '0-5 1000'
+    /// samples 1000 times a second for the first 5 seconds. Lines starting with '#' are ignored
+    pub fn parse(contents: &str) -> Result<Schedule, Error> {
+        let mut entries: Vec<ScheduleEntry> = Vec::new();
+        for (lineno, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let entry = parse_entry(line)
+                .with_context(|| format!("Invalid schedule entry on line {}", lineno + 1))?;
+
+            if let Some(prev) = entries.last() {
+                if entry.start < prev.end {
+                    return Err(format_err!(
+                        "Schedule entry on line {} overlaps with the previous entry",
+                        lineno + 1
+                    ));
+                }
+            }
+            entries.push(entry);
+        }
+
+        if entries.is_empty() {
+            return Err(format_err!("Schedule doesn't have any entries"));
+        }
+        Ok(Schedule { entries })
+    }
+
+    /// Returns the entry that covers the time given, or the next entry if the time falls
+    /// in a gap between entries. Returns None once the schedule is over
+    pub fn entry_at(&self, time: Duration) -> Option<&ScheduleEntry> {
+        self.entries.iter().find(|entry| time < entry.end)
+    }
+}
+
+fn parse_entry(line: &str) -> Result<ScheduleEntry, Error> {
+    let (range, rate) = line
+        .split_once(char::is_whitespace)
+        .ok_or_else(|| format_err!("expected 'start-end rate'"))?;
+    let (start, end) = range
+        .split_once('-')
+        .ok_or_else(|| format_err!("expected a time range like '0-5'"))?;
+
+    let start: f64 = start.trim().parse().context("invalid start time")?;
+    let end: f64 = end.trim().parse().context("invalid end time")?;
+    let rate: f64 = rate.trim().parse().context("invalid sampling rate")?;
+
+    if !(start >= 0.0 && start < end && end.is_finite()) {
+        return Err(format_err!("invalid time range {}-{}", start, end));
+    }
+    if !(rate > 0.0 && rate.is_finite()) {
+        return Err(format_err!("sampling rate must be positive"));
+    }
+
+    let duration = |secs: f64| {
+        Duration::try_from_secs_f64(secs).map_err(|_| format_err!("time {} is out of range", secs))
+    };
+    Ok(ScheduleEntry {
+        start: duration(start)?,
+        end: duration(end)?,
+        rate,
+    })
+}
+
 impl Drop for Timer {
     fn drop(&mut self) {
         #[cfg(windows)]
@@ -70,3 +184,33 @@ impl Drop for Timer {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_schedule() {
+        let schedule = Schedule::parse("# warmup\n0-5 1000\n\n5-60 100\n").unwrap();
+        assert_eq!(schedule.entries.len(), 2);
+        assert_eq!(schedule.entries[1].start, Duration::from_secs(5));
+        assert_eq!(schedule.entries[1].rate, 100.0);
+
+        assert_eq!(
+            schedule.entry_at(Duration::from_secs(2)).unwrap().rate,
+            1000.0
+        );
+        assert_eq!(
+            schedule.entry_at(Duration::from_secs(5)).unwrap().rate,
+            100.0
+        );
+        assert!(schedule.entry_at(Duration::from_secs(60)).is_none());
+
+        assert!(Schedule::parse("").is_err());
+        assert!(Schedule::parse("5-0 100").is_err());
+        assert!(Schedule::parse("0-5 0").is_err());
+        assert!(Schedule::parse("0-5").is_err());
+        assert!(Schedule::parse("0-10 100\n5-20 10").is_err());
+        assert!(Schedule::parse("0-1e30 100").is_err());
+    }
+}
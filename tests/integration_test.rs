@@ -102,7 +102,12 @@ fn test_long_sleep() {
         }
     }
 
-    let mut runner = TestRunner::new(Config::default(), "./tests/scripts/longsleep.py");
+    // module names are only read when they're used
+    let config = Config {
+        name_format: Some("{module}.{func}".to_owned()),
+        ..Default::default()
+    };
+    let mut runner = TestRunner::new(config, "./tests/scripts/longsleep.py");
 
     let traces = runner.spy.get_stack_traces().unwrap();
     assert_eq!(traces.len(), 1);
@@ -123,6 +128,10 @@ fn test_long_sleep() {
         Some("longsleep.py".to_owned())
     );
 
+    // the module names come from the frame globals
+    assert_eq!(trace.frames[0].module, Some("__main__".to_owned()));
+    assert_eq!(trace.frames[1].module, Some("__main__".to_owned()));
+
     assert!(!traces[0].owns_gil);
 
     // we should reliably be able to detect the thread is sleeping on osx/windows
@@ -131,6 +140,50 @@ fn test_long_sleep() {
     assert!(!traces[0].active);
 }
 
+#[test]
+fn test_subinterpreters() {
+    #[cfg(target_os = "macos")]
+    {
+        // We need root permissions here to run this on OSX
+        if unsafe { libc::geteuid() } != 0 {
+            return;
+        }
+    }
+    let mut runner = TestRunner::new(Config::default(), "./tests/scripts/subinterpreter.py");
+
+    // the _xxsubinterpreters module was added in python 3.8
+    let version = &runner.spy.version;
+    if version.major != 3 || version.minor < 8 {
+        return;
+    }
+
+    let in_subinterpreter = |traces: &[py_spy::StackTrace]| {
+        traces.iter().any(|trace| {
+            trace
+                .frames
+                .iter()
+                .any(|frame| frame.name == "subinterpreter_sleep")
+        })
+    };
+
+    // the sub-interpreter is only created after we've attached
+    let traces = runner.spy.get_stack_traces().unwrap();
+    assert!(!in_subinterpreter(&traces));
+
+    let mut found = false;
+    for _ in 0..100 {
+        let traces = runner.spy.get_stack_traces().unwrap();
+        if in_subinterpreter(&traces) {
+            let ids: HashSet<Option<i64>> = traces.iter().map(|t| t.interpreter_id).collect();
+            assert_eq!(ids.len(), 2);
+            found = true;
+            break;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+    assert!(found);
+}
+
 #[test]
 fn test_thread_names() {
     #[cfg(target_os = "macos")]
@@ -159,11 +212,164 @@ fn test_thread_names() {
             .map(|trace| trace.thread_name.as_ref().unwrap().clone())
             .collect();
         assert_eq!(expected_threads, detected_threads);
+
+        // the even numbered threads are daemon threads
+        for trace in traces.iter() {
+            let name = trace.thread_name.as_ref().unwrap();
+            let daemon = name
+                .strip_prefix("CustomThreadName-")
+                .is_some_and(|n| n.parse::<u32>().unwrap() % 2 == 0);
+            assert_eq!(trace.daemon, Some(daemon));
+        }
     } else {
         for trace in traces.iter() {
             assert!(trace.thread_name.is_none());
+            assert!(trace.daemon.is_none());
+        }
+    }
+}
+
+#[test]
+fn test_thread_filter() {
+    #[cfg(target_os = "macos")]
+    {
+        // We need root permissions here to run this on OSX
+        if unsafe { libc::geteuid() } != 0 {
+            return;
+        }
+    }
+    let config = Config {
+        include_idle: true,
+        only_threads: vec!["CustomThreadName-1".to_owned(), "MainThread".to_owned()],
+        exclude_threads: vec!["MainThread".to_owned()],
+        ..Default::default()
+    };
+    let mut runner = TestRunner::new(config, "./tests/scripts/thread_names.py");
+    // thread names are only known with python 3.6+
+    if runner.spy.version.major == 3 && runner.spy.version.minor < 6 {
+        return;
+    }
+
+    let traces = runner.spy.get_stack_traces().unwrap();
+    let names: Vec<Option<&str>> = traces
+        .iter()
+        .map(|trace| trace.thread_name.as_deref())
+        .collect();
+    assert_eq!(names, vec![Some("CustomThreadName-1")]);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_os_thread_names() {
+    let config = Config {
+        include_idle: true,
+        ..Default::default()
+    };
+    let mut runner = TestRunner::new(config, "./tests/scripts/os_thread_name.py");
+
+    let traces = runner.spy.get_stack_traces().unwrap();
+    let names: Vec<Option<&str>> = traces
+        .iter()
+        .map(|trace| trace.thread_name.as_deref())
+        .collect();
+
+    // the worker thread isn't known to the threading module, so should fall back to the
+    // name it was given by the OS
+    assert!(names.contains(&Some("named-by-os")));
+}
+
+#[test]
+fn test_tracing() {
+    #[cfg(target_os = "macos")]
+    {
+        // We need root permissions here to run this on OSX
+        if unsafe { libc::geteuid() } != 0 {
+            return;
         }
     }
+    let config = Config {
+        include_idle: true,
+        ..Default::default()
+    };
+    let mut runner = TestRunner::new(config, "./tests/scripts/tracing.py");
+
+    // wait for the main thread to set its trace function
+    let mut traces = Vec::new();
+    for _ in 0..20 {
+        traces = runner.spy.get_stack_traces().unwrap();
+        if traces.iter().any(|trace| trace.traced) {
+            break;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+
+    assert_eq!(traces.len(), 2);
+    let traced: Vec<_> = traces.iter().filter(|trace| trace.traced).collect();
+    assert_eq!(traced.len(), 1);
+    assert_eq!(traced[0].frames[0].name, "traced");
+}
+
+#[test]
+fn test_collect_samples() {
+    #[cfg(target_os = "macos")]
+    {
+        // We need root permissions here to run this on OSX
+        if unsafe { libc::geteuid() } != 0 {
+            return;
+        }
+    }
+    let child = ScriptRunner::new("python", "./tests/scripts/busyloop.py");
+    std::thread::sleep(std::time::Duration::from_millis(400));
+    let interval = std::time::Duration::from_millis(10);
+    let start = std::time::Instant::now();
+    let samples = PythonSpy::collect_samples(child.id(), &Config::default(), 5, interval).unwrap();
+    assert!(start.elapsed() >= interval * 4);
+
+    assert_eq!(samples.len(), 5);
+    for traces in &samples {
+        assert!(traces
+            .iter()
+            .any(|trace| trace.frames.iter().any(|f| f.name == "busy_loop")));
+    }
+}
+
+#[test]
+fn test_exec() {
+    #[cfg(target_os = "macos")]
+    {
+        // We need root permissions here to run this on OSX
+        if unsafe { libc::geteuid() } != 0 {
+            return;
+        }
+    }
+    let config = Config {
+        include_idle: true,
+        ..Default::default()
+    };
+    let child = ScriptRunner::new("python", "./tests/scripts/exec.py");
+    std::thread::sleep(std::time::Duration::from_millis(400));
+    let sampler = py_spy::sampler::Sampler::new(child.id(), &config).unwrap();
+
+    // the script execs itself after a few seconds, we should keep sampling the new image
+    // until we get a sample from after the exec
+    let stop_reason = sampler.stop_reason.clone();
+    let mut before_exec = false;
+    let mut after_exec = false;
+    for sample in sampler {
+        for frame in sample.traces.iter().flat_map(|trace| trace.frames.iter()) {
+            before_exec |= frame.name == "before_exec";
+            after_exec |= frame.name == "after_exec";
+        }
+        if after_exec || sample.timestamp > std::time::Duration::from_secs(60) {
+            break;
+        }
+    }
+    assert!(before_exec);
+    assert!(
+        after_exec,
+        "stopped sampling: {:?}",
+        stop_reason.lock().unwrap()
+    );
 }
 
 #[test]
@@ -198,6 +404,35 @@ fn test_recursive() {
     }
 }
 
+#[test]
+fn test_deep_recursion() {
+    #[cfg(target_os = "macos")]
+    {
+        // We need root permissions here to run this on OSX
+        if unsafe { libc::geteuid() } != 0 {
+            return;
+        }
+    }
+
+    // stacks that are too deep to read all of are cut off, rather than being treated as
+    // a bad read that should be retried
+    let mut runner = TestRunner::new(Config::default(), "./tests/scripts/deep_recursion.py");
+    let mut trace = runner.spy.get_stack_traces().unwrap().remove(0);
+    for _ in 0..100 {
+        if trace.frames[0].name == "recurse" && trace.frames.len() > 4096 {
+            break;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        trace = runner.spy.get_stack_traces().unwrap().remove(0);
+    }
+    assert!(!trace.incomplete);
+    assert_eq!(trace.frames.len(), 4097);
+    assert!(trace.frames[..4096]
+        .iter()
+        .all(|frame| frame.name == "recurse"));
+    assert_eq!(trace.frames[4096].name, "<truncated>");
+}
+
 #[test]
 fn test_unicode() {
     #[cfg(target_os = "macos")]
@@ -229,6 +464,122 @@ fn test_unicode() {
     assert!(!traces[0].owns_gil);
 }
 
+#[test]
+fn test_unicode_kinds() {
+    #[cfg(target_os = "macos")]
+    {
+        if unsafe { libc::geteuid() } != 0 {
+            return;
+        }
+    }
+    // python stores the filename with 2 bytes per character, and the function name with 1
+    let mut runner = TestRunner::new(Config::default(), "./tests/scripts/ünïcödé_путь.py");
+
+    let traces = runner.spy.get_stack_traces().unwrap();
+    assert_eq!(traces.len(), 1);
+    let trace = &traces[0];
+
+    assert_eq!(trace.frames[0].name, "función");
+    assert!(trace.frames[0].filename.ends_with("ünïcödé_путь.py"));
+    assert_eq!(
+        trace.frames[0].short_filename,
+        Some("ünïcödé_путь.py".to_owned())
+    );
+    assert_eq!(trace.frames[1].name, "<module>");
+}
+
+#[test]
+fn test_deadlock() {
+    #[cfg(target_os = "macos")]
+    {
+        // We need root permissions here to run this on OSX
+        if unsafe { libc::geteuid() } != 0 {
+            return;
+        }
+    }
+
+    let config = Config {
+        dump_locals: 1,
+        dump_deadlock: true,
+        include_idle: true,
+        ..Default::default()
+    };
+    let mut runner = TestRunner::new(config, "./tests/scripts/deadlock.py");
+
+    // deadlock detection relies on reading dictionaries, which is only supported for 3.6+
+    let version = &runner.spy.version;
+    if version.major != 3 || version.minor < 6 || version.minor > 11 {
+        return;
+    }
+
+    // wait for the threads to finish taking their first lock
+    let mut cycles = Vec::new();
+    for _ in 0..20 {
+        let traces = runner.spy.get_stack_traces().unwrap();
+        cycles = py_spy::deadlock::find_deadlocks(&runner.spy, &traces)
+            .unwrap()
+            .cycles;
+        if cycles.len() == 2 {
+            break;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+    assert_eq!(cycles.len(), 2);
+
+    let mut names: Vec<String> = cycles
+        .iter()
+        .map(|cycle| {
+            let mut names: Vec<&str> = cycle.iter().map(|(_, lock)| lock.name.as_str()).collect();
+            names.sort();
+            names.join(",")
+        })
+        .collect();
+    names.sort();
+    assert_eq!(names, vec!["a,b", "second.lock,second.lock"]);
+}
+
+#[test]
+fn test_deadlock_idle() {
+    #[cfg(target_os = "macos")]
+    {
+        // We need root permissions here to run this on OSX
+        if unsafe { libc::geteuid() } != 0 {
+            return;
+        }
+    }
+
+    let config = Config {
+        dump_locals: 1,
+        dump_deadlock: true,
+        include_idle: true,
+        ..Default::default()
+    };
+    let mut runner = TestRunner::new(config, "./tests/scripts/deadlock_idle.py");
+
+    let version = &runner.spy.version;
+    if version.major != 3 || version.minor < 6 || version.minor > 11 {
+        return;
+    }
+
+    // two sleeping threads that each hold one of the locks of the same object reference
+    // both locks through 'self', but aren't waiting on each other
+    let mut holding = 0;
+    for _ in 0..50 {
+        let traces = runner.spy.get_stack_traces().unwrap();
+        holding = traces
+            .iter()
+            .filter(|trace| !trace.active && trace.frames[0].name == "hold")
+            .count();
+        if holding == 2 {
+            let report = py_spy::deadlock::find_deadlocks(&runner.spy, &traces).unwrap();
+            assert!(report.cycles.is_empty());
+            break;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+    assert_eq!(holding, 2);
+}
+
 #[test]
 fn test_local_vars() {
     #[cfg(target_os = "macos")]
@@ -307,6 +658,36 @@ fn test_local_vars() {
     }
 }
 
+#[test]
+fn test_capture_locals() {
+    #[cfg(target_os = "macos")]
+    {
+        // We need root permissions here to run this on OSX
+        if unsafe { libc::geteuid() } != 0 {
+            return;
+        }
+    }
+
+    let config = Config {
+        capture_locals: vec!["local_variable_lookup".to_owned()],
+        ..Default::default()
+    };
+    let mut runner = TestRunner::new(config, "./tests/scripts/local_vars.py");
+
+    let traces = runner.spy.get_stack_traces().unwrap();
+    assert_eq!(traces.len(), 1);
+    let frames = &traces[0].frames;
+    assert_eq!(frames.len(), 2);
+
+    // only the frames of the selected functions have their locals copied
+    assert_eq!(frames[0].name, "local_variable_lookup");
+    assert!(frames[1].locals.is_none());
+    let locals = frames[0].locals.as_ref().unwrap();
+    assert_eq!(locals.len(), 9);
+    assert_eq!(locals[0].repr, Some("\"foo\"".to_owned()));
+    assert_eq!(locals[5].repr, Some("123456789123456789".to_owned()));
+}
+
 #[cfg(not(target_os = "freebsd"))]
 #[test]
 fn test_subprocesses() {
@@ -423,3 +804,28 @@ fn test_delayed_subprocess() {
         break;
     }
 }
+
+#[test]
+fn test_check_process() {
+    #[cfg(target_os = "macos")]
+    {
+        // We need root permissions here to run this on OSX
+        if unsafe { libc::geteuid() } != 0 {
+            return;
+        }
+    }
+    let config = Config::default();
+    let runner = ScriptRunner::new("python", "./tests/scripts/busyloop.py");
+    std::thread::sleep(std::time::Duration::from_millis(400));
+    let report = py_spy::check::check_process(runner.id(), &config).unwrap();
+    assert!(report.ok(), "{:?}", report.error);
+    assert!(report.version.is_some());
+    assert!(report.interpreter_address.is_some());
+
+    // not a python process, so there is no interpreter to find
+    let runner = ScriptRunner::new("sleep", "10");
+    std::thread::sleep(std::time::Duration::from_millis(100));
+    let report = py_spy::check::check_process(runner.id(), &config).unwrap();
+    assert!(!report.ok());
+    assert!(report.interpreter_address.is_none());
+}
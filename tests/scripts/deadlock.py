@@ -0,0 +1,40 @@
+import threading
+import time
+
+a = threading.RLock()
+b = threading.RLock()
+
+
+class Account:
+    def __init__(self):
+        self.lock = threading.RLock()
+
+
+def transfer(first, second):
+    with first.lock:
+        time.sleep(0.2)
+        with second.lock:
+            pass
+
+
+def forwards():
+    with a:
+        time.sleep(0.2)
+        with b:
+            pass
+
+
+def backwards():
+    with b:
+        time.sleep(0.2)
+        with a:
+            pass
+
+
+x, y = Account(), Account()
+threads = [threading.Thread(target=f, args=args) for f, args in
+           [(forwards, ()), (backwards, ()), (transfer, (x, y)), (transfer, (y, x))]]
+for t in threads:
+    t.start()
+for t in threads:
+    t.join()
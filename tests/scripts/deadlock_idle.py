@@ -0,0 +1,22 @@
+import threading
+import time
+
+
+class Worker:
+    def __init__(self):
+        self.first = threading.RLock()
+        self.second = threading.RLock()
+
+    def hold(self, lock):
+        # each thread holds one of the locks and sleeps, so neither is waiting on the other
+        with lock:
+            time.sleep(1000)
+
+
+worker = Worker()
+threads = [threading.Thread(target=worker.hold, args=(lock,))
+           for lock in (worker.first, worker.second)]
+for t in threads:
+    t.start()
+for t in threads:
+    t.join()
@@ -0,0 +1,13 @@
+import sys
+import time
+
+
+def recurse(depth):
+    if depth == 0:
+        time.sleep(1000)
+    recurse(depth - 1)
+
+
+# deeper than the most frames py-spy reads from a stack
+sys.setrecursionlimit(10000)
+recurse(5000)
@@ -0,0 +1,19 @@
+import os
+import sys
+import time
+
+
+def before_exec():
+    time.sleep(3)
+
+
+def after_exec():
+    time.sleep(10000)
+
+
+if __name__ == "__main__":
+    if len(sys.argv) > 1:
+        after_exec()
+    else:
+        before_exec()
+        os.execv(sys.executable, [sys.executable, __file__, "execd"])
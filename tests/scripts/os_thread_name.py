@@ -0,0 +1,15 @@
+import _thread
+import ctypes
+import time
+
+
+def worker():
+    # name the OS thread, on a thread that python's threading module doesn't know about
+    PR_SET_NAME = 15
+    ctypes.CDLL(None).prctl(PR_SET_NAME, b"named-by-os", 0, 0, 0)
+    time.sleep(10000)
+
+
+if __name__ == "__main__":
+    _thread.start_new_thread(worker, ())
+    time.sleep(10000)
@@ -0,0 +1,23 @@
+import threading
+import time
+
+import _xxsubinterpreters as interpreters
+
+
+def run_subinterpreter():
+    interp = interpreters.create()
+    interpreters.run_string(
+        interp,
+        "import time\n"
+        "def subinterpreter_sleep():\n"
+        "    time.sleep(100)\n"
+        "subinterpreter_sleep()\n",
+    )
+
+
+if __name__ == "__main__":
+    # give py-spy time to attach before the sub-interpreter is created
+    time.sleep(2)
+    thread = threading.Thread(target=run_subinterpreter)
+    thread.start()
+    thread.join()
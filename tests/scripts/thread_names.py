@@ -6,6 +6,7 @@ def main():
     for i in range(10):
         th = threading.Thread(target = lambda: time.sleep(10000))
         th.name = "CustomThreadName-%s" % i
+        th.daemon = i % 2 == 0
         th.start()
     time.sleep(10000)
 
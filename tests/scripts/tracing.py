@@ -0,0 +1,24 @@
+import sys
+import threading
+import time
+
+
+def trace(frame, event, arg):
+    return trace
+
+
+def traced():
+    time.sleep(10000)
+
+
+def main():
+    # threads started before the trace function is set aren't traced
+    thread = threading.Thread(target=lambda: time.sleep(10000))
+    thread.daemon = True
+    thread.start()
+    sys.settrace(trace)
+    traced()
+
+
+if __name__ == "__main__":
+    main()
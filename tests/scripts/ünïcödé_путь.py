@@ -0,0 +1,9 @@
+#!/env/bin/python
+# -*- coding: utf-8 -*-
+import time
+
+def función(seconds):
+    time.sleep(seconds)
+
+if __name__ == "__main__":
+    función(100)